@@ -115,9 +115,15 @@ pub trait StreamMuxer {
 
 /// An event produced by a [`StreamMuxer`].
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum StreamMuxerEvent {
     /// The address of the remote has changed.
     AddressChange(Multiaddr),
+    /// The remote half-closed one of the streams, i.e. it will not write to it anymore.
+    ///
+    /// The stream can still be written to. Reading from it returns EOF once all buffered data
+    /// has been consumed. Muxers that don't track half-closed streams never emit this event.
+    StreamHalfClosed,
 }
 
 /// Extension trait for [`StreamMuxer`].
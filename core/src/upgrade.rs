@@ -67,6 +67,7 @@ mod select;
 pub(crate) use apply::{
     apply, apply_inbound, apply_outbound, InboundUpgradeApply, OutboundUpgradeApply,
 };
+pub use error::NegotiationFailure;
 pub(crate) use error::UpgradeError;
 use futures::future::Future;
 pub use multistream_select::{NegotiatedComplete, NegotiationError, ProtocolError, Version};
@@ -18,9 +18,9 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use std::fmt;
+use std::{fmt, io};
 
-use multistream_select::NegotiationError;
+use multistream_select::{NegotiationError, ProtocolError};
 
 /// Error that can happen when upgrading a connection or substream to use a protocol.
 #[derive(Debug)]
@@ -48,6 +48,36 @@ impl<E> UpgradeError<E> {
     {
         self.map_err(Into::into)
     }
+
+    /// Classifies why the upgrade failed, without matching on the nested negotiation errors.
+    pub fn classify(self) -> NegotiationFailure<E> {
+        match self {
+            UpgradeError::Select(NegotiationError::Failed) => NegotiationFailure::NoCommonProtocol,
+            UpgradeError::Select(NegotiationError::ProtocolError(ProtocolError::IoError(e))) => {
+                NegotiationFailure::Io(e)
+            }
+            UpgradeError::Select(NegotiationError::ProtocolError(e)) => {
+                NegotiationFailure::ProtocolError(e)
+            }
+            UpgradeError::Apply(e) => NegotiationFailure::ApplyFailed(e),
+        }
+    }
+}
+
+/// Why an upgrade failed, as returned by `UpgradeError::classify` and by
+/// `StreamUpgradeError::classify` of `libp2p-swarm`.
+#[derive(Debug)]
+pub enum NegotiationFailure<E> {
+    /// No protocol could be agreed upon with the remote.
+    NoCommonProtocol,
+    /// The remote violated the multistream-select protocol.
+    ///
+    /// Never [`ProtocolError::IoError`], which is classified as [`NegotiationFailure::Io`].
+    ProtocolError(ProtocolError),
+    /// An I/O error occurred during the negotiation.
+    Io(io::Error),
+    /// A protocol was agreed upon, but the post-negotiation handshake failed.
+    ApplyFailed(E),
 }
 
 impl<E> fmt::Display for UpgradeError<E>
@@ -79,3 +109,32 @@ impl<E> From<NegotiationError> for UpgradeError<E> {
         UpgradeError::Select(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_each_underlying_error() {
+        assert!(matches!(
+            UpgradeError::<()>::Select(NegotiationError::Failed).classify(),
+            NegotiationFailure::NoCommonProtocol
+        ));
+        assert!(matches!(
+            UpgradeError::<()>::Select(NegotiationError::ProtocolError(
+                ProtocolError::InvalidMessage
+            ))
+            .classify(),
+            NegotiationFailure::ProtocolError(ProtocolError::InvalidMessage)
+        ));
+        assert!(matches!(
+            UpgradeError::<()>::Select(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+                .classify(),
+            NegotiationFailure::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        assert!(matches!(
+            UpgradeError::Apply("handshake failed").classify(),
+            NegotiationFailure::ApplyFailed("handshake failed")
+        ));
+    }
+}
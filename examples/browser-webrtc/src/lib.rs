@@ -54,7 +54,7 @@ pub async fn run(libp2p_endpoint: String) -> Result<(), JsError> {
             } => {
                 tracing::info!("Swarm event: {:?}", cause);
 
-                if let libp2p::swarm::ConnectionError::KeepAliveTimeout = cause {
+                if let libp2p::swarm::ConnectionError::KeepAliveTimeout { .. } = cause {
                     body.append_p("All done with pinging! ")?;
 
                     break;
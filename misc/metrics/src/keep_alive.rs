@@ -0,0 +1,98 @@
+use libp2p_swarm::ShutdownReason;
+use prometheus_client::{
+    encoding::{EncodeLabelSet, EncodeLabelValue},
+    metrics::{counter::Counter, family::Family},
+    registry::Registry,
+};
+
+/// Exports the keep-alive decisions and shutdowns of connections to Prometheus.
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use libp2p_identity::PeerId;
+/// use libp2p_metrics::{KeepAliveMetrics, Registry};
+///
+/// let mut registry = Registry::default();
+/// let builder = libp2p_swarm::ConnectionBuilder::new(PeerId::random())
+///     .keep_alive_metrics(Arc::new(KeepAliveMetrics::new(&mut registry)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct KeepAliveMetrics {
+    decisions: Family<DecisionLabels, Counter>,
+    shutdowns: Family<ShutdownLabels, Counter>,
+}
+
+impl KeepAliveMetrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let sub_registry = registry
+            .sub_registry_with_prefix("libp2p")
+            .sub_registry_with_prefix("swarm");
+
+        let decisions = Family::default();
+        sub_registry.register(
+            "connection_keep_alive_decisions",
+            "Number of changes of the keep-alive decision of idle connections",
+            decisions.clone(),
+        );
+
+        let shutdowns = Family::default();
+        sub_registry.register(
+            "connection_shutdowns",
+            "Number of connection shutdowns by reason",
+            shutdowns.clone(),
+        );
+
+        Self {
+            decisions,
+            shutdowns,
+        }
+    }
+}
+
+impl libp2p_swarm::KeepAliveMetrics for KeepAliveMetrics {
+    fn on_keep_alive(&self, keep_alive: bool) {
+        let decision = if keep_alive {
+            Decision::KeepAlive
+        } else {
+            Decision::Idle
+        };
+        self.decisions
+            .get_or_create(&DecisionLabels { decision })
+            .inc();
+    }
+
+    fn on_shutdown(&self, reason: ShutdownReason) {
+        let reason = match reason {
+            ShutdownReason::KeepAliveTimeout => Reason::KeepAliveTimeout,
+            ShutdownReason::Graceful => Reason::Graceful,
+            _ => Reason::Error,
+        };
+        self.shutdowns
+            .get_or_create(&ShutdownLabels { reason })
+            .inc();
+    }
+}
+
+#[derive(EncodeLabelSet, Hash, Clone, Eq, PartialEq, Debug)]
+struct DecisionLabels {
+    decision: Decision,
+}
+
+#[derive(EncodeLabelValue, Hash, Clone, Eq, PartialEq, Debug)]
+enum Decision {
+    KeepAlive,
+    Idle,
+}
+
+#[derive(EncodeLabelSet, Hash, Clone, Eq, PartialEq, Debug)]
+struct ShutdownLabels {
+    reason: Reason,
+}
+
+#[derive(EncodeLabelValue, Hash, Clone, Eq, PartialEq, Debug)]
+enum Reason {
+    KeepAliveTimeout,
+    Graceful,
+    Error,
+}
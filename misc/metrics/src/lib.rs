@@ -36,6 +36,7 @@ mod gossipsub;
 mod identify;
 #[cfg(feature = "kad")]
 mod kad;
+mod keep_alive;
 #[cfg(feature = "ping")]
 mod ping;
 mod protocol_stack;
@@ -44,6 +45,7 @@ mod relay;
 mod swarm;
 
 pub use bandwidth::Transport as BandwidthTransport;
+pub use keep_alive::KeepAliveMetrics;
 pub use prometheus_client::registry::Registry;
 
 /// Set of Swarm and protocol metrics derived from emitted events.
@@ -366,13 +366,23 @@ struct ConnectionClosedLabels {
 enum ConnectionError {
     Io,
     KeepAliveTimeout,
+    NegotiationDeadline,
+    Silent,
+    HandlerPanic,
 }
 
 impl From<&libp2p_swarm::ConnectionError> for ConnectionError {
     fn from(value: &libp2p_swarm::ConnectionError) -> Self {
         match value {
             libp2p_swarm::ConnectionError::IO(_) => ConnectionError::Io,
-            libp2p_swarm::ConnectionError::KeepAliveTimeout => ConnectionError::KeepAliveTimeout,
+            libp2p_swarm::ConnectionError::KeepAliveTimeout { .. } => {
+                ConnectionError::KeepAliveTimeout
+            }
+            libp2p_swarm::ConnectionError::NegotiationDeadline { .. } => {
+                ConnectionError::NegotiationDeadline
+            }
+            libp2p_swarm::ConnectionError::Silent { .. } => ConnectionError::Silent,
+            libp2p_swarm::ConnectionError::HandlerPanic { .. } => ConnectionError::HandlerPanic,
         }
     }
 }
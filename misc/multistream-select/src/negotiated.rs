@@ -89,7 +89,10 @@ where
 
 impl<TInner> Negotiated<TInner> {
     /// Creates a `Negotiated` in state [`State::Completed`].
-    pub(crate) fn completed(io: TInner) -> Self {
+    ///
+    /// Useful for I/O streams whose protocol was agreed upon by other means, e.g. by the
+    /// transport, and thus skip the negotiation.
+    pub fn completed(io: TInner) -> Self {
         Negotiated {
             state: State::Completed { io },
         }
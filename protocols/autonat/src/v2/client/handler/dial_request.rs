@@ -214,6 +214,7 @@ async fn start_stream_handle(
             #[allow(unreachable_patterns)]
             StreamUpgradeError::Apply(v) => libp2p_core::util::unreachable(v),
             StreamUpgradeError::Io(e) => Error::Io(e),
+            error => Error::Io(io::Error::other(error)),
         })?;
 
     let mut coder = Coder::new(stream);
@@ -173,6 +173,7 @@ impl Handler {
             StreamUpgradeError::NegotiationFailed => outbound::Error::Unsupported,
             StreamUpgradeError::Io(e) => outbound::Error::Io(e),
             StreamUpgradeError::Timeout => outbound::Error::Io(io::ErrorKind::TimedOut.into()),
+            error => outbound::Error::Io(io::Error::other(error)),
         };
 
         self.queued_events
@@ -476,6 +476,7 @@ impl Handler {
         FullyNegotiatedOutbound {
             protocol: stream,
             info: (),
+            ..
         }: FullyNegotiatedOutbound<<Self as ConnectionHandler>::OutboundProtocol>,
     ) {
         if let Some(sender) = self.pending_streams.pop_front() {
@@ -571,6 +572,7 @@ impl Handler {
                             "protocol not supported",
                         ),
                         StreamUpgradeError::Io(e) => e,
+                        error => io::Error::other(error),
                     })?;
 
                 let has_answer = !matches!(msg, KadRequestMsg::AddProvider { .. });
@@ -88,6 +88,7 @@ impl ConnectionHandler for Handler {
             ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
                 protocol,
                 info: _,
+                ..
             }) => {
                 if self
                     .inbound
@@ -215,6 +215,9 @@ impl Handler {
             #[allow(unreachable_patterns)]
             StreamUpgradeError::Apply(e) => libp2p_core::util::unreachable(e),
             StreamUpgradeError::Io(e) => Failure::Other { error: Box::new(e) },
+            error => Failure::Other {
+                error: Box::new(error),
+            },
         };
 
         self.pending_errors.push_front(error);
@@ -458,6 +458,7 @@ impl Handler {
             // TODO: remove when Rust 1.82 is MSRV
             #[allow(unreachable_patterns)]
             StreamUpgradeError::Apply(v) => libp2p_core::util::unreachable(v),
+            error => outbound_stop::Error::Io(io::Error::other(error)),
         };
 
         let stop_command = self
@@ -102,12 +102,14 @@ impl ConnectionHandler for Handler {
             ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
                 protocol: (stream, protocol),
                 info: (),
+                ..
             }) => {
                 Shared::lock(&self.shared).on_inbound_stream(self.remote, stream, protocol);
             }
             ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
                 protocol: (stream, actual_protocol),
                 info: (),
+                ..
             }) => {
                 let Some((expected_protocol, sender)) = self.pending_upgrade.take() else {
                     debug_assert!(
@@ -140,6 +142,7 @@ impl ConnectionHandler for Handler {
                         OpenStreamError::UnsupportedProtocol(p)
                     }
                     swarm::StreamUpgradeError::Io(io) => OpenStreamError::Io(io),
+                    error => OpenStreamError::Io(io::Error::other(error)),
                 };
 
                 let _ = sender.send(Err(error));
@@ -0,0 +1,172 @@
+use std::{
+    convert::Infallible,
+    io,
+    num::NonZeroUsize,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use futures::{io::Cursor, task::noop_waker_ref, StreamExt};
+use libp2p_core::{
+    muxing::{StreamMuxerBox, StreamMuxerEvent},
+    upgrade::DeniedUpgrade,
+    Multiaddr, StreamMuxer,
+};
+use libp2p_identity::PeerId;
+use libp2p_swarm::{
+    handler::{ConnectionEvent, ConnectionEventMask},
+    ConnectionBuilder, ConnectionHandler, ConnectionHandlerEvent, SubstreamProtocol,
+};
+
+/// Number of informational events emitted by the muxer per iteration.
+const EVENTS: usize = 10_000;
+
+fn dispatch_events(c: &mut Criterion) {
+    for (name, interested_events) in [
+        ("dispatch_events(all)", ConnectionEventMask::ALL),
+        ("dispatch_events(none)", ConnectionEventMask::NONE),
+    ] {
+        c.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    ConnectionBuilder::new(PeerId::random())
+                        .poll_budget(NonZeroUsize::MAX)
+                        .build(
+                            StreamMuxerBox::new(EventsMuxer { remaining: EVENTS }),
+                            IgnoringHandler { interested_events },
+                        )
+                },
+                |mut connection| {
+                    let mut cx = Context::from_waker(noop_waker_ref());
+                    while let Poll::Ready(Some(event)) = connection.poll_next_unpin(&mut cx) {
+                        event.unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+}
+
+/// Compares returning one event per poll with returning up to a batch of events per poll.
+fn batch_events(c: &mut Criterion) {
+    for batch in [1, 16, 256] {
+        c.bench_function(&format!("batch_events({batch})"), |b| {
+            b.iter_batched(
+                || {
+                    ConnectionBuilder::new(PeerId::random())
+                        .poll_budget(NonZeroUsize::MAX)
+                        .build(
+                            StreamMuxerBox::new(EventsMuxer { remaining: EVENTS }),
+                            IgnoringHandler {
+                                interested_events: ConnectionEventMask::NONE,
+                            },
+                        )
+                },
+                |mut connection| {
+                    let mut cx = Context::from_waker(noop_waker_ref());
+                    let mut buf = Vec::with_capacity(batch);
+                    while let Poll::Ready(Some(result)) = connection.poll_batch(&mut cx, &mut buf) {
+                        result.unwrap();
+                        buf.clear();
+                    }
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+}
+
+criterion_group!(event_dispatch, dispatch_events, batch_events);
+criterion_main!(event_dispatch);
+
+/// A handler that ignores all events.
+struct IgnoringHandler {
+    interested_events: ConnectionEventMask,
+}
+
+impl ConnectionHandler for IgnoringHandler {
+    type FromBehaviour = Infallible;
+    type ToBehaviour = Infallible;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        true
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        self.interested_events
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+        Poll::Pending
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        match event {}
+    }
+
+    fn on_connection_event(
+        &mut self,
+        _: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+    ) {
+    }
+}
+
+/// Emits the given number of alternating half-close and address change events.
+struct EventsMuxer {
+    remaining: usize,
+}
+
+impl StreamMuxer for EventsMuxer {
+    type Substream = Cursor<Vec<u8>>;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        Poll::Pending
+    }
+
+    fn poll_outbound(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        Poll::Pending
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        if self.remaining == 0 {
+            return Poll::Pending;
+        }
+        self.remaining -= 1;
+
+        if self.remaining % 2 == 0 {
+            return Poll::Ready(Ok(StreamMuxerEvent::StreamHalfClosed));
+        }
+
+        let address = Multiaddr::empty().with(libp2p_core::multiaddr::Protocol::Memory(
+            self.remaining as u64,
+        ));
+        Poll::Ready(Ok(StreamMuxerEvent::AddressChange(address)))
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use async_std::stream::StreamExt;
+use criterion::{criterion_group, criterion_main, Criterion};
+use libp2p_core::{
+    muxing::{StreamMuxerBox, StreamMuxerEvent},
+    transport::MemoryTransport,
+    upgrade::ReadyUpgrade,
+    Multiaddr, StreamMuxer, Transport,
+};
+use libp2p_identity::PeerId;
+use libp2p_swarm::{
+    handler::{ConnectionEvent, FullyNegotiatedOutbound},
+    ConnectionHandler, ConnectionHandlerEvent, NetworkBehaviour, StreamProtocol, SubstreamProtocol,
+    ToSwarm,
+};
+use web_time::Duration;
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/muxer-polls-bench");
+
+/// Total number of [`StreamMuxer::poll`] calls across all connections of the benchmark.
+static MUXER_POLLS: AtomicUsize = AtomicUsize::new(0);
+static OFFSET: AtomicUsize = AtomicUsize::new(9000);
+
+fn open_streams(c: &mut Criterion) {
+    for streams in [10, 100, 500] {
+        let name = format!("open_streams({streams})");
+        let mut iterations = 0;
+        MUXER_POLLS.store(0, Ordering::Relaxed);
+
+        c.bench_function(&name, |b| {
+            b.to_async(tokio::runtime::Builder::new_multi_thread().build().unwrap())
+                .iter_batched(
+                    || {
+                        iterations += 1;
+                        init(streams)
+                    },
+                    |(mut swarm_a, mut swarm_b)| async move {
+                        while !swarm_a.behaviour().finished {
+                            futures::future::select(swarm_b.next(), swarm_a.next()).await;
+                        }
+                    },
+                    criterion::BatchSize::LargeInput,
+                );
+        });
+
+        println!(
+            "{name}: {} muxer polls per iteration",
+            MUXER_POLLS.load(Ordering::Relaxed) / iterations.max(1)
+        );
+    }
+}
+
+criterion_group!(muxer_polls, open_streams);
+criterion_main!(muxer_polls);
+
+fn init(
+    streams: usize,
+) -> (
+    libp2p_swarm::Swarm<OpenStreams>,
+    libp2p_swarm::Swarm<OpenStreams>,
+) {
+    let mut swarm_a = new_swarm(OpenStreams::new(streams));
+    let mut swarm_b = new_swarm(OpenStreams::new(0));
+
+    let offset = OFFSET.fetch_add(1, Ordering::Relaxed);
+
+    swarm_b
+        .listen_on(format!("/memory/{offset}").parse().unwrap())
+        .unwrap();
+    swarm_a
+        .dial(format!("/memory/{offset}").parse::<Multiaddr>().unwrap())
+        .unwrap();
+
+    (swarm_a, swarm_b)
+}
+
+fn new_swarm<T: NetworkBehaviour>(beh: T) -> libp2p_swarm::Swarm<T> {
+    let keypair = libp2p_identity::Keypair::generate_ed25519();
+    libp2p_swarm::Swarm::new(
+        MemoryTransport::default()
+            .upgrade(multistream_select::Version::V1)
+            .authenticate(libp2p_plaintext::Config::new(&keypair))
+            .multiplex(libp2p_yamux::Config::default())
+            .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(PollCountingMuxer(muxer))))
+            .boxed(),
+        beh,
+        keypair.public().to_peer_id(),
+        libp2p_swarm::Config::without_executor().with_idle_connection_timeout(Duration::MAX),
+    )
+}
+
+/// Opens the configured number of streams to the remote and finishes once all of them have been
+/// negotiated.
+struct OpenStreams {
+    streams: usize,
+    finished: bool,
+    emitted: bool,
+}
+
+#[derive(Debug)]
+struct FinishedOpening;
+
+impl OpenStreams {
+    fn new(streams: usize) -> Self {
+        Self {
+            streams,
+            finished: false,
+            emitted: false,
+        }
+    }
+}
+
+impl NetworkBehaviour for OpenStreams {
+    type ConnectionHandler = OpenStreamsHandler;
+    type ToSwarm = FinishedOpening;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: libp2p_swarm::ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<libp2p_swarm::THandler<Self>, libp2p_swarm::ConnectionDenied> {
+        Ok(OpenStreamsHandler::new(self.streams))
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: libp2p_swarm::ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: libp2p_core::Endpoint,
+        _: libp2p_core::transport::PortUse,
+    ) -> Result<libp2p_swarm::THandler<Self>, libp2p_swarm::ConnectionDenied> {
+        Ok(OpenStreamsHandler::new(self.streams))
+    }
+
+    fn on_swarm_event(&mut self, _: libp2p_swarm::FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: libp2p_swarm::ConnectionId,
+        _: libp2p_swarm::THandlerOutEvent<Self>,
+    ) {
+        self.finished = true;
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, libp2p_swarm::THandlerInEvent<Self>>> {
+        if self.finished && !self.emitted {
+            self.emitted = true;
+            Poll::Ready(ToSwarm::GenerateEvent(FinishedOpening))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+struct OpenStreamsHandler {
+    to_request: usize,
+    pending: usize,
+    finished: bool,
+}
+
+impl OpenStreamsHandler {
+    fn new(streams: usize) -> Self {
+        Self {
+            to_request: streams,
+            pending: streams,
+            finished: streams == 0,
+        }
+    }
+}
+
+impl ConnectionHandler for OpenStreamsHandler {
+    type FromBehaviour = Infallible;
+    type ToBehaviour = ();
+    type InboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type OutboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+        SubstreamProtocol::new(ReadyUpgrade::new(PROTOCOL), ())
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+        if self.to_request > 0 {
+            self.to_request -= 1;
+            return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(ReadyUpgrade::new(PROTOCOL), ()),
+            });
+        }
+
+        if self.pending == 0 && !self.finished {
+            self.finished = true;
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(()));
+        }
+
+        Poll::Pending
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        match event {}
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+    ) {
+        if let ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound { .. }) = event {
+            self.pending -= 1;
+        }
+    }
+}
+
+/// Wraps a [`StreamMuxer`] and counts the calls to [`StreamMuxer::poll`].
+struct PollCountingMuxer<M>(M);
+
+impl<M> StreamMuxer for PollCountingMuxer<M>
+where
+    M: StreamMuxer + Unpin,
+{
+    type Substream = M::Substream;
+    type Error = M::Error;
+
+    fn poll_inbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        Pin::new(&mut self.0).poll_inbound(cx)
+    }
+
+    fn poll_outbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        Pin::new(&mut self.0).poll_outbound(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        MUXER_POLLS.fetch_add(1, Ordering::Relaxed);
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
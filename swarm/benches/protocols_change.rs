@@ -0,0 +1,284 @@
+use std::{
+    convert::Infallible,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use async_std::stream::StreamExt;
+use criterion::{criterion_group, criterion_main, Criterion};
+use libp2p_core::{
+    muxing::StreamMuxerBox, transport::MemoryTransport, upgrade::ReadyUpgrade, InboundUpgrade,
+    Multiaddr, Transport, UpgradeInfo,
+};
+use libp2p_identity::PeerId;
+use libp2p_swarm::{
+    handler::{ConnectionEvent, FullyNegotiatedInbound},
+    ConnectionHandler, ConnectionHandlerEvent, NetworkBehaviour, StreamProtocol, SubstreamProtocol,
+    ToSwarm,
+};
+use web_time::Duration;
+
+/// Number of inbound streams accepted per iteration.
+const STREAMS: usize = 200;
+/// Number of protocols the accepting handler listens on.
+const PROTOCOLS: usize = 100;
+
+static OFFSET: AtomicUsize = AtomicUsize::new(10000);
+
+fn accept_streams(c: &mut Criterion) {
+    for (wants_protocols_change, protocols_generation) in
+        [(true, false), (true, true), (false, false)]
+    {
+        let name = format!(
+            "accept_streams(wants_protocols_change = {wants_protocols_change}, \
+             protocols_generation = {protocols_generation})"
+        );
+
+        c.bench_function(&name, |b| {
+            b.to_async(tokio::runtime::Builder::new_multi_thread().build().unwrap())
+                .iter_batched(
+                    || init(wants_protocols_change, protocols_generation),
+                    |(mut swarm_a, mut swarm_b)| async move {
+                        while !swarm_b.behaviour().finished {
+                            futures::future::select(swarm_b.next(), swarm_a.next()).await;
+                        }
+                    },
+                    criterion::BatchSize::LargeInput,
+                );
+        });
+    }
+}
+
+criterion_group!(protocols_change, accept_streams);
+criterion_main!(protocols_change);
+
+fn init(
+    wants_protocols_change: bool,
+    protocols_generation: bool,
+) -> (
+    libp2p_swarm::Swarm<AcceptStreams>,
+    libp2p_swarm::Swarm<AcceptStreams>,
+) {
+    let mut swarm_a = new_swarm(AcceptStreams::dialer());
+    let mut swarm_b = new_swarm(AcceptStreams::listener(
+        wants_protocols_change,
+        protocols_generation,
+    ));
+
+    let offset = OFFSET.fetch_add(1, Ordering::Relaxed);
+
+    swarm_b
+        .listen_on(format!("/memory/{offset}").parse().unwrap())
+        .unwrap();
+    swarm_a
+        .dial(format!("/memory/{offset}").parse::<Multiaddr>().unwrap())
+        .unwrap();
+
+    (swarm_a, swarm_b)
+}
+
+fn new_swarm<T: NetworkBehaviour>(beh: T) -> libp2p_swarm::Swarm<T> {
+    let keypair = libp2p_identity::Keypair::generate_ed25519();
+    libp2p_swarm::Swarm::new(
+        MemoryTransport::default()
+            .upgrade(multistream_select::Version::V1)
+            .authenticate(libp2p_plaintext::Config::new(&keypair))
+            .multiplex(libp2p_yamux::Config::default())
+            .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+            .boxed(),
+        beh,
+        keypair.public().to_peer_id(),
+        libp2p_swarm::Config::without_executor().with_idle_connection_timeout(Duration::MAX),
+    )
+}
+
+fn protocol(i: usize) -> StreamProtocol {
+    StreamProtocol::try_from_owned(format!("/protocols-change-bench/{i}")).unwrap()
+}
+
+/// The dialer opens [`STREAMS`] streams, the listener accepts them and finishes once all of them
+/// have been negotiated.
+struct AcceptStreams {
+    to_open: usize,
+    wants_protocols_change: bool,
+    protocols_generation: bool,
+    finished: bool,
+    emitted: bool,
+}
+
+#[derive(Debug)]
+struct FinishedAccepting;
+
+impl AcceptStreams {
+    fn dialer() -> Self {
+        Self {
+            to_open: STREAMS,
+            wants_protocols_change: true,
+            protocols_generation: false,
+            finished: false,
+            emitted: false,
+        }
+    }
+
+    fn listener(wants_protocols_change: bool, protocols_generation: bool) -> Self {
+        Self {
+            to_open: 0,
+            wants_protocols_change,
+            protocols_generation,
+            finished: false,
+            emitted: false,
+        }
+    }
+
+    fn handler(&self) -> AcceptStreamsHandler {
+        AcceptStreamsHandler {
+            to_open: self.to_open,
+            to_accept: if self.to_open == 0 { STREAMS } else { 0 },
+            protocols: (0..PROTOCOLS).map(protocol).collect(),
+            wants_protocols_change: self.wants_protocols_change,
+            protocols_generation: self.protocols_generation,
+            finished: false,
+        }
+    }
+}
+
+impl NetworkBehaviour for AcceptStreams {
+    type ConnectionHandler = AcceptStreamsHandler;
+    type ToSwarm = FinishedAccepting;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: libp2p_swarm::ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<libp2p_swarm::THandler<Self>, libp2p_swarm::ConnectionDenied> {
+        Ok(self.handler())
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: libp2p_swarm::ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: libp2p_core::Endpoint,
+        _: libp2p_core::transport::PortUse,
+    ) -> Result<libp2p_swarm::THandler<Self>, libp2p_swarm::ConnectionDenied> {
+        Ok(self.handler())
+    }
+
+    fn on_swarm_event(&mut self, _: libp2p_swarm::FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: libp2p_swarm::ConnectionId,
+        _: libp2p_swarm::THandlerOutEvent<Self>,
+    ) {
+        self.finished = true;
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, libp2p_swarm::THandlerInEvent<Self>>> {
+        if self.finished && !self.emitted {
+            self.emitted = true;
+            Poll::Ready(ToSwarm::GenerateEvent(FinishedAccepting))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+struct AcceptStreamsHandler {
+    to_open: usize,
+    to_accept: usize,
+    protocols: Vec<StreamProtocol>,
+    wants_protocols_change: bool,
+    /// Whether to report a constant [`ConnectionHandler::protocols_generation`].
+    protocols_generation: bool,
+    finished: bool,
+}
+
+impl ConnectionHandler for AcceptStreamsHandler {
+    type FromBehaviour = Infallible;
+    type ToBehaviour = ();
+    type InboundProtocol = ManyProtocols;
+    type OutboundProtocol = ReadyUpgrade<StreamProtocol>;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+        SubstreamProtocol::new(ManyProtocols(self.protocols.clone()), ())
+    }
+
+    fn wants_protocols_change(&self) -> bool {
+        self.wants_protocols_change
+    }
+
+    fn protocols_generation(&self) -> Option<u64> {
+        self.protocols_generation.then_some(0)
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        true
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+        if self.to_open > 0 {
+            self.to_open -= 1;
+            return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(
+                    ReadyUpgrade::new(self.protocols[PROTOCOLS - 1].clone()),
+                    (),
+                ),
+            });
+        }
+
+        if self.to_accept == 0 && !self.finished {
+            self.finished = true;
+            return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(()));
+        }
+
+        Poll::Pending
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        match event {}
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+    ) {
+        if let ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound { .. }) = event {
+            self.to_accept = self.to_accept.saturating_sub(1);
+        }
+    }
+}
+
+/// Accepts inbound streams on any of the given protocols.
+struct ManyProtocols(Vec<StreamProtocol>);
+
+impl UpgradeInfo for ManyProtocols {
+    type Info = StreamProtocol;
+    type InfoIter = std::vec::IntoIter<StreamProtocol>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.0.clone().into_iter()
+    }
+}
+
+impl<C> InboundUpgrade<C> for ManyProtocols {
+    type Output = C;
+    type Error = Infallible;
+    type Future = futures::future::Ready<Result<C, Infallible>>;
+
+    fn upgrade_inbound(self, stream: C, _: Self::Info) -> Self::Future {
+        futures::future::ready(Ok(stream))
+    }
+}
@@ -29,9 +29,9 @@ use crate::{
     behaviour::FromSwarm,
     connection::ConnectionId,
     handler::{
-        AddressChange, ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent,
-        DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound, ListenUpgradeError,
-        SubstreamProtocol,
+        AddressChange, ConnectionEvent, ConnectionEventMask, ConnectionHandler,
+        ConnectionHandlerEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
+        ListenUpgradeError, SubstreamProtocol,
     },
     upgrade::SendWrapper,
     ConnectionDenied, NetworkBehaviour, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
@@ -206,6 +206,7 @@ where
         FullyNegotiatedInbound {
             protocol: out,
             info,
+            negotiated_protocol,
         }: FullyNegotiatedInbound<
             <Self as ConnectionHandler>::InboundProtocol,
             <Self as ConnectionHandler>::InboundOpenInfo,
@@ -218,7 +219,8 @@ where
             future::Either::Right(v) => libp2p_core::util::unreachable(v),
         };
 
-        if let Either::Left(info) = info {
+        if let (Either::Left(info), Either::Left(negotiated_protocol)) = (info, negotiated_protocol)
+        {
             self.inner
                 .as_mut()
                 .expect("Can't receive an inbound substream if disabled; QED")
@@ -226,6 +228,7 @@ where
                     FullyNegotiatedInbound {
                         protocol: out,
                         info,
+                        negotiated_protocol,
                     },
                 ));
         } else {
@@ -305,6 +308,24 @@ where
             .unwrap_or(false)
     }
 
+    fn wants_protocols_change(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_some_and(|h| h.wants_protocols_change())
+    }
+
+    fn protocols_generation(&self) -> Option<u64> {
+        self.inner
+            .as_ref()
+            .map_or(Some(0), |h| h.protocols_generation())
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        self.inner
+            .as_ref()
+            .map_or(ConnectionEventMask::NONE, |h| h.interested_events())
+    }
+
     fn poll(
         &mut self,
         cx: &mut Context<'_>,
@@ -334,6 +355,7 @@ where
             ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
                 protocol: out,
                 info,
+                negotiated_protocol,
             }) => self
                 .inner
                 .as_mut()
@@ -342,6 +364,7 @@ where
                     FullyNegotiatedOutbound {
                         protocol: out,
                         info,
+                        negotiated_protocol,
                     },
                 )),
             ConnectionEvent::AddressChange(address_change) => {
@@ -372,6 +395,31 @@ where
                     inner.on_connection_event(ConnectionEvent::RemoteProtocolsChange(change));
                 }
             }
+            ConnectionEvent::StreamHalfClosed => {
+                if let Some(inner) = self.inner.as_mut() {
+                    inner.on_connection_event(ConnectionEvent::StreamHalfClosed);
+                }
+            }
+            ConnectionEvent::OutboundStreamsStalled => {
+                if let Some(inner) = self.inner.as_mut() {
+                    inner.on_connection_event(ConnectionEvent::OutboundStreamsStalled);
+                }
+            }
+            ConnectionEvent::OutboundCapacity { available } => {
+                if let Some(inner) = self.inner.as_mut() {
+                    inner.on_connection_event(ConnectionEvent::OutboundCapacity { available });
+                }
+            }
+            ConnectionEvent::EnteredIdle => {
+                if let Some(inner) = self.inner.as_mut() {
+                    inner.on_connection_event(ConnectionEvent::EnteredIdle);
+                }
+            }
+            ConnectionEvent::ExitedIdle => {
+                if let Some(inner) = self.inner.as_mut() {
+                    inner.on_connection_event(ConnectionEvent::ExitedIdle);
+                }
+            }
         }
     }
 
@@ -382,4 +430,10 @@ where
 
         inner.poll_close(cx)
     }
+
+    fn on_address_change(&mut self, new_address: &Multiaddr) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.on_address_change(new_address);
+        }
+    }
 }
@@ -18,9 +18,27 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
+mod bandwidth;
+mod clock;
 mod error;
+mod first_stream;
+mod idle;
+mod idle_probe;
+mod keep_alive;
+mod negotiating;
+mod outbound_backoff;
+mod outbound_requests;
+mod outbound_retry;
+mod outbound_stall;
 
 pub(crate) mod pool;
+mod rate_limit;
+mod shutdown;
+mod silence;
+mod snapshot;
+mod stream_reset;
+mod stream_upgrade;
+mod success_rate;
 mod supported_protocols;
 
 use std::{
@@ -28,47 +46,84 @@ use std::{
     fmt,
     fmt::{Display, Formatter},
     future::Future,
-    io, mem,
+    mem,
+    num::NonZeroUsize,
     pin::Pin,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, PoisonError, RwLock,
+    },
     task::{Context, Poll, Waker},
     time::Duration,
 };
 
-pub use error::ConnectionError;
+use bandwidth::{count_bytes, BandwidthCounters};
+pub use clock::Clock;
+pub(crate) use clock::{Sleep, SystemClock};
 pub(crate) use error::{
-    PendingConnectionError, PendingInboundConnectionError, PendingOutboundConnectionError,
+    CloseError, PendingConnectionError, PendingInboundConnectionError,
+    PendingOutboundConnectionError,
 };
-use futures::{future::BoxFuture, stream, stream::FuturesUnordered, FutureExt, StreamExt};
-use futures_timer::Delay;
+pub use error::{ConnectionError, PeerConnectionError};
+use first_stream::FirstStreamDeadline;
+use futures::{ready, stream, stream::FuturesUnordered, StreamExt, TryFutureExt};
+use idle::IdleTracker;
+pub use idle_probe::IdleProbe;
+use idle_probe::IdleProbing;
+use keep_alive::KeepAliveReporter;
+pub use keep_alive::{KeepAliveMetrics, LivenessReason, PendingShutdownReason, ShutdownReason};
 use libp2p_core::{
     connection::ConnectedPoint,
     multiaddr::Multiaddr,
-    muxing::{StreamMuxerBox, StreamMuxerEvent, StreamMuxerExt, SubstreamBox},
+    muxing::{StreamMuxerBox, StreamMuxerEvent, StreamMuxerExt},
     transport::PortUse,
-    upgrade,
-    upgrade::{NegotiationError, ProtocolError},
-    Endpoint,
+    upgrade, Endpoint,
 };
 use libp2p_identity::PeerId;
+use negotiating::NegotiatingStreams;
+pub use outbound_backoff::OutboundUpgradeBackoff;
+use outbound_backoff::OutboundUpgradeBackoffs;
+use outbound_requests::{
+    has_waiting_requests, queue_outbound_request, OutboundGrants, OutboundQueue,
+    OutboundTimeoutPolicy, SubstreamRequested,
+};
+use outbound_retry::OutboundRetries;
+use outbound_stall::OutboundStall;
+pub use rate_limit::InboundStreamRateLimit;
+use rate_limit::InboundStreamRateLimiter;
+pub(crate) use shutdown::checked_add_fraction;
+use shutdown::{ForcedKeepAlive, ScheduledShutdown, Shutdown};
+use silence::SilenceWatchdog;
+pub use snapshot::{ConnectionSnapshot, PlannedShutdown};
+use stream_reset::ResettableStreams;
+use stream_upgrade::{
+    effective_upgrade_version, protocol_names, NegotiationExecutor, ProtocolRewriter,
+    RewrittenProtocol, StreamUpgrade,
+};
+use success_rate::SuccessRate;
 pub use supported_protocols::SupportedProtocols;
 use web_time::Instant;
 
 use crate::{
     handler::{
-        AddressChange, ConnectionEvent, ConnectionHandler, DialUpgradeError,
-        FullyNegotiatedInbound, FullyNegotiatedOutbound, ListenUpgradeError, ProtocolSupport,
-        ProtocolsChange, UpgradeInfoSend,
+        AddressChange, ConnectionEvent, ConnectionEventMask, ConnectionHandler, DialUpgradeError,
+        FullyNegotiatedInbound, FullyNegotiatedOutbound, ListenUpgradeError, MetricsDelta,
+        ProtocolSupport, ProtocolsChange, ResetCause, UpgradeInfoSend,
     },
-    stream::ActiveStreamCounter,
+    stream::{ActiveStreamCounter, OpenStreamCounter, StreamId},
     upgrade::{InboundUpgradeSend, OutboundUpgradeSend},
-    ConnectionHandlerEvent, Stream, StreamProtocol, StreamUpgradeError, SubstreamProtocol,
+    ConnectionHandlerEvent, Executor, StreamProtocol, StreamUpgradeError, SubstreamProtocol,
 };
 
 static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
 
 /// Connection identifier.
+///
+/// With the `serde` feature enabled, [`ConnectionId`]s can be serialized and deserialized.
+/// Deserialized [`ConnectionId`]s are _unchecked_, see [`ConnectionId::new_unchecked`], and must
+/// not be mixed with the [`ConnectionId`]s of a live [`Swarm`](crate::Swarm).
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnectionId(usize);
 
 impl ConnectionId {
@@ -83,9 +138,72 @@ impl ConnectionId {
         Self(id)
     }
 
+    /// Whether this is the dummy [`ConnectionId`] with the value `0`.
+    ///
+    /// [`Swarm`](crate::Swarm) never hands out this value, hence it can be used to detect
+    /// [`ConnectionId`]s that were not assigned to an actual connection.
+    pub fn is_dummy(&self) -> bool {
+        self.0 == 0
+    }
+
     /// Returns the next available [`ConnectionId`].
+    ///
+    /// # Panics
+    ///
+    /// Panics once all ids are exhausted instead of wrapping around and handing out ids that may
+    /// still be in use. Only conceivable on 32-bit targets, after billions of connections.
     pub(crate) fn next() -> Self {
-        Self(NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst))
+        Self(next_id(&NEXT_CONNECTION_ID))
+    }
+}
+
+/// Takes the next id from `counter`, panicking once it is exhausted.
+fn next_id(counter: &AtomicUsize) -> usize {
+    counter
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |id| id.checked_add(1))
+        .expect("ConnectionIds to not be exhausted, reusing them would break their uniqueness")
+}
+
+/// Hands out [`ConnectionId`]s for new connections.
+///
+/// Production code always uses [`ConnectionIdAllocator::Global`], i.e. the process-wide counter
+/// behind [`ConnectionId::next`], which guarantees uniqueness across all [`Swarm`](crate::Swarm)s.
+#[derive(Debug, Default)]
+pub(crate) enum ConnectionIdAllocator {
+    /// Allocate from the process-wide counter.
+    #[default]
+    Global,
+    /// Allocate from a local counter, making ids deterministic regardless of other tests running
+    /// in parallel.
+    #[cfg(test)]
+    Local(usize),
+}
+
+impl ConnectionIdAllocator {
+    /// Creates an allocator with a local counter, handing out ids starting from `1`.
+    #[cfg(test)]
+    pub(crate) fn local() -> Self {
+        ConnectionIdAllocator::Local(1)
+    }
+
+    /// Returns the next [`ConnectionId`] of this allocator.
+    pub(crate) fn next(&mut self) -> ConnectionId {
+        match self {
+            ConnectionIdAllocator::Global => ConnectionId::next(),
+            #[cfg(test)]
+            ConnectionIdAllocator::Local(next) => {
+                let id = ConnectionId(*next);
+                *next += 1;
+                id
+            }
+        }
+    }
+}
+
+/// Creates an _unchecked_ [`ConnectionId`], see [`ConnectionId::new_unchecked`].
+impl From<usize> for ConnectionId {
+    fn from(id: usize) -> Self {
+        Self::new_unchecked(id)
     }
 }
 
@@ -95,6 +213,542 @@ impl Display for ConnectionId {
     }
 }
 
+/// Builder for a [`Connection`].
+///
+/// All options default to the values used by the [`Swarm`](crate::Swarm).
+///
+/// ```
+/// # use std::{num::NonZeroUsize, time::Duration};
+/// # use libp2p_core::muxing::StreamMuxerBox;
+/// # use libp2p_identity::PeerId;
+/// # use libp2p_swarm::{dummy, Connection, ConnectionBuilder, SubstreamPollPriority};
+/// fn new_connection(
+///     peer_id: PeerId,
+///     muxer: StreamMuxerBox,
+/// ) -> Connection<dummy::ConnectionHandler> {
+///     ConnectionBuilder::new(peer_id)
+///         .max_negotiating_inbound_streams(16)
+///         .substream_poll_priority(SubstreamPollPriority::InboundFirst)
+///         .handler_poll_budget(NonZeroUsize::new(32).unwrap())
+///         .idle_timeout(Duration::from_secs(30))
+///         .build(muxer, dummy::ConnectionHandler)
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectionBuilder {
+    connection_id: Option<ConnectionId>,
+    peer_id: PeerId,
+    endpoint: Option<ConnectedPoint>,
+    remote_address: Multiaddr,
+    substream_upgrade_protocol_override: Option<upgrade::Version>,
+    max_negotiating_inbound_streams: usize,
+    max_negotiating_outbound_streams: usize,
+    substream_poll_priority: SubstreamPollPriority,
+    handler_poll_budget: usize,
+    poll_budget: usize,
+    outbound_stall_threshold: Option<Duration>,
+    idle_timeout: Duration,
+    count_bandwidth: bool,
+    resettable_streams: bool,
+    track_open_substreams: bool,
+    keep_alive_metrics: Option<Arc<dyn KeepAliveMetrics>>,
+    idle_probe: Option<IdleProbe>,
+    inbound_stream_rate_limit: Option<InboundStreamRateLimit>,
+    outbound_upgrade_backoff: Option<OutboundUpgradeBackoff>,
+    first_stream_deadline: Option<Duration>,
+    ordered_inbound_streams: bool,
+    negotiation_executor: Option<NegotiationExecutor>,
+    fair_outbound_grants: bool,
+    outbound_timeout_policy: Option<OutboundTimeoutPolicy>,
+    clock: Option<Arc<dyn Clock>>,
+    outbound_stream_retries: usize,
+    congestion_threshold: usize,
+    graceful_keep_alive_timeout: bool,
+    pre_negotiated_protocol: Option<StreamProtocol>,
+    substream_deadline: Option<Instant>,
+    protocol_rewriter: Option<ProtocolRewriter>,
+    outbound_success_window: usize,
+    negotiation_poll_window: usize,
+    drain_removed_protocols: bool,
+    max_connection_silence: Option<Duration>,
+}
+
+impl ConnectionBuilder {
+    /// Creates a builder for a connection to `peer_id` with the default options.
+    pub fn new(peer_id: PeerId) -> Self {
+        Self {
+            connection_id: None,
+            peer_id,
+            endpoint: None,
+            remote_address: Multiaddr::empty(),
+            substream_upgrade_protocol_override: None,
+            max_negotiating_inbound_streams: 128,
+            max_negotiating_outbound_streams: usize::MAX,
+            substream_poll_priority: SubstreamPollPriority::default(),
+            handler_poll_budget: usize::MAX,
+            poll_budget: 128,
+            outbound_stall_threshold: None,
+            idle_timeout: Duration::from_secs(10),
+            count_bandwidth: false,
+            resettable_streams: false,
+            track_open_substreams: false,
+            keep_alive_metrics: None,
+            idle_probe: None,
+            inbound_stream_rate_limit: None,
+            outbound_upgrade_backoff: None,
+            first_stream_deadline: None,
+            ordered_inbound_streams: false,
+            negotiation_executor: None,
+            fair_outbound_grants: false,
+            outbound_timeout_policy: None,
+            clock: None,
+            outbound_stream_retries: 0,
+            congestion_threshold: 16,
+            graceful_keep_alive_timeout: false,
+            pre_negotiated_protocol: None,
+            substream_deadline: None,
+            protocol_rewriter: None,
+            outbound_success_window: 32,
+            negotiation_poll_window: usize::MAX,
+            drain_removed_protocols: false,
+            max_connection_silence: None,
+        }
+    }
+
+    /// The id of the connection, attached to its events and errors.
+    ///
+    /// Defaults to a fresh [`ConnectionId`], distinct from the ones of all other connections.
+    pub fn connection_id(mut self, id: ConnectionId) -> Self {
+        self.connection_id = Some(id);
+        self
+    }
+
+    /// The address of the remote, used to detect actual address changes.
+    ///
+    /// Defaults to the empty [`Multiaddr`].
+    pub fn remote_address(mut self, address: Multiaddr) -> Self {
+        self.remote_address = address;
+        self
+    }
+
+    /// How the connection was established, also setting the address of the remote.
+    pub fn endpoint(mut self, endpoint: ConnectedPoint) -> Self {
+        self.remote_address = endpoint.get_remote_address().clone();
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// Hand all streams of the connection to the [`ConnectionHandler`] as streams of the given
+    /// protocol, without negotiating them via multistream-select.
+    ///
+    /// Meant for transports that agree on the application protocol themselves. The streams are
+    /// upgraded with the protocol of the upgrade that matches `protocol`, streams of upgrades that
+    /// don't support it fail with [`StreamUpgradeError::NegotiationFailed`]. Disabled by default.
+    pub fn pre_negotiated_protocol(mut self, protocol: StreamProtocol) -> Self {
+        self.pre_negotiated_protocol = Some(protocol);
+        self
+    }
+
+    /// Negotiate outbound substreams with the given multistream-select version instead of the one
+    /// requested by the [`ConnectionHandler`].
+    pub fn upgrade_version_override(mut self, version: upgrade::Version) -> Self {
+        self.substream_upgrade_protocol_override = Some(version);
+        self
+    }
+
+    /// The maximum number of inbound streams concurrently negotiating on the connection.
+    ///
+    /// Defaults to 128.
+    pub fn max_negotiating_inbound_streams(mut self, max: usize) -> Self {
+        self.max_negotiating_inbound_streams = max;
+        self
+    }
+
+    /// The maximum number of outbound streams concurrently negotiating on the connection.
+    ///
+    /// Outbound stream requests exceeding the limit wait until enough of the negotiating streams
+    /// completed. Defaults to no limit.
+    pub fn max_negotiating_outbound_streams(mut self, max: usize) -> Self {
+        self.max_negotiating_outbound_streams = max;
+        self
+    }
+
+    /// Retry opening an outbound stream up to `retries` times in a row when the stream muxer fails
+    /// with a transient error, i.e. [`io::ErrorKind::Interrupted`](std::io::ErrorKind::Interrupted)
+    /// or [`io::ErrorKind::WouldBlock`](std::io::ErrorKind::WouldBlock), instead of closing the
+    /// connection.
+    ///
+    /// The requests stay queued while the stream is retried on the next poll. Other errors, and
+    /// transient errors beyond the retries, still close the connection. Defaults to no retries.
+    pub fn outbound_stream_retries(mut self, retries: usize) -> Self {
+        self.outbound_stream_retries = retries;
+        self
+    }
+
+    /// The number of outbound stream requests waiting for a stream beyond which the connection
+    /// counts as congested, see [`Connection::is_congested`].
+    ///
+    /// Defaults to 16.
+    pub fn congestion_threshold(mut self, threshold: usize) -> Self {
+        self.congestion_threshold = threshold;
+        self
+    }
+
+    /// Limit the rate at which new inbound streams are accepted.
+    ///
+    /// Disabled by default, in which case only
+    /// [`ConnectionBuilder::max_negotiating_inbound_streams`] applies.
+    pub fn inbound_stream_rate_limit(mut self, limit: InboundStreamRateLimit) -> Self {
+        self.inbound_stream_rate_limit = Some(limit);
+        self
+    }
+
+    /// Hand negotiated inbound streams to the [`ConnectionHandler`] in the order the muxer
+    /// accepted them, e.g. for protocols that handle requests in order.
+    ///
+    /// Inbound streams still negotiate concurrently, but a stream that negotiated is held back
+    /// until all streams accepted before it negotiated, failed or timed out. A single slow stream
+    /// thus delays all later ones, which keep counting towards
+    /// [`ConnectionBuilder::max_negotiating_inbound_streams`] in the meantime.
+    ///
+    /// Disabled by default, in which case streams are handed over as soon as they negotiated.
+    pub fn ordered_inbound_streams(mut self, enabled: bool) -> Self {
+        self.ordered_inbound_streams = enabled;
+        self
+    }
+
+    /// Negotiate substreams on the given [`Executor`] instead of the task polling the
+    /// [`Connection`], e.g. to keep CPU-heavy upgrades from blocking that task.
+    ///
+    /// The connection still enforces the upgrade timeouts and drops negotiations that time out.
+    /// Disabled by default.
+    pub fn negotiation_executor(mut self, executor: impl Executor + Send + Sync + 'static) -> Self {
+        self.negotiation_executor = Some(NegotiationExecutor(Arc::new(executor)));
+        self
+    }
+
+    /// Grant outbound streams round-robin among the protocols they were requested for, keyed by
+    /// the first protocol of each [`ConnectionHandler::OutboundProtocol`].
+    ///
+    /// Requests of a higher [`OutboundPriority`](crate::handler::OutboundPriority) are still served
+    /// first. Disabled by default, in which case requests of the same priority are served in
+    /// the order they were made, which lets a protocol with many requests delay all others.
+    pub fn fair_outbound_grants(mut self, enabled: bool) -> Self {
+        self.fair_outbound_grants = enabled;
+        self
+    }
+
+    /// Adjust the timeout of every outbound stream request, e.g. to scale it with the round-trip
+    /// time to the peer.
+    ///
+    /// The policy receives the timeout the [`ConnectionHandler`] set via
+    /// [`SubstreamProtocol::with_timeout`] and returns the timeout to apply instead. Disabled by
+    /// default.
+    pub fn outbound_timeout_policy(
+        mut self,
+        policy: impl Fn(Duration) -> Duration + Send + Sync + 'static,
+    ) -> Self {
+        self.outbound_timeout_policy = Some(OutboundTimeoutPolicy(Arc::new(policy)));
+        self
+    }
+
+    /// Cap the timeout of every substream upgrade so that it expires by `deadline` at the latest,
+    /// e.g. to keep negotiations within the budget of the dial that established the connection.
+    ///
+    /// Applies to both inbound and outbound streams, including the time outbound stream requests
+    /// wait for the muxer to grant a stream. Upgrades started after `deadline` time out right
+    /// away. Disabled by default.
+    pub fn substream_deadline(mut self, deadline: Instant) -> Self {
+        self.substream_deadline = Some(deadline);
+        self
+    }
+
+    /// Rewrite the names of the protocols the [`ConnectionHandler`] listens on, e.g. to accept
+    /// `/foo/1.0.1` in place of `/foo/1.0.0` when bridging between protocol versions.
+    ///
+    /// The rewriter maps a protocol name of the handler to the name to negotiate with the remote
+    /// instead, or returns `None` to keep the name. The rewritten names are also the ones reported
+    /// in [`ConnectionEvent::LocalProtocolsChange`]. The upgrade of the handler still receives the
+    /// original name, as does [`FullyNegotiatedInbound::negotiated_protocol`]. Disabled by
+    /// default.
+    pub fn protocol_rewriter(
+        mut self,
+        rewriter: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.protocol_rewriter = Some(ProtocolRewriter(Arc::new(rewriter)));
+        self
+    }
+
+    /// The number of most recent outbound stream negotiations that
+    /// [`Connection::outbound_success_rate`] is computed over.
+    ///
+    /// Defaults to 32.
+    pub fn outbound_success_window(mut self, window: NonZeroUsize) -> Self {
+        self.outbound_success_window = window.get();
+        self
+    }
+
+    /// The maximum number of completed inbound and outbound stream negotiations handled per
+    /// [`Connection`] poll.
+    ///
+    /// Further completed negotiations are handled on the next poll, for which the connection
+    /// wakes itself. Smooths the load of connections with many concurrent negotiations.
+    ///
+    /// Defaults to no limit.
+    pub fn negotiation_poll_window(mut self, window: NonZeroUsize) -> Self {
+        self.negotiation_poll_window = window.get();
+        self
+    }
+
+    /// Whether inbound streams that are negotiating a protocol the [`ConnectionHandler`] stops
+    /// listening on continue to negotiate it.
+    ///
+    /// The protocol is removed from the protocols offered on new inbound streams either way. By
+    /// default, streams that already started negotiating are dropped once they negotiated the
+    /// removed protocol. Enable this to let them complete instead, e.g. to deprecate a protocol
+    /// without failing the streams in flight.
+    ///
+    /// Defaults to `false`.
+    pub fn drain_removed_protocols(mut self, drain: bool) -> Self {
+        self.drain_removed_protocols = drain;
+        self
+    }
+
+    /// Fail the connection with [`ConnectionError::Silent`] once neither the
+    /// [`ConnectionHandler`] nor the muxer produced an event, and no substream completed its
+    /// negotiation, for the given duration.
+    ///
+    /// Applies regardless of the keep-alive of the connection, to catch connections that are
+    /// wedged while kept alive. Disabled by default.
+    pub fn max_connection_silence(mut self, max: Duration) -> Self {
+        self.max_connection_silence = Some(max);
+        self
+    }
+
+    /// Back off outbound streams for protocols whose upgrades failed repeatedly.
+    ///
+    /// Disabled by default.
+    pub fn outbound_upgrade_backoff(mut self, backoff: OutboundUpgradeBackoff) -> Self {
+        self.outbound_upgrade_backoff = Some(backoff);
+        self
+    }
+
+    /// Fail the connection with [`ConnectionError::NegotiationDeadline`] unless a first substream
+    /// is negotiated within the given duration after the connection was built.
+    ///
+    /// Useful to abandon connections that never become productive, e.g. because the remote
+    /// supports none of the protocols. Disabled by default.
+    pub fn first_stream_deadline(mut self, deadline: Duration) -> Self {
+        self.first_stream_deadline = Some(deadline);
+        self
+    }
+
+    /// The order in which new substreams are requested from the muxer.
+    ///
+    /// Defaults to [`SubstreamPollPriority::OutboundFirst`].
+    pub fn substream_poll_priority(mut self, priority: SubstreamPollPriority) -> Self {
+        self.substream_poll_priority = priority;
+        self
+    }
+
+    /// The maximum number of consecutive times the [`ConnectionHandler`] is polled before
+    /// substream negotiation and the muxer are polled.
+    ///
+    /// Defaults to no limit.
+    pub fn handler_poll_budget(mut self, budget: NonZeroUsize) -> Self {
+        self.handler_poll_budget = budget.get();
+        self
+    }
+
+    /// The maximum number of iterations of a single [`Connection`] poll before it yields.
+    ///
+    /// Defaults to 128.
+    pub fn poll_budget(mut self, budget: NonZeroUsize) -> Self {
+        self.poll_budget = budget.get();
+        self
+    }
+
+    /// Report [`ConnectionEvent::OutboundStreamsStalled`] to the [`ConnectionHandler`] once the
+    /// muxer did not grant requested outbound streams for the given duration.
+    ///
+    /// Disabled by default.
+    pub fn outbound_stall_threshold(mut self, threshold: Duration) -> Self {
+        self.outbound_stall_threshold = Some(threshold);
+        self
+    }
+
+    /// How long to keep the connection alive once it is idling.
+    ///
+    /// Defaults to 10s.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Close the connection gracefully once the keep-alive timeout expired, i.e. emit
+    /// [`Event::CloseRequested`] instead of failing with [`ConnectionError::KeepAliveTimeout`].
+    ///
+    /// The connection is then closed like on [`ConnectionHandlerEvent::CloseGracefully`] and
+    /// reported as closed without an error. Disabled by default.
+    pub fn graceful_keep_alive_timeout(mut self, enabled: bool) -> Self {
+        self.graceful_keep_alive_timeout = enabled;
+        self
+    }
+
+    /// Count the bytes read from and written to the substreams of the connection.
+    ///
+    /// Disabled by default.
+    pub fn count_bandwidth(mut self, enabled: bool) -> Self {
+        self.count_bandwidth = enabled;
+        self
+    }
+
+    /// Tag the negotiated [`Stream`](crate::Stream)s of the connection with a [`StreamId`],
+    /// allowing to reset them via [`Connection::reset_substream`].
+    ///
+    /// Disabled by default, in which case the connection does not track its streams once they
+    /// are negotiated.
+    pub fn resettable_streams(mut self, enabled: bool) -> Self {
+        self.resettable_streams = enabled;
+        self
+    }
+
+    /// Count the negotiated [`Stream`](crate::Stream)s the [`ConnectionHandler`] still holds, see
+    /// [`Connection::open_substreams`].
+    ///
+    /// Disabled by default.
+    pub fn track_open_substreams(mut self, enabled: bool) -> Self {
+        self.track_open_substreams = enabled;
+        self
+    }
+
+    /// Report keep-alive decisions and the shutdown of the connection to the given
+    /// [`KeepAliveMetrics`].
+    ///
+    /// Disabled by default.
+    pub fn keep_alive_metrics(mut self, metrics: Arc<dyn KeepAliveMetrics>) -> Self {
+        self.keep_alive_metrics = Some(metrics);
+        self
+    }
+
+    /// Use the given [`Clock`] for all timers of the connection, e.g. its keep-alive and upgrade
+    /// timeouts.
+    ///
+    /// Defaults to the clock of the operating system.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Probe the liveness of the connection while it is idle, closing it once the probe fails.
+    ///
+    /// Disabled by default.
+    pub fn idle_probe(mut self, probe: IdleProbe) -> Self {
+        self.idle_probe = Some(probe);
+        self
+    }
+
+    /// Builds the [`Connection`] from the given substream multiplexer and connection handler.
+    pub fn build<THandler>(
+        self,
+        muxer: StreamMuxerBox,
+        mut handler: THandler,
+    ) -> Connection<THandler>
+    where
+        THandler: ConnectionHandler,
+    {
+        let initial_protocols =
+            gather_supported_protocols(&handler, self.protocol_rewriter.as_ref());
+        let protocols_generation = handler.protocols_generation();
+        let mut buffer = Vec::new();
+
+        if !initial_protocols.is_empty()
+            && handler.wants_protocols_change()
+            && handler
+                .interested_events()
+                .contains(ConnectionEventMask::LOCAL_PROTOCOLS_CHANGE)
+        {
+            handler.on_connection_event(ConnectionEvent::LocalProtocolsChange(
+                ProtocolsChange::from_initial_protocols(
+                    initial_protocols.keys().map(|e| &e.0),
+                    &mut buffer,
+                ),
+            ));
+        }
+
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+
+        Connection {
+            muxing: muxer,
+            handler,
+            id: self.connection_id.unwrap_or_else(ConnectionId::next),
+            peer_id: self.peer_id,
+            endpoint: self.endpoint,
+            remote_address: self.remote_address,
+            negotiating_in: NegotiatingStreams::new(self.ordered_inbound_streams),
+            negotiating_out: Default::default(),
+            negotiation_high_water: (0, 0),
+            total_negotiated: (0, 0),
+            shutdown: Shutdown::None,
+            substream_upgrade_protocol_override: self.substream_upgrade_protocol_override,
+            max_negotiating_inbound_streams: self.max_negotiating_inbound_streams,
+            max_negotiating_outbound_streams: self.max_negotiating_outbound_streams,
+            substream_poll_priority: self.substream_poll_priority,
+            handler_poll_budget: self.handler_poll_budget,
+            handler_polls: 0,
+            poll_budget: self.poll_budget,
+            outbound_stall: OutboundStall::new(self.outbound_stall_threshold),
+            round_robin_inbound_first: false,
+            requested_substreams: Default::default(),
+            substream_requests: 0,
+            listen_protocols: Arc::new(RwLock::new(protocol_names_of(&initial_protocols))),
+            local_supported_protocols: initial_protocols,
+            protocols_generation,
+            remote_supported_protocols: Default::default(),
+            protocol_buffer: buffer,
+            idle_timeout: self.idle_timeout,
+            established_at: clock.now(),
+            bandwidth: self.count_bandwidth.then(Default::default),
+            resettable_streams: self.resettable_streams.then(Default::default),
+            open_streams: self.track_open_substreams.then(Default::default),
+            keep_alive_metrics: KeepAliveReporter::new(self.keep_alive_metrics),
+            pending_metrics: MetricsDelta::new(),
+            idle: IdleTracker::default(),
+            idle_probing: self.idle_probe.map(IdleProbing::new),
+            inbound_rate_limiter: self
+                .inbound_stream_rate_limit
+                .map(|limit| InboundStreamRateLimiter::new(limit, clock.now())),
+            outbound_backoffs: self
+                .outbound_upgrade_backoff
+                .map(OutboundUpgradeBackoffs::new),
+            first_stream_deadline: self
+                .first_stream_deadline
+                .map(|after| FirstStreamDeadline::new(after, &*clock)),
+            forced_keep_alive: None,
+            scheduled_shutdown: None,
+            negotiation_executor: self.negotiation_executor,
+            outbound_grants: self.fair_outbound_grants.then(Default::default),
+            outbound_timeout_policy: self.outbound_timeout_policy,
+            outbound_retries: OutboundRetries::new(self.outbound_stream_retries),
+            congestion_threshold: self.congestion_threshold,
+            graceful_keep_alive_timeout: self.graceful_keep_alive_timeout,
+            pre_negotiated_protocol: self.pre_negotiated_protocol,
+            substream_deadline: self.substream_deadline,
+            protocol_rewriter: self.protocol_rewriter,
+            outbound_success: SuccessRate::new(self.outbound_success_window),
+            negotiation_poll_window: self.negotiation_poll_window,
+            drain_removed_protocols: self.drain_removed_protocols,
+            silence_watchdog: self
+                .max_connection_silence
+                .map(|max| SilenceWatchdog::new(max, &*clock)),
+            last_stream_event: None,
+            stream_counter: ActiveStreamCounter::default(),
+            clock,
+            terminated: false,
+        }
+    }
+}
+
 /// Information about a successfully established connection.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct Connected {
@@ -106,15 +760,56 @@ pub(crate) struct Connected {
 
 /// Event generated by a [`Connection`].
 #[derive(Debug, Clone)]
-pub(crate) enum Event<T> {
+pub enum Event<T> {
     /// Event generated by the [`ConnectionHandler`].
     Handler(T),
     /// Address of the remote has changed.
-    AddressChange(Multiaddr),
+    AddressChange {
+        /// The previously known address of the remote.
+        old_address: Multiaddr,
+        /// The new address of the remote.
+        new_address: Multiaddr,
+    },
+    /// Counters reported by the [`ConnectionHandler`], or the upgrade timeouts counted by the
+    /// connection itself, see e.g. [`MetricsDelta::INBOUND_UPGRADE_TIMEOUTS`].
+    Metrics(MetricsDelta),
+    /// The [`ConnectionHandler`] requested to close the connection via
+    /// [`ConnectionHandlerEvent::CloseGracefully`] and no streams are negotiating anymore, or the
+    /// keep-alive timeout expired with [`ConnectionBuilder::graceful_keep_alive_timeout`] enabled.
+    ///
+    /// The connection should now be closed.
+    CloseRequested {
+        /// The reason given by the [`ConnectionHandler`], or the expired keep-alive timeout.
+        reason: String,
+    },
+}
+
+/// An [`Event`] together with the id of the [`Connection`] that emitted it.
+#[derive(Debug, Clone)]
+pub struct IdentifiedEvent<T> {
+    connection_id: ConnectionId,
+    event: Event<T>,
+}
+
+impl<T> IdentifiedEvent<T> {
+    /// The id of the connection that emitted the event.
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    /// The event emitted by the connection.
+    pub fn event(&self) -> &Event<T> {
+        &self.event
+    }
+
+    /// Discards the id and returns the [`Event`].
+    pub fn into_event(self) -> Event<T> {
+        self.event
+    }
 }
 
 /// A multiplexed connection to a peer with an associated [`ConnectionHandler`].
-pub(crate) struct Connection<THandler>
+pub struct Connection<THandler>
 where
     THandler: ConnectionHandler,
 {
@@ -122,21 +817,38 @@ where
     muxing: StreamMuxerBox,
     /// The underlying handler.
     handler: THandler,
-    /// Futures that upgrade incoming substreams.
+    /// The id of the connection, attached to its events and errors.
+    id: ConnectionId,
+    /// The peer of the connection.
+    peer_id: PeerId,
+    /// How the connection was established, if known, with the last known address of the remote.
+    endpoint: Option<ConnectedPoint>,
+    /// The last known address of the remote.
+    remote_address: Multiaddr,
+    /// Futures that upgrade incoming substreams, yielded in the order they were accepted if
+    /// [`ConnectionBuilder::ordered_inbound_streams`] is enabled.
     #[expect(deprecated)] // TODO: Remove when {In, Out}boundOpenInfo is fully removed.
-    negotiating_in: FuturesUnordered<
+    negotiating_in: NegotiatingStreams<
         StreamUpgrade<
             THandler::InboundOpenInfo,
-            <THandler::InboundProtocol as InboundUpgradeSend>::Output,
+            (
+                <THandler::InboundProtocol as UpgradeInfoSend>::Info,
+                <THandler::InboundProtocol as InboundUpgradeSend>::Output,
+            ),
             <THandler::InboundProtocol as InboundUpgradeSend>::Error,
         >,
     >,
     /// Futures that upgrade outgoing substreams.
     #[expect(deprecated)] // TODO: Remove when {In, Out}boundOpenInfo is fully removed.
+    /// Tagged with the names of the negotiated protocols, if outbound upgrades are backed off on
+    /// failure.
     negotiating_out: FuturesUnordered<
         StreamUpgrade<
-            THandler::OutboundOpenInfo,
-            <THandler::OutboundProtocol as OutboundUpgradeSend>::Output,
+            (THandler::OutboundOpenInfo, Option<Vec<String>>),
+            (
+                <THandler::OutboundProtocol as UpgradeInfoSend>::Info,
+                <THandler::OutboundProtocol as OutboundUpgradeSend>::Output,
+            ),
             <THandler::OutboundProtocol as OutboundUpgradeSend>::Error,
         >,
     >,
@@ -153,6 +865,35 @@ where
     /// connection is the sum of negotiating and negotiated streams. A limit on
     /// the total number of streams can be enforced at the [`StreamMuxerBox`] level.
     max_negotiating_inbound_streams: usize,
+    /// The maximum number of outbound streams concurrently negotiating on the connection.
+    ///
+    /// Also bounds how many requested outbound streams are granted at once.
+    max_negotiating_outbound_streams: usize,
+    /// The highest number of inbound and outbound streams that were negotiating concurrently.
+    negotiation_high_water: (usize, usize),
+    /// The number of inbound and outbound streams that were successfully negotiated so far.
+    total_negotiated: (u64, u64),
+    /// The order in which new outbound and inbound substreams are requested from the
+    /// [`StreamMuxerBox`].
+    substream_poll_priority: SubstreamPollPriority,
+    /// The maximum number of consecutive times the [`ConnectionHandler`] is polled before
+    /// substream negotiation and the [`StreamMuxerBox`] are polled.
+    handler_poll_budget: usize,
+    /// The number of times the [`ConnectionHandler`] was polled since substream negotiation and
+    /// the [`StreamMuxerBox`] were last polled.
+    handler_polls: usize,
+    /// The maximum number of iterations of a single [`Connection::poll`] call.
+    ///
+    /// Once exhausted, the connection wakes itself and returns [`Poll::Pending`], giving other
+    /// tasks a chance to run even if the connection could make progress indefinitely.
+    poll_budget: usize,
+    /// Informs the [`ConnectionHandler`] while the [`StreamMuxerBox`] withholds requested
+    /// outbound substreams.
+    outbound_stall: OutboundStall,
+    /// Whether inbound substreams are polled first under [`SubstreamPollPriority::RoundRobin`].
+    ///
+    /// Flipped every time a substream is granted, so that neither direction starves.
+    round_robin_inbound_first: bool,
     /// Contains all upgrades that are waiting for a new outbound substream.
     ///
     /// The upgrade timeout is already ticking here so this may fail in case the remote is not
@@ -161,14 +902,105 @@ where
     requested_substreams: FuturesUnordered<
         SubstreamRequested<THandler::OutboundOpenInfo, THandler::OutboundProtocol>,
     >,
+    /// The number of outbound substreams requested so far, used to serve requests of the same
+    /// priority in order.
+    substream_requests: u64,
 
-    local_supported_protocols:
-        HashMap<AsStrHashEq<<THandler::InboundProtocol as UpgradeInfoSend>::Info>, bool>,
+    local_supported_protocols: HashMap<
+        AsStrHashEq<RewrittenProtocol<<THandler::InboundProtocol as UpgradeInfoSend>::Info>>,
+        bool,
+    >,
+    /// The names of the protocols the [`ConnectionHandler`] currently listens on, shared with
+    /// the inbound upgrades in `negotiating_in`.
+    ///
+    /// Inbound streams that negotiated a protocol that is no longer in this set are dropped,
+    /// unless the [`ConnectionHandler`] opted out of
+    /// [`ConnectionHandler::wants_protocols_change`] or the connection
+    /// [drains removed protocols](ConnectionBuilder::drain_removed_protocols).
+    listen_protocols: Arc<RwLock<HashSet<String>>>,
+    /// The [`ConnectionHandler::protocols_generation`] that `local_supported_protocols` were last
+    /// collected at.
+    protocols_generation: Option<u64>,
     remote_supported_protocols: HashSet<StreamProtocol>,
     protocol_buffer: Vec<StreamProtocol>,
 
     idle_timeout: Duration,
+    /// The source of time for the keep-alive and upgrade timeouts and the age of the connection.
+    clock: Arc<dyn Clock>,
+    /// When the connection was built, according to `clock`.
+    established_at: Instant,
+    /// Bytes read from and written to all substreams, if counting is enabled.
+    bandwidth: Option<Arc<BandwidthCounters>>,
+    /// Reports keep-alive decisions and the shutdown to the [`KeepAliveMetrics`], if any.
+    keep_alive_metrics: KeepAliveReporter,
+    /// Counters of the connection itself, e.g. [`MetricsDelta::INBOUND_UPGRADE_TIMEOUTS`], that
+    /// were not reported yet.
+    ///
+    /// Reported along with the next [`ConnectionHandlerEvent::ReportMetrics`] of the
+    /// [`ConnectionHandler`], or on their own once the connection can't make progress anymore.
+    pending_metrics: MetricsDelta,
+    /// Tracks when the connection became idle, according to `clock`.
+    idle: IdleTracker,
+    /// Probes the liveness of the connection while idle, if enabled.
+    idle_probing: Option<IdleProbing>,
+    /// Limits the rate at which new inbound streams are accepted, if enabled.
+    inbound_rate_limiter: Option<InboundStreamRateLimiter>,
+    /// Tracks failed outbound upgrades, if [`ConnectionBuilder::outbound_upgrade_backoff`] is set.
+    outbound_backoffs: Option<OutboundUpgradeBackoffs>,
+    /// Fails the connection unless a substream is negotiated in time, if enabled.
+    ///
+    /// Cleared once the first substream is negotiated.
+    first_stream_deadline: Option<FirstStreamDeadline>,
+    /// Overrides the keep-alive decision of the [`ConnectionHandler`], if set via
+    /// [`Connection::force_keep_alive_until`].
+    forced_keep_alive: Option<ForcedKeepAlive>,
+    /// Overrides the keep-alive of the connection, if set via [`Connection::schedule_shutdown`].
+    scheduled_shutdown: Option<ScheduledShutdown>,
+    /// Runs substream negotiations, if enabled. Otherwise, they run as part of `poll`.
+    negotiation_executor: Option<NegotiationExecutor>,
+    /// Tracks which protocols were granted outbound streams, if
+    /// [`ConnectionBuilder::fair_outbound_grants`] is enabled.
+    outbound_grants: Option<OutboundGrants>,
+    /// Adjusts the timeout of outbound stream requests, if set via
+    /// [`ConnectionBuilder::outbound_timeout_policy`].
+    outbound_timeout_policy: Option<OutboundTimeoutPolicy>,
+    /// Retries opening outbound streams after transient errors of the [`StreamMuxerBox`].
+    outbound_retries: OutboundRetries,
+    /// See [`ConnectionBuilder::congestion_threshold`].
+    congestion_threshold: usize,
+    /// Whether the keep-alive timeout closes the connection gracefully, see
+    /// [`ConnectionBuilder::graceful_keep_alive_timeout`].
+    graceful_keep_alive_timeout: bool,
+    /// The protocol of all streams, skipping their negotiation, see
+    /// [`ConnectionBuilder::pre_negotiated_protocol`].
+    pre_negotiated_protocol: Option<StreamProtocol>,
+    /// Caps the timeout of every substream upgrade, if set via
+    /// [`ConnectionBuilder::substream_deadline`].
+    substream_deadline: Option<Instant>,
+    /// Rewrites the names of the protocols the handler listens on, if set via
+    /// [`ConnectionBuilder::protocol_rewriter`].
+    protocol_rewriter: Option<ProtocolRewriter>,
+    /// The outcomes of the most recent outbound stream negotiations.
+    outbound_success: SuccessRate,
+    /// The maximum number of completed negotiations handled per poll, see
+    /// [`ConnectionBuilder::negotiation_poll_window`].
+    negotiation_poll_window: usize,
+    /// Whether inbound streams keep negotiating protocols the [`ConnectionHandler`] stopped
+    /// listening on, see [`ConnectionBuilder::drain_removed_protocols`].
+    drain_removed_protocols: bool,
+    /// Fails the connection once it was silent for too long, if
+    /// [`ConnectionBuilder::max_connection_silence`] is set.
+    silence_watchdog: Option<SilenceWatchdog>,
+    /// The direction of the stream the muxer produced last and when it did.
+    last_stream_event: Option<(SubstreamDirection, Instant)>,
     stream_counter: ActiveStreamCounter,
+    /// Tracks the negotiated streams, if [`ConnectionBuilder::resettable_streams`] is enabled.
+    resettable_streams: Option<ResettableStreams>,
+    /// Counts the negotiated streams that were not dropped yet, if
+    /// [`ConnectionBuilder::track_open_substreams`] is enabled.
+    open_streams: Option<OpenStreamCounter>,
+    /// Whether the connection returned an error as a [`stream::Stream`] and is thus terminated.
+    terminated: bool,
 }
 
 #[expect(deprecated)] // TODO: Remove when {In, Out}boundOpenInfo is fully removed.
@@ -186,71 +1018,600 @@ where
 
 impl<THandler> Unpin for Connection<THandler> where THandler: ConnectionHandler {}
 
+/// Yields the events of the [`Connection`] and terminates after the first [`PeerConnectionError`].
+///
+/// Events and errors carry the [`ConnectionId`] of the connection, allowing to merge the streams
+/// of many connections.
+///
+/// ```
+/// # use futures::StreamExt;
+/// # use libp2p_swarm::{dummy, Connection, ConnectionPollEvent};
+/// async fn drive(mut connection: Connection<dummy::ConnectionHandler>) {
+///     while let Some(event) = connection.next().await {
+///         match event {
+///             Ok(event) => match event.event() {
+///                 ConnectionPollEvent::AddressChange { new_address, .. } => {
+///                     println!(
+///                         "Remote of {:?} moved to {new_address}",
+///                         event.connection_id()
+///                     )
+///                 }
+///                 other => println!("{other:?}"),
+///             },
+///             Err(error) => println!("{error}"),
+///         }
+///     }
+/// }
+/// ```
+impl<THandler> stream::Stream for Connection<THandler>
+where
+    THandler: ConnectionHandler,
+{
+    type Item = Result<IdentifiedEvent<THandler::ToBehaviour>, PeerConnectionError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.terminated {
+            return Poll::Ready(None);
+        }
+
+        let result = ready!(Pin::new(&mut *this).poll(cx));
+        this.terminated = result.is_err();
+
+        Poll::Ready(Some(result.map(|event| IdentifiedEvent {
+            connection_id: this.id,
+            event,
+        })))
+    }
+}
+
+impl<THandler> stream::FusedStream for Connection<THandler>
+where
+    THandler: ConnectionHandler,
+{
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
 impl<THandler> Connection<THandler>
 where
     THandler: ConnectionHandler,
 {
-    /// Builds a new `Connection` from the given substream multiplexer
-    /// and connection handler.
-    pub(crate) fn new(
+    /// Builds a new `Connection` to `peer_id` from the given substream multiplexer and connection
+    /// handler.
+    ///
+    /// Thin wrapper around [`ConnectionBuilder`], leaving all other options at their defaults.
+    #[deprecated(note = "Use `ConnectionBuilder` instead.")]
+    pub fn new(
         muxer: StreamMuxerBox,
-        mut handler: THandler,
+        handler: THandler,
+        peer_id: PeerId,
         substream_upgrade_protocol_override: Option<upgrade::Version>,
         max_negotiating_inbound_streams: usize,
         idle_timeout: Duration,
     ) -> Self {
-        let initial_protocols = gather_supported_protocols(&handler);
-        let mut buffer = Vec::new();
-
-        if !initial_protocols.is_empty() {
-            handler.on_connection_event(ConnectionEvent::LocalProtocolsChange(
-                ProtocolsChange::from_initial_protocols(
-                    initial_protocols.keys().map(|e| &e.0),
-                    &mut buffer,
-                ),
-            ));
+        let mut builder = ConnectionBuilder::new(peer_id)
+            .max_negotiating_inbound_streams(max_negotiating_inbound_streams)
+            .idle_timeout(idle_timeout);
+        if let Some(version) = substream_upgrade_protocol_override {
+            builder = builder.upgrade_version_override(version);
         }
 
-        Connection {
-            muxing: muxer,
-            handler,
-            negotiating_in: Default::default(),
-            negotiating_out: Default::default(),
-            shutdown: Shutdown::None,
-            substream_upgrade_protocol_override,
-            max_negotiating_inbound_streams,
-            requested_substreams: Default::default(),
-            local_supported_protocols: initial_protocols,
-            remote_supported_protocols: Default::default(),
-            protocol_buffer: buffer,
-            idle_timeout,
-            stream_counter: ActiveStreamCounter::default(),
-        }
+        builder.build(muxer, handler)
     }
 
-    /// Notifies the connection handler of an event.
-    pub(crate) fn on_behaviour_event(&mut self, event: THandler::FromBehaviour) {
-        self.handler.on_behaviour_event(event);
+    /// Returns a reference to the [`ConnectionHandler`] of this connection.
+    ///
+    /// ```
+    /// # use libp2p_swarm::{dummy, Connection, ConnectionHandler};
+    /// fn is_kept_alive(connection: &Connection<dummy::ConnectionHandler>) -> bool {
+    ///     connection.handler().connection_keep_alive()
+    /// }
+    /// ```
+    pub fn handler(&self) -> &THandler {
+        &self.handler
     }
 
-    /// Begins an orderly shutdown of the connection, returning a stream of final events and a
-    /// `Future` that resolves when connection shutdown is complete.
-    pub(crate) fn close(
-        self,
-    ) -> (
-        impl futures::Stream<Item = THandler::ToBehaviour>,
-        impl Future<Output = io::Result<()>>,
-    ) {
-        let Connection {
-            mut handler,
-            muxing,
-            ..
-        } = self;
+    /// Returns a mutable reference to the [`ConnectionHandler`] of this connection.
+    ///
+    /// Mutating the handler directly bypasses the regular event flow, i.e. neither
+    /// [`ConnectionHandler::on_behaviour_event`] nor [`ConnectionHandler::on_connection_event`] is
+    /// called. Prefer sending events via the [`NetworkBehaviour`](crate::NetworkBehaviour) where
+    /// possible.
+    pub fn handler_mut(&mut self) -> &mut THandler {
+        &mut self.handler
+    }
 
-        (
-            stream::poll_fn(move |cx| handler.poll_close(cx)),
-            muxing.close(),
-        )
+    /// Returns the total number of bytes read from and written to the substreams of this
+    /// connection, in that order.
+    ///
+    /// Bytes are only counted if enabled when creating the connection. Otherwise, this always
+    /// returns `(0, 0)`.
+    pub fn bandwidth(&self) -> (u64, u64) {
+        self.bandwidth
+            .as_ref()
+            .map_or((0, 0), |counters| (counters.inbound(), counters.outbound()))
+    }
+
+    /// Updates the maximum number of inbound streams concurrently negotiating on this connection.
+    ///
+    /// The new limit applies from the next call to `poll` onwards. If lowered below the number of
+    /// inbound streams currently negotiating, these continue negotiating but no new inbound
+    /// streams are accepted until enough of them completed.
+    pub fn set_max_negotiating_inbound_streams(&mut self, max: usize) {
+        self.max_negotiating_inbound_streams = max;
+    }
+
+    /// Keeps the connection alive until `until`, regardless of
+    /// [`ConnectionHandler::connection_keep_alive`], e.g. during maintenance windows.
+    ///
+    /// Takes precedence over the decision of the handler from the next call to `poll` onwards,
+    /// including a zero idle timeout. Once `until` passed, the handler decides again and the idle
+    /// timeout starts over. Replaces a previous override. Closing the connection explicitly or
+    /// via [`ConnectionHandlerEvent::CloseGracefully`] is not affected.
+    pub fn force_keep_alive_until(&mut self, until: Instant) {
+        self.forced_keep_alive = Some(ForcedKeepAlive::new(until, &*self.clock));
+    }
+
+    /// Whether the connection is idle, i.e. no substreams are requested, negotiating or in use.
+    ///
+    /// Only idle connections are shut down once their [`ConnectionHandler`] no longer keeps them
+    /// alive, see [`ConnectionHandler::connection_keep_alive`].
+    ///
+    /// Requests that were already granted a substream are not considered, even before they are
+    /// removed on the next poll, see [`Connection::flush_completed_requests`].
+    pub fn is_idle(&self) -> bool {
+        self.negotiating_in.is_empty()
+            && self.negotiating_out.is_empty()
+            && !has_waiting_requests(&self.requested_substreams)
+            && self.stream_counter.has_no_active_streams()
+    }
+
+    /// Removes the outbound substream requests that were already granted a substream right away,
+    /// rather than on the next poll.
+    ///
+    /// The connection should be polled afterwards, as the requests still waiting for a substream
+    /// only register the wake-up of their timeouts again once polled.
+    pub fn flush_completed_requests(&mut self) {
+        if self
+            .requested_substreams
+            .iter()
+            .all(SubstreamRequested::is_waiting)
+        {
+            return;
+        }
+
+        self.requested_substreams = mem::take(&mut self.requested_substreams)
+            .into_iter()
+            .filter(SubstreamRequested::is_waiting)
+            .collect();
+    }
+
+    /// Registers a [`Waker`] that is woken whenever the connection becomes idle, replacing the
+    /// previously registered one.
+    ///
+    /// Lets e.g. a [`NetworkBehaviour`](crate::NetworkBehaviour) evicting idle connections wait
+    /// for the transition instead of repeatedly checking [`Connection::is_idle`]. The transition
+    /// is detected while polling the connection. If the connection is already idle, the waker is
+    /// woken right away.
+    pub fn set_idle_waker(&mut self, waker: Waker) {
+        self.idle.set_waker(waker);
+    }
+
+    /// Reports a change to the protocols the remote supports, learned outside of the
+    /// [`ConnectionHandler`], e.g. via identify.
+    ///
+    /// Equivalent to the handler emitting [`ConnectionHandlerEvent::ReportRemoteProtocols`]: The
+    /// handler is notified via [`ConnectionEvent::RemoteProtocolsChange`] if the protocols
+    /// changed. Changes to the protocols the handler itself listens on are reported via
+    /// [`ConnectionEvent::LocalProtocolsChange`] instead.
+    pub fn report_remote_protocols(&mut self, support: ProtocolSupport) {
+        apply_remote_protocols(
+            &mut self.handler,
+            &mut self.remote_supported_protocols,
+            &mut self.protocol_buffer,
+            support,
+        );
+    }
+
+    /// Returns the id of the connection, see [`ConnectionBuilder::connection_id`].
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Returns the peer the connection is established with, see [`ConnectionBuilder::new`].
+    ///
+    /// ```
+    /// # use std::{convert::Infallible, pin::Pin, task::{Context, Poll}};
+    /// # use libp2p_core::{muxing::{StreamMuxerBox, StreamMuxerEvent}, StreamMuxer};
+    /// # use libp2p_identity::PeerId;
+    /// # use libp2p_swarm::{dummy, ConnectionBuilder};
+    /// # struct Muxer;
+    /// # impl StreamMuxer for Muxer {
+    /// #     type Substream = futures::io::Cursor<Vec<u8>>;
+    /// #     type Error = Infallible;
+    /// #     fn poll_inbound(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<Self::Substream, Infallible>> { Poll::Pending }
+    /// #     fn poll_outbound(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<Self::Substream, Infallible>> { Poll::Pending }
+    /// #     fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> { Poll::Ready(Ok(())) }
+    /// #     fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<StreamMuxerEvent, Infallible>> { Poll::Pending }
+    /// # }
+    /// let peer_id = PeerId::random();
+    /// let connection =
+    ///     ConnectionBuilder::new(peer_id).build(StreamMuxerBox::new(Muxer), dummy::ConnectionHandler);
+    ///
+    /// assert_eq!(connection.remote_peer_id(), peer_id);
+    /// ```
+    pub fn remote_peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Returns how the connection was established, if known, see [`ConnectionBuilder::endpoint`].
+    ///
+    /// The address of the remote is updated whenever it changes.
+    pub fn endpoint(&self) -> Option<&ConnectedPoint> {
+        self.endpoint.as_ref()
+    }
+
+    /// Returns when the connection was established.
+    ///
+    /// The [`Instant`] is monotonic, i.e. not affected by changes of the system time.
+    pub fn established_at(&self) -> Instant {
+        self.established_at
+    }
+
+    /// Returns for how long the connection has been established.
+    pub fn age(&self) -> Duration {
+        self.clock
+            .now()
+            .saturating_duration_since(self.established_at)
+    }
+
+    /// Returns the multistream-select version outbound substreams of this connection are
+    /// negotiated with, i.e. the one set via [`ConnectionBuilder::upgrade_version_override`] or
+    /// the default.
+    pub fn effective_upgrade_version(&self) -> upgrade::Version {
+        effective_upgrade_version(self.substream_upgrade_protocol_override)
+    }
+
+    /// Returns the highest number of inbound and outbound streams that were negotiating
+    /// concurrently on this connection, in that order.
+    ///
+    /// The marks only ever grow over the lifetime of the connection. Compare the first one with
+    /// the limit set via [`Connection::set_max_negotiating_inbound_streams`] to find out whether
+    /// the limit is ever reached.
+    pub fn negotiation_high_water(&self) -> (usize, usize) {
+        self.negotiation_high_water
+    }
+
+    /// Returns the number of inbound and outbound streams that were successfully negotiated over
+    /// the lifetime of this connection, in that order.
+    ///
+    /// Unlike the streams negotiating at a given moment, the counts only ever grow. Streams that
+    /// failed to negotiate or were injected via [`Connection::inject_fully_negotiated_inbound`]
+    /// are not counted.
+    pub fn total_negotiated(&self) -> (u64, u64) {
+        self.total_negotiated
+    }
+
+    /// Returns the user data of the outbound substream requests that still wait for a substream,
+    /// in no particular order.
+    ///
+    /// Requests that were granted a substream and are negotiating are not included.
+    #[expect(deprecated)] // TODO: Remove when {In, Out}boundOpenInfo is fully removed.
+    pub fn pending_outbound_info(&self) -> impl Iterator<Item = &THandler::OutboundOpenInfo> {
+        self.requested_substreams
+            .iter()
+            .filter_map(SubstreamRequested::user_data)
+    }
+
+    /// Resets the negotiated [`Stream`](crate::Stream) with the given id, see
+    /// [`Stream::id`](crate::Stream::id).
+    ///
+    /// Reads and writes of the stream fail with
+    /// [`io::ErrorKind::ConnectionReset`](std::io::ErrorKind::ConnectionReset) afterwards,
+    /// waking the task of the [`ConnectionHandler`] if it waits on the stream. Returns `false` if
+    /// the stream is unknown, was already dropped or reset, or
+    /// [`ConnectionBuilder::resettable_streams`] is disabled.
+    pub fn reset_substream(&mut self, id: StreamId) -> bool {
+        self.resettable_streams
+            .as_mut()
+            .is_some_and(|streams| streams.reset(id))
+    }
+
+    /// Returns the number of negotiated [`Stream`](crate::Stream)s that were not dropped yet, or
+    /// `0` unless [`ConnectionBuilder::track_open_substreams`] is enabled.
+    ///
+    /// Unlike [`Connection::is_idle`], this includes the streams ignored for keep-alive, see
+    /// [`Stream::ignore_for_keep_alive`](crate::Stream::ignore_for_keep_alive). A count that keeps
+    /// growing hints at a [`ConnectionHandler`] leaking streams.
+    pub fn open_substreams(&self) -> usize {
+        self.open_streams
+            .as_ref()
+            .map_or(0, OpenStreamCounter::num_open_streams)
+    }
+
+    /// Returns a snapshot of the internals of the connection, for diagnostics.
+    ///
+    /// Richer than the [`Debug`](fmt::Debug) output of the connection, which only includes the
+    /// [`ConnectionHandler`].
+    pub fn debug_snapshot(&self) -> ConnectionSnapshot {
+        let mut local_protocols = protocol_names_of(&self.local_supported_protocols)
+            .into_iter()
+            .collect::<Vec<_>>();
+        local_protocols.sort_unstable();
+        let mut remote_protocols = self
+            .remote_supported_protocols
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        remote_protocols.sort_unstable();
+
+        ConnectionSnapshot {
+            id: self.id,
+            negotiating_inbound: self.negotiating_in.len(),
+            negotiating_outbound: self.negotiating_out.len(),
+            requested_outbound: self.requested_substreams.len(),
+            shutdown: self.shutdown.planned(self.clock.now()),
+            local_protocols,
+            remote_protocols,
+            age: self.age(),
+        }
+    }
+
+    /// Returns why the connection is going to shut down, or `None` if no shutdown is planned.
+    ///
+    /// See [`Connection::debug_snapshot`] for when the shutdown is due.
+    pub fn pending_shutdown_reason(&self) -> Option<PendingShutdownReason> {
+        self.shutdown
+            .pending_reason(self.scheduled_shutdown, self.clock.now())
+    }
+
+    /// Returns the share of the most recent outbound stream negotiations that succeeded, between
+    /// `0.0` and `1.0`, e.g. to prefer the healthier of several connections to a peer.
+    ///
+    /// Considers the streams that were granted by the muxer and either negotiated or failed with
+    /// a [`DialUpgradeError`], over the [`ConnectionBuilder::outbound_success_window`]. Returns
+    /// `1.0` until the first negotiation completed.
+    pub fn outbound_success_rate(&self) -> f32 {
+        self.outbound_success.rate()
+    }
+
+    /// Returns the direction of the stream the muxer produced last and when it did, or `None` if
+    /// it did not produce any stream yet.
+    ///
+    /// Helps to diagnose a muxer that starves one of the directions.
+    pub fn last_stream_event(&self) -> Option<(SubstreamDirection, Instant)> {
+        self.last_stream_event
+    }
+
+    /// Shuts the connection down at `when`, or as soon as possible if `None`, regardless of
+    /// [`ConnectionHandler::connection_keep_alive`], e.g. to enforce a budget of connections.
+    ///
+    /// Takes precedence over the decision of the handler and over
+    /// [`Connection::force_keep_alive_until`] from the next call to `poll` onwards, including the
+    /// idle timeout. The shutdown is still postponed while the connection is not idle, see
+    /// [`Connection::is_idle`], and then ends the connection like an elapsed idle timeout, see
+    /// [`ConnectionError::KeepAliveTimeout`]. Replaces a previously scheduled shutdown.
+    pub fn schedule_shutdown(&mut self, when: Option<Instant>) {
+        self.scheduled_shutdown = Some(match when {
+            Some(at) => ScheduledShutdown::At(at),
+            None => ScheduledShutdown::Asap,
+        });
+    }
+
+    /// Returns why the connection is kept alive, or `None` if it is going to shut down, see
+    /// [`Connection::pending_shutdown_reason`].
+    ///
+    /// Reports the first of the reasons that apply, in the order of [`LivenessReason`]'s
+    /// variants. The keep-alive of idle connections is decided anew, it may thus differ from the
+    /// [`ConnectionHandler::connection_keep_alive`] the connection acted on when last polled.
+    pub fn liveness_reason(&self) -> Option<LivenessReason> {
+        if matches!(self.shutdown, Shutdown::Graceful { .. }) {
+            return None;
+        }
+        if !self.negotiating_in.is_empty() || !self.negotiating_out.is_empty() {
+            return Some(LivenessReason::Negotiating {
+                inbound: self.negotiating_in.len(),
+                outbound: self.negotiating_out.len(),
+            });
+        }
+        let waiting = self
+            .requested_substreams
+            .iter()
+            .filter(|request| request.is_waiting())
+            .count();
+        if waiting > 0 {
+            return Some(LivenessReason::RequestedStreams { outbound: waiting });
+        }
+        if !self.stream_counter.has_no_active_streams() {
+            return Some(LivenessReason::ActiveStreams);
+        }
+        if self.scheduled_shutdown.is_some() {
+            return None;
+        }
+        if let Some(until) = self
+            .forced_keep_alive
+            .as_ref()
+            .and_then(|forced| forced.active_until(self.clock.now()))
+        {
+            return Some(LivenessReason::ForcedKeepAlive { until });
+        }
+
+        self.handler
+            .connection_keep_alive()
+            .then_some(LivenessReason::HandlerKeepAlive)
+    }
+
+    /// Returns whether the connection can't keep up with its streams.
+    ///
+    /// That is the case while the maximum of inbound streams is negotiating, see
+    /// [`ConnectionBuilder::max_negotiating_inbound_streams`], and more outbound stream requests
+    /// than the [`ConnectionBuilder::congestion_threshold`] wait for a stream. New streams are
+    /// better opened on other connections in the meantime.
+    pub fn is_congested(&self) -> bool {
+        self.negotiating_in.len() >= self.max_negotiating_inbound_streams
+            && self
+                .requested_substreams
+                .iter()
+                .filter(|request| request.is_waiting())
+                .count()
+                > self.congestion_threshold
+    }
+
+    /// Hands an inbound stream that was already upgraded by other means directly to the
+    /// [`ConnectionHandler`] as [`ConnectionEvent::FullyNegotiatedInbound`].
+    ///
+    /// This is meant for schemes that negotiate protocols over an existing stream and thus bypass
+    /// the upgrade of new inbound streams. The connection should be polled afterwards, as the
+    /// handler may have become ready to make progress. `negotiated_protocol` is reported to the
+    /// handler as the protocol the stream was negotiated for.
+    #[expect(deprecated)] // TODO: Remove when {In, Out}boundOpenInfo is fully removed.
+    pub fn inject_fully_negotiated_inbound(
+        &mut self,
+        protocol: <THandler::InboundProtocol as InboundUpgradeSend>::Output,
+        info: THandler::InboundOpenInfo,
+        negotiated_protocol: <THandler::InboundProtocol as UpgradeInfoSend>::Info,
+    ) {
+        self.first_stream_deadline = None;
+        self.handler
+            .on_connection_event(ConnectionEvent::FullyNegotiatedInbound(
+                FullyNegotiatedInbound {
+                    protocol,
+                    info,
+                    negotiated_protocol,
+                },
+            ));
+    }
+
+    /// Requests an outbound stream as if the [`ConnectionHandler`] emitted a
+    /// [`ConnectionHandlerEvent::OutboundSubstreamRequest`] for `protocol`.
+    ///
+    /// This is meant for embeddings that drive the connection directly rather than via a
+    /// [`Swarm`](crate::Swarm). It bypasses the accounting of the handler: the handler did not ask
+    /// for the stream, yet receives its outcome as [`ConnectionEvent::FullyNegotiatedOutbound`] or
+    /// [`ConnectionEvent::DialUpgradeError`], including [`StreamUpgradeError::Throttled`] if the
+    /// protocols are backed off. The stream is only opened once the connection is polled.
+    ///
+    /// ```
+    /// # use std::{convert::Infallible, pin::Pin, task::{Context, Poll}};
+    /// # use libp2p_core::{muxing::{StreamMuxerBox, StreamMuxerEvent}, upgrade::DeniedUpgrade, StreamMuxer};
+    /// # use libp2p_identity::PeerId;
+    /// # use libp2p_swarm::{dummy, ConnectionBuilder, SubstreamProtocol};
+    /// # struct Muxer;
+    /// # impl StreamMuxer for Muxer {
+    /// #     type Substream = futures::io::Cursor<Vec<u8>>;
+    /// #     type Error = Infallible;
+    /// #     fn poll_inbound(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<Self::Substream, Infallible>> { Poll::Pending }
+    /// #     fn poll_outbound(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<Self::Substream, Infallible>> { Poll::Pending }
+    /// #     fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> { Poll::Ready(Ok(())) }
+    /// #     fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<StreamMuxerEvent, Infallible>> { Poll::Pending }
+    /// # }
+    /// let mut connection = ConnectionBuilder::new(PeerId::random())
+    ///     .build(StreamMuxerBox::new(Muxer), dummy::ConnectionHandler);
+    ///
+    /// connection.request_outbound(SubstreamProtocol::new(DeniedUpgrade, ()));
+    ///
+    /// assert_eq!(connection.pending_outbound_info().count(), 1);
+    /// ```
+    #[expect(deprecated)] // TODO: Remove when {In, Out}boundOpenInfo is fully removed.
+    pub fn request_outbound(
+        &mut self,
+        protocol: SubstreamProtocol<THandler::OutboundProtocol, THandler::OutboundOpenInfo>,
+    ) {
+        if let Err((info, retry_after)) = queue_outbound_request(
+            protocol,
+            &mut self.requested_substreams,
+            &mut self.substream_requests,
+            self.outbound_backoffs.as_ref(),
+            self.outbound_grants.is_some(),
+            self.outbound_timeout_policy.as_ref(),
+            self.substream_deadline,
+            &*self.clock,
+        ) {
+            self.handler
+                .on_connection_event(ConnectionEvent::DialUpgradeError(DialUpgradeError {
+                    info,
+                    error: StreamUpgradeError::Throttled { retry_after },
+                }));
+        }
+    }
+
+    /// Notifies the connection handler of an event.
+    pub(crate) fn on_behaviour_event(&mut self, event: THandler::FromBehaviour) {
+        self.handler.on_behaviour_event(event);
+    }
+
+    /// Begins an orderly shutdown of the connection, returning a stream of final events, a
+    /// `Future` that resolves when connection shutdown is complete and the user data of all
+    /// outbound substream requests that were abandoned because no substream was granted yet.
+    #[expect(deprecated)] // TODO: Remove when {In, Out}boundOpenInfo is fully removed.
+    pub(crate) fn close(
+        self,
+    ) -> (
+        impl futures::Stream<Item = THandler::ToBehaviour>,
+        impl Future<Output = Result<(), CloseError>>,
+        Vec<THandler::OutboundOpenInfo>,
+    ) {
+        let Connection {
+            mut handler,
+            muxing,
+            requested_substreams,
+            negotiating_in,
+            negotiating_out,
+            mut keep_alive_metrics,
+            ..
+        } = self;
+
+        keep_alive_metrics.on_shutdown(ShutdownReason::Graceful);
+
+        let abandoned = requested_substreams
+            .into_iter()
+            .filter_map(SubstreamRequested::into_user_data)
+            .collect::<Vec<_>>();
+        let abandoned_substreams = abandoned.len() + negotiating_in.len() + negotiating_out.len();
+
+        (
+            stream::poll_fn(move |cx| handler.poll_close(cx)),
+            muxing.close().map_err(move |source| CloseError {
+                source,
+                abandoned_substreams,
+            }),
+            abandoned,
+        )
+    }
+
+    /// Polls the connection like [`Stream::poll_next`](stream::Stream::poll_next), but pushes up
+    /// to `buf.capacity()` events, and at least one, into `buf` before returning.
+    ///
+    /// Saves a wake-up per event for handlers that emit many events at once. Returns
+    /// `Poll::Pending` if no event is available yet and `None` once the connection failed. If the
+    /// connection fails while events are pushed, the error is returned and the events already
+    /// pushed remain in `buf`.
+    pub fn poll_batch(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut Vec<Event<THandler::ToBehaviour>>,
+    ) -> Poll<Option<Result<(), PeerConnectionError>>> {
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+
+        let start = buf.len();
+        let limit = buf.capacity().max(start + 1);
+        while buf.len() < limit {
+            match Pin::new(&mut *self).poll(cx) {
+                Poll::Ready(Ok(event)) => buf.push(event),
+                Poll::Ready(Err(error)) => {
+                    self.terminated = true;
+                    return Poll::Ready(Some(Err(error)));
+                }
+                Poll::Pending if buf.len() == start => return Poll::Pending,
+                Poll::Pending => break,
+            }
+        }
+
+        Poll::Ready(Some(Ok(())))
     }
 
     /// Polls the handler and the substream, forwarding events from the former to the latter and
@@ -259,25 +1620,147 @@ where
     pub(crate) fn poll(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
+    ) -> Poll<Result<Event<THandler::ToBehaviour>, PeerConnectionError>> {
+        let this = self.get_mut();
+        let (id, peer_id) = (this.id, this.peer_id);
+
+        #[cfg(not(feature = "catch-handler-panics"))]
+        let poll = Pin::new(&mut *this).poll_inner(cx);
+        // The connection is not polled again after the error, so its possibly inconsistent state
+        // is never observed.
+        #[cfg(feature = "catch-handler-panics")]
+        let poll = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Pin::new(&mut *this).poll_inner(cx)
+        }))
+        .unwrap_or_else(|payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned());
+            tracing::error!(?message, "Connection handler panicked");
+            Poll::Ready(Err(ConnectionError::HandlerPanic { message }))
+        });
+
+        if let Poll::Ready(Err(error)) = &poll {
+            this.keep_alive_metrics.on_shutdown(match error {
+                ConnectionError::KeepAliveTimeout { .. } => ShutdownReason::KeepAliveTimeout,
+                ConnectionError::IO(_)
+                | ConnectionError::NegotiationDeadline { .. }
+                | ConnectionError::Silent { .. }
+                | ConnectionError::HandlerPanic { .. } => ShutdownReason::Error,
+            });
+        }
+
+        poll.map_err(|error| {
+            let abandoned_substreams = this
+                .requested_substreams
+                .iter()
+                .filter(|request| request.is_waiting())
+                .count()
+                + this.negotiating_in.len()
+                + this.negotiating_out.len();
+
+            PeerConnectionError::new(
+                id,
+                peer_id,
+                error,
+                abandoned_substreams,
+                this.debug_snapshot(),
+            )
+        })
+    }
+
+    fn poll_inner(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
     ) -> Poll<Result<Event<THandler::ToBehaviour>, ConnectionError>> {
         let Self {
             requested_substreams,
+            substream_requests,
             muxing,
             handler,
+            endpoint,
+            remote_address,
             negotiating_out,
             negotiating_in,
+            negotiation_high_water,
+            total_negotiated,
             shutdown,
             max_negotiating_inbound_streams,
+            max_negotiating_outbound_streams,
             substream_upgrade_protocol_override,
+            substream_poll_priority,
+            handler_poll_budget,
+            handler_polls,
+            poll_budget,
+            outbound_stall,
+            round_robin_inbound_first,
             local_supported_protocols: supported_protocols,
+            listen_protocols,
+            protocols_generation,
             remote_supported_protocols,
             protocol_buffer,
             idle_timeout,
+            clock,
+            bandwidth,
+            keep_alive_metrics,
+            pending_metrics,
+            idle,
+            idle_probing,
+            inbound_rate_limiter,
+            outbound_backoffs,
+            first_stream_deadline,
+            forced_keep_alive,
+            scheduled_shutdown,
+            negotiation_executor,
+            outbound_grants,
+            outbound_timeout_policy,
+            outbound_retries,
+            congestion_threshold: _,
+            graceful_keep_alive_timeout,
+            pre_negotiated_protocol,
+            substream_deadline,
+            protocol_rewriter,
+            outbound_success,
+            negotiation_poll_window,
+            drain_removed_protocols,
+            silence_watchdog,
+            last_stream_event,
+            established_at,
             stream_counter,
+            resettable_streams,
+            open_streams,
             ..
         } = self.get_mut();
 
-        loop {
+        // Once the muxer returned `Poll::Pending` for one of its poll functions, it has registered
+        // our waker and will wake us once it can make progress. There is no point in polling it
+        // again within the same call, so we remember which parts of the muxer are pending.
+        let mut muxer_pending = MuxerPending::default();
+        let mut iterations = 0;
+        let mut negotiations = 0;
+
+        if let Some(deadline) = first_stream_deadline {
+            if let Poll::Ready(error) = deadline.poll(&**clock, *established_at, cx) {
+                return Poll::Ready(Err(error));
+            }
+        }
+
+        if let Some(watchdog) = silence_watchdog {
+            if let Poll::Ready(error) = watchdog.poll(&**clock, cx) {
+                return Poll::Ready(Err(error));
+            }
+        }
+
+        'poll: loop {
+            // Yield once the budget is exhausted, so an always-ready connection can't monopolize
+            // the executor.
+            if iterations == *poll_budget {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            iterations += 1;
+
             match requested_substreams.poll_next_unpin(cx) {
                 Poll::Ready(Some(Ok(()))) => continue,
                 Poll::Ready(Some(Err(info))) => {
@@ -287,75 +1770,168 @@ where
                             error: StreamUpgradeError::Timeout,
                         },
                     ));
+                    pending_metrics.add(MetricsDelta::OUTBOUND_GRANT_TIMEOUTS, 1);
                     continue;
                 }
                 Poll::Ready(None) | Poll::Pending => {}
             }
 
-            // Poll the [`ConnectionHandler`].
-            match handler.poll(cx) {
+            // Poll the [`ConnectionHandler`], unless it exhausted its budget. In that case, give
+            // substream negotiation and the muxer a chance to make progress first.
+            let handler_skipped = *handler_polls >= *handler_poll_budget;
+            let handler_event = if handler_skipped {
+                *handler_polls = 0;
+                Poll::Pending
+            } else {
+                *handler_polls += 1;
+                handler.poll(cx)
+            };
+
+            if handler_event.is_ready() {
+                SilenceWatchdog::on_activity(silence_watchdog, &**clock);
+            }
+
+            match handler_event {
                 Poll::Pending => {}
                 Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { protocol }) => {
-                    let timeout = *protocol.timeout();
-                    let (upgrade, user_data) = protocol.into_upgrade();
-
-                    requested_substreams.push(SubstreamRequested::new(user_data, timeout, upgrade));
+                    if let Err((info, retry_after)) = queue_outbound_request(
+                        protocol,
+                        requested_substreams,
+                        substream_requests,
+                        outbound_backoffs.as_ref(),
+                        outbound_grants.is_some(),
+                        outbound_timeout_policy.as_ref(),
+                        *substream_deadline,
+                        &**clock,
+                    ) {
+                        handler.on_connection_event(ConnectionEvent::DialUpgradeError(
+                            DialUpgradeError {
+                                info,
+                                error: StreamUpgradeError::Throttled { retry_after },
+                            },
+                        ));
+                    }
+                    continue; // Poll handler until exhausted.
+                }
+                Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequestBatch {
+                    protocols,
+                }) => {
+                    for protocol in protocols {
+                        if let Err((info, retry_after)) = queue_outbound_request(
+                            protocol,
+                            requested_substreams,
+                            substream_requests,
+                            outbound_backoffs.as_ref(),
+                            outbound_grants.is_some(),
+                            outbound_timeout_policy.as_ref(),
+                            *substream_deadline,
+                            &**clock,
+                        ) {
+                            handler.on_connection_event(ConnectionEvent::DialUpgradeError(
+                                DialUpgradeError {
+                                    info,
+                                    error: StreamUpgradeError::Throttled { retry_after },
+                                },
+                            ));
+                        }
+                    }
                     continue; // Poll handler until exhausted.
                 }
                 Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event)) => {
                     return Poll::Ready(Ok(Event::Handler(event)));
                 }
-                Poll::Ready(ConnectionHandlerEvent::ReportRemoteProtocols(
-                    ProtocolSupport::Added(protocols),
-                )) => {
-                    if let Some(added) =
-                        ProtocolsChange::add(remote_supported_protocols, protocols, protocol_buffer)
-                    {
-                        handler.on_connection_event(ConnectionEvent::RemoteProtocolsChange(added));
-                        remote_supported_protocols.extend(protocol_buffer.drain(..));
+                Poll::Ready(ConnectionHandlerEvent::ReportMetrics(mut metrics)) => {
+                    for (label, delta) in mem::take(pending_metrics).iter() {
+                        metrics.add(label, delta);
                     }
+                    return Poll::Ready(Ok(Event::Metrics(metrics)));
+                }
+                Poll::Ready(ConnectionHandlerEvent::CloseGracefully { reason }) => {
+                    *shutdown = Shutdown::Graceful { reason };
                     continue;
                 }
-                Poll::Ready(ConnectionHandlerEvent::ReportRemoteProtocols(
-                    ProtocolSupport::Removed(protocols),
-                )) => {
-                    if let Some(removed) = ProtocolsChange::remove(
+                Poll::Ready(ConnectionHandlerEvent::ReportRemoteProtocols(support)) => {
+                    apply_remote_protocols(
+                        handler,
                         remote_supported_protocols,
-                        protocols,
                         protocol_buffer,
-                    ) {
-                        handler
-                            .on_connection_event(ConnectionEvent::RemoteProtocolsChange(removed));
-                    }
+                        support,
+                    );
                     continue;
                 }
             }
 
             // In case the [`ConnectionHandler`] can not make any more progress, poll the
             // negotiating outbound streams.
-            match negotiating_out.poll_next_unpin(cx) {
+            let outbound = if negotiations < *negotiation_poll_window {
+                negotiating_out.poll_next_unpin(cx)
+            } else {
+                Poll::Pending
+            };
+            if let Poll::Ready(Some(_)) = outbound {
+                negotiations += 1;
+                SilenceWatchdog::on_activity(silence_watchdog, &**clock);
+            }
+            match outbound {
                 Poll::Pending | Poll::Ready(None) => {}
-                Poll::Ready(Some((info, Ok(protocol)))) => {
+                Poll::Ready(Some(((info, protocols), Ok((negotiated_protocol, protocol))))) => {
+                    if let (Some(backoffs), Some(protocols)) =
+                        (outbound_backoffs.as_mut(), protocols)
+                    {
+                        backoffs.on_success(&protocols);
+                    }
+                    total_negotiated.1 += 1;
+                    outbound_success.record(true);
+                    *first_stream_deadline = None;
                     handler.on_connection_event(ConnectionEvent::FullyNegotiatedOutbound(
-                        FullyNegotiatedOutbound { protocol, info },
+                        FullyNegotiatedOutbound {
+                            protocol,
+                            info,
+                            negotiated_protocol,
+                        },
                     ));
                     continue;
                 }
-                Poll::Ready(Some((info, Err(error)))) => {
+                Poll::Ready(Some(((info, protocols), Err(error)))) => {
+                    if let (Some(backoffs), Some(protocols)) =
+                        (outbound_backoffs.as_mut(), protocols)
+                    {
+                        backoffs.on_failure(protocols, clock.now());
+                    }
+                    outbound_success.record(false);
+                    let timed_out = matches!(error, StreamUpgradeError::Timeout);
                     handler.on_connection_event(ConnectionEvent::DialUpgradeError(
                         DialUpgradeError { info, error },
                     ));
+                    if timed_out {
+                        pending_metrics.add(MetricsDelta::OUTBOUND_UPGRADE_TIMEOUTS, 1);
+                    }
                     continue;
                 }
             }
 
             // In case both the [`ConnectionHandler`] and the negotiating outbound streams can not
             // make any more progress, poll the negotiating inbound streams.
-            match negotiating_in.poll_next_unpin(cx) {
+            let inbound = if negotiations < *negotiation_poll_window {
+                negotiating_in.poll_next_unpin(cx)
+            } else {
+                Poll::Pending
+            };
+            if let Poll::Ready(Some(_)) = inbound {
+                negotiations += 1;
+                SilenceWatchdog::on_activity(silence_watchdog, &**clock);
+            }
+            match inbound {
                 Poll::Pending | Poll::Ready(None) => {}
-                Poll::Ready(Some((info, Ok(protocol)))) => {
+                Poll::Ready(Some((info, Ok((negotiated_protocol, protocol))))) => {
+                    total_negotiated.0 += 1;
+                    *first_stream_deadline = None;
                     handler.on_connection_event(ConnectionEvent::FullyNegotiatedInbound(
-                        FullyNegotiatedInbound { protocol, info },
+                        FullyNegotiatedInbound {
+                            protocol,
+                            info,
+                            negotiated_protocol,
+                        },
                     ));
                     continue;
                 }
@@ -366,7 +1942,8 @@ where
                     continue;
                 }
                 Poll::Ready(Some((_, Err(StreamUpgradeError::Io(e))))) => {
-                    tracing::debug!("failed to upgrade inbound stream: {e}");
+                    let cause = ResetCause::from_io_error(&e);
+                    tracing::debug!(?cause, "failed to upgrade inbound stream: {e}");
                     continue;
                 }
                 Poll::Ready(Some((_, Err(StreamUpgradeError::NegotiationFailed)))) => {
@@ -375,6 +1952,13 @@ where
                 }
                 Poll::Ready(Some((_, Err(StreamUpgradeError::Timeout)))) => {
                     tracing::debug!("inbound stream upgrade timed out");
+                    pending_metrics.add(MetricsDelta::INBOUND_UPGRADE_TIMEOUTS, 1);
+                    continue;
+                }
+                Poll::Ready(Some((_, Err(StreamUpgradeError::Throttled { retry_after })))) => {
+                    // Only outbound stream requests are backed off, but don't fail the connection
+                    // if an inbound upgrade reports it anyway.
+                    tracing::debug!("inbound stream upgrade throttled for {retry_after:?}");
                     continue;
                 }
             }
@@ -382,151 +1966,420 @@ where
             // Check if the connection (and handler) should be shut down.
             // As long as we're still negotiating substreams or have
             // any active streams shutdown is always postponed.
-            if negotiating_in.is_empty()
+            if let Shutdown::Graceful { reason } = shutdown {
+                // Requested substreams are abandoned, only those negotiating are drained.
+                if negotiating_in.is_empty() && negotiating_out.is_empty() {
+                    return Poll::Ready(Ok(Event::CloseRequested {
+                        reason: mem::take(reason),
+                    }));
+                }
+            } else if negotiating_in.is_empty()
                 && negotiating_out.is_empty()
-                && requested_substreams.is_empty()
+                && !has_waiting_requests(requested_substreams)
                 && stream_counter.has_no_active_streams()
             {
-                if let Some(new_timeout) =
-                    compute_new_shutdown(handler.connection_keep_alive(), shutdown, *idle_timeout)
+                let now = clock.now();
+                idle.on_idle(handler, now);
+                let keep_alive = ForcedKeepAlive::poll_active(forced_keep_alive, now, cx)
+                    || handler.connection_keep_alive();
+                keep_alive_metrics.on_keep_alive(keep_alive);
+
+                if shutdown.poll_idle(keep_alive, *scheduled_shutdown, *idle_timeout, &**clock, cx)
                 {
-                    *shutdown = new_timeout;
+                    let error = ConnectionError::KeepAliveTimeout {
+                        idle_for: idle.idle_for(now),
+                    };
+                    if !*graceful_keep_alive_timeout {
+                        return Poll::Ready(Err(error));
+                    }
+
+                    keep_alive_metrics.on_shutdown(ShutdownReason::KeepAliveTimeout);
+                    return Poll::Ready(Ok(Event::CloseRequested {
+                        reason: error.to_string(),
+                    }));
                 }
 
-                match shutdown {
-                    Shutdown::None => {}
-                    Shutdown::Asap => return Poll::Ready(Err(ConnectionError::KeepAliveTimeout)),
-                    Shutdown::Later(delay) => match Future::poll(Pin::new(delay), cx) {
-                        Poll::Ready(_) => {
-                            return Poll::Ready(Err(ConnectionError::KeepAliveTimeout))
-                        }
-                        Poll::Pending => {}
-                    },
+                // Probe idle connections regardless of the keep-alive decision, so a connection
+                // kept alive by its handler is still closed once the remote is gone.
+                if let Some(probing) = idle_probing {
+                    if let Poll::Ready(error) = probing.poll(&**clock, cx) {
+                        return Poll::Ready(Err(ConnectionError::IO(error)));
+                    }
                 }
             } else {
                 *shutdown = Shutdown::None;
-            }
-
-            match muxing.poll_unpin(cx)? {
-                Poll::Pending => {}
-                Poll::Ready(StreamMuxerEvent::AddressChange(address)) => {
-                    handler.on_connection_event(ConnectionEvent::AddressChange(AddressChange {
-                        new_address: &address,
-                    }));
-                    return Poll::Ready(Ok(Event::AddressChange(address)));
+                idle.on_busy(handler);
+                if let Some(probing) = idle_probing {
+                    probing.stop();
                 }
             }
 
-            if let Some(requested_substream) = requested_substreams.iter_mut().next() {
-                match muxing.poll_outbound_unpin(cx)? {
-                    Poll::Pending => {}
-                    Poll::Ready(substream) => {
-                        let (user_data, timeout, upgrade) = requested_substream.extract();
-
-                        negotiating_out.push(StreamUpgrade::new_outbound(
-                            substream,
-                            user_data,
-                            timeout,
-                            upgrade,
-                            *substream_upgrade_protocol_override,
-                            stream_counter.clone(),
-                        ));
+            if !muxer_pending.events {
+                let muxer_event = match muxing.poll_unpin(cx) {
+                    Poll::Ready(Ok(event)) => {
+                        SilenceWatchdog::on_activity(silence_watchdog, &**clock);
+                        Some(event)
+                    }
+                    Poll::Ready(Err(error)) => {
+                        // Fails the connection like any other error, which reports the requested
+                        // and negotiating substreams as abandoned.
+                        tracing::debug!("Stream muxer failed: {error}");
+                        return Poll::Ready(Err(ConnectionError::IO(error)));
+                    }
+                    Poll::Pending => None,
+                };
+
+                match muxer_event {
+                    None => muxer_pending.events = true,
+                    Some(StreamMuxerEvent::AddressChange(address)) => {
+                        if address == *remote_address {
+                            continue;
+                        }
 
-                        // Go back to the top,
-                        // handler can potentially make progress again.
+                        if handler
+                            .interested_events()
+                            .contains(ConnectionEventMask::ADDRESS_CHANGE)
+                        {
+                            handler.on_connection_event(ConnectionEvent::AddressChange(
+                                AddressChange {
+                                    new_address: &address,
+                                },
+                            ));
+                            handler.on_address_change(&address);
+                        }
+                        if let Some(endpoint) = endpoint {
+                            endpoint.set_remote_address(address.clone());
+                        }
+                        let old_address = mem::replace(remote_address, address.clone());
+                        return Poll::Ready(Ok(Event::AddressChange {
+                            old_address,
+                            new_address: address,
+                        }));
+                    }
+                    Some(StreamMuxerEvent::StreamHalfClosed) => {
+                        if handler
+                            .interested_events()
+                            .contains(ConnectionEventMask::STREAM_HALF_CLOSED)
+                        {
+                            handler.on_connection_event(ConnectionEvent::StreamHalfClosed);
+                        }
+                        continue;
+                    }
+                    Some(event) => {
+                        tracing::debug!(?event, "Ignoring unknown stream muxer event");
                         continue;
                     }
                 }
             }
 
-            if negotiating_in.len() < *max_negotiating_inbound_streams {
-                match muxing.poll_inbound_unpin(cx)? {
-                    Poll::Pending => {}
-                    Poll::Ready(substream) => {
-                        let protocol = handler.listen_protocol();
+            let closing = matches!(shutdown, Shutdown::Graceful { .. });
 
-                        negotiating_in.push(StreamUpgrade::new_inbound(
-                            substream,
-                            protocol,
-                            stream_counter.clone(),
-                        ));
+            for direction in substream_poll_priority.order(*round_robin_inbound_first) {
+                match direction {
+                    SubstreamDirection::Outbound => {
+                        if requested_substreams.is_empty() {
+                            outbound_stall.on_no_requests();
+                        }
 
-                        // Go back to the top,
-                        // handler can potentially make progress again.
-                        continue;
+                        // Grant as many requested streams as the muxer allows at once, instead of
+                        // one per iteration. The batch is bounded by the requests already made,
+                        // after which inbound streams get their turn.
+                        let mut granted = false;
+                        let mut waiting = if muxer_pending.outbound || closing {
+                            OutboundQueue::default()
+                        } else {
+                            OutboundQueue::new(requested_substreams, outbound_grants.as_ref())
+                        };
+                        while negotiating_out.len() < *max_negotiating_outbound_streams {
+                            let Some(requested_substream) = waiting.pop(outbound_grants.as_ref())
+                            else {
+                                break;
+                            };
+
+                            let poll = muxing.poll_outbound_unpin(cx);
+                            if let Poll::Ready(Err(error)) = &poll {
+                                if outbound_retries.on_error(error) {
+                                    tracing::debug!(
+                                        "Retrying outbound stream after transient error: {error}"
+                                    );
+                                    // Retry on the next poll, the requests stay queued.
+                                    muxer_pending.outbound = true;
+                                    cx.waker().wake_by_ref();
+                                    break;
+                                }
+                            }
+
+                            match poll? {
+                                Poll::Pending => {
+                                    muxer_pending.outbound = true;
+
+                                    if outbound_stall.on_pending(handler, &**clock, cx) {
+                                        continue 'poll;
+                                    }
+                                    break;
+                                }
+                                Poll::Ready(substream) => {
+                                    *last_stream_event =
+                                        Some((SubstreamDirection::Outbound, clock.now()));
+                                    outbound_stall.on_granted(handler);
+                                    outbound_retries.on_granted();
+
+                                    let (user_data, timeout, upgrade, protocol) =
+                                        requested_substream.extract();
+                                    if let (Some(grants), Some(protocol)) =
+                                        (outbound_grants.as_mut(), protocol)
+                                    {
+                                        grants.on_granted(protocol);
+                                    }
+                                    let protocols = outbound_backoffs
+                                        .as_ref()
+                                        .map(|_| protocol_names(&upgrade));
+
+                                    let mut upgrade = StreamUpgrade::new_outbound(
+                                        count_bytes(substream, bandwidth),
+                                        (user_data, protocols),
+                                        timeout,
+                                        upgrade,
+                                        *substream_upgrade_protocol_override,
+                                        pre_negotiated_protocol.clone(),
+                                        stream_counter.clone(),
+                                        resettable_streams
+                                            .as_mut()
+                                            .map(ResettableStreams::register),
+                                        open_streams.clone(),
+                                    );
+                                    if let Some(executor) = negotiation_executor {
+                                        upgrade = upgrade.offload_to(executor);
+                                    }
+                                    negotiating_out.push(upgrade);
+                                    negotiation_high_water.1 =
+                                        negotiation_high_water.1.max(negotiating_out.len());
+                                    granted = true;
+                                }
+                            }
+                        }
+
+                        if granted {
+                            *round_robin_inbound_first = true;
+
+                            // Go back to the top,
+                            // handler can potentially make progress again.
+                            continue 'poll;
+                        }
+                    }
+                    SubstreamDirection::Inbound => {
+                        if negotiating_in.len() >= *max_negotiating_inbound_streams
+                            || muxer_pending.inbound
+                            || closing
+                        {
+                            continue;
+                        }
+
+                        // Leave new inbound streams queued in the muxer until the next token is
+                        // refilled, streams that are already negotiating continue to make progress.
+                        if let Some(limiter) = inbound_rate_limiter {
+                            if !limiter.poll_ready(&**clock, cx) {
+                                continue;
+                            }
+                        }
+
+                        match muxing.poll_inbound_unpin(cx)? {
+                            Poll::Pending => muxer_pending.inbound = true,
+                            Poll::Ready(substream) => {
+                                *last_stream_event =
+                                    Some((SubstreamDirection::Inbound, clock.now()));
+                                if let Some(limiter) = inbound_rate_limiter {
+                                    limiter.acquire();
+                                }
+                                let protocol = handler.listen_protocol();
+
+                                let Some(mut upgrade) = StreamUpgrade::new_inbound(
+                                    count_bytes(substream, bandwidth),
+                                    protocol,
+                                    &**clock,
+                                    *substream_deadline,
+                                    protocol_rewriter.clone(),
+                                    (handler.wants_protocols_change() && !*drain_removed_protocols)
+                                        .then(|| listen_protocols.clone()),
+                                    pre_negotiated_protocol.clone(),
+                                    stream_counter.clone(),
+                                    resettable_streams.as_mut().map(ResettableStreams::register),
+                                    open_streams.clone(),
+                                ) else {
+                                    continue 'poll;
+                                };
+                                if let Some(executor) = negotiation_executor {
+                                    upgrade = upgrade.offload_to(executor);
+                                }
+                                negotiating_in.push(upgrade);
+                                negotiation_high_water.0 =
+                                    negotiation_high_water.0.max(negotiating_in.len());
+                                *round_robin_inbound_first = false;
+
+                                // Go back to the top,
+                                // handler can potentially make progress again.
+                                continue 'poll;
+                            }
+                        }
                     }
                 }
             }
 
-            let changes = ProtocolsChange::from_full_sets(
+            if apply_local_protocols(
+                handler,
                 supported_protocols,
-                handler.listen_protocol().upgrade().protocol_info(),
+                listen_protocols,
+                protocols_generation,
+                protocol_rewriter.as_ref(),
                 protocol_buffer,
-            );
-
-            if !changes.is_empty() {
-                for change in changes {
-                    handler.on_connection_event(ConnectionEvent::LocalProtocolsChange(change));
-                }
+            ) {
                 // Go back to the top, handler can potentially make progress again.
                 continue;
             }
 
+            // The handler was skipped and thus did not register a waker, poll it again.
+            if handler_skipped {
+                continue;
+            }
+
+            // The remaining negotiations were not polled and thus did not register a waker, handle
+            // them on the next call.
+            if negotiations >= *negotiation_poll_window
+                && !(negotiating_in.is_empty() && negotiating_out.is_empty())
+            {
+                cx.waker().wake_by_ref();
+            }
+
+            // Report the counters of the connection that the handler did not pick up.
+            if !pending_metrics.is_empty() {
+                return Poll::Ready(Ok(Event::Metrics(mem::take(pending_metrics))));
+            }
+
             // Nothing can make progress, return `Pending`.
             return Poll::Pending;
         }
     }
 
+    /// Replaces the [`Clock`] used for the keep-alive timer and the age of the connection.
+    #[cfg(test)]
+    fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.established_at = clock.now();
+        self.clock = Arc::new(clock);
+        self
+    }
+
     #[cfg(test)]
     fn poll_noop_waker(&mut self) -> Poll<Result<Event<THandler::ToBehaviour>, ConnectionError>> {
-        Pin::new(self).poll(&mut Context::from_waker(futures::task::noop_waker_ref()))
+        Pin::new(self)
+            .poll(&mut Context::from_waker(futures::task::noop_waker_ref()))
+            .map_err(PeerConnectionError::into_error)
     }
 }
 
 fn gather_supported_protocols<C: ConnectionHandler>(
     handler: &C,
-) -> HashMap<AsStrHashEq<<C::InboundProtocol as UpgradeInfoSend>::Info>, bool> {
+    rewriter: Option<&ProtocolRewriter>,
+) -> HashMap<AsStrHashEq<RewrittenProtocol<<C::InboundProtocol as UpgradeInfoSend>::Info>>, bool> {
     handler
         .listen_protocol()
         .upgrade()
         .protocol_info()
-        .map(|info| (AsStrHashEq(info), true))
+        .map(|info| (AsStrHashEq(RewrittenProtocol::new(info, rewriter)), true))
         .collect()
 }
 
-fn compute_new_shutdown(
-    handler_keep_alive: bool,
-    current_shutdown: &Shutdown,
-    idle_timeout: Duration,
-) -> Option<Shutdown> {
-    match (current_shutdown, handler_keep_alive) {
-        (_, false) if idle_timeout == Duration::ZERO => Some(Shutdown::Asap),
-        // Do nothing, i.e. let the shutdown timer continue to tick.
-        (Shutdown::Later(_), false) => None,
-        (_, false) => {
-            let now = Instant::now();
-            let safe_keep_alive = checked_add_fraction(now, idle_timeout);
+/// Collects the protocols the `handler` listens on anew, unless it opted out via
+/// [`ConnectionHandler::wants_protocols_change`] or its
+/// [`ConnectionHandler::protocols_generation`] did not change, notifying it via
+/// [`ConnectionEvent::LocalProtocolsChange`] if anything changed.
+///
+/// Returns whether the protocols changed.
+fn apply_local_protocols<C: ConnectionHandler>(
+    handler: &mut C,
+    supported_protocols: &mut HashMap<
+        AsStrHashEq<RewrittenProtocol<<C::InboundProtocol as UpgradeInfoSend>::Info>>,
+        bool,
+    >,
+    listen_protocols: &RwLock<HashSet<String>>,
+    protocols_generation: &mut Option<u64>,
+    rewriter: Option<&ProtocolRewriter>,
+    protocol_buffer: &mut Vec<StreamProtocol>,
+) -> bool {
+    if !handler.wants_protocols_change() {
+        return false;
+    }
+    let generation = handler.protocols_generation();
+    if generation.is_some_and(|generation| Some(generation) == *protocols_generation) {
+        return false;
+    }
+    *protocols_generation = generation;
+
+    let changes = ProtocolsChange::from_full_sets(
+        supported_protocols,
+        handler
+            .listen_protocol()
+            .upgrade()
+            .protocol_info()
+            .map(|info| RewrittenProtocol::new(info, rewriter)),
+        protocol_buffer,
+    );
+    if changes.is_empty() {
+        return false;
+    }
 
-            Some(Shutdown::Later(Delay::new(safe_keep_alive)))
+    if handler
+        .interested_events()
+        .contains(ConnectionEventMask::LOCAL_PROTOCOLS_CHANGE)
+    {
+        for change in changes {
+            handler.on_connection_event(ConnectionEvent::LocalProtocolsChange(change));
         }
-        (_, true) => Some(Shutdown::None),
     }
+    *listen_protocols
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = protocol_names_of(supported_protocols);
+    true
 }
 
-/// Repeatedly halves and adds the [`Duration`]
-/// to the [`Instant`] until [`Instant::checked_add`] succeeds.
-///
-/// [`Instant`] depends on the underlying platform and has a limit of which points in time it can
-/// represent. The [`Duration`] computed by the this function may not be the longest possible that
-/// we can add to `now` but it will work.
-fn checked_add_fraction(start: Instant, mut duration: Duration) -> Duration {
-    while start.checked_add(duration).is_none() {
-        tracing::debug!(start=?start, duration=?duration, "start + duration cannot be presented, halving duration");
-
-        duration /= 2;
-    }
-
-    duration
+/// Applies a change to the protocols the remote supports, notifying the `handler` via
+/// [`ConnectionEvent::RemoteProtocolsChange`] if anything changed.
+fn apply_remote_protocols<C: ConnectionHandler>(
+    handler: &mut C,
+    remote_supported_protocols: &mut HashSet<StreamProtocol>,
+    protocol_buffer: &mut Vec<StreamProtocol>,
+    support: ProtocolSupport,
+) {
+    let notify = handler
+        .interested_events()
+        .contains(ConnectionEventMask::REMOTE_PROTOCOLS_CHANGE);
+
+    match support {
+        ProtocolSupport::Added(protocols) if !notify => {
+            remote_supported_protocols.extend(protocols);
+        }
+        ProtocolSupport::Added(protocols) => {
+            if let Some(added) =
+                ProtocolsChange::add(remote_supported_protocols, protocols, protocol_buffer)
+            {
+                handler.on_connection_event(ConnectionEvent::RemoteProtocolsChange(added));
+                remote_supported_protocols.extend(protocol_buffer.drain(..));
+            }
+        }
+        ProtocolSupport::Removed(protocols) if !notify => {
+            remote_supported_protocols.retain(|p| !protocols.contains(p));
+        }
+        ProtocolSupport::Removed(protocols) => {
+            if let Some(removed) =
+                ProtocolsChange::remove(remote_supported_protocols, protocols, protocol_buffer)
+            {
+                handler.on_connection_event(ConnectionEvent::RemoteProtocolsChange(removed));
+            }
+        }
+    }
+}
+
+fn protocol_names_of<T: AsRef<str>>(protocols: &HashMap<AsStrHashEq<T>, bool>) -> HashSet<String> {
+    protocols.keys().map(|p| p.0.as_ref().to_owned()).collect()
 }
 
 /// Borrowed information about an incoming connection currently being negotiated.
@@ -548,224 +2401,56 @@ impl IncomingInfo<'_> {
     }
 }
 
-struct StreamUpgrade<UserData, TOk, TErr> {
-    user_data: Option<UserData>,
-    timeout: Delay,
-    upgrade: BoxFuture<'static, Result<TOk, StreamUpgradeError<TErr>>>,
-}
-
-impl<UserData, TOk, TErr> StreamUpgrade<UserData, TOk, TErr> {
-    fn new_outbound<Upgrade>(
-        substream: SubstreamBox,
-        user_data: UserData,
-        timeout: Delay,
-        upgrade: Upgrade,
-        version_override: Option<upgrade::Version>,
-        counter: ActiveStreamCounter,
-    ) -> Self
-    where
-        Upgrade: OutboundUpgradeSend<Output = TOk, Error = TErr>,
-    {
-        let effective_version = match version_override {
-            Some(version_override) if version_override != upgrade::Version::default() => {
-                tracing::debug!(
-                    "Substream upgrade protocol override: {:?} -> {:?}",
-                    upgrade::Version::default(),
-                    version_override
-                );
-
-                version_override
-            }
-            _ => upgrade::Version::default(),
-        };
-        let protocols = upgrade.protocol_info();
-
-        Self {
-            user_data: Some(user_data),
-            timeout,
-            upgrade: Box::pin(async move {
-                let (info, stream) = multistream_select::dialer_select_proto(
-                    substream,
-                    protocols,
-                    effective_version,
-                )
-                .await
-                .map_err(to_stream_upgrade_error)?;
-
-                let output = upgrade
-                    .upgrade_outbound(Stream::new(stream, counter), info)
-                    .await
-                    .map_err(StreamUpgradeError::Apply)?;
-
-                Ok(output)
-            }),
-        }
-    }
-}
-
-impl<UserData, TOk, TErr> StreamUpgrade<UserData, TOk, TErr> {
-    fn new_inbound<Upgrade>(
-        substream: SubstreamBox,
-        protocol: SubstreamProtocol<Upgrade, UserData>,
-        counter: ActiveStreamCounter,
-    ) -> Self
-    where
-        Upgrade: InboundUpgradeSend<Output = TOk, Error = TErr>,
-    {
-        let timeout = *protocol.timeout();
-        let (upgrade, open_info) = protocol.into_upgrade();
-        let protocols = upgrade.protocol_info();
-
-        Self {
-            user_data: Some(open_info),
-            timeout: Delay::new(timeout),
-            upgrade: Box::pin(async move {
-                let (info, stream) =
-                    multistream_select::listener_select_proto(substream, protocols)
-                        .await
-                        .map_err(to_stream_upgrade_error)?;
-
-                let output = upgrade
-                    .upgrade_inbound(Stream::new(stream, counter), info)
-                    .await
-                    .map_err(StreamUpgradeError::Apply)?;
-
-                Ok(output)
-            }),
-        }
-    }
-}
-
-fn to_stream_upgrade_error<T>(e: NegotiationError) -> StreamUpgradeError<T> {
-    match e {
-        NegotiationError::Failed => StreamUpgradeError::NegotiationFailed,
-        NegotiationError::ProtocolError(ProtocolError::IoError(e)) => StreamUpgradeError::Io(e),
-        NegotiationError::ProtocolError(other) => {
-            StreamUpgradeError::Io(io::Error::new(io::ErrorKind::Other, other))
-        }
-    }
-}
-
-impl<UserData, TOk, TErr> Unpin for StreamUpgrade<UserData, TOk, TErr> {}
-
-impl<UserData, TOk, TErr> Future for StreamUpgrade<UserData, TOk, TErr> {
-    type Output = (UserData, Result<TOk, StreamUpgradeError<TErr>>);
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        match self.timeout.poll_unpin(cx) {
-            Poll::Ready(()) => {
-                return Poll::Ready((
-                    self.user_data
-                        .take()
-                        .expect("Future not to be polled again once ready."),
-                    Err(StreamUpgradeError::Timeout),
-                ))
-            }
-
-            Poll::Pending => {}
-        }
-
-        let result = futures::ready!(self.upgrade.poll_unpin(cx));
-        let user_data = self
-            .user_data
-            .take()
-            .expect("Future not to be polled again once ready.");
-
-        Poll::Ready((user_data, result))
-    }
-}
-
-enum SubstreamRequested<UserData, Upgrade> {
-    Waiting {
-        user_data: UserData,
-        timeout: Delay,
-        upgrade: Upgrade,
-        /// A waker to notify our [`FuturesUnordered`] that we have extracted the data.
-        ///
-        /// This will ensure that we will get polled again in the next iteration which allows us to
-        /// resolve with `Ok(())` and be removed from the [`FuturesUnordered`].
-        extracted_waker: Option<Waker>,
-    },
-    Done,
+/// The order in which a connection asks its [`StreamMuxer`] for new substreams.
+///
+/// [`StreamMuxer`]: libp2p_core::StreamMuxer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SubstreamPollPriority {
+    /// Grant pending outbound substream requests before accepting inbound substreams.
+    ///
+    /// This favours nodes that mostly act as a client.
+    #[default]
+    OutboundFirst,
+    /// Accept inbound substreams before granting pending outbound substream requests.
+    ///
+    /// This favours nodes that mostly act as a server.
+    InboundFirst,
+    /// Alternate between both directions every time a substream is granted.
+    RoundRobin,
 }
 
-impl<UserData, Upgrade> SubstreamRequested<UserData, Upgrade> {
-    fn new(user_data: UserData, timeout: Duration, upgrade: Upgrade) -> Self {
-        Self::Waiting {
-            user_data,
-            timeout: Delay::new(timeout),
-            upgrade,
-            extracted_waker: None,
-        }
-    }
-
-    fn extract(&mut self) -> (UserData, Delay, Upgrade) {
-        match mem::replace(self, Self::Done) {
-            SubstreamRequested::Waiting {
-                user_data,
-                timeout,
-                upgrade,
-                extracted_waker: waker,
-            } => {
-                if let Some(waker) = waker {
-                    waker.wake();
-                }
-
-                (user_data, timeout, upgrade)
-            }
-            SubstreamRequested::Done => panic!("cannot extract twice"),
+impl SubstreamPollPriority {
+    fn order(self, round_robin_inbound_first: bool) -> [SubstreamDirection; 2] {
+        const OUTBOUND_FIRST: [SubstreamDirection; 2] =
+            [SubstreamDirection::Outbound, SubstreamDirection::Inbound];
+        const INBOUND_FIRST: [SubstreamDirection; 2] =
+            [SubstreamDirection::Inbound, SubstreamDirection::Outbound];
+
+        match self {
+            SubstreamPollPriority::OutboundFirst => OUTBOUND_FIRST,
+            SubstreamPollPriority::InboundFirst => INBOUND_FIRST,
+            SubstreamPollPriority::RoundRobin if round_robin_inbound_first => INBOUND_FIRST,
+            SubstreamPollPriority::RoundRobin => OUTBOUND_FIRST,
         }
     }
 }
 
-impl<UserData, Upgrade> Unpin for SubstreamRequested<UserData, Upgrade> {}
-
-impl<UserData, Upgrade> Future for SubstreamRequested<UserData, Upgrade> {
-    type Output = Result<(), UserData>;
-
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.get_mut();
-
-        match mem::replace(this, Self::Done) {
-            SubstreamRequested::Waiting {
-                user_data,
-                upgrade,
-                mut timeout,
-                ..
-            } => match timeout.poll_unpin(cx) {
-                Poll::Ready(()) => Poll::Ready(Err(user_data)),
-                Poll::Pending => {
-                    *this = Self::Waiting {
-                        user_data,
-                        upgrade,
-                        timeout,
-                        extracted_waker: Some(cx.waker().clone()),
-                    };
-                    Poll::Pending
-                }
-            },
-            SubstreamRequested::Done => Poll::Ready(Ok(())),
-        }
-    }
+/// The direction of a stream produced by the muxer of a [`Connection`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubstreamDirection {
+    /// A stream opened by the local node.
+    Outbound,
+    /// A stream opened by the remote.
+    Inbound,
 }
 
-/// The options for a planned connection & handler shutdown.
-///
-/// A shutdown is planned anew based on the return value of
-/// [`ConnectionHandler::connection_keep_alive`] of the underlying handler
-/// after every invocation of [`ConnectionHandler::poll`].
-///
-/// A planned shutdown is always postponed for as long as there are ingoing
-/// or outgoing substreams being negotiated, i.e. it is a graceful, "idle"
-/// shutdown.
-#[derive(Debug)]
-enum Shutdown {
-    /// No shutdown is planned.
-    None,
-    /// A shut down is planned as soon as possible.
-    Asap,
-    /// A shut down is planned for when a `Delay` has elapsed.
-    Later(Delay),
+/// Tracks which poll functions of the [`StreamMuxerBox`] returned [`Poll::Pending`] during a
+/// single call to [`Connection::poll`].
+#[derive(Debug, Default)]
+struct MuxerPending {
+    events: bool,
+    outbound: bool,
+    inbound: bool,
 }
 
 // Structure used to avoid allocations when storing the protocols in the `HashMap.
@@ -790,21 +2475,33 @@ impl<T: AsRef<str>> std::hash::Hash for AsStrHashEq<T> {
 #[cfg(test)]
 mod tests {
     use std::{
+        collections::VecDeque,
         convert::Infallible,
-        sync::{Arc, Weak},
+        io,
+        num::NonZeroU32,
+        sync::{Arc, Mutex, Weak},
         time::Instant,
     };
 
-    use futures::{future, AsyncRead, AsyncWrite};
+    use futures::{future, future::BoxFuture, AsyncRead, AsyncWrite, FutureExt};
     use libp2p_core::{
-        upgrade::{DeniedUpgrade, InboundUpgrade, OutboundUpgrade, UpgradeInfo},
-        StreamMuxer,
+        multiaddr::Protocol,
+        transport::{memory::Channel, DialOpts, ListenerId, MemoryTransport, TransportEvent},
+        upgrade::{
+            DeniedUpgrade, InboundUpgrade, NegotiationFailure, OutboundUpgrade, PendingUpgrade,
+            ReadyUpgrade, UpgradeInfo,
+        },
+        StreamMuxer, Transport,
     };
     use quickcheck::*;
     use tracing_subscriber::EnvFilter;
 
-    use super::*;
-    use crate::dummy;
+    use super::{outbound_retry::is_transient_muxer_error, *};
+    use crate::{
+        dummy,
+        handler::{BoxedConnectionHandler, OutboundPriority},
+        keep_alive, testing, Stream,
+    };
 
     #[test]
     fn max_negotiating_inbound_streams() {
@@ -816,15 +2513,16 @@ mod tests {
             let max_negotiating_inbound_streams: usize = max_negotiating_inbound_streams.into();
 
             let alive_substream_counter = Arc::new(());
-            let mut connection = Connection::new(
-                StreamMuxerBox::new(DummyStreamMuxer {
-                    counter: alive_substream_counter.clone(),
-                }),
-                MockConnectionHandler::new(Duration::from_secs(10)),
-                None,
-                max_negotiating_inbound_streams,
-                Duration::ZERO,
-            );
+            let mut connection = builder()
+                .max_negotiating_inbound_streams(max_negotiating_inbound_streams)
+                .poll_budget(NonZeroUsize::MAX)
+                .idle_timeout(Duration::ZERO)
+                .build(
+                    StreamMuxerBox::new(DummyStreamMuxer {
+                        counter: alive_substream_counter.clone(),
+                    }),
+                    MockConnectionHandler::new(Duration::from_secs(10)),
+                );
 
             let result = connection.poll_noop_waker();
 
@@ -840,396 +2538,4419 @@ mod tests {
     }
 
     #[test]
-    fn outbound_stream_timeout_starts_on_request() {
-        let upgrade_timeout = Duration::from_secs(1);
-        let mut connection = Connection::new(
-            StreamMuxerBox::new(PendingStreamMuxer),
-            MockConnectionHandler::new(upgrade_timeout),
-            None,
-            2,
-            Duration::ZERO,
+    fn muxer_is_polled_at_most_once_per_connection_poll() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(10)
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(PollCountingStreamMuxer {
+                    inner: DummyStreamMuxer {
+                        counter: Arc::new(()),
+                    },
+                    polls: polls.clone(),
+                }),
+                MockConnectionHandler::new(Duration::from_secs(10)),
+            );
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_in.len(), 10);
+        assert_eq!(
+            polls.load(Ordering::SeqCst),
+            1,
+            "Expect muxer to not be polled again after returning `Pending`"
         );
+    }
 
-        connection.handler.open_new_outbound();
-        let _ = connection.poll_noop_waker();
+    #[test]
+    fn substream_poll_priority_determines_next_granted_direction() {
+        fn granted_directions(priority: SubstreamPollPriority) -> Vec<SubstreamDirection> {
+            let muxer = BudgetStreamMuxer::default();
+            let budget = muxer.budget.clone();
+            let granted = muxer.granted.clone();
+            let mut connection = builder()
+                .max_negotiating_inbound_streams(10)
+                .substream_poll_priority(priority)
+                .idle_timeout(Duration::ZERO)
+                .build(
+                    StreamMuxerBox::new(muxer),
+                    MockConnectionHandler::new(Duration::from_secs(10)),
+                );
 
-        std::thread::sleep(upgrade_timeout + Duration::from_secs(1));
+            for _ in 0..3 {
+                connection.handler.open_new_outbound();
+                budget.fetch_add(1, Ordering::SeqCst);
+                assert!(connection.poll_noop_waker().is_pending());
+            }
 
-        let _ = connection.poll_noop_waker();
+            let granted = granted.lock().unwrap().clone();
+            granted
+        }
 
-        assert!(matches!(
-            connection.handler.error.unwrap(),
-            StreamUpgradeError::Timeout
-        ))
-    }
+        use SubstreamDirection::*;
 
-    #[test]
-    fn propagates_changes_to_supported_inbound_protocols() {
-        let mut connection = Connection::new(
-            StreamMuxerBox::new(PendingStreamMuxer),
-            ConfigurableProtocolConnectionHandler::default(),
-            None,
-            0,
-            Duration::ZERO,
+        assert_eq!(
+            granted_directions(SubstreamPollPriority::OutboundFirst),
+            [Outbound, Outbound, Outbound]
+        );
+        assert_eq!(
+            granted_directions(SubstreamPollPriority::InboundFirst),
+            [Inbound, Inbound, Inbound]
+        );
+        assert_eq!(
+            granted_directions(SubstreamPollPriority::RoundRobin),
+            [Outbound, Inbound, Outbound]
         );
+    }
 
-        // First, start listening on a single protocol.
-        connection.handler.listen_on(&["/foo"]);
-        let _ = connection.poll_noop_waker();
+    #[test]
+    fn last_stream_event_follows_alternating_muxer_directions() {
+        let clock = MockClock::default();
+        let muxer = BudgetStreamMuxer::default();
+        let budget = muxer.budget.clone();
+        let mut connection = builder()
+            .substream_poll_priority(SubstreamPollPriority::RoundRobin)
+            .build(
+                StreamMuxerBox::new(muxer),
+                MockConnectionHandler::new(Duration::from_secs(10)),
+            )
+            .with_clock(clock.clone());
+        let start = clock.now();
 
-        assert_eq!(connection.handler.local_added, vec![vec!["/foo"]]);
-        assert!(connection.handler.local_removed.is_empty());
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.last_stream_event(), None);
+
+        let mut events = Vec::new();
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(1));
+            connection.handler.open_new_outbound();
+            budget.fetch_add(1, Ordering::SeqCst);
+            assert!(connection.poll_noop_waker().is_pending());
+            events.push(connection.last_stream_event().unwrap());
+        }
 
-        // Second, listen on two protocols.
-        connection.handler.listen_on(&["/foo", "/bar"]);
-        let _ = connection.poll_noop_waker();
+        use SubstreamDirection::*;
 
         assert_eq!(
-            connection.handler.local_added,
-            vec![vec!["/foo"], vec!["/bar"]],
-            "expect to only receive an event for the newly added protocols"
+            events,
+            [
+                (Outbound, start + Duration::from_secs(1)),
+                (Inbound, start + Duration::from_secs(2)),
+                (Outbound, start + Duration::from_secs(3)),
+            ]
         );
-        assert!(connection.handler.local_removed.is_empty());
+    }
 
-        // Third, stop listening on the first protocol.
-        connection.handler.listen_on(&["/bar"]);
-        let _ = connection.poll_noop_waker();
+    #[test]
+    fn inbound_stream_is_dropped_once_handler_stops_listening_on_its_protocol() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        handler.listen_on(&["/foo", "/bar"]);
+
+        let mut connection = builder().max_negotiating_inbound_streams(2).build(
+            StreamMuxerBox::new(OnceInboundStreamMuxer {
+                substream: Some(local),
+            }),
+            handler,
+        );
+
+        // Accept the inbound stream, negotiation is now in-flight.
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_in.len(), 1);
 
+        connection.handler.listen_on(&["/bar"]);
+        assert!(connection.poll_noop_waker().is_pending());
         assert_eq!(
-            connection.handler.local_added,
-            vec![vec!["/foo"], vec!["/bar"]]
+            connection.handler.local_removed,
+            vec![vec![StreamProtocol::new("/foo")]]
         );
-        assert_eq!(connection.handler.local_removed, vec![vec!["/foo"]]);
+
+        futures::executor::block_on(async {
+            // The remote proposes `/foo` which was part of the offer when negotiation started.
+            let negotiate = async {
+                multistream_select::dialer_select_proto(
+                    &mut remote,
+                    ["/foo"],
+                    multistream_select::Version::V1,
+                )
+                .await
+                .unwrap();
+            };
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if connection.negotiating_in.is_empty() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            });
+
+            future::join(negotiate, drive).await;
+        });
+
+        assert_eq!(connection.handler.fully_negotiated_inbound, 0);
     }
 
     #[test]
-    fn only_propagtes_actual_changes_to_remote_protocols_to_handler() {
-        let mut connection = Connection::new(
-            StreamMuxerBox::new(PendingStreamMuxer),
-            ConfigurableProtocolConnectionHandler::default(),
-            None,
-            0,
-            Duration::ZERO,
+    fn inbound_stream_completes_negotiation_of_drained_protocol() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        handler.listen_on(&["/foo", "/bar"]);
+
+        let mut connection = builder().drain_removed_protocols(true).build(
+            StreamMuxerBox::new(OnceInboundStreamMuxer {
+                substream: Some(local),
+            }),
+            handler,
         );
 
-        // First, remote supports a single protocol.
-        connection.handler.remote_adds_support_for(&["/foo"]);
-        let _ = connection.poll_noop_waker();
+        // Accept the inbound stream, negotiation is now in-flight.
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_in.len(), 1);
 
-        assert_eq!(connection.handler.remote_added, vec![vec!["/foo"]]);
-        assert!(connection.handler.remote_removed.is_empty());
+        connection.handler.listen_on(&["/bar"]);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.handler.local_removed,
+            vec![vec![StreamProtocol::new("/foo")]]
+        );
 
-        // Second, it adds a protocol but also still includes the first one.
-        connection
-            .handler
-            .remote_adds_support_for(&["/foo", "/bar"]);
-        let _ = connection.poll_noop_waker();
+        futures::executor::block_on(async {
+            let negotiate = async {
+                multistream_select::dialer_select_proto(
+                    &mut remote,
+                    ["/foo"],
+                    multistream_select::Version::V1,
+                )
+                .await
+                .unwrap();
+            };
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if connection.negotiating_in.is_empty() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            });
+
+            future::join(negotiate, drive).await;
+        });
 
+        assert_eq!(connection.handler.fully_negotiated_inbound, 1);
         assert_eq!(
-            connection.handler.remote_added,
-            vec![vec!["/foo"], vec!["/bar"]],
-            "expect to only receive an event for the newly added protocol"
+            connection.handler.negotiated_protocols,
+            vec![StreamProtocol::new("/foo")]
         );
-        assert!(connection.handler.remote_removed.is_empty());
+    }
 
-        // Third, stop listening on a protocol it never advertised (we can't control what handlers
-        // do so this needs to be handled gracefully).
-        connection.handler.remote_removes_support_for(&["/baz"]);
-        let _ = connection.poll_noop_waker();
+    #[test]
+    fn fully_negotiated_inbound_reports_selected_protocol() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        handler.listen_on(&["/foo", "/bar"]);
+
+        let mut connection = builder().max_negotiating_inbound_streams(2).build(
+            StreamMuxerBox::new(OnceInboundStreamMuxer {
+                substream: Some(local),
+            }),
+            handler,
+        );
+
+        futures::executor::block_on(async {
+            let negotiate = multistream_select::dialer_select_proto(
+                &mut remote,
+                ["/bar"],
+                multistream_select::Version::V1,
+            );
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if connection.handler.fully_negotiated_inbound > 0 {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            });
+
+            let (negotiated, ()) = future::join(negotiate, drive).await;
+            assert_eq!(negotiated.unwrap().0, "/bar");
+        });
 
         assert_eq!(
-            connection.handler.remote_added,
-            vec![vec!["/foo"], vec!["/bar"]]
+            connection.handler.negotiated_protocols,
+            vec![StreamProtocol::new("/bar")]
         );
-        assert!(&connection.handler.remote_removed.is_empty());
+    }
 
-        // Fourth, stop listening on a protocol that was previously supported
-        connection.handler.remote_removes_support_for(&["/bar"]);
-        let _ = connection.poll_noop_waker();
+    #[test]
+    fn fully_negotiated_outbound_reports_selected_protocol() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut connection = builder().build(
+            StreamMuxerBox::new(OnceOutboundStreamMuxer {
+                substream: Some(local),
+            }),
+            OutboundProtocolsConnectionHandler {
+                protocols: vec![StreamProtocol::new("/foo"), StreamProtocol::new("/bar")],
+                requested: false,
+                negotiated_protocols: Vec::new(),
+            },
+        );
+
+        futures::executor::block_on(async {
+            let negotiate = multistream_select::listener_select_proto(&mut remote, ["/bar"]);
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if connection.handler.negotiated_protocols.is_empty() {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            });
+
+            let (negotiated, ()) = future::join(negotiate, drive).await;
+            assert_eq!(negotiated.unwrap().0, "/bar");
+        });
 
         assert_eq!(
-            connection.handler.remote_added,
-            vec![vec!["/foo"], vec!["/bar"]]
+            connection.handler.negotiated_protocols,
+            vec![StreamProtocol::new("/bar")]
         );
-        assert_eq!(connection.handler.remote_removed, vec![vec!["/bar"]]);
     }
 
-    #[tokio::test]
-    async fn idle_timeout_with_keep_alive_no() {
-        let idle_timeout = Duration::from_millis(100);
-
-        let mut connection = Connection::new(
-            StreamMuxerBox::new(PendingStreamMuxer),
-            dummy::ConnectionHandler,
-            None,
-            0,
-            idle_timeout,
+    #[test]
+    fn failed_outbound_negotiation_is_classified_as_no_common_protocol() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut connection = builder().build(
+            StreamMuxerBox::new(OnceOutboundStreamMuxer {
+                substream: Some(local),
+            }),
+            MockConnectionHandler::new(Duration::from_secs(10)),
         );
+        connection.handler.open_new_outbound();
 
-        assert!(connection.poll_noop_waker().is_pending());
+        futures::executor::block_on(async {
+            // The remote does not support `/mock`, which the handler requested.
+            let negotiate = multistream_select::listener_select_proto(&mut remote, ["/other"]);
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if connection.handler.error.is_some() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            });
 
-        tokio::time::sleep(idle_timeout).await;
+            future::select(Box::pin(negotiate), Box::pin(drive)).await;
+        });
 
         assert!(matches!(
-            connection.poll_noop_waker(),
-            Poll::Ready(Err(ConnectionError::KeepAliveTimeout))
+            connection.handler.error.take().unwrap().classify(),
+            Some(NegotiationFailure::NoCommonProtocol)
         ));
     }
 
     #[test]
-    fn checked_add_fraction_can_add_u64_max() {
-        let _ = tracing_subscriber::fmt()
-            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-            .try_init();
-        let start = Instant::now();
+    fn outbound_negotiation_violating_multistream_select_is_classified_as_protocol_error() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut connection = builder().build(
+            StreamMuxerBox::new(OnceOutboundStreamMuxer {
+                substream: Some(local),
+            }),
+            MockConnectionHandler::new(Duration::from_secs(10)),
+        );
+        connection.handler.open_new_outbound();
+
+        futures::executor::block_on(async {
+            // A length-prefixed message that lacks the trailing newline.
+            let respond = async {
+                use futures::AsyncWriteExt;
+
+                remote.write_all(b"\x05hello").await.unwrap();
+                future::pending::<()>().await
+            };
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if connection.handler.error.is_some() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            });
 
-        let duration = checked_add_fraction(start, Duration::from_secs(u64::MAX));
+            future::select(Box::pin(respond), Box::pin(drive)).await;
+        });
 
-        assert!(start.checked_add(duration).is_some())
+        assert!(matches!(
+            connection.handler.error.take().unwrap().classify(),
+            Some(NegotiationFailure::ProtocolError(_))
+        ));
     }
 
     #[test]
-    fn compute_new_shutdown_does_not_panic() {
-        let _ = tracing_subscriber::fmt()
-            .with_env_filter(EnvFilter::from_default_env())
-            .try_init();
-
-        #[derive(Debug)]
-        struct ArbitraryShutdown(Shutdown);
-
-        impl Clone for ArbitraryShutdown {
-            fn clone(&self) -> Self {
-                let shutdown = match self.0 {
-                    Shutdown::None => Shutdown::None,
-                    Shutdown::Asap => Shutdown::Asap,
-                    Shutdown::Later(_) => Shutdown::Later(
-                        // compute_new_shutdown does not touch the delay. Delay does not
-                        // implement Clone. Thus use a placeholder delay.
-                        Delay::new(Duration::from_secs(1)),
-                    ),
-                };
+    fn rewritten_protocol_names_are_negotiated_in_place_of_the_originals() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        handler.listen_on(&["/foo/1.0.0"]);
+        let mut connection = builder()
+            .protocol_rewriter(|protocol| {
+                (protocol == "/foo/1.0.0").then(|| "/foo/1.0.1".to_owned())
+            })
+            .build(
+                StreamMuxerBox::new(OnceInboundStreamMuxer {
+                    substream: Some(local),
+                }),
+                handler,
+            );
 
-                ArbitraryShutdown(shutdown)
+        futures::executor::block_on(async {
+            let negotiate = multistream_select::dialer_select_proto(
+                &mut remote,
+                ["/foo/1.0.1"],
+                multistream_select::Version::V1,
+            );
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if connection.handler.fully_negotiated_inbound > 0 {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            });
+
+            let (negotiated, ()) = future::join(negotiate, drive).await;
+            assert_eq!(negotiated.unwrap().0, "/foo/1.0.1");
+        });
+
+        assert_eq!(
+            connection.handler.negotiated_protocols,
+            vec![StreamProtocol::new("/foo/1.0.0")]
+        );
+        assert_eq!(
+            connection.handler.local_added,
+            vec![vec![StreamProtocol::new("/foo/1.0.1")]]
+        );
+    }
+
+    #[test]
+    fn selected_handlers_each_negotiate_their_own_protocol() {
+        let (local_a, mut remote_a) = memory_stream_pair();
+        let (local_b, mut remote_b) = memory_stream_pair();
+        let mut handler_a = ConfigurableProtocolConnectionHandler::default();
+        handler_a.listen_on(&["/a"]);
+        let mut handler_b = ConfigurableProtocolConnectionHandler::default();
+        handler_b.listen_on(&["/b"]);
+
+        let mut connection = builder().max_negotiating_inbound_streams(2).build(
+            StreamMuxerBox::new(InboundStreamsMuxer {
+                substreams: VecDeque::from([local_a, local_b]),
+            }),
+            handler_a.select(handler_b),
+        );
+
+        futures::executor::block_on(async {
+            let negotiate = future::join(
+                multistream_select::dialer_select_proto(
+                    &mut remote_a,
+                    ["/a"],
+                    multistream_select::Version::V1,
+                ),
+                multistream_select::dialer_select_proto(
+                    &mut remote_b,
+                    ["/b"],
+                    multistream_select::Version::V1,
+                ),
+            );
+            let drive = future::poll_fn(|cx| loop {
+                match Pin::new(&mut connection).poll(cx) {
+                    Poll::Ready(Ok(_)) => continue,
+                    Poll::Ready(Err(error)) => return Poll::Ready(error),
+                    Poll::Pending => return Poll::Pending,
+                }
+            });
+
+            let ((a, b), _) = match future::select(negotiate, drive).await {
+                future::Either::Left(negotiated) => negotiated,
+                future::Either::Right((error, _)) => panic!("connection failed: {error}"),
+            };
+            assert_eq!(a.unwrap().0, "/a");
+            assert_eq!(b.unwrap().0, "/b");
+        });
+        assert!(connection.poll_noop_waker().is_pending());
+
+        let (handler_a, handler_b) = connection.handler.into_inner();
+        assert_eq!(handler_a.fully_negotiated_inbound, 1);
+        assert_eq!(handler_b.fully_negotiated_inbound, 1);
+    }
+
+    #[test]
+    fn selected_handlers_keep_connection_alive_while_either_wants_to() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                KeepAliveConnectionHandler { keep_alive: false }
+                    .select(KeepAliveConnectionHandler { keep_alive: true }),
+            )
+            .with_clock(clock.clone());
+
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(20));
+        assert!(connection.poll_noop_waker().is_pending());
+
+        let (first, _) = connection.handler.into_inner();
+        connection.handler = first.select(KeepAliveConnectionHandler { keep_alive: false });
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(10));
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::KeepAliveTimeout { .. }))
+        ));
+    }
+
+    #[test]
+    fn negotiation_high_water_tracks_concurrent_negotiations() {
+        let mut connection = builder().max_negotiating_inbound_streams(2).build(
+            StreamMuxerBox::new(ReadyStreamMuxer {
+                counter: Arc::new(()),
+            }),
+            MockConnectionHandler::new(Duration::from_secs(60)),
+        );
+        assert_eq!(connection.negotiation_high_water(), (0, 0));
+
+        for _ in 0..3 {
+            connection.handler.open_new_outbound();
+            assert!(connection.poll_noop_waker().is_pending());
+        }
+        assert_eq!(connection.negotiating_in.len(), 2);
+        assert_eq!(connection.negotiating_out.len(), 3);
+        assert_eq!(connection.negotiation_high_water(), (2, 3));
+
+        connection.negotiating_in.clear();
+        connection.negotiating_out.clear();
+        connection.set_max_negotiating_inbound_streams(1);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.negotiation_high_water(),
+            (2, 3),
+            "high-water marks never decrease"
+        );
+    }
+
+    #[test]
+    fn total_negotiated_accumulates_successful_negotiations() {
+        let mut inbound = builder()
+            .pre_negotiated_protocol(StreamProtocol::new("/foo"))
+            .build(
+                StreamMuxerBox::new(InboundStreamsMuxer {
+                    substreams: (0..3).map(|_| memory_stream_pair().0).collect(),
+                }),
+                StreamCollectingConnectionHandler::default(),
+            );
+        assert_eq!(inbound.total_negotiated(), (0, 0));
+        assert!(inbound.poll_noop_waker().is_pending());
+        inbound.handler.streams.clear();
+        assert_eq!(
+            inbound.total_negotiated(),
+            (3, 0),
+            "streams are counted after they were dropped"
+        );
+
+        let outbound = |protocol| {
+            let mut connection = builder()
+                .pre_negotiated_protocol(StreamProtocol::new(protocol))
+                .build(
+                    StreamMuxerBox::new(GrantingStreamMuxer {
+                        grants: Arc::new(AtomicUsize::new(2)),
+                        counter: Arc::new(()),
+                    }),
+                    MockConnectionHandler::new(Duration::from_secs(10)),
+                );
+            for _ in 0..2 {
+                connection.handler.open_new_outbound();
+                assert!(connection.poll_noop_waker().is_pending());
+            }
+            connection.total_negotiated()
+        };
+        assert_eq!(outbound("/mock"), (0, 2));
+        assert_eq!(
+            outbound("/other"),
+            (0, 0),
+            "failed negotiations are not counted"
+        );
+    }
+
+    #[test]
+    fn outbound_success_rate_covers_the_most_recent_negotiations() {
+        let mut connection = builder()
+            .pre_negotiated_protocol(StreamProtocol::new("/mock"))
+            .outbound_success_window(NonZeroUsize::new(4).unwrap())
+            .build(
+                StreamMuxerBox::new(GrantingStreamMuxer {
+                    grants: Arc::new(AtomicUsize::new(6)),
+                    counter: Arc::new(()),
+                }),
+                MockConnectionHandler::new(Duration::from_secs(10)),
+            );
+        let mut negotiate = |protocol| {
+            connection.request_outbound(SubstreamProtocol::new(
+                ReadyUpgrade::new(StreamProtocol::new(protocol)),
+                (),
+            ));
+            assert!(connection.poll_noop_waker().is_pending());
+            connection.outbound_success_rate()
+        };
+
+        assert_eq!(negotiate("/mock"), 1.0);
+        assert_eq!(negotiate("/mock"), 1.0);
+        assert_eq!(negotiate("/mock"), 1.0);
+        assert_eq!(negotiate("/other"), 0.75);
+        assert_eq!(negotiate("/other"), 0.5);
+        assert_eq!(
+            negotiate("/other"),
+            0.25,
+            "the oldest outcomes are evicted from the window"
+        );
+    }
+
+    #[test]
+    fn negotiation_poll_window_caps_negotiations_handled_per_poll() {
+        let mut connection = builder()
+            .pre_negotiated_protocol(StreamProtocol::new("/foo"))
+            .negotiation_poll_window(NonZeroUsize::new(1).unwrap())
+            .build(
+                StreamMuxerBox::new(InboundStreamsMuxer {
+                    substreams: (0..3).map(|_| memory_stream_pair().0).collect(),
+                }),
+                StreamCollectingConnectionHandler::default(),
+            );
+        let wakes = Arc::new(WakeCounter::default());
+        let waker = futures::task::waker(wakes.clone());
+
+        for handled in 1..=3 {
+            let poll = Pin::new(&mut connection).poll(&mut Context::from_waker(&waker));
+            assert!(poll.is_pending());
+            assert_eq!(connection.handler.streams.len(), handled);
+        }
+        assert_eq!(
+            wakes.0.load(Ordering::SeqCst),
+            2,
+            "connection must wake itself while negotiations remain"
+        );
+    }
+
+    #[test]
+    fn graceful_close_drains_negotiating_streams() {
+        let (local_a, mut remote_a) = memory_stream_pair();
+        let (local_b, _remote_b) = memory_stream_pair();
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        handler.listen_on(&["/a"]);
+
+        let mut connection = builder().max_negotiating_inbound_streams(1).build(
+            StreamMuxerBox::new(InboundStreamsMuxer {
+                substreams: VecDeque::from([local_a, local_b]),
+            }),
+            handler,
+        );
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_in.len(), 1);
+
+        connection.handler.close_gracefully("done");
+        assert!(
+            connection.poll_noop_waker().is_pending(),
+            "close waits for the negotiating stream"
+        );
+
+        let event = futures::executor::block_on(async {
+            let negotiate = multistream_select::dialer_select_proto(
+                &mut remote_a,
+                ["/a"],
+                multistream_select::Version::V1,
+            );
+            let drive = future::poll_fn(|cx| Pin::new(&mut connection).poll(cx));
+
+            let (negotiated, event) = future::join(negotiate, drive).await;
+            negotiated.unwrap();
+            event
+        });
+
+        assert!(matches!(event, Ok(Event::CloseRequested { reason }) if reason == "done"));
+        assert_eq!(connection.handler.fully_negotiated_inbound, 1);
+        assert!(connection.negotiating_in.is_empty());
+    }
+
+    #[test]
+    fn handler_events_are_drained_before_graceful_close() {
+        let mut handler =
+            testing::ScriptedHandler::<DeniedUpgrade, DeniedUpgrade, u8>::new(DeniedUpgrade);
+        handler.push_event(ConnectionHandlerEvent::NotifyBehaviour(1));
+        handler.push_event(ConnectionHandlerEvent::CloseGracefully {
+            reason: "done".to_owned(),
+        });
+        handler.push_event(ConnectionHandlerEvent::NotifyBehaviour(2));
+        let mut connection = builder().build(StreamMuxerBox::new(PendingStreamMuxer), handler);
+
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Ok(Event::Handler(1)))
+        ));
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Ok(Event::Handler(2)))
+        ));
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Ok(Event::CloseRequested { reason })) if reason == "done"
+        ));
+    }
+
+    #[test]
+    fn high_priority_outbound_request_is_served_first() {
+        let grants = Arc::new(AtomicUsize::new(0));
+        let mut connection = builder().max_negotiating_inbound_streams(0).build(
+            StreamMuxerBox::new(GrantingStreamMuxer {
+                grants: grants.clone(),
+                counter: Arc::new(()),
+            }),
+            PriorityConnectionHandler {
+                requests: VecDeque::from([
+                    (OutboundPriority::Low, "/priority", 0),
+                    (OutboundPriority::Low, "/priority", 1),
+                    (OutboundPriority::High, "/priority", 2),
+                    (OutboundPriority::Normal, "/priority", 3),
+                    (OutboundPriority::High, "/priority", 4),
+                ]),
+            },
+        );
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.requested_substreams.len(), 5);
+
+        let mut served = Vec::new();
+        for _ in 0..5 {
+            grants.store(1, Ordering::SeqCst);
+            assert!(connection.poll_noop_waker().is_pending());
+            served.extend(
+                mem::take(&mut connection.negotiating_out)
+                    .into_iter()
+                    .filter_map(|upgrade| upgrade.user_data)
+                    .map(|(info, _)| info),
+            );
+        }
+
+        assert_eq!(served, [2, 4, 3, 0, 1]);
+    }
+
+    #[test]
+    fn outbound_request_batch_is_queued_and_granted_at_once() {
+        let grants = Arc::new(AtomicUsize::new(0));
+        let mut connection = builder().poll_budget(NonZeroUsize::new(2).unwrap()).build(
+            StreamMuxerBox::new(GrantingStreamMuxer {
+                grants: grants.clone(),
+                counter: Arc::new(()),
+            }),
+            BatchRequestingConnectionHandler {
+                batch: Some(vec![0, 1, 2]),
+            },
+        );
+
+        let _ = connection.poll_noop_waker();
+        let mut pending = connection
+            .pending_outbound_info()
+            .copied()
+            .collect::<Vec<_>>();
+        pending.sort();
+        assert_eq!(pending, [0, 1, 2]);
+
+        grants.store(3, Ordering::SeqCst);
+        let _ = connection.poll_noop_waker();
+        assert_eq!(connection.negotiating_out.len(), 3);
+        assert_eq!(connection.pending_outbound_info().count(), 0);
+    }
+
+    #[test]
+    fn connection_is_congested_once_both_directions_back_up() {
+        let congested = |inbound: usize, outbound: usize| {
+            let mut remotes = Vec::new();
+            let mut substreams = VecDeque::new();
+            for _ in 0..inbound {
+                let (local, remote) = memory_stream_pair();
+                substreams.push_back(local);
+                remotes.push(remote);
+            }
+            let mut connection = builder()
+                .max_negotiating_inbound_streams(2)
+                .congestion_threshold(2)
+                .build(
+                    StreamMuxerBox::new(InboundStreamsMuxer { substreams }),
+                    BatchRequestingConnectionHandler {
+                        batch: Some((0..outbound).collect()),
+                    },
+                );
+            assert!(!connection.is_congested());
+
+            let _ = connection.poll_noop_waker();
+            connection.is_congested()
+        };
+
+        assert!(congested(2, 3));
+        assert!(!congested(1, 3));
+        assert!(!congested(2, 2));
+    }
+
+    #[test]
+    fn connection_is_not_congested_by_extracted_outbound_requests() {
+        let mut remotes = Vec::new();
+        let mut substreams = VecDeque::new();
+        for _ in 0..2 {
+            let (local, remote) = memory_stream_pair();
+            substreams.push_back(local);
+            remotes.push(remote);
+        }
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(2)
+            .congestion_threshold(2)
+            .build(
+                StreamMuxerBox::new(InboundStreamsMuxer { substreams }),
+                BatchRequestingConnectionHandler {
+                    batch: Some((0..3).collect()),
+                },
+            );
+        let _ = connection.poll_noop_waker();
+        assert!(connection.is_congested());
+
+        // The request is granted a stream but stays queued until the queue is polled again.
+        let _ = connection
+            .requested_substreams
+            .iter_mut()
+            .next()
+            .unwrap()
+            .extract();
+
+        assert_eq!(connection.requested_substreams.len(), 3);
+        assert!(!connection.is_congested());
+    }
+
+    #[test]
+    fn pending_outbound_info_lists_waiting_requests() {
+        let grants = Arc::new(AtomicUsize::new(0));
+        let mut connection = builder().build(
+            StreamMuxerBox::new(GrantingStreamMuxer {
+                grants: grants.clone(),
+                counter: Arc::new(()),
+            }),
+            PriorityConnectionHandler {
+                requests: (0..3)
+                    .map(|id| (OutboundPriority::Normal, "/priority", id))
+                    .collect(),
+            },
+        );
+        let pending = |connection: &Connection<PriorityConnectionHandler>| {
+            let mut info = connection
+                .pending_outbound_info()
+                .copied()
+                .collect::<Vec<_>>();
+            info.sort();
+            info
+        };
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(pending(&connection), [0, 1, 2]);
+
+        grants.store(1, Ordering::SeqCst);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_out.len(), 1);
+        assert_eq!(pending(&connection), [1, 2]);
+    }
+
+    #[test]
+    fn fair_outbound_grants_alternate_between_protocols() {
+        let grants = Arc::new(AtomicUsize::new(0));
+        let mut connection = builder().fair_outbound_grants(true).build(
+            StreamMuxerBox::new(GrantingStreamMuxer {
+                grants: grants.clone(),
+                counter: Arc::new(()),
+            }),
+            PriorityConnectionHandler {
+                requests: VecDeque::from([
+                    (OutboundPriority::Normal, "/a", 0),
+                    (OutboundPriority::Normal, "/a", 1),
+                    (OutboundPriority::Normal, "/a", 2),
+                    (OutboundPriority::Normal, "/b", 3),
+                    (OutboundPriority::Normal, "/b", 4),
+                    (OutboundPriority::High, "/a", 5),
+                ]),
+            },
+        );
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.requested_substreams.len(), 6);
+
+        let mut served = Vec::new();
+        for _ in 0..6 {
+            grants.store(1, Ordering::SeqCst);
+            assert!(connection.poll_noop_waker().is_pending());
+            served.extend(
+                mem::take(&mut connection.negotiating_out)
+                    .into_iter()
+                    .filter_map(|upgrade| upgrade.user_data)
+                    .map(|(info, _)| info),
+            );
+        }
+
+        assert_eq!(served, [5, 3, 0, 4, 1, 2]);
+    }
+
+    #[test]
+    fn grants_several_outbound_streams_per_iteration() {
+        let grants = Arc::new(AtomicUsize::new(0));
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .max_negotiating_outbound_streams(3)
+            .build(
+                StreamMuxerBox::new(GrantingStreamMuxer {
+                    grants: grants.clone(),
+                    counter: Arc::new(()),
+                }),
+                PriorityConnectionHandler {
+                    requests: (0..4)
+                        .map(|id| (OutboundPriority::Normal, "/priority", id))
+                        .collect(),
+                },
+            );
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.requested_substreams.len(), 4);
+
+        // A single iteration grants as many streams as the muxer and the limit allow.
+        grants.store(4, Ordering::SeqCst);
+        connection.poll_budget = 1;
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_out.len(), 3);
+        assert_eq!(
+            grants.load(Ordering::SeqCst),
+            1,
+            "last request waits for the limit"
+        );
+    }
+
+    #[test]
+    fn idle_transitions_are_reported_once() {
+        let mut connection = builder().build(
+            StreamMuxerBox::new(PendingStreamMuxer),
+            MockConnectionHandler::new(Duration::from_secs(10)),
+        );
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.handler.idle_changes, [true]);
+
+        for _ in 0..3 {
+            let active_stream = connection.stream_counter.clone();
+            assert!(connection.poll_noop_waker().is_pending());
+            assert!(connection.poll_noop_waker().is_pending());
+
+            drop(active_stream);
+            assert!(connection.poll_noop_waker().is_pending());
+        }
+        assert_eq!(
+            connection.handler.idle_changes,
+            [true, false, true, false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn idle_waker_is_woken_on_transition_to_idle() {
+        let mut connection = builder().build(
+            StreamMuxerBox::new(PendingStreamMuxer),
+            MockConnectionHandler::new(Duration::from_secs(10)),
+        );
+        let wakes = Arc::new(WakeCounter::default());
+        let active_stream = connection.stream_counter.clone();
+        connection.set_idle_waker(futures::task::waker(wakes.clone()));
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0, "not idle yet");
+
+        drop(active_stream);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            wakes.0.load(Ordering::SeqCst),
+            1,
+            "woken once on transition"
+        );
+
+        let active_stream = connection.stream_counter.clone();
+        assert!(connection.poll_noop_waker().is_pending());
+        drop(active_stream);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            wakes.0.load(Ordering::SeqCst),
+            2,
+            "woken again on the next transition"
+        );
+
+        let wakes = Arc::new(WakeCounter::default());
+        connection.set_idle_waker(futures::task::waker(wakes.clone()));
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1, "already idle");
+    }
+
+    #[test]
+    fn granted_requests_do_not_keep_connection_alive() {
+        let mut connection = builder().build(
+            StreamMuxerBox::new(PendingStreamMuxer),
+            MockConnectionHandler::new(Duration::from_secs(10)),
+        );
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert!(!connection.is_idle());
+
+        // Grant the request without polling the connection, as the outbound path does before the
+        // request is removed.
+        let _ = connection
+            .requested_substreams
+            .iter_mut()
+            .next()
+            .unwrap()
+            .extract();
+        assert_eq!(connection.requested_substreams.len(), 1);
+        assert!(
+            connection.is_idle(),
+            "granted request is not outstanding work"
+        );
+
+        connection.flush_completed_requests();
+        assert!(connection.requested_substreams.is_empty());
+        assert!(connection.is_idle());
+    }
+
+    #[test]
+    fn outbound_capacity_changes_are_reported() {
+        let grants = Arc::new(AtomicUsize::new(0));
+        let mut connection = builder().max_negotiating_inbound_streams(0).build(
+            StreamMuxerBox::new(GrantingStreamMuxer {
+                grants: grants.clone(),
+                counter: Arc::new(()),
+            }),
+            MockConnectionHandler::new(Duration::from_secs(60)),
+        );
+
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.handler.outbound_capacity,
+            [false],
+            "reported once"
+        );
+
+        grants.store(2, Ordering::SeqCst);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.handler.outbound_capacity, [false, true]);
+
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.handler.outbound_capacity,
+            [false, true],
+            "no change while the muxer grants streams"
+        );
+
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.handler.outbound_capacity, [false, true, false]);
+    }
+
+    #[test]
+    fn is_idle_only_without_requested_or_negotiating_streams() {
+        let grants = Arc::new(AtomicUsize::new(0));
+        let mut connection = builder().max_negotiating_inbound_streams(0).build(
+            StreamMuxerBox::new(GrantingStreamMuxer {
+                grants: grants.clone(),
+                counter: Arc::new(()),
+            }),
+            MockConnectionHandler::new(Duration::from_secs(60)),
+        );
+        assert!(connection.is_idle());
+
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.requested_substreams.len(), 1);
+        assert!(!connection.is_idle(), "requested stream");
+
+        grants.store(1, Ordering::SeqCst);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_out.len(), 1);
+        assert!(!connection.is_idle(), "negotiating stream");
+
+        connection.negotiating_out.clear();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert!(connection.is_idle());
+    }
+
+    #[test]
+    fn age_increases_over_time() {
+        let clock = MockClock::default();
+        let connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                dummy::ConnectionHandler,
+            )
+            .with_clock(clock.clone());
+        let established_at = connection.established_at();
+        assert_eq!(connection.age(), Duration::ZERO);
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(connection.age(), Duration::from_secs(3));
+
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(connection.age(), Duration::from_secs(7));
+        assert_eq!(connection.established_at(), established_at);
+    }
+
+    #[test]
+    fn muxer_error_reports_abandoned_substreams() {
+        let mut connection = builder().max_negotiating_inbound_streams(0).build(
+            StreamMuxerBox::new(FailingStreamMuxer),
+            MockConnectionHandler::new(Duration::from_secs(60)),
+        );
+        connection.handler.open_new_outbound();
+
+        let Poll::Ready(Err(error)) = Pin::new(&mut connection)
+            .poll(&mut Context::from_waker(futures::task::noop_waker_ref()))
+        else {
+            panic!("muxer error to fail the connection");
+        };
+
+        assert!(matches!(error.error(), ConnectionError::IO(_)));
+        assert_eq!(error.abandoned_substreams(), 1);
+    }
+
+    #[test]
+    fn connection_error_is_accompanied_by_snapshot() {
+        let mut connection = builder().build(
+            StreamMuxerBox::new(FailingStreamMuxer),
+            MockConnectionHandler::new(Duration::from_secs(60)),
+        );
+        connection.handler.open_new_outbound();
+
+        let Poll::Ready(Err(error)) = Pin::new(&mut connection)
+            .poll(&mut Context::from_waker(futures::task::noop_waker_ref()))
+        else {
+            panic!("muxer error to fail the connection");
+        };
+
+        let snapshot = error.snapshot();
+        assert_eq!(snapshot.id, error.connection_id());
+        assert_eq!(snapshot.requested_outbound, 1);
+        assert!(snapshot.age <= connection.age());
+    }
+
+    #[test]
+    fn half_closed_streams_are_reported_to_handler() {
+        let mut connection = builder().max_negotiating_inbound_streams(0).build(
+            StreamMuxerBox::new(HalfCloseStreamMuxer { half_closed: 2 }),
+            ConfigurableProtocolConnectionHandler::default(),
+        );
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.handler.streams_half_closed, 2);
+    }
+
+    #[test]
+    fn uninteresting_events_are_not_dispatched() {
+        let mut handler = ConfigurableProtocolConnectionHandler {
+            interested_events: Some(ConnectionEventMask::STREAM_HALF_CLOSED),
+            ..Default::default()
+        };
+        handler.listen_on(&["/foo"]);
+        let mut connection = builder().max_negotiating_inbound_streams(0).build(
+            StreamMuxerBox::new(HalfCloseStreamMuxer { half_closed: 2 }),
+            handler,
+        );
+
+        connection.handler.listen_on(&["/foo", "/bar"]);
+        connection.handler.remote_adds_support_for(&["/baz"]);
+        assert!(connection.poll_noop_waker().is_pending());
+
+        assert_eq!(connection.handler.streams_half_closed, 2);
+        assert!(connection.handler.local_added.is_empty());
+        assert!(connection.handler.remote_added.is_empty());
+        assert_eq!(
+            connection.remote_supported_protocols,
+            HashSet::from([StreamProtocol::new("/baz")]),
+            "protocols are still tracked"
+        );
+        assert!(connection.listen_protocols.read().unwrap().contains("/bar"));
+    }
+
+    #[test]
+    fn always_ready_connection_yields_once_poll_budget_is_exhausted() {
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .poll_budget(NonZeroUsize::new(10).unwrap())
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                AlwaysReadyConnectionHandler::default(),
+            );
+        let wakes = Arc::new(WakeCounter::default());
+        let waker = futures::task::waker(wakes.clone());
+
+        let poll = Pin::new(&mut connection).poll(&mut Context::from_waker(&waker));
+
+        assert!(poll.is_pending());
+        assert_eq!(connection.handler.polls, 10);
+        assert_eq!(
+            wakes.0.load(Ordering::SeqCst),
+            1,
+            "connection must wake itself"
+        );
+    }
+
+    #[test]
+    fn extracted_request_resolves_ok_even_if_its_timeout_elapsed() {
+        for (timeout, sleep) in [
+            // Extracted right after its timeout fired, but before it was polled again.
+            (Duration::from_millis(10), Duration::from_millis(50)),
+            // Extracted well before its timeout, only `extract` wakes the task.
+            (Duration::from_secs(60), Duration::ZERO),
+        ] {
+            let wakes = Arc::new(WakeCounter::default());
+            let waker = futures::task::waker(wakes.clone());
+            let mut cx = Context::from_waker(&waker);
+
+            let mut requests = FuturesUnordered::new();
+            requests.push(SubstreamRequested::new(
+                (),
+                Sleep::new(&SystemClock, timeout),
+                (),
+                OutboundPriority::default(),
+                0,
+                None,
+            ));
+            assert!(requests.poll_next_unpin(&mut cx).is_pending());
+            let wakes_after_poll = wakes.0.load(Ordering::SeqCst);
+
+            std::thread::sleep(sleep);
+            let _ = requests.iter_mut().next().unwrap().extract();
+
+            assert!(
+                wakes.0.load(Ordering::SeqCst) > wakes_after_poll,
+                "task must be woken to resolve the extracted request"
+            );
+            assert!(matches!(
+                requests.poll_next_unpin(&mut cx),
+                Poll::Ready(Some(Ok(())))
+            ));
+            assert!(requests.is_empty());
+        }
+    }
+
+    #[test]
+    fn injected_inbound_stream_reaches_handler_and_is_usable() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut connection = builder().max_negotiating_inbound_streams(0).build(
+            StreamMuxerBox::new(PendingStreamMuxer),
+            InjectedStreamConnectionHandler::default(),
+        );
+
+        connection.inject_fully_negotiated_inbound(local, (), StreamProtocol::new("/injected"));
+        assert!(connection.poll_noop_waker().is_pending());
+
+        let mut stream = connection
+            .handler
+            .streams
+            .pop()
+            .expect("handler to receive the injected stream");
+        futures::executor::block_on(async {
+            use futures::{AsyncReadExt, AsyncWriteExt};
+
+            stream.write_all(b"ping").await.unwrap();
+            stream.flush().await.unwrap();
+
+            let mut buf = [0u8; 4];
+            remote.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        });
+    }
+
+    #[test]
+    fn pre_negotiated_streams_skip_negotiation() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut connection = builder()
+            .pre_negotiated_protocol(StreamProtocol::new("/foo"))
+            .build(
+                StreamMuxerBox::new(OnceInboundStreamMuxer {
+                    substream: Some(local),
+                }),
+                StreamCollectingConnectionHandler::default(),
+            );
+        assert!(connection.poll_noop_waker().is_pending());
+
+        let mut stream = connection
+            .handler
+            .streams
+            .pop()
+            .expect("handler to receive the stream without negotiating it");
+        futures::executor::block_on(async {
+            use futures::{AsyncReadExt, AsyncWriteExt};
+
+            stream.write_all(b"ping").await.unwrap();
+            stream.flush().await.unwrap();
+
+            // The payload comes first, i.e. no multistream-select messages precede it.
+            let mut buf = [0u8; 4];
+            remote.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        });
+    }
+
+    #[test]
+    fn reset_substream_fails_the_stream_of_the_handler() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut connection = builder().resettable_streams(true).build(
+            StreamMuxerBox::new(OnceInboundStreamMuxer {
+                substream: Some(local),
+            }),
+            StreamCollectingConnectionHandler::default(),
+        );
+
+        futures::executor::block_on(async {
+            let negotiate = async {
+                multistream_select::dialer_select_proto(
+                    &mut remote,
+                    ["/foo"],
+                    multistream_select::Version::V1,
+                )
+                .await
+                .unwrap();
+            };
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if connection.handler.streams.is_empty() {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            });
+
+            future::join(negotiate, drive).await;
+        });
+
+        let mut stream = connection.handler.streams.pop().unwrap();
+        let id = stream.id().expect("streams to be tagged");
+        assert!(connection.reset_substream(id));
+        assert!(!connection.reset_substream(id), "stream was already reset");
+
+        let error = futures::executor::block_on(async {
+            use futures::AsyncReadExt;
+
+            stream.read(&mut [0u8; 1]).await.unwrap_err()
+        });
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn open_substreams_counts_streams_until_dropped() {
+        let (local1, mut remote1) = memory_stream_pair();
+        let (local2, mut remote2) = memory_stream_pair();
+        let mut connection = builder().track_open_substreams(true).build(
+            StreamMuxerBox::new(InboundStreamsMuxer {
+                substreams: VecDeque::from([local1, local2]),
+            }),
+            StreamCollectingConnectionHandler::default(),
+        );
+
+        futures::executor::block_on(async {
+            let negotiate = |remote| {
+                multistream_select::dialer_select_proto(
+                    remote,
+                    ["/foo"],
+                    multistream_select::Version::V1,
+                )
+            };
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if connection.handler.streams.len() < 2 {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            });
+
+            let (first, second, ()) =
+                future::join3(negotiate(&mut remote1), negotiate(&mut remote2), drive).await;
+            first.unwrap();
+            second.unwrap();
+        });
+        assert_eq!(connection.open_substreams(), 2);
+
+        connection.handler.streams[0].ignore_for_keep_alive();
+        assert_eq!(
+            connection.open_substreams(),
+            2,
+            "streams ignored for keep-alive are still open"
+        );
+
+        connection.handler.streams.pop();
+        assert_eq!(connection.open_substreams(), 1);
+
+        connection.handler.streams.clear();
+        assert_eq!(connection.open_substreams(), 0);
+    }
+
+    #[test]
+    fn liveness_reason_reports_why_connection_is_kept_alive() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .idle_timeout(Duration::from_secs(10))
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                KeepAliveConnectionHandler { keep_alive: true },
+            )
+            .with_clock(clock.clone());
+        assert_eq!(
+            connection.liveness_reason(),
+            Some(LivenessReason::HandlerKeepAlive)
+        );
+
+        connection.handler.keep_alive = false;
+        assert_eq!(connection.liveness_reason(), None);
+
+        let until = clock.now() + Duration::from_secs(10);
+        connection.force_keep_alive_until(until);
+        assert_eq!(
+            connection.liveness_reason(),
+            Some(LivenessReason::ForcedKeepAlive { until })
+        );
+
+        let active_stream = connection.stream_counter.clone();
+        assert_eq!(
+            connection.liveness_reason(),
+            Some(LivenessReason::ActiveStreams)
+        );
+        drop(active_stream);
+
+        let mut connection = builder().build(
+            StreamMuxerBox::new(PendingStreamMuxer),
+            MockConnectionHandler::new(Duration::from_secs(10)),
+        );
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.liveness_reason(),
+            Some(LivenessReason::RequestedStreams { outbound: 1 })
+        );
+
+        let mut connection = builder().build(
+            StreamMuxerBox::new(GrantingStreamMuxer {
+                grants: Arc::new(AtomicUsize::new(1)),
+                counter: Arc::new(()),
+            }),
+            MockConnectionHandler::new(Duration::from_secs(10)),
+        );
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.liveness_reason(),
+            Some(LivenessReason::Negotiating {
+                inbound: 0,
+                outbound: 1
+            })
+        );
+
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        handler.close_gracefully("done");
+        let mut connection = builder().build(StreamMuxerBox::new(PendingStreamMuxer), handler);
+        assert!(connection.poll_noop_waker().is_ready());
+        assert_eq!(connection.liveness_reason(), None);
+    }
+
+    #[test]
+    fn scheduled_shutdown_overrides_keep_alive_once_drained() {
+        let clock = MockClock::default();
+        let build = || {
+            builder()
+                .idle_timeout(Duration::from_secs(60))
+                .build(
+                    StreamMuxerBox::new(PendingStreamMuxer),
+                    KeepAliveConnectionHandler { keep_alive: true },
+                )
+                .with_clock(clock.clone())
+        };
+
+        let mut connection = build();
+        let active_stream = connection.stream_counter.clone();
+        connection.schedule_shutdown(None);
+        assert!(
+            connection.poll_noop_waker().is_pending(),
+            "active streams postpone the shutdown"
+        );
+        drop(active_stream);
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::KeepAliveTimeout { .. }))
+        ));
+
+        let mut connection = build();
+        connection.schedule_shutdown(Some(clock.now() + Duration::from_secs(5)));
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(4));
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::KeepAliveTimeout { .. }))
+        ));
+    }
+
+    #[test]
+    fn pending_shutdown_reason_reports_scheduled_shutdown() {
+        let clock = MockClock::default();
+        let build = || {
+            builder()
+                .idle_timeout(Duration::from_secs(60))
+                .graceful_keep_alive_timeout(true)
+                .build(
+                    StreamMuxerBox::new(PendingStreamMuxer),
+                    KeepAliveConnectionHandler { keep_alive: false },
+                )
+                .with_clock(clock.clone())
+        };
+
+        let mut connection = build();
+        let deadline = clock.now() + Duration::from_secs(5);
+        connection.schedule_shutdown(Some(deadline));
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.pending_shutdown_reason(),
+            Some(PendingShutdownReason::Scheduled {
+                deadline: Some(deadline)
+            })
+        );
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            connection.pending_shutdown_reason(),
+            Some(PendingShutdownReason::Scheduled {
+                deadline: Some(deadline)
+            }),
+            "an elapsed scheduled shutdown is not mistaken for an elapsed idle timeout"
+        );
+
+        let mut connection = build();
+        connection.schedule_shutdown(None);
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Ok(Event::CloseRequested { .. }))
+        ));
+        assert_eq!(
+            connection.pending_shutdown_reason(),
+            Some(PendingShutdownReason::Scheduled { deadline: None })
+        );
+    }
+
+    #[test]
+    fn silent_connection_fails_after_max_connection_silence() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .clock(Arc::new(clock.clone()))
+            .max_connection_silence(Duration::from_secs(5))
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                keep_alive::ConnectionHandler,
+            );
+
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(4));
+        assert!(
+            connection.poll_noop_waker().is_pending(),
+            "keep-alive is irrelevant, but the window did not pass yet"
+        );
+        clock.advance(Duration::from_secs(1));
+
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::Silent { silent_for }))
+                if silent_for == Duration::from_secs(5)
+        ));
+    }
+
+    #[test]
+    fn keep_alive_handler_keeps_idle_connection_alive() {
+        let mut connection = builder().idle_timeout(Duration::ZERO).build(
+            StreamMuxerBox::new(PendingStreamMuxer),
+            keep_alive::ConnectionHandler,
+        );
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert!(connection.poll_noop_waker().is_pending());
+    }
+
+    #[test]
+    fn keep_alive_for_duration_shuts_down_once_duration_elapsed() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .idle_timeout(Duration::ZERO)
+            .clock(Arc::new(clock.clone()))
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                keep_alive::for_duration_with_clock(
+                    Duration::from_secs(10),
+                    Arc::new(clock.clone()),
+                ),
+            );
+        assert_eq!(
+            connection.handler.until(),
+            clock.now() + Duration::from_secs(10)
+        );
+
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(9));
+        assert!(
+            connection.poll_noop_waker().is_pending(),
+            "connection is kept alive before the duration elapsed"
+        );
+        clock.advance(Duration::from_secs(1));
+
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::KeepAliveTimeout { .. }))
+        ));
+    }
+
+    #[test]
+    fn keep_alive_for_duration_saturates_unrepresentable_duration() {
+        let handler = keep_alive::for_duration(Duration::MAX);
+
+        assert!(handler.connection_keep_alive());
+    }
+
+    #[test]
+    fn forced_keep_alive_overrides_handler_until_deadline() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                KeepAliveConnectionHandler { keep_alive: false },
+            )
+            .with_clock(clock.clone());
+        connection.force_keep_alive_until(clock.now() + Duration::from_secs(10));
+
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(9));
+        assert!(connection.poll_noop_waker().is_pending());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::KeepAliveTimeout { .. }))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "catch-handler-panics")]
+    fn handler_panic_fails_the_connection() {
+        let mut connection = builder().build(
+            StreamMuxerBox::new(PendingStreamMuxer),
+            PanickingConnectionHandler,
+        );
+
+        match connection.poll_noop_waker() {
+            Poll::Ready(Err(ConnectionError::HandlerPanic { message })) => {
+                assert_eq!(message.as_deref(), Some("handler bug"))
+            }
+            other => panic!("unexpected poll result {other:?}"),
+        }
+    }
+
+    #[test]
+    fn activity_restarts_idle_timeout() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .idle_timeout(Duration::from_secs(10))
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                KeepAliveConnectionHandler { keep_alive: false },
+            )
+            .with_clock(clock.clone());
+
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(9));
+        assert!(connection.poll_noop_waker().is_pending());
+
+        let active_stream = connection.stream_counter.clone();
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(5));
+        assert!(
+            connection.poll_noop_waker().is_pending(),
+            "active streams suspend the idle timeout"
+        );
+
+        drop(active_stream);
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(9));
+        assert!(
+            connection.poll_noop_waker().is_pending(),
+            "idle timeout starts over once idle again"
+        );
+
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::KeepAliveTimeout { idle_for }))
+                if idle_for == Duration::from_secs(10)
+        ));
+    }
+
+    #[test]
+    fn pending_shutdown_reason_follows_keep_alive() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .idle_timeout(Duration::from_secs(10))
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                KeepAliveConnectionHandler { keep_alive: true },
+            )
+            .with_clock(clock.clone());
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.pending_shutdown_reason(), None);
+
+        connection.handler.keep_alive = false;
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.pending_shutdown_reason(),
+            Some(PendingShutdownReason::IdleTimeout)
+        );
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(
+            connection.pending_shutdown_reason(),
+            Some(PendingShutdownReason::IdleTimeoutElapsed)
+        );
+
+        connection.handler.keep_alive = true;
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.pending_shutdown_reason(), None);
+    }
+
+    #[test]
+    fn ordered_inbound_streams_are_handed_over_in_acceptance_order() {
+        // Returns the order in which the handler received the inbound streams, when the remote
+        // negotiates them in reverse order of acceptance.
+        let handover_order = |ordered: bool| {
+            let (locals, remotes): (Vec<_>, Vec<_>) = (0..3).map(|_| memory_stream_pair()).unzip();
+            let mut connection = builder().ordered_inbound_streams(ordered).build(
+                StreamMuxerBox::new(InboundStreamsMuxer {
+                    substreams: locals.into(),
+                }),
+                StreamCollectingConnectionHandler::default(),
+            );
+
+            futures::executor::block_on(async {
+                use futures::{AsyncReadExt, AsyncWriteExt};
+
+                let negotiate = async {
+                    let mut remotes = remotes.into_iter().enumerate().collect::<Vec<_>>();
+                    for (i, remote) in remotes.iter_mut().rev() {
+                        multistream_select::dialer_select_proto(
+                            &mut *remote,
+                            ["/foo"],
+                            multistream_select::Version::V1,
+                        )
+                        .await
+                        .unwrap();
+                        remote.write_all(&[*i as u8]).await.unwrap();
+                    }
+                    remotes
+                };
+                let drive = future::poll_fn(|cx| {
+                    let _ = Pin::new(&mut connection).poll(cx);
+                    if connection.handler.streams.len() < 3 {
+                        Poll::Pending
+                    } else {
+                        Poll::Ready(())
+                    }
+                });
+                let (_remotes, ()) = future::join(negotiate, drive).await;
+
+                let mut order = Vec::new();
+                for stream in &mut connection.handler.streams {
+                    let mut i = [0u8];
+                    stream.read_exact(&mut i).await.unwrap();
+                    order.push(i[0]);
+                }
+                order
+            })
+        };
+
+        assert_eq!(handover_order(true), [0, 1, 2]);
+        assert_eq!(
+            handover_order(false),
+            [2, 1, 0],
+            "Expect streams to be handed over as they negotiated by default"
+        );
+    }
+
+    #[test]
+    fn connection_ids_do_not_wrap_around() {
+        let counter = AtomicUsize::new(usize::MAX - 1);
+
+        assert_eq!(next_id(&counter), usize::MAX - 1);
+        assert!(std::panic::catch_unwind(|| next_id(&counter)).is_err());
+        assert!(
+            std::panic::catch_unwind(|| next_id(&counter)).is_err(),
+            "Exhausted counter to not recover"
+        );
+    }
+
+    #[test]
+    fn negotiation_is_offloaded_to_executor() {
+        let (local, mut remote) = memory_stream_pair();
+        let pool = futures::executor::ThreadPool::new().unwrap();
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let mut connection = builder()
+            .negotiation_executor({
+                let spawned = spawned.clone();
+                move |negotiation| {
+                    spawned.fetch_add(1, Ordering::SeqCst);
+                    pool.spawn_ok(negotiation);
+                }
+            })
+            .build(
+                StreamMuxerBox::new(OnceInboundStreamMuxer {
+                    substream: Some(local),
+                }),
+                StreamCollectingConnectionHandler::default(),
+            );
+
+        futures::executor::block_on(async {
+            let negotiate = async {
+                multistream_select::dialer_select_proto(
+                    &mut remote,
+                    ["/foo"],
+                    multistream_select::Version::V1,
+                )
+                .await
+                .unwrap();
+            };
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if connection.handler.streams.is_empty() {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            });
+
+            future::join(negotiate, drive).await;
+        });
+
+        assert_eq!(connection.handler.streams.len(), 1);
+        assert_eq!(spawned.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn boxed_handler_receives_unboxed_streams() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut connection = builder().build(
+            StreamMuxerBox::new(OnceInboundStreamMuxer {
+                substream: Some(local),
+            }),
+            BoxedConnectionHandler::new(StreamCollectingConnectionHandler::default()),
+        );
+        let streams = |connection: &Connection<BoxedConnectionHandler>| {
+            connection
+                .handler
+                .downcast_ref::<StreamCollectingConnectionHandler>()
+                .expect("handler to be erased")
+                .streams
+                .len()
+        };
+
+        futures::executor::block_on(async {
+            let negotiate = async {
+                multistream_select::dialer_select_proto(
+                    &mut remote,
+                    ["/foo"],
+                    multistream_select::Version::V1,
+                )
+                .await
+                .unwrap();
+            };
+            let drive = future::poll_fn(|cx| {
+                let _ = Pin::new(&mut connection).poll(cx);
+                if streams(&connection) == 0 {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            });
+
+            future::join(negotiate, drive).await;
+        });
+
+        assert_eq!(streams(&connection), 1);
+        assert!(connection
+            .handler
+            .downcast_ref::<dummy::ConnectionHandler>()
+            .is_none());
+    }
+
+    #[test]
+    fn address_change_is_only_reported_on_actual_change() {
+        let addr = |a: &str| a.parse::<Multiaddr>().unwrap();
+        let mut connection = builder()
+            .endpoint(ConnectedPoint::Dialer {
+                address: addr("/memory/1"),
+                role_override: Endpoint::Dialer,
+                port_use: PortUse::Reuse,
+            })
+            .max_negotiating_inbound_streams(0)
+            .build(
+                StreamMuxerBox::new(AddressChangeStreamMuxer {
+                    addresses: vec![
+                        addr("/memory/1"),
+                        addr("/memory/2"),
+                        addr("/memory/2"),
+                        addr("/memory/3"),
+                    ]
+                    .into(),
+                }),
+                dummy::ConnectionHandler,
+            );
+
+        let mut reported = Vec::new();
+        while let Poll::Ready(event) = connection.poll_noop_waker() {
+            match event.unwrap() {
+                Event::AddressChange {
+                    old_address,
+                    new_address,
+                } => reported.push((old_address, new_address)),
+                other => panic!("Unexpected event: {other:?}"),
+            }
+        }
+
+        assert_eq!(
+            reported,
+            [
+                (addr("/memory/1"), addr("/memory/2")),
+                (addr("/memory/2"), addr("/memory/3")),
+            ]
+        );
+        assert_eq!(
+            connection
+                .endpoint()
+                .map(ConnectedPoint::get_remote_address),
+            Some(&addr("/memory/3"))
+        );
+    }
+
+    #[test]
+    fn address_change_is_forwarded_to_on_address_change() {
+        let addr = |a: &str| a.parse::<Multiaddr>().unwrap();
+        let mut connection = builder()
+            .endpoint(ConnectedPoint::Dialer {
+                address: addr("/memory/1"),
+                role_override: Endpoint::Dialer,
+                port_use: PortUse::Reuse,
+            })
+            .max_negotiating_inbound_streams(0)
+            .build(
+                StreamMuxerBox::new(AddressChangeStreamMuxer {
+                    addresses: vec![addr("/memory/2"), addr("/memory/2"), addr("/memory/3")].into(),
+                }),
+                AddressRecordingConnectionHandler::default().select(dummy::ConnectionHandler),
+            );
+
+        while let Poll::Ready(event) = connection.poll_noop_waker() {
+            event.unwrap();
+        }
+
+        let (handler, _) = connection.handler.into_inner();
+        assert_eq!(handler.addresses, [addr("/memory/2"), addr("/memory/3")]);
+    }
+
+    #[test]
+    fn dial_upgrade_error_reports_reset_cause() {
+        for (kind, cause) in [
+            (io::ErrorKind::ConnectionReset, ResetCause::RemoteReset),
+            (io::ErrorKind::BrokenPipe, ResetCause::ConnectionClosed),
+            (io::ErrorKind::Other, ResetCause::Io),
+        ] {
+            let mut connection = builder().build(
+                StreamMuxerBox::new(FailingOutboundStreamMuxer(kind)),
+                MockConnectionHandler::new(Duration::from_secs(10)),
+            );
+
+            connection.handler.open_new_outbound();
+            assert!(connection.poll_noop_waker().is_pending());
+
+            let error = connection.handler.error.take().expect("request to fail");
+            assert_eq!(error.reset_cause(), Some(cause), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn transient_muxer_errors_are_retried() {
+        let connection = |retries: usize| {
+            let mut connection = builder().outbound_stream_retries(retries).build(
+                StreamMuxerBox::new(FlakyOutboundStreamMuxer { errors: 2 }),
+                MockConnectionHandler::new(Duration::from_secs(10)),
+            );
+            connection.handler.open_new_outbound();
+            connection
+        };
+
+        let mut retrying = connection(2);
+        for _ in 0..3 {
+            assert!(retrying.poll_noop_waker().is_pending());
+        }
+        assert_eq!(retrying.negotiating_out.len(), 1);
+        assert!(retrying.requested_substreams.is_empty());
+
+        let mut failing = connection(1);
+        assert!(failing.poll_noop_waker().is_pending());
+        assert!(matches!(
+            failing.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::IO(e))) if is_transient_muxer_error(&e)
+        ));
+    }
+
+    #[test]
+    fn upgrade_timeouts_are_reported_per_direction() {
+        let upgrade_timeout = Duration::from_secs(10);
+        let clock = MockClock::default();
+        let build = |muxer: StreamMuxerBox| {
+            builder()
+                .clock(Arc::new(clock.clone()))
+                .build(muxer, MockConnectionHandler::new(upgrade_timeout))
+        };
+        let timeouts = |connection: &mut Connection<MockConnectionHandler>| {
+            clock.advance(upgrade_timeout);
+            let mut metrics = MetricsDelta::new();
+            while let Poll::Ready(event) = connection.poll_noop_waker() {
+                if let Ok(Event::Metrics(delta)) = event {
+                    delta
+                        .iter()
+                        .for_each(|(label, value)| metrics.add(label, value));
+                }
+            }
+            metrics.iter().collect::<Vec<_>>()
+        };
+
+        // The muxer never grants the requested stream.
+        let mut connection = build(StreamMuxerBox::new(PendingStreamMuxer));
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            timeouts(&mut connection),
+            [(MetricsDelta::OUTBOUND_GRANT_TIMEOUTS, 1)]
+        );
+        assert!(matches!(
+            connection.handler.error.take(),
+            Some(StreamUpgradeError::Timeout)
+        ));
+
+        // The muxer grants the stream, but the remote never responds.
+        let mut connection = build(StreamMuxerBox::new(GrantingStreamMuxer {
+            grants: Arc::new(AtomicUsize::new(1)),
+            counter: Arc::new(()),
+        }));
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            timeouts(&mut connection),
+            [(MetricsDelta::OUTBOUND_UPGRADE_TIMEOUTS, 1)]
+        );
+        assert!(matches!(
+            connection.handler.error.take(),
+            Some(StreamUpgradeError::Timeout)
+        ));
+
+        // The remote opens a stream, but never proposes a protocol.
+        let (local, _remote) = memory_stream_pair();
+        let mut connection = build(StreamMuxerBox::new(OnceInboundStreamMuxer {
+            substream: Some(local),
+        }));
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            timeouts(&mut connection),
+            [(MetricsDelta::INBOUND_UPGRADE_TIMEOUTS, 1)]
+        );
+        assert!(connection.handler.error.is_none());
+    }
+
+    #[test]
+    fn upgrade_timeouts_are_accumulated_until_reported() {
+        let clock = MockClock::default();
+        let build = |substreams: Vec<Channel<Vec<u8>>>, poll_budget: usize| {
+            let mut handler = ConfigurableProtocolConnectionHandler::default();
+            handler.listen_on(&["/foo"]);
+            builder()
+                .clock(Arc::new(clock.clone()))
+                .poll_budget(NonZeroUsize::new(poll_budget).unwrap())
+                .build(
+                    StreamMuxerBox::new(InboundStreamsMuxer {
+                        substreams: substreams.into(),
+                    }),
+                    handler,
+                )
+        };
+
+        // Both negotiations time out within the same poll and are reported at once.
+        let (local_a, _remote_a) = memory_stream_pair();
+        let (local_b, _remote_b) = memory_stream_pair();
+        let mut connection = build(vec![local_a, local_b], 128);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_in.len(), 2);
+
+        clock.advance(Duration::from_secs(10));
+        match connection.poll_noop_waker() {
+            Poll::Ready(Ok(Event::Metrics(metrics))) => assert_eq!(
+                metrics.iter().collect::<Vec<_>>(),
+                [(MetricsDelta::INBOUND_UPGRADE_TIMEOUTS, 2)]
+            ),
+            other => panic!("Unexpected poll result: {other:?}"),
+        }
+        assert!(connection.poll_noop_waker().is_pending());
+
+        // The poll yields right after the timeout, which is then reported along with the metrics
+        // of the handler.
+        let (local, _remote) = memory_stream_pair();
+        let mut connection = build(vec![local], 1);
+        while connection.negotiating_in.is_empty() {
+            assert!(connection.poll_noop_waker().is_pending());
+        }
+        assert!(connection.poll_noop_waker().is_pending());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(connection.poll_noop_waker().is_pending());
+        assert!(connection.negotiating_in.is_empty());
+
+        connection
+            .handler
+            .report_metrics(MetricsDelta::new().with("messages", 1));
+        match connection.poll_noop_waker() {
+            Poll::Ready(Ok(Event::Metrics(metrics))) => {
+                assert_eq!(metrics.get("messages"), Some(1));
+                assert_eq!(metrics.get(MetricsDelta::INBOUND_UPGRADE_TIMEOUTS), Some(1));
+            }
+            other => panic!("Unexpected poll result: {other:?}"),
+        }
+        assert!(connection.poll_noop_waker().is_pending());
+    }
+
+    #[test]
+    fn substream_deadline_caps_upgrade_timeouts() {
+        let clock = MockClock::default();
+        let build = |muxer: StreamMuxerBox| {
+            builder()
+                .clock(Arc::new(clock.clone()))
+                .substream_deadline(clock.now() + Duration::from_secs(2))
+                .build(muxer, MockConnectionHandler::new(Duration::from_secs(10)))
+        };
+
+        // The muxer never grants the requested stream.
+        let mut connection = build(StreamMuxerBox::new(PendingStreamMuxer));
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(2));
+        while connection.poll_noop_waker().is_ready() {}
+        assert!(matches!(
+            connection.handler.error.take(),
+            Some(StreamUpgradeError::Timeout)
+        ));
+
+        // The remote opens a stream, but never proposes a protocol.
+        let (local, _remote) = memory_stream_pair();
+        let mut connection = build(StreamMuxerBox::new(OnceInboundStreamMuxer {
+            substream: Some(local),
+        }));
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(2));
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Ok(Event::Metrics(delta)))
+                if delta.iter().eq([(MetricsDelta::INBOUND_UPGRADE_TIMEOUTS, 1)])
+        ));
+    }
+
+    #[test]
+    fn repeated_outbound_upgrade_failures_are_backed_off() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .outbound_upgrade_backoff(OutboundUpgradeBackoff::new(
+                NonZeroU32::new(2).unwrap(),
+                Duration::from_secs(10),
+                Duration::from_secs(15),
+            ))
+            .build(
+                StreamMuxerBox::new(FailingOutboundStreamMuxer(io::ErrorKind::BrokenPipe)),
+                MockConnectionHandler::new(Duration::from_secs(10)),
+            )
+            .with_clock(clock.clone());
+        let request = |connection: &mut Connection<MockConnectionHandler>| {
+            connection.handler.open_new_outbound();
+            assert!(connection.poll_noop_waker().is_pending());
+            connection.handler.error.take().expect("request to fail")
+        };
+
+        for _ in 0..2 {
+            assert!(matches!(
+                request(&mut connection),
+                StreamUpgradeError::Io(_)
+            ));
+        }
+        assert!(matches!(
+            request(&mut connection),
+            StreamUpgradeError::Throttled { retry_after } if retry_after == Duration::from_secs(10)
+        ));
+
+        clock.advance(Duration::from_secs(4));
+        assert!(matches!(
+            request(&mut connection),
+            StreamUpgradeError::Throttled { retry_after } if retry_after == Duration::from_secs(6)
+        ));
+
+        clock.advance(Duration::from_secs(6));
+        assert!(
+            matches!(request(&mut connection), StreamUpgradeError::Io(_)),
+            "request is honored once the backoff expired"
+        );
+        assert!(
+            matches!(
+                request(&mut connection),
+                StreamUpgradeError::Throttled { retry_after } if retry_after == Duration::from_secs(15)
+            ),
+            "backoff doubles up to the maximum"
+        );
+    }
+
+    #[test]
+    fn debug_snapshot_reflects_driven_connection() {
+        let clock = MockClock::default();
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        handler.listen_on(&["/foo", "/bar"]);
+        handler.remote_adds_support_for(&["/baz"]);
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(2)
+            .build(
+                StreamMuxerBox::new(DummyStreamMuxer {
+                    counter: Arc::new(()),
+                }),
+                handler,
+            )
+            .with_clock(clock.clone());
+
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(5));
+
+        let snapshot = connection.debug_snapshot();
+        assert_eq!(snapshot.id, connection.id());
+        assert_eq!(snapshot.negotiating_inbound, 2);
+        assert_eq!(snapshot.negotiating_outbound, 0);
+        assert_eq!(snapshot.requested_outbound, 0);
+        assert_eq!(snapshot.shutdown, PlannedShutdown::None);
+        assert_eq!(snapshot.local_protocols, ["/bar", "/foo"]);
+        assert_eq!(snapshot.remote_protocols, ["/baz"]);
+        assert_eq!(snapshot.age, Duration::from_secs(5));
+
+        connection.handler.close_gracefully("done");
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.debug_snapshot().shutdown,
+            PlannedShutdown::Graceful {
+                reason: "done".to_owned()
+            }
+        );
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_value(connection.debug_snapshot()).unwrap();
+            assert_eq!(json["negotiating_inbound"], 2);
+            assert_eq!(json["shutdown"]["Graceful"]["reason"], "done");
+        }
+    }
+
+    #[test]
+    fn connection_fails_without_stream_before_first_stream_deadline() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .first_stream_deadline(Duration::from_secs(5))
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                KeepAliveConnectionHandler { keep_alive: true },
+            )
+            .with_clock(clock.clone());
+
+        clock.advance(Duration::from_secs(4));
+        assert!(connection.poll_noop_waker().is_pending());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::NegotiationDeadline { deadline }))
+                if deadline == Duration::from_secs(5)
+        ));
+    }
+
+    #[test]
+    fn events_and_errors_carry_connection_id() {
+        let addr = |a: &str| a.parse::<Multiaddr>().unwrap();
+        let clock = MockClock::default();
+        let id = ConnectionId::new_unchecked(42);
+        let mut connection = builder()
+            .connection_id(id)
+            .remote_address(addr("/memory/1"))
+            .build(
+                StreamMuxerBox::new(AddressChangeStreamMuxer {
+                    addresses: vec![addr("/memory/2")].into(),
+                }),
+                dummy::ConnectionHandler,
+            )
+            .with_clock(clock.clone());
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        let Poll::Ready(Some(Ok(event))) = connection.poll_next_unpin(&mut cx) else {
+            panic!("Expected an event");
+        };
+        assert_eq!(connection.id(), id);
+        assert_eq!(event.connection_id(), id);
+        assert!(matches!(event.into_event(), Event::AddressChange { .. }));
+
+        clock.advance(Duration::from_secs(10));
+        let Poll::Ready(Some(Err(error))) = connection.poll_next_unpin(&mut cx) else {
+            panic!("Expected the connection to terminate");
+        };
+        assert_eq!(error.connection_id(), id);
+    }
+
+    #[test]
+    fn handler_poll_budget_lets_negotiation_advance() {
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        handler.listen_on(&["/foo"]);
+        for _ in 0..10 {
+            handler.report_metrics(MetricsDelta::new().with("events", 1));
+        }
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(1)
+            .handler_poll_budget(NonZeroUsize::new(2).unwrap())
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(DummyStreamMuxer {
+                    counter: Arc::new(()),
+                }),
+                handler,
+            );
+
+        for _ in 0..3 {
+            assert!(matches!(
+                connection.poll_noop_waker(),
+                Poll::Ready(Ok(Event::Metrics(_)))
+            ));
+        }
+
+        assert_eq!(
+            connection.negotiating_in.len(),
+            1,
+            "Expect inbound stream to be accepted despite handler always being ready"
+        );
+    }
+
+    #[test]
+    fn inbound_streams_are_dropped_without_negotiation_if_handler_denies_all() {
+        let (local, mut remote) = memory_stream_pair();
+        let mut connection = builder().build(
+            StreamMuxerBox::new(OnceInboundStreamMuxer {
+                substream: Some(local),
+            }),
+            dummy::ConnectionHandler,
+        );
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert!(connection.negotiating_in.is_empty());
+
+        use futures::AsyncReadExt;
+        let read = futures::executor::block_on(remote.read(&mut [0u8; 1]));
+        assert!(
+            matches!(read, Ok(0) | Err(_)),
+            "Expect the stream to be closed"
+        );
+    }
+
+    #[test]
+    fn poll_batch_drains_events_up_to_the_capacity_of_the_buffer() {
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        for _ in 0..5 {
+            handler.report_metrics(MetricsDelta::new().with("events", 1));
+        }
+        let mut connection = builder()
+            .idle_timeout(Duration::from_secs(10))
+            .build(StreamMuxerBox::new(PendingStreamMuxer), handler);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        let mut buf = Vec::with_capacity(3);
+        assert!(matches!(
+            connection.poll_batch(&mut cx, &mut buf),
+            Poll::Ready(Some(Ok(())))
+        ));
+        assert_eq!(buf.len(), 3);
+
+        buf.clear();
+        assert!(matches!(
+            connection.poll_batch(&mut cx, &mut buf),
+            Poll::Ready(Some(Ok(())))
+        ));
+        assert_eq!(buf.len(), 2, "Expect the remaining events");
+        assert!(buf.iter().all(|event| matches!(event, Event::Metrics(_))));
+
+        buf.clear();
+        assert!(connection.poll_batch(&mut cx, &mut buf).is_pending());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn inbound_stream_flood_is_rate_limited() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(1000)
+            .inbound_stream_rate_limit(InboundStreamRateLimit::new(
+                NonZeroU32::new(5).unwrap(),
+                Duration::from_secs(1),
+            ))
+            .build(
+                StreamMuxerBox::new(DummyStreamMuxer {
+                    counter: Arc::new(()),
+                }),
+                MockConnectionHandler::new(Duration::from_secs(60)),
+            )
+            .with_clock(clock.clone());
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_in.len(), 5, "accepts a burst");
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_in.len(), 5);
+
+        clock.advance(Duration::from_millis(300));
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_in.len(), 6);
+
+        clock.advance(Duration::from_millis(100));
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.negotiating_in.len(),
+            7,
+            "fraction of the previous refill is kept"
+        );
+
+        clock.advance(Duration::from_secs(10));
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.negotiating_in.len(),
+            12,
+            "refills at most a burst after a pause"
+        );
+    }
+
+    #[test]
+    fn inbound_stream_rate_limit_wakes_connection_through_clock() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .clock(Arc::new(clock.clone()))
+            .max_negotiating_inbound_streams(1000)
+            .inbound_stream_rate_limit(InboundStreamRateLimit::new(
+                NonZeroU32::new(2).unwrap(),
+                Duration::from_secs(1),
+            ))
+            .build(
+                StreamMuxerBox::new(DummyStreamMuxer {
+                    counter: Arc::new(()),
+                }),
+                MockConnectionHandler::new(Duration::from_secs(60)),
+            );
+        let wakes = Arc::new(WakeCounter::default());
+        let waker = futures::task::waker(wakes.clone());
+
+        let poll = Pin::new(&mut connection).poll(&mut Context::from_waker(&waker));
+        assert!(poll.is_pending());
+        assert_eq!(connection.negotiating_in.len(), 2);
+
+        clock.advance(Duration::from_millis(499));
+        let poll = Pin::new(&mut connection).poll(&mut Context::from_waker(&waker));
+        assert!(poll.is_pending());
+        assert_eq!(connection.negotiating_in.len(), 2, "no token refilled yet");
+
+        let woken = wakes.0.load(Ordering::SeqCst);
+        clock.advance(Duration::from_millis(1));
+        assert!(
+            wakes.0.load(Ordering::SeqCst) > woken,
+            "woken once the next token is refilled"
+        );
+        let poll = Pin::new(&mut connection).poll(&mut Context::from_waker(&waker));
+        assert!(poll.is_pending());
+        assert_eq!(connection.negotiating_in.len(), 3);
+    }
+
+    #[test]
+    fn max_negotiating_inbound_streams_can_be_adjusted() {
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(2)
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(DummyStreamMuxer {
+                    counter: Arc::new(()),
+                }),
+                MockConnectionHandler::new(Duration::from_secs(10)),
+            );
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_in.len(), 2);
+
+        connection.set_max_negotiating_inbound_streams(4);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.negotiating_in.len(), 4);
+
+        connection.set_max_negotiating_inbound_streams(1);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.negotiating_in.len(),
+            4,
+            "Expect existing negotiations to continue"
+        );
+    }
+
+    #[test]
+    fn connection_id_from_usize() {
+        assert_eq!(ConnectionId::from(42), ConnectionId::new_unchecked(42));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn connection_id_serde_roundtrip() {
+        let id = ConnectionId::new_unchecked(42);
+
+        let serialized = serde_json::to_string(&id).unwrap();
+        let deserialized: ConnectionId = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(serialized, "42");
+        assert_eq!(deserialized, id);
+    }
+
+    #[test]
+    fn outbound_stream_timeout_starts_on_request() {
+        let upgrade_timeout = Duration::from_secs(1);
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(2)
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                MockConnectionHandler::new(upgrade_timeout),
+            );
+
+        connection.handler.open_new_outbound();
+        let _ = connection.poll_noop_waker();
+
+        std::thread::sleep(upgrade_timeout + Duration::from_secs(1));
+
+        let _ = connection.poll_noop_waker();
+
+        assert!(matches!(
+            connection.handler.error.unwrap(),
+            StreamUpgradeError::Timeout
+        ))
+    }
+
+    #[test]
+    fn outbound_timeout_policy_adjusts_request_timeout() {
+        let upgrade_timeout = Duration::from_millis(500);
+        let mut connection = builder()
+            .outbound_timeout_policy(|timeout| timeout * 2)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                MockConnectionHandler::new(upgrade_timeout),
+            );
+
+        connection.handler.open_new_outbound();
+        let _ = connection.poll_noop_waker();
+
+        std::thread::sleep(upgrade_timeout + Duration::from_millis(250));
+        let _ = connection.poll_noop_waker();
+        assert!(
+            connection.handler.error.is_none(),
+            "request must outlive the timeout set by the handler"
+        );
+
+        std::thread::sleep(upgrade_timeout);
+        let _ = connection.poll_noop_waker();
+        assert!(matches!(
+            connection.handler.error.take().unwrap(),
+            StreamUpgradeError::Timeout
+        ));
+    }
+
+    #[test]
+    fn close_returns_abandoned_outbound_requests() {
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(2)
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                MockConnectionHandler::new(Duration::from_secs(10)),
+            );
+
+        connection.handler.open_new_outbound();
+        let _ = connection.poll_noop_waker();
+        connection.handler.open_new_outbound();
+        let _ = connection.poll_noop_waker();
+
+        let (_, _, abandoned) = connection.close();
+
+        assert_eq!(abandoned.len(), 2);
+    }
+
+    #[test]
+    #[expect(deprecated)]
+    fn positional_constructor_builds_through_builder() {
+        let peer_id = PeerId::random();
+        let connection = Connection::new(
+            StreamMuxerBox::new(PendingStreamMuxer),
+            MockConnectionHandler::new(Duration::from_secs(10)),
+            peer_id,
+            Some(upgrade::Version::V1Lazy),
+            3,
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(connection.remote_peer_id(), peer_id);
+        assert_eq!(
+            connection.substream_upgrade_protocol_override,
+            Some(upgrade::Version::V1Lazy)
+        );
+        assert_eq!(connection.max_negotiating_inbound_streams, 3);
+        assert_eq!(connection.idle_timeout, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn close_error_reports_abandoned_substreams() {
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(2)
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(FailingCloseStreamMuxer),
+                MockConnectionHandler::new(Duration::from_secs(10)),
+            );
+
+        connection.handler.open_new_outbound();
+        let _ = connection.poll_noop_waker();
+        connection.handler.open_new_outbound();
+        let _ = connection.poll_noop_waker();
+
+        // A request that was granted a substream but not yet removed is not abandoned.
+        let _ = connection
+            .requested_substreams
+            .iter_mut()
+            .next()
+            .unwrap()
+            .extract();
+        assert_eq!(connection.requested_substreams.len(), 2);
+
+        let (_, closing_muxer, abandoned) = connection.close();
+        let error = futures::executor::block_on(closing_muxer).unwrap_err();
+
+        assert_eq!(abandoned.len(), 1);
+        assert_eq!(error.abandoned_substreams, 1);
+    }
+
+    #[test]
+    fn forwards_metrics_reported_by_handler() {
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                ConfigurableProtocolConnectionHandler::default(),
+            );
+
+        connection.handler.report_metrics(
+            MetricsDelta::new()
+                .with("bytes_sent", 42)
+                .with("messages", 1),
+        );
+
+        match connection.poll_noop_waker() {
+            Poll::Ready(Ok(Event::Metrics(metrics))) => {
+                assert_eq!(metrics.get("bytes_sent"), Some(42));
+                assert_eq!(metrics.get("messages"), Some(1));
+            }
+            other => panic!("Unexpected poll result: {other:?}"),
+        }
+        assert!(connection.poll_noop_waker().is_pending());
+    }
+
+    #[test]
+    fn propagates_changes_to_supported_inbound_protocols() {
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                ConfigurableProtocolConnectionHandler::default(),
+            );
+
+        // First, start listening on a single protocol.
+        connection.handler.listen_on(&["/foo"]);
+        let _ = connection.poll_noop_waker();
+
+        assert_eq!(connection.handler.local_added, vec![vec!["/foo"]]);
+        assert!(connection.handler.local_removed.is_empty());
+
+        // Second, listen on two protocols.
+        connection.handler.listen_on(&["/foo", "/bar"]);
+        let _ = connection.poll_noop_waker();
+
+        assert_eq!(
+            connection.handler.local_added,
+            vec![vec!["/foo"], vec!["/bar"]],
+            "expect to only receive an event for the newly added protocols"
+        );
+        assert!(connection.handler.local_removed.is_empty());
+
+        // Third, stop listening on the first protocol.
+        connection.handler.listen_on(&["/bar"]);
+        let _ = connection.poll_noop_waker();
+
+        assert_eq!(
+            connection.handler.local_added,
+            vec![vec!["/foo"], vec!["/bar"]]
+        );
+        assert_eq!(connection.handler.local_removed, vec![vec!["/foo"]]);
+    }
+
+    #[test]
+    fn protocols_are_only_collected_once_generation_changes() {
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        handler.listen_on(&["/foo"]);
+        handler.protocols_generation = Some(0);
+        let mut connection = builder().build(StreamMuxerBox::new(PendingStreamMuxer), handler);
+        assert_eq!(connection.handler.local_added, vec![vec!["/foo"]]);
+
+        // The handler did not bump its generation, the change goes unnoticed.
+        connection.handler.listen_on(&["/foo", "/bar"]);
+        let _ = connection.poll_noop_waker();
+        assert_eq!(connection.handler.local_added, vec![vec!["/foo"]]);
+
+        connection.handler.protocols_generation = Some(1);
+        let _ = connection.poll_noop_waker();
+        assert_eq!(
+            connection.handler.local_added,
+            vec![vec!["/foo"], vec!["/bar"]]
+        );
+        assert!(connection.listen_protocols.read().unwrap().contains("/bar"));
+    }
+
+    #[test]
+    fn skips_protocols_change_if_handler_does_not_want_it() {
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                ConfigurableProtocolConnectionHandler {
+                    ignores_protocols_change: true,
+                    ..Default::default()
+                },
+            );
+
+        connection.handler.listen_on(&["/foo"]);
+        let _ = connection.poll_noop_waker();
+        connection.handler.listen_on(&["/bar"]);
+        let _ = connection.poll_noop_waker();
+
+        assert!(connection.handler.local_added.is_empty());
+        assert!(connection.handler.local_removed.is_empty());
+    }
+
+    #[test]
+    fn reported_remote_protocols_are_kept_apart_from_local_protocols() {
+        let mut handler = ConfigurableProtocolConnectionHandler::default();
+        handler.listen_on(&["/local"]);
+        let mut connection = builder().build(StreamMuxerBox::new(PendingStreamMuxer), handler);
+        assert_eq!(connection.handler.local_added, vec![vec!["/local"]]);
+
+        // E.g. learned via identify.
+        let protocols = |names: &[&'static str]| {
+            names
+                .iter()
+                .copied()
+                .map(StreamProtocol::new)
+                .collect::<HashSet<_>>()
+        };
+        connection.report_remote_protocols(ProtocolSupport::Added(protocols(&["/foo", "/bar"])));
+        connection.report_remote_protocols(ProtocolSupport::Added(protocols(&["/foo"])));
+        connection.report_remote_protocols(ProtocolSupport::Removed(protocols(&["/bar"])));
+
+        assert_eq!(connection.handler.remote_added.len(), 1);
+        assert_eq!(
+            connection.handler.remote_added[0]
+                .iter()
+                .cloned()
+                .collect::<HashSet<_>>(),
+            protocols(&["/foo", "/bar"])
+        );
+        assert_eq!(connection.handler.remote_removed, vec![vec!["/bar"]]);
+        assert_eq!(connection.handler.local_added, vec![vec!["/local"]]);
+
+        connection.handler.listen_on(&["/local", "/other"]);
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.handler.local_added,
+            vec![vec!["/local"], vec!["/other"]]
+        );
+        assert_eq!(connection.handler.remote_added.len(), 1);
+        assert_eq!(connection.handler.remote_removed, vec![vec!["/bar"]]);
+    }
+
+    #[test]
+    fn only_propagtes_actual_changes_to_remote_protocols_to_handler() {
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                ConfigurableProtocolConnectionHandler::default(),
+            );
+
+        // First, remote supports a single protocol.
+        connection.handler.remote_adds_support_for(&["/foo"]);
+        let _ = connection.poll_noop_waker();
+
+        assert_eq!(connection.handler.remote_added, vec![vec!["/foo"]]);
+        assert!(connection.handler.remote_removed.is_empty());
+
+        // Second, it adds a protocol but also still includes the first one.
+        connection
+            .handler
+            .remote_adds_support_for(&["/foo", "/bar"]);
+        let _ = connection.poll_noop_waker();
+
+        assert_eq!(
+            connection.handler.remote_added,
+            vec![vec!["/foo"], vec!["/bar"]],
+            "expect to only receive an event for the newly added protocol"
+        );
+        assert!(connection.handler.remote_removed.is_empty());
+
+        // Third, stop listening on a protocol it never advertised (we can't control what handlers
+        // do so this needs to be handled gracefully).
+        connection.handler.remote_removes_support_for(&["/baz"]);
+        let _ = connection.poll_noop_waker();
+
+        assert_eq!(
+            connection.handler.remote_added,
+            vec![vec!["/foo"], vec!["/bar"]]
+        );
+        assert!(&connection.handler.remote_removed.is_empty());
+
+        // Fourth, stop listening on a protocol that was previously supported
+        connection.handler.remote_removes_support_for(&["/bar"]);
+        let _ = connection.poll_noop_waker();
+
+        assert_eq!(
+            connection.handler.remote_added,
+            vec![vec!["/foo"], vec!["/bar"]]
+        );
+        assert_eq!(connection.handler.remote_removed, vec![vec!["/bar"]]);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_with_keep_alive_no() {
+        let idle_timeout = Duration::from_millis(100);
+
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .idle_timeout(idle_timeout)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                dummy::ConnectionHandler,
+            );
+
+        assert!(connection.poll_noop_waker().is_pending());
+
+        tokio::time::sleep(idle_timeout).await;
+
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::KeepAliveTimeout { .. }))
+        ));
+    }
+
+    #[test]
+    fn idle_connection_times_out_once_clock_advances() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                dummy::ConnectionHandler,
+            )
+            .with_clock(clock.clone());
+
+        let (events, error) = testing::drive(&mut connection, 2);
+        assert!(events.is_empty());
+        assert!(error.is_none());
+
+        clock.advance(Duration::from_secs(9));
+        assert!(testing::drive(&mut connection, 2).1.is_none());
+
+        clock.advance(Duration::from_secs(1));
+        let (events, error) = testing::drive(&mut connection, 2);
+        assert!(events.is_empty());
+        assert!(matches!(
+            error.map(PeerConnectionError::into_error),
+            Some(ConnectionError::KeepAliveTimeout { .. })
+        ));
+        assert!(connection.terminated);
+    }
+
+    #[test]
+    fn effective_upgrade_version_falls_back_to_default() {
+        let build = |builder: ConnectionBuilder| {
+            builder.build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                dummy::ConnectionHandler,
+            )
+        };
+
+        assert_eq!(
+            build(builder()).effective_upgrade_version(),
+            upgrade::Version::default()
+        );
+        assert_eq!(
+            build(builder().upgrade_version_override(upgrade::Version::V1Lazy))
+                .effective_upgrade_version(),
+            upgrade::Version::V1Lazy
+        );
+    }
+
+    #[test]
+    fn clock_timer_wakes_idle_connection_on_keep_alive_timeout() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .idle_timeout(Duration::from_secs(10))
+            .clock(Arc::new(clock.clone()))
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                dummy::ConnectionHandler,
+            );
+        let wakes = Arc::new(WakeCounter::default());
+        let waker = futures::task::waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(connection.poll_next_unpin(&mut cx).is_pending());
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(
+            wakes.0.load(Ordering::SeqCst),
+            1,
+            "advancing the clock wakes pending timers"
+        );
+        assert!(connection.poll_next_unpin(&mut cx).is_pending());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 2);
+        assert!(matches!(
+            connection.poll_next_unpin(&mut cx),
+            Poll::Ready(Some(Err(error)))
+                if matches!(error.error(), ConnectionError::KeepAliveTimeout { .. })
+        ));
+    }
+
+    #[test]
+    fn keep_alive_timeout_reports_idle_duration() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                KeepAliveConnectionHandler { keep_alive: true },
+            )
+            .with_clock(clock.clone());
+
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(20));
+        assert!(connection.poll_noop_waker().is_pending());
+
+        connection.handler.keep_alive = false;
+        assert!(connection.poll_noop_waker().is_pending());
+        clock.advance(Duration::from_secs(10));
+
+        match connection.poll_noop_waker() {
+            Poll::Ready(Err(ConnectionError::KeepAliveTimeout { idle_for })) => {
+                assert_eq!(
+                    idle_for,
+                    Duration::from_secs(30),
+                    "idle since the first poll, not since the handler stopped keeping it alive"
+                );
+            }
+            other => panic!("unexpected poll result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn stalled_outbound_streams_are_reported_once_threshold_passes() {
+        let clock = MockClock::default();
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .outbound_stall_threshold(Duration::from_secs(1))
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                MockConnectionHandler::new(Duration::from_secs(60)),
+            )
+            .with_clock(clock.clone());
+
+        connection.handler.open_new_outbound();
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.handler.outbound_stalls, 0);
+
+        clock.advance(Duration::from_millis(500));
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.handler.outbound_stalls, 0);
+
+        clock.advance(Duration::from_millis(500));
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(connection.handler.outbound_stalls, 1);
+
+        clock.advance(Duration::from_secs(10));
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            connection.handler.outbound_stalls, 1,
+            "a stall is only reported once"
+        );
+    }
+
+    #[test]
+    fn keep_alive_transitions_and_shutdown_are_reported_to_metrics() {
+        let clock = MockClock::default();
+        let metrics = Arc::new(RecordingKeepAliveMetrics::default());
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .keep_alive_metrics(metrics.clone())
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                KeepAliveConnectionHandler { keep_alive: true },
+            )
+            .with_clock(clock.clone());
+
+        for keep_alive in [true, true, false, false, true, false] {
+            connection.handler.keep_alive = keep_alive;
+            assert!(connection.poll_noop_waker().is_pending());
+        }
+        assert_eq!(
+            *metrics.keep_alive.lock().unwrap(),
+            [true, false, true, false]
+        );
+        assert!(metrics.shutdowns.lock().unwrap().is_empty());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Err(ConnectionError::KeepAliveTimeout { .. }))
+        ));
+        let _ = connection.close();
+
+        assert_eq!(
+            *metrics.shutdowns.lock().unwrap(),
+            [ShutdownReason::KeepAliveTimeout],
+            "closing after an error is not reported as graceful shutdown"
+        );
+    }
+
+    #[test]
+    fn graceful_keep_alive_timeout_requests_close_instead_of_error() {
+        let metrics = Arc::new(RecordingKeepAliveMetrics::default());
+        let mut connection = builder()
+            .idle_timeout(Duration::ZERO)
+            .graceful_keep_alive_timeout(true)
+            .keep_alive_metrics(metrics.clone())
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                KeepAliveConnectionHandler { keep_alive: false },
+            );
+
+        assert!(matches!(
+            connection.poll_noop_waker(),
+            Poll::Ready(Ok(Event::CloseRequested { reason })) if reason.contains("keep-alive")
+        ));
+        let _ = connection.close();
+
+        assert_eq!(
+            *metrics.shutdowns.lock().unwrap(),
+            [ShutdownReason::KeepAliveTimeout]
+        );
+    }
+
+    #[test]
+    fn closing_connection_is_reported_as_graceful_shutdown() {
+        let metrics = Arc::new(RecordingKeepAliveMetrics::default());
+        let connection = builder().keep_alive_metrics(metrics.clone()).build(
+            StreamMuxerBox::new(PendingStreamMuxer),
+            KeepAliveConnectionHandler { keep_alive: true },
+        );
+
+        let _ = connection.close();
+
+        assert_eq!(
+            *metrics.shutdowns.lock().unwrap(),
+            [ShutdownReason::Graceful]
+        );
+    }
+
+    #[test]
+    fn failing_idle_probe_closes_kept_alive_connection() {
+        let clock = MockClock::default();
+        let probes = Arc::new(AtomicUsize::new(0));
+        let probe = IdleProbe::new(Duration::from_secs(5), {
+            let probes = probes.clone();
+            move || {
+                let probe = probes.fetch_add(1, Ordering::SeqCst);
+                future::ready(if probe == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::from(io::ErrorKind::TimedOut))
+                })
+            }
+        });
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .idle_probe(probe)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                KeepAliveConnectionHandler { keep_alive: true },
+            )
+            .with_clock(clock.clone());
+
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(
+            probes.load(Ordering::SeqCst),
+            0,
+            "not probed before the interval"
+        );
+
+        clock.advance(Duration::from_secs(5));
+        assert!(connection.poll_noop_waker().is_pending());
+        assert_eq!(probes.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(5));
+        let error = match connection.poll_noop_waker() {
+            Poll::Ready(Err(ConnectionError::IO(error))) => error,
+            other => panic!("unexpected poll result: {other:?}"),
+        };
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(probes.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn connection_stream_terminates_after_error() {
+        let mut connection = builder()
+            .max_negotiating_inbound_streams(0)
+            .idle_timeout(Duration::ZERO)
+            .build(
+                StreamMuxerBox::new(PendingStreamMuxer),
+                dummy::ConnectionHandler,
+            );
+        let peer_id = connection.remote_peer_id();
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        let Poll::Ready(Some(Err(error))) = connection.poll_next_unpin(&mut cx) else {
+            panic!("Expected the connection to fail");
+        };
+        assert!(matches!(
+            error.error(),
+            ConnectionError::KeepAliveTimeout { .. }
+        ));
+        assert_eq!(error.peer_id(), peer_id);
+        assert!(stream::FusedStream::is_terminated(&connection));
+        assert!(matches!(
+            connection.poll_next_unpin(&mut cx),
+            Poll::Ready(None)
+        ));
+    }
+
+    /// A [`Clock`] which only advances when told to.
+    ///
+    /// Its timers complete once the clock was advanced past them.
+    #[derive(Debug, Clone)]
+    struct MockClock {
+        now: Arc<Mutex<Instant>>,
+        /// The wakers of the timers that did not complete yet.
+        timers: Arc<Mutex<Vec<Waker>>>,
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self {
+                now: Arc::new(Mutex::new(Instant::now())),
+                timers: Default::default(),
+            }
+        }
+    }
+
+    impl MockClock {
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+            for waker in self.timers.lock().unwrap().drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            let clock = self.clone();
+            let deadline = self.now() + duration;
+
+            future::poll_fn(move |cx| {
+                if clock.now() >= deadline {
+                    return Poll::Ready(());
+                }
+                clock.timers.lock().unwrap().push(cx.waker().clone());
+                Poll::Pending
+            })
+            .boxed()
+        }
+    }
+
+    struct DummyStreamMuxer {
+        counter: Arc<()>,
+    }
+
+    impl StreamMuxer for DummyStreamMuxer {
+        type Substream = PendingSubstream;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Ready(Ok(PendingSubstream {
+                _weak: Arc::downgrade(&self.counter),
+            }))
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    /// A [`StreamMuxer`] that immediately grants inbound and outbound substreams which never
+    /// make progress.
+    struct ReadyStreamMuxer {
+        counter: Arc<()>,
+    }
+
+    impl StreamMuxer for ReadyStreamMuxer {
+        type Substream = PendingSubstream;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Ready(Ok(PendingSubstream {
+                _weak: Arc::downgrade(&self.counter),
+            }))
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Ready(Ok(PendingSubstream {
+                _weak: Arc::downgrade(&self.counter),
+            }))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    /// A [`StreamMuxer`] that grants as many outbound substreams as allowed by `grants`.
+    struct GrantingStreamMuxer {
+        grants: Arc<AtomicUsize>,
+        counter: Arc<()>,
+    }
+
+    impl StreamMuxer for GrantingStreamMuxer {
+        type Substream = PendingSubstream;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            if self
+                .grants
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |g| g.checked_sub(1))
+                .is_err()
+            {
+                return Poll::Pending;
+            }
+
+            Poll::Ready(Ok(PendingSubstream {
+                _weak: Arc::downgrade(&self.counter),
+            }))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    /// A [`StreamMuxer`] which counts the calls to [`StreamMuxer::poll`].
+    struct PollCountingStreamMuxer<M> {
+        inner: M,
+        polls: Arc<AtomicUsize>,
+    }
+
+    impl<M> StreamMuxer for PollCountingStreamMuxer<M>
+    where
+        M: StreamMuxer + Unpin,
+    {
+        type Substream = M::Substream;
+        type Error = M::Error;
+
+        fn poll_inbound(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Pin::new(&mut self.inner).poll_inbound(cx)
+        }
+
+        fn poll_outbound(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Pin::new(&mut self.inner).poll_outbound(cx)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.inner).poll_close(cx)
+        }
+
+        fn poll(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            self.polls.fetch_add(1, Ordering::SeqCst);
+            Pin::new(&mut self.inner).poll(cx)
+        }
+    }
+
+    /// A [`StreamMuxer`] which hands out a limited number of streams in either direction and
+    /// records the direction of every granted stream.
+    #[derive(Default)]
+    struct BudgetStreamMuxer {
+        budget: Arc<AtomicUsize>,
+        granted: Arc<Mutex<Vec<SubstreamDirection>>>,
+        counter: Arc<()>,
+    }
+
+    impl BudgetStreamMuxer {
+        fn grant(
+            &self,
+            direction: SubstreamDirection,
+        ) -> Poll<Result<PendingSubstream, Infallible>> {
+            if self
+                .budget
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |b| b.checked_sub(1))
+                .is_err()
+            {
+                return Poll::Pending;
+            }
+
+            self.granted.lock().unwrap().push(direction);
+
+            Poll::Ready(Ok(PendingSubstream {
+                _weak: Arc::downgrade(&self.counter),
+            }))
+        }
+    }
+
+    impl StreamMuxer for BudgetStreamMuxer {
+        type Substream = PendingSubstream;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            self.grant(SubstreamDirection::Inbound)
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            self.grant(SubstreamDirection::Outbound)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    /// A [`StreamMuxer`] which reports the given address changes and never returns a stream.
+    struct AddressChangeStreamMuxer {
+        addresses: VecDeque<Multiaddr>,
+    }
+
+    impl StreamMuxer for AddressChangeStreamMuxer {
+        type Substream = PendingSubstream;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            match self.addresses.pop_front() {
+                Some(address) => Poll::Ready(Ok(StreamMuxerEvent::AddressChange(address))),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    /// A [`StreamMuxer`] which reports the given number of half-closed streams.
+    struct HalfCloseStreamMuxer {
+        half_closed: usize,
+    }
+
+    impl StreamMuxer for HalfCloseStreamMuxer {
+        type Substream = PendingSubstream;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            if self.half_closed == 0 {
+                return Poll::Pending;
+            }
+
+            self.half_closed -= 1;
+            Poll::Ready(Ok(StreamMuxerEvent::StreamHalfClosed))
+        }
+    }
+
+    /// A [`StreamMuxer`] which returns a single inbound stream.
+    struct OnceInboundStreamMuxer {
+        substream: Option<Channel<Vec<u8>>>,
+    }
+
+    impl StreamMuxer for OnceInboundStreamMuxer {
+        type Substream = Channel<Vec<u8>>;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            match self.substream.take() {
+                Some(substream) => Poll::Ready(Ok(substream)),
+                None => Poll::Pending,
+            }
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    /// A [`StreamMuxer`] which opens a single outbound stream.
+    struct OnceOutboundStreamMuxer {
+        substream: Option<Channel<Vec<u8>>>,
+    }
+
+    impl StreamMuxer for OnceOutboundStreamMuxer {
+        type Substream = Channel<Vec<u8>>;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_outbound(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            match self.substream.take() {
+                Some(substream) => Poll::Ready(Ok(substream)),
+                None => Poll::Pending,
+            }
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    /// A [`StreamMuxer`] that yields the given inbound substreams, one at a time.
+    struct InboundStreamsMuxer {
+        substreams: VecDeque<Channel<Vec<u8>>>,
+    }
+
+    impl StreamMuxer for InboundStreamsMuxer {
+        type Substream = Channel<Vec<u8>>;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            match self.substreams.pop_front() {
+                Some(substream) => Poll::Ready(Ok(substream)),
+                None => Poll::Pending,
+            }
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    /// Returns both ends of an in-memory stream.
+    fn memory_stream_pair() -> (Channel<Vec<u8>>, Channel<Vec<u8>>) {
+        futures::executor::block_on(async {
+            let mut transport = MemoryTransport::default();
+            let address = Multiaddr::empty().with(Protocol::Memory(rand::random::<u64>()));
+            transport
+                .listen_on(ListenerId::next(), address.clone())
+                .unwrap();
+            let dial = transport
+                .dial(
+                    address,
+                    DialOpts {
+                        role: Endpoint::Dialer,
+                        port_use: PortUse::Reuse,
+                    },
+                )
+                .unwrap();
+
+            let incoming = future::poll_fn(|cx| loop {
+                match Pin::new(&mut transport).poll(cx) {
+                    Poll::Ready(TransportEvent::Incoming { upgrade, .. }) => {
+                        return Poll::Ready(upgrade)
+                    }
+                    Poll::Ready(_) => continue,
+                    Poll::Pending => return Poll::Pending,
+                }
+            });
+            let (dialer, upgrade) = future::join(dial, incoming).await;
+
+            (upgrade.await.unwrap(), dialer.unwrap())
+        })
+    }
+
+    /// Information about a dialed connection to a random peer.
+    /// Returns a [`ConnectionBuilder`] for a connection dialed to a random peer, to build every
+    /// connection under test from.
+    fn builder() -> ConnectionBuilder {
+        ConnectionBuilder::new(PeerId::random()).endpoint(ConnectedPoint::Dialer {
+            address: Multiaddr::empty(),
+            role_override: Endpoint::Dialer,
+            port_use: PortUse::Reuse,
+        })
+    }
+
+    /// A [`StreamMuxer`] which never returns a stream.
+    struct PendingStreamMuxer;
+
+    impl StreamMuxer for PendingStreamMuxer {
+        type Substream = PendingSubstream;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    /// A [`StreamMuxer`] which never returns a stream and fails to close.
+    /// A [`StreamMuxer`] that fails as soon as it is polled.
+    struct FailingStreamMuxer;
+
+    impl StreamMuxer for FailingStreamMuxer {
+        type Substream = PendingSubstream;
+        type Error = io::Error;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    struct FailingCloseStreamMuxer;
+
+    impl StreamMuxer for FailingCloseStreamMuxer {
+        type Substream = PendingSubstream;
+        type Error = io::Error;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    /// A [`StreamMuxer`] that grants outbound streams which are already closed by the remote.
+    /// Fails to open the first `errors` outbound streams with a transient error.
+    struct FlakyOutboundStreamMuxer {
+        errors: usize,
+    }
+
+    impl StreamMuxer for FlakyOutboundStreamMuxer {
+        type Substream = PendingSubstream;
+        type Error = io::Error;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            let this = self.get_mut();
+            if this.errors > 0 {
+                this.errors -= 1;
+                return Poll::Ready(Err(io::ErrorKind::WouldBlock.into()));
+            }
+
+            Poll::Ready(Ok(PendingSubstream { _weak: Weak::new() }))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    /// Opens outbound streams that fail with the given error on write.
+    struct FailingOutboundStreamMuxer(io::ErrorKind);
+
+    impl StreamMuxer for FailingOutboundStreamMuxer {
+        type Substream = FailingSubstream;
+        type Error = Infallible;
+
+        fn poll_inbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_outbound(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Self::Substream, Self::Error>> {
+            Poll::Ready(Ok(FailingSubstream(self.0)))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    struct FailingSubstream(io::ErrorKind);
+
+    impl AsyncRead for FailingSubstream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(0))
+        }
+    }
+
+    impl AsyncWrite for FailingSubstream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Err(self.0.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(self.0.into()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    struct PendingSubstream {
+        _weak: Weak<()>,
+    }
+
+    impl AsyncRead for PendingSubstream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for PendingSubstream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    struct MockConnectionHandler {
+        outbound_requested: bool,
+        error: Option<StreamUpgradeError<Infallible>>,
+        upgrade_timeout: Duration,
+        outbound_stalls: usize,
+        outbound_capacity: Vec<bool>,
+        /// Whether the connection entered (`true`) or exited (`false`) idle, in order.
+        idle_changes: Vec<bool>,
+    }
+
+    impl MockConnectionHandler {
+        fn new(upgrade_timeout: Duration) -> Self {
+            Self {
+                outbound_requested: false,
+                error: None,
+                upgrade_timeout,
+                outbound_stalls: 0,
+                outbound_capacity: Vec::new(),
+                idle_changes: Vec::new(),
             }
         }
 
-        impl Arbitrary for ArbitraryShutdown {
-            fn arbitrary(g: &mut Gen) -> Self {
-                let shutdown = match g.gen_range(1u8..4) {
-                    1 => Shutdown::None,
-                    2 => Shutdown::Asap,
-                    3 => Shutdown::Later(Delay::new(Duration::from_secs(u32::arbitrary(g) as u64))),
-                    _ => unreachable!(),
-                };
+        fn open_new_outbound(&mut self) {
+            self.outbound_requested = true;
+        }
+    }
+
+    #[derive(Default)]
+    struct ConfigurableProtocolConnectionHandler {
+        events: Vec<ConnectionHandlerEvent<DeniedUpgrade, (), Infallible>>,
+        active_protocols: HashSet<StreamProtocol>,
+        local_added: Vec<Vec<StreamProtocol>>,
+        local_removed: Vec<Vec<StreamProtocol>>,
+        remote_added: Vec<Vec<StreamProtocol>>,
+        remote_removed: Vec<Vec<StreamProtocol>>,
+        fully_negotiated_inbound: usize,
+        negotiated_protocols: Vec<StreamProtocol>,
+        protocols_generation: Option<u64>,
+        streams_half_closed: usize,
+        ignores_protocols_change: bool,
+        interested_events: Option<ConnectionEventMask>,
+    }
+
+    impl ConfigurableProtocolConnectionHandler {
+        fn listen_on(&mut self, protocols: &[&'static str]) {
+            self.active_protocols = protocols.iter().copied().map(StreamProtocol::new).collect();
+        }
+
+        fn remote_adds_support_for(&mut self, protocols: &[&'static str]) {
+            self.events
+                .push(ConnectionHandlerEvent::ReportRemoteProtocols(
+                    ProtocolSupport::Added(
+                        protocols.iter().copied().map(StreamProtocol::new).collect(),
+                    ),
+                ));
+        }
+
+        fn report_metrics(&mut self, metrics: MetricsDelta) {
+            self.events
+                .push(ConnectionHandlerEvent::ReportMetrics(metrics));
+        }
 
-                Self(shutdown)
+        fn close_gracefully(&mut self, reason: &str) {
+            self.events.push(ConnectionHandlerEvent::CloseGracefully {
+                reason: reason.to_owned(),
+            });
+        }
+
+        fn remote_removes_support_for(&mut self, protocols: &[&'static str]) {
+            self.events
+                .push(ConnectionHandlerEvent::ReportRemoteProtocols(
+                    ProtocolSupport::Removed(
+                        protocols.iter().copied().map(StreamProtocol::new).collect(),
+                    ),
+                ));
+        }
+    }
+
+    impl ConnectionHandler for MockConnectionHandler {
+        type FromBehaviour = Infallible;
+        type ToBehaviour = Infallible;
+        type InboundProtocol = PendingUpgrade<StreamProtocol>;
+        type OutboundProtocol = ReadyUpgrade<StreamProtocol>;
+        type InboundOpenInfo = ();
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+            SubstreamProtocol::new(PendingUpgrade::new(StreamProtocol::new("/mock")), ())
+                .with_timeout(self.upgrade_timeout)
+        }
+
+        fn on_connection_event(
+            &mut self,
+            event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+        ) {
+            match event {
+                // TODO: remove when Rust 1.82 is MSRV
+                #[allow(unreachable_patterns)]
+                ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                    protocol,
+                    ..
+                }) => libp2p_core::util::unreachable(protocol),
+                ConnectionEvent::DialUpgradeError(DialUpgradeError { error, .. }) => {
+                    self.error = Some(error)
+                }
+                ConnectionEvent::OutboundStreamsStalled => self.outbound_stalls += 1,
+                ConnectionEvent::OutboundCapacity { available } => {
+                    self.outbound_capacity.push(available)
+                }
+                ConnectionEvent::EnteredIdle => self.idle_changes.push(true),
+                ConnectionEvent::ExitedIdle => self.idle_changes.push(false),
+                // TODO: remove when Rust 1.82 is MSRV
+                #[allow(unreachable_patterns)]
+                ConnectionEvent::FullyNegotiatedOutbound(_)
+                | ConnectionEvent::AddressChange(_)
+                | ConnectionEvent::ListenUpgradeError(_)
+                | ConnectionEvent::LocalProtocolsChange(_)
+                | ConnectionEvent::RemoteProtocolsChange(_)
+                | ConnectionEvent::StreamHalfClosed => {}
+            }
+        }
+
+        fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+            // TODO: remove when Rust 1.82 is MSRV
+            #[allow(unreachable_patterns)]
+            libp2p_core::util::unreachable(event)
+        }
+
+        fn connection_keep_alive(&self) -> bool {
+            true
+        }
+
+        fn poll(
+            &mut self,
+            _: &mut Context<'_>,
+        ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+            if self.outbound_requested {
+                self.outbound_requested = false;
+                return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                    protocol: SubstreamProtocol::new(
+                        ReadyUpgrade::new(StreamProtocol::new("/mock")),
+                        (),
+                    )
+                    .with_timeout(self.upgrade_timeout),
+                });
             }
+
+            Poll::Pending
+        }
+    }
+
+    /// A [`ConnectionHandler`] that is always ready to report (empty) remote protocol changes.
+    #[derive(Default)]
+    struct AlwaysReadyConnectionHandler {
+        polls: usize,
+    }
+
+    impl ConnectionHandler for AlwaysReadyConnectionHandler {
+        type FromBehaviour = Infallible;
+        type ToBehaviour = Infallible;
+        type InboundProtocol = DeniedUpgrade;
+        type OutboundProtocol = DeniedUpgrade;
+        type InboundOpenInfo = ();
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+            SubstreamProtocol::new(DeniedUpgrade, ())
         }
 
-        fn prop(
-            handler_keep_alive: bool,
-            current_shutdown: ArbitraryShutdown,
-            idle_timeout: Duration,
+        fn on_connection_event(
+            &mut self,
+            _: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
         ) {
-            compute_new_shutdown(handler_keep_alive, &current_shutdown.0, idle_timeout);
         }
 
-        QuickCheck::new().quickcheck(prop as fn(_, _, _));
+        fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+            // TODO: remove when Rust 1.82 is MSRV
+            #[allow(unreachable_patterns)]
+            libp2p_core::util::unreachable(event)
+        }
+
+        fn connection_keep_alive(&self) -> bool {
+            true
+        }
+
+        fn poll(
+            &mut self,
+            _: &mut Context<'_>,
+        ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+            self.polls += 1;
+
+            Poll::Ready(ConnectionHandlerEvent::ReportRemoteProtocols(
+                ProtocolSupport::Added(HashSet::new()),
+            ))
+        }
     }
 
-    struct DummyStreamMuxer {
-        counter: Arc<()>,
+    /// Counts how often the [`Waker`] it backs is woken.
+    #[derive(Default)]
+    struct WakeCounter(AtomicUsize);
+
+    impl futures::task::ArcWake for WakeCounter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
     }
 
-    impl StreamMuxer for DummyStreamMuxer {
-        type Substream = PendingSubstream;
-        type Error = Infallible;
+    #[derive(Debug, Default)]
+    struct RecordingKeepAliveMetrics {
+        keep_alive: Mutex<Vec<bool>>,
+        shutdowns: Mutex<Vec<ShutdownReason>>,
+    }
 
-        fn poll_inbound(
-            self: Pin<&mut Self>,
+    impl KeepAliveMetrics for RecordingKeepAliveMetrics {
+        fn on_keep_alive(&self, keep_alive: bool) {
+            self.keep_alive.lock().unwrap().push(keep_alive);
+        }
+
+        fn on_shutdown(&self, reason: ShutdownReason) {
+            self.shutdowns.lock().unwrap().push(reason);
+        }
+    }
+
+    /// A [`ConnectionHandler`] that requests outbound substreams with the given priorities.
+    struct PriorityConnectionHandler {
+        requests: VecDeque<(OutboundPriority, &'static str, usize)>,
+    }
+
+    impl ConnectionHandler for PriorityConnectionHandler {
+        type FromBehaviour = Infallible;
+        type ToBehaviour = Infallible;
+        type InboundProtocol = DeniedUpgrade;
+        type OutboundProtocol = ReadyUpgrade<StreamProtocol>;
+        type InboundOpenInfo = ();
+        type OutboundOpenInfo = usize;
+
+        fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+            SubstreamProtocol::new(DeniedUpgrade, ())
+        }
+
+        fn on_connection_event(
+            &mut self,
+            _: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol, (), usize>,
+        ) {
+        }
+
+        fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+            // TODO: remove when Rust 1.82 is MSRV
+            #[allow(unreachable_patterns)]
+            libp2p_core::util::unreachable(event)
+        }
+
+        fn connection_keep_alive(&self) -> bool {
+            true
+        }
+
+        fn poll(
+            &mut self,
             _: &mut Context<'_>,
-        ) -> Poll<Result<Self::Substream, Self::Error>> {
-            Poll::Ready(Ok(PendingSubstream {
-                _weak: Arc::downgrade(&self.counter),
-            }))
+        ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, usize, Self::ToBehaviour>>
+        {
+            match self.requests.pop_front() {
+                Some((priority, protocol, id)) => {
+                    Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                        protocol: SubstreamProtocol::new(
+                            ReadyUpgrade::new(StreamProtocol::new(protocol)),
+                            id,
+                        )
+                        .with_priority(priority),
+                    })
+                }
+                None => Poll::Pending,
+            }
         }
+    }
 
-        fn poll_outbound(
-            self: Pin<&mut Self>,
+    /// A [`ConnectionHandler`] that requests a batch of outbound streams once.
+    struct BatchRequestingConnectionHandler {
+        batch: Option<Vec<usize>>,
+    }
+
+    impl ConnectionHandler for BatchRequestingConnectionHandler {
+        type FromBehaviour = Infallible;
+        type ToBehaviour = Infallible;
+        type InboundProtocol = PendingUpgrade<StreamProtocol>;
+        type OutboundProtocol = ReadyUpgrade<StreamProtocol>;
+        type InboundOpenInfo = ();
+        type OutboundOpenInfo = usize;
+
+        fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+            SubstreamProtocol::new(PendingUpgrade::new(StreamProtocol::new("/batch")), ())
+        }
+
+        fn on_connection_event(
+            &mut self,
+            _: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol, (), usize>,
+        ) {
+        }
+
+        fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+            // TODO: remove when Rust 1.82 is MSRV
+            #[allow(unreachable_patterns)]
+            libp2p_core::util::unreachable(event)
+        }
+
+        fn connection_keep_alive(&self) -> bool {
+            true
+        }
+
+        fn poll(
+            &mut self,
             _: &mut Context<'_>,
-        ) -> Poll<Result<Self::Substream, Self::Error>> {
-            Poll::Pending
+        ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, usize, Self::ToBehaviour>>
+        {
+            match self.batch.take() {
+                Some(batch) => Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequestBatch {
+                    protocols: batch
+                        .into_iter()
+                        .map(|id| {
+                            SubstreamProtocol::new(
+                                ReadyUpgrade::new(StreamProtocol::new("/batch")),
+                                id,
+                            )
+                        })
+                        .collect(),
+                }),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    /// A [`ConnectionHandler`] that panics when polled.
+    #[cfg(feature = "catch-handler-panics")]
+    struct PanickingConnectionHandler;
+
+    #[cfg(feature = "catch-handler-panics")]
+    impl ConnectionHandler for PanickingConnectionHandler {
+        type FromBehaviour = Infallible;
+        type ToBehaviour = Infallible;
+        type InboundProtocol = DeniedUpgrade;
+        type OutboundProtocol = DeniedUpgrade;
+        type InboundOpenInfo = ();
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+            SubstreamProtocol::new(DeniedUpgrade, ())
+        }
+
+        fn on_connection_event(
+            &mut self,
+            _: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+        ) {
         }
 
-        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-            Poll::Ready(Ok(()))
+        fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+            // TODO: remove when Rust 1.82 is MSRV
+            #[allow(unreachable_patterns)]
+            libp2p_core::util::unreachable(event)
         }
 
         fn poll(
-            self: Pin<&mut Self>,
+            &mut self,
             _: &mut Context<'_>,
-        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
-            Poll::Pending
+        ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+            panic!("handler bug")
         }
     }
 
-    /// A [`StreamMuxer`] which never returns a stream.
-    struct PendingStreamMuxer;
+    /// A [`ConnectionHandler`] whose keep-alive decision is set by the test.
+    struct KeepAliveConnectionHandler {
+        keep_alive: bool,
+    }
 
-    impl StreamMuxer for PendingStreamMuxer {
-        type Substream = PendingSubstream;
-        type Error = Infallible;
+    impl ConnectionHandler for KeepAliveConnectionHandler {
+        type FromBehaviour = Infallible;
+        type ToBehaviour = Infallible;
+        type InboundProtocol = DeniedUpgrade;
+        type OutboundProtocol = DeniedUpgrade;
+        type InboundOpenInfo = ();
+        type OutboundOpenInfo = ();
 
-        fn poll_inbound(
-            self: Pin<&mut Self>,
-            _: &mut Context<'_>,
-        ) -> Poll<Result<Self::Substream, Self::Error>> {
-            Poll::Pending
+        fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+            SubstreamProtocol::new(DeniedUpgrade, ())
         }
 
-        fn poll_outbound(
-            self: Pin<&mut Self>,
-            _: &mut Context<'_>,
-        ) -> Poll<Result<Self::Substream, Self::Error>> {
-            Poll::Pending
+        fn on_connection_event(
+            &mut self,
+            _: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+        ) {
         }
 
-        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-            Poll::Pending
+        fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+            // TODO: remove when Rust 1.82 is MSRV
+            #[allow(unreachable_patterns)]
+            libp2p_core::util::unreachable(event)
+        }
+
+        fn connection_keep_alive(&self) -> bool {
+            self.keep_alive
         }
 
         fn poll(
-            self: Pin<&mut Self>,
+            &mut self,
             _: &mut Context<'_>,
-        ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
             Poll::Pending
         }
     }
 
-    struct PendingSubstream {
-        _weak: Weak<()>,
+    /// A [`ConnectionHandler`] that only overrides [`ConnectionHandler::on_address_change`].
+    #[derive(Default)]
+    struct AddressRecordingConnectionHandler {
+        addresses: Vec<Multiaddr>,
     }
 
-    impl AsyncRead for PendingSubstream {
-        fn poll_read(
-            self: Pin<&mut Self>,
-            _cx: &mut Context<'_>,
-            _buf: &mut [u8],
-        ) -> Poll<std::io::Result<usize>> {
-            Poll::Pending
+    impl ConnectionHandler for AddressRecordingConnectionHandler {
+        type FromBehaviour = Infallible;
+        type ToBehaviour = Infallible;
+        type InboundProtocol = DeniedUpgrade;
+        type OutboundProtocol = DeniedUpgrade;
+        type InboundOpenInfo = ();
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+            SubstreamProtocol::new(DeniedUpgrade, ())
         }
-    }
 
-    impl AsyncWrite for PendingSubstream {
-        fn poll_write(
-            self: Pin<&mut Self>,
-            _cx: &mut Context<'_>,
-            _buf: &[u8],
-        ) -> Poll<std::io::Result<usize>> {
-            Poll::Pending
+        fn on_connection_event(
+            &mut self,
+            _: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+        ) {
         }
 
-        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-            Poll::Pending
+        fn on_address_change(&mut self, new_address: &Multiaddr) {
+            self.addresses.push(new_address.clone());
         }
 
-        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+            // TODO: remove when Rust 1.82 is MSRV
+            #[allow(unreachable_patterns)]
+            libp2p_core::util::unreachable(event)
+        }
+
+        fn poll(
+            &mut self,
+            _: &mut Context<'_>,
+        ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
             Poll::Pending
         }
     }
 
-    struct MockConnectionHandler {
-        outbound_requested: bool,
-        error: Option<StreamUpgradeError<Infallible>>,
-        upgrade_timeout: Duration,
+    /// A [`ConnectionHandler`] that collects the inbound streams of the `/foo` protocol.
+    #[derive(Default)]
+    struct StreamCollectingConnectionHandler {
+        streams: Vec<Stream>,
     }
 
-    impl MockConnectionHandler {
-        fn new(upgrade_timeout: Duration) -> Self {
-            Self {
-                outbound_requested: false,
-                error: None,
-                upgrade_timeout,
-            }
-        }
+    impl ConnectionHandler for StreamCollectingConnectionHandler {
+        type FromBehaviour = Infallible;
+        type ToBehaviour = Infallible;
+        type InboundProtocol = ReadyUpgrade<StreamProtocol>;
+        type OutboundProtocol = DeniedUpgrade;
+        type InboundOpenInfo = ();
+        type OutboundOpenInfo = ();
 
-        fn open_new_outbound(&mut self) {
-            self.outbound_requested = true;
+        fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+            SubstreamProtocol::new(ReadyUpgrade::new(StreamProtocol::new("/foo")), ())
         }
-    }
 
-    #[derive(Default)]
-    struct ConfigurableProtocolConnectionHandler {
-        events: Vec<ConnectionHandlerEvent<DeniedUpgrade, (), Infallible>>,
-        active_protocols: HashSet<StreamProtocol>,
-        local_added: Vec<Vec<StreamProtocol>>,
-        local_removed: Vec<Vec<StreamProtocol>>,
-        remote_added: Vec<Vec<StreamProtocol>>,
-        remote_removed: Vec<Vec<StreamProtocol>>,
-    }
+        fn on_connection_event(
+            &mut self,
+            event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+        ) {
+            if let ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol,
+                ..
+            }) = event
+            {
+                self.streams.push(protocol);
+            }
+        }
 
-    impl ConfigurableProtocolConnectionHandler {
-        fn listen_on(&mut self, protocols: &[&'static str]) {
-            self.active_protocols = protocols.iter().copied().map(StreamProtocol::new).collect();
+        fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+            // TODO: remove when Rust 1.82 is MSRV
+            #[allow(unreachable_patterns)]
+            libp2p_core::util::unreachable(event)
         }
 
-        fn remote_adds_support_for(&mut self, protocols: &[&'static str]) {
-            self.events
-                .push(ConnectionHandlerEvent::ReportRemoteProtocols(
-                    ProtocolSupport::Added(
-                        protocols.iter().copied().map(StreamProtocol::new).collect(),
-                    ),
-                ));
+        fn connection_keep_alive(&self) -> bool {
+            true
         }
 
-        fn remote_removes_support_for(&mut self, protocols: &[&'static str]) {
-            self.events
-                .push(ConnectionHandlerEvent::ReportRemoteProtocols(
-                    ProtocolSupport::Removed(
-                        protocols.iter().copied().map(StreamProtocol::new).collect(),
-                    ),
-                ));
+        fn poll(
+            &mut self,
+            _: &mut Context<'_>,
+        ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+            Poll::Pending
         }
     }
 
-    impl ConnectionHandler for MockConnectionHandler {
+    /// A [`ConnectionHandler`] that only collects the inbound streams injected into the
+    /// connection.
+    #[derive(Default)]
+    struct InjectedStreamConnectionHandler {
+        streams: Vec<Channel<Vec<u8>>>,
+    }
+
+    impl ConnectionHandler for InjectedStreamConnectionHandler {
         type FromBehaviour = Infallible;
         type ToBehaviour = Infallible;
-        type InboundProtocol = DeniedUpgrade;
+        type InboundProtocol = InjectedStreamUpgrade;
         type OutboundProtocol = DeniedUpgrade;
         type InboundOpenInfo = ();
         type OutboundOpenInfo = ();
 
         fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
-            SubstreamProtocol::new(DeniedUpgrade, ()).with_timeout(self.upgrade_timeout)
+            SubstreamProtocol::new(InjectedStreamUpgrade, ())
         }
 
         fn on_connection_event(
             &mut self,
             event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
         ) {
-            match event {
-                // TODO: remove when Rust 1.82 is MSRV
-                #[allow(unreachable_patterns)]
-                ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
-                    protocol,
-                    ..
-                }) => libp2p_core::util::unreachable(protocol),
-                // TODO: remove when Rust 1.82 is MSRV
-                #[allow(unreachable_patterns)]
-                ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
-                    protocol,
-                    ..
-                }) => libp2p_core::util::unreachable(protocol),
-                ConnectionEvent::DialUpgradeError(DialUpgradeError { error, .. }) => {
-                    self.error = Some(error)
-                }
-                // TODO: remove when Rust 1.82 is MSRV
-                #[allow(unreachable_patterns)]
-                ConnectionEvent::AddressChange(_)
-                | ConnectionEvent::ListenUpgradeError(_)
-                | ConnectionEvent::LocalProtocolsChange(_)
-                | ConnectionEvent::RemoteProtocolsChange(_) => {}
+            if let ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol,
+                ..
+            }) = event
+            {
+                self.streams.push(protocol);
             }
         }
 
@@ -1247,18 +6968,32 @@ mod tests {
             &mut self,
             _: &mut Context<'_>,
         ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
-            if self.outbound_requested {
-                self.outbound_requested = false;
-                return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
-                    protocol: SubstreamProtocol::new(DeniedUpgrade, ())
-                        .with_timeout(self.upgrade_timeout),
-                });
-            }
-
             Poll::Pending
         }
     }
 
+    /// An upgrade that supports no protocols, its streams can only be injected.
+    struct InjectedStreamUpgrade;
+
+    impl UpgradeInfo for InjectedStreamUpgrade {
+        type Info = StreamProtocol;
+        type InfoIter = std::iter::Empty<Self::Info>;
+
+        fn protocol_info(&self) -> Self::InfoIter {
+            std::iter::empty()
+        }
+    }
+
+    impl<C> InboundUpgrade<C> for InjectedStreamUpgrade {
+        type Output = Channel<Vec<u8>>;
+        type Error = Infallible;
+        type Future = future::Pending<Result<Self::Output, Self::Error>>;
+
+        fn upgrade_inbound(self, _: C, _: Self::Info) -> Self::Future {
+            future::pending()
+        }
+    }
+
     impl ConnectionHandler for ConfigurableProtocolConnectionHandler {
         type FromBehaviour = Infallible;
         type ToBehaviour = Infallible;
@@ -1293,6 +7028,14 @@ mod tests {
                 ConnectionEvent::RemoteProtocolsChange(ProtocolsChange::Removed(removed)) => {
                     self.remote_removed.push(removed.cloned().collect())
                 }
+                ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                    negotiated_protocol,
+                    ..
+                }) => {
+                    self.fully_negotiated_inbound += 1;
+                    self.negotiated_protocols.push(negotiated_protocol);
+                }
+                ConnectionEvent::StreamHalfClosed => self.streams_half_closed += 1,
                 _ => {}
             }
         }
@@ -1307,6 +7050,18 @@ mod tests {
             true
         }
 
+        fn wants_protocols_change(&self) -> bool {
+            !self.ignores_protocols_change
+        }
+
+        fn protocols_generation(&self) -> Option<u64> {
+            self.protocols_generation
+        }
+
+        fn interested_events(&self) -> ConnectionEventMask {
+            self.interested_events.unwrap_or(ConnectionEventMask::ALL)
+        }
+
         fn poll(
             &mut self,
             _: &mut Context<'_>,
@@ -1319,6 +7074,68 @@ mod tests {
         }
     }
 
+    /// Opens one outbound stream offering the given protocols and records the one selected.
+    struct OutboundProtocolsConnectionHandler {
+        protocols: Vec<StreamProtocol>,
+        requested: bool,
+        negotiated_protocols: Vec<StreamProtocol>,
+    }
+
+    impl ConnectionHandler for OutboundProtocolsConnectionHandler {
+        type FromBehaviour = Infallible;
+        type ToBehaviour = Infallible;
+        type InboundProtocol = DeniedUpgrade;
+        type OutboundProtocol = ManyProtocolsUpgrade;
+        type InboundOpenInfo = ();
+        type OutboundOpenInfo = ();
+
+        fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+            SubstreamProtocol::new(DeniedUpgrade, ())
+        }
+
+        fn on_connection_event(
+            &mut self,
+            event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+        ) {
+            if let ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                negotiated_protocol,
+                ..
+            }) = event
+            {
+                self.negotiated_protocols.push(negotiated_protocol);
+            }
+        }
+
+        fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+            // TODO: remove when Rust 1.82 is MSRV
+            #[allow(unreachable_patterns)]
+            libp2p_core::util::unreachable(event)
+        }
+
+        fn connection_keep_alive(&self) -> bool {
+            true
+        }
+
+        fn poll(
+            &mut self,
+            _: &mut Context<'_>,
+        ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+            if self.requested {
+                return Poll::Pending;
+            }
+            self.requested = true;
+
+            Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(
+                    ManyProtocolsUpgrade {
+                        protocols: self.protocols.clone(),
+                    },
+                    (),
+                ),
+            })
+        }
+    }
+
     struct ManyProtocolsUpgrade {
         protocols: Vec<StreamProtocol>,
     }
@@ -0,0 +1,171 @@
+use std::{
+    io,
+    io::{IoSlice, IoSliceMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures::{ready, AsyncRead, AsyncWrite};
+use libp2p_core::muxing::SubstreamBox;
+
+/// Number of bytes read from and written to the substreams of a connection.
+#[derive(Debug, Default)]
+pub(crate) struct BandwidthCounters {
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+}
+
+impl BandwidthCounters {
+    /// Total number of bytes read from all substreams.
+    pub(crate) fn inbound(&self) -> u64 {
+        self.inbound.load(Ordering::Relaxed)
+    }
+
+    /// Total number of bytes written to all substreams.
+    pub(crate) fn outbound(&self) -> u64 {
+        self.outbound.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps the substream to count its bytes, if bandwidth counting is enabled.
+pub(crate) fn count_bytes(
+    substream: SubstreamBox,
+    bandwidth: &Option<Arc<BandwidthCounters>>,
+) -> SubstreamBox {
+    match bandwidth {
+        Some(counters) => SubstreamBox::new(CountingSubstream::new(substream, counters.clone())),
+        None => substream,
+    }
+}
+
+/// Wraps a [`SubstreamBox`] and adds the number of bytes read and written to the given
+/// [`BandwidthCounters`].
+pub(crate) struct CountingSubstream {
+    inner: SubstreamBox,
+    counters: Arc<BandwidthCounters>,
+}
+
+impl CountingSubstream {
+    pub(crate) fn new(inner: SubstreamBox, counters: Arc<BandwidthCounters>) -> Self {
+        Self { inner, counters }
+    }
+}
+
+fn to_u64(num_bytes: usize) -> u64 {
+    u64::try_from(num_bytes).unwrap_or(u64::MAX)
+}
+
+impl AsyncRead for CountingSubstream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let num_bytes = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.counters
+            .inbound
+            .fetch_add(to_u64(num_bytes), Ordering::Relaxed);
+        Poll::Ready(Ok(num_bytes))
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let num_bytes = ready!(Pin::new(&mut this.inner).poll_read_vectored(cx, bufs))?;
+        this.counters
+            .inbound
+            .fetch_add(to_u64(num_bytes), Ordering::Relaxed);
+        Poll::Ready(Ok(num_bytes))
+    }
+}
+
+impl AsyncWrite for CountingSubstream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let num_bytes = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.counters
+            .outbound
+            .fetch_add(to_u64(num_bytes), Ordering::Relaxed);
+        Poll::Ready(Ok(num_bytes))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let num_bytes = ready!(Pin::new(&mut this.inner).poll_write_vectored(cx, bufs))?;
+        this.counters
+            .outbound
+            .fetch_add(to_u64(num_bytes), Ordering::Relaxed);
+        Poll::Ready(Ok(num_bytes))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn counts_partial_reads() {
+        let counters = Arc::new(BandwidthCounters::default());
+        let mut substream = CountingSubstream::new(
+            SubstreamBox::new(Cursor::new(vec![0u8; 10])),
+            counters.clone(),
+        );
+
+        let mut buf = [0u8; 4];
+        block_on(async {
+            assert_eq!(substream.read(&mut buf).await.unwrap(), 4);
+            assert_eq!(substream.read(&mut buf).await.unwrap(), 4);
+            assert_eq!(substream.read(&mut buf).await.unwrap(), 2);
+            assert_eq!(substream.read(&mut buf).await.unwrap(), 0);
+        });
+
+        assert_eq!(counters.inbound(), 10);
+        assert_eq!(counters.outbound(), 0);
+    }
+
+    #[test]
+    fn counts_writes_and_closes_inner() {
+        let counters = Arc::new(BandwidthCounters::default());
+        let mut substream =
+            CountingSubstream::new(SubstreamBox::new(Cursor::new(Vec::new())), counters.clone());
+
+        block_on(async {
+            substream.write_all(b"hello").await.unwrap();
+            let written = substream
+                .write_vectored(&[IoSlice::new(b"wor"), IoSlice::new(b"ld")])
+                .await
+                .unwrap();
+            assert_eq!(written, 5);
+            substream.close().await.unwrap();
+        });
+
+        assert_eq!(counters.inbound(), 0);
+        assert_eq!(counters.outbound(), 10);
+    }
+}
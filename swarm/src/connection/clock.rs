@@ -0,0 +1,64 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use futures_timer::Delay;
+use web_time::Instant;
+
+/// Source of time for the timers of a [`Connection`](super::Connection), e.g. its keep-alive and
+/// upgrade timeouts, see [`ConnectionBuilder::clock`](super::ConnectionBuilder::clock).
+///
+/// Defaults to the clock of the operating system. Replace it e.g. on targets with their own timer
+/// source, or to drive the timeouts deterministically in tests.
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+    /// Returns the current point in time.
+    fn now(&self) -> Instant;
+
+    /// Returns a future that completes once `duration` has passed according to this clock.
+    ///
+    /// All timers of a connection fire once the future completes. The keep-alive deadlines are
+    /// also checked against [`Clock::now`], the future only needs to wake up the connection.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// A timer created by a [`Clock`].
+pub(crate) struct Sleep(BoxFuture<'static, ()>);
+
+impl Sleep {
+    pub(crate) fn new(clock: &dyn Clock, duration: Duration) -> Self {
+        Sleep(clock.sleep(duration))
+    }
+}
+
+impl fmt::Debug for Sleep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Sleep")
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.poll_unpin(cx)
+    }
+}
+
+/// The [`Clock`] of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Delay::new(duration).boxed()
+    }
+}
@@ -18,9 +18,12 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use std::{fmt, io};
+use std::{fmt, io, time::Duration};
 
-use crate::{transport::TransportError, ConnectedPoint, Multiaddr, PeerId};
+use crate::{
+    connection::ConnectionSnapshot, transport::TransportError, ConnectedPoint, ConnectionId,
+    Multiaddr, PeerId,
+};
 
 /// Errors that can occur in the context of an established `Connection`.
 #[derive(Debug)]
@@ -30,15 +33,59 @@ pub enum ConnectionError {
     IO(io::Error),
 
     /// The connection keep-alive timeout expired.
-    KeepAliveTimeout,
+    KeepAliveTimeout {
+        /// For how long the connection was idle, i.e. without any requested, negotiating or
+        /// active substreams.
+        idle_for: Duration,
+    },
+
+    /// No substream was negotiated within the deadline after the connection was established,
+    /// see [`ConnectionBuilder::first_stream_deadline`](crate::ConnectionBuilder::first_stream_deadline).
+    NegotiationDeadline {
+        /// The deadline that passed.
+        deadline: Duration,
+    },
+
+    /// Neither the handler nor the muxer produced an event, and no substream completed its
+    /// negotiation, for longer than allowed, see
+    /// [`ConnectionBuilder::max_connection_silence`](crate::ConnectionBuilder::max_connection_silence).
+    Silent {
+        /// For how long the connection was silent.
+        silent_for: Duration,
+    },
+
+    /// The [`ConnectionHandler`](crate::ConnectionHandler) panicked while the connection was
+    /// polled.
+    ///
+    /// Only returned with the `catch-handler-panics` feature enabled, otherwise the panic unwinds
+    /// through the connection.
+    HandlerPanic {
+        /// The message of the panic, if it carried one.
+        message: Option<String>,
+    },
 }
 
 impl fmt::Display for ConnectionError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ConnectionError::IO(err) => write!(f, "Connection error: I/O error: {err}"),
-            ConnectionError::KeepAliveTimeout => {
-                write!(f, "Connection closed due to expired keep-alive timeout.")
+            ConnectionError::KeepAliveTimeout { idle_for } => write!(
+                f,
+                "Connection closed due to expired keep-alive timeout after being idle for {idle_for:?}."
+            ),
+            ConnectionError::NegotiationDeadline { deadline } => write!(
+                f,
+                "Connection closed because no substream was negotiated within {deadline:?}."
+            ),
+            ConnectionError::Silent { silent_for } => write!(
+                f,
+                "Connection closed because it was silent for {silent_for:?}."
+            ),
+            ConnectionError::HandlerPanic {
+                message: Some(message),
+            } => write!(f, "Connection closed because its handler panicked: {message}"),
+            ConnectionError::HandlerPanic { message: None } => {
+                write!(f, "Connection closed because its handler panicked.")
             }
         }
     }
@@ -48,7 +95,10 @@ impl std::error::Error for ConnectionError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             ConnectionError::IO(err) => Some(err),
-            ConnectionError::KeepAliveTimeout => None,
+            ConnectionError::KeepAliveTimeout { .. }
+            | ConnectionError::NegotiationDeadline { .. }
+            | ConnectionError::Silent { .. }
+            | ConnectionError::HandlerPanic { .. } => None,
         }
     }
 }
@@ -59,6 +109,105 @@ impl From<io::Error> for ConnectionError {
     }
 }
 
+/// A [`ConnectionError`] together with the id and peer of the connection it occurred on.
+#[derive(Debug)]
+pub struct PeerConnectionError {
+    connection_id: ConnectionId,
+    peer_id: PeerId,
+    error: ConnectionError,
+    abandoned_substreams: usize,
+    snapshot: Box<ConnectionSnapshot>,
+}
+
+impl PeerConnectionError {
+    pub(crate) fn new(
+        connection_id: ConnectionId,
+        peer_id: PeerId,
+        error: ConnectionError,
+        abandoned_substreams: usize,
+        snapshot: ConnectionSnapshot,
+    ) -> Self {
+        Self {
+            connection_id,
+            peer_id,
+            error,
+            abandoned_substreams,
+            snapshot: Box::new(snapshot),
+        }
+    }
+
+    /// The id of the connection.
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    /// The peer of the connection.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// The error that occurred on the connection.
+    pub fn error(&self) -> &ConnectionError {
+        &self.error
+    }
+
+    /// The number of substreams that were still being requested or negotiated when the error
+    /// occurred.
+    pub fn abandoned_substreams(&self) -> usize {
+        self.abandoned_substreams
+    }
+
+    /// The state of the connection when the error occurred, taken just before it is torn down.
+    ///
+    /// See [`Connection::debug_snapshot`](crate::Connection::debug_snapshot).
+    pub fn snapshot(&self) -> &ConnectionSnapshot {
+        &self.snapshot
+    }
+
+    /// Discards the id and peer and returns the [`ConnectionError`].
+    pub fn into_error(self) -> ConnectionError {
+        self.error
+    }
+}
+
+impl fmt::Display for PeerConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Connection to {} failed: {}", self.peer_id, self.error)
+    }
+}
+
+impl std::error::Error for PeerConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Error returned when closing an established `Connection` fails.
+#[derive(Debug)]
+pub(crate) struct CloseError {
+    /// The error returned by the muxer while closing.
+    pub(crate) source: io::Error,
+    /// The number of substreams that were still being requested or negotiated when the
+    /// connection was closed.
+    pub(crate) abandoned_substreams: usize,
+}
+
+impl fmt::Display for CloseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to close connection with {} abandoned substreams: {}",
+            self.abandoned_substreams, self.source
+        )
+    }
+}
+
+impl std::error::Error for CloseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
 /// Errors that can occur in the context of a pending outgoing `Connection`.
 ///
 /// Note: Addresses for an outbound connection are dialed in parallel. Thus, compared to
@@ -137,7 +286,7 @@ where
 {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            PendingConnectionError::Transport(_) => None,
+            PendingConnectionError::Transport(err) => Some(err),
             PendingConnectionError::WrongPeerId { .. } => None,
             PendingConnectionError::LocalPeerId { .. } => None,
             PendingConnectionError::Aborted => None,
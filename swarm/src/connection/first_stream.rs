@@ -0,0 +1,45 @@
+use std::{
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::FutureExt;
+use web_time::Instant;
+
+use super::{Clock, ConnectionError, Sleep};
+
+/// Fails a connection unless its first substream is negotiated in time, see
+/// [`ConnectionBuilder::first_stream_deadline`](super::ConnectionBuilder::first_stream_deadline).
+pub(crate) struct FirstStreamDeadline {
+    /// How long after the connection was established the first substream must be negotiated.
+    after: Duration,
+    /// Wakes up the connection once the deadline has passed according to the [`Clock`].
+    timer: Sleep,
+}
+
+impl FirstStreamDeadline {
+    pub(crate) fn new(after: Duration, clock: &dyn Clock) -> Self {
+        Self {
+            after,
+            timer: Sleep::new(clock, after),
+        }
+    }
+
+    /// Fails a connection established at `established_at` once the deadline has passed.
+    pub(crate) fn poll(
+        &mut self,
+        clock: &dyn Clock,
+        established_at: Instant,
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionError> {
+        if clock.now().saturating_duration_since(established_at) >= self.after
+            || self.timer.poll_unpin(cx).is_ready()
+        {
+            return Poll::Ready(ConnectionError::NegotiationDeadline {
+                deadline: self.after,
+            });
+        }
+
+        Poll::Pending
+    }
+}
@@ -0,0 +1,64 @@
+use std::{task::Waker, time::Duration};
+
+use web_time::Instant;
+
+use crate::handler::{ConnectionEvent, ConnectionEventMask, ConnectionHandler};
+
+/// Tracks when a connection became idle, see [`Connection::is_idle`](super::Connection::is_idle).
+#[derive(Default)]
+pub(crate) struct IdleTracker {
+    /// When the connection last became idle, if it is idle.
+    since: Option<Instant>,
+    /// Woken whenever the connection becomes idle, see
+    /// [`Connection::set_idle_waker`](super::Connection::set_idle_waker).
+    waker: Option<Waker>,
+}
+
+impl IdleTracker {
+    /// Records that the connection is idle at `now`.
+    ///
+    /// If it just became idle, the waker is woken and the `handler` is notified via
+    /// [`ConnectionEvent::EnteredIdle`].
+    pub(crate) fn on_idle<H: ConnectionHandler>(&mut self, handler: &mut H, now: Instant) {
+        if self.since.is_some() {
+            return;
+        }
+
+        self.since = Some(now);
+        if let Some(waker) = &self.waker {
+            waker.wake_by_ref();
+        }
+        if handler
+            .interested_events()
+            .contains(ConnectionEventMask::IDLE_CHANGE)
+        {
+            handler.on_connection_event(ConnectionEvent::EnteredIdle);
+        }
+    }
+
+    /// Records that the connection is in use, notifying the `handler` via
+    /// [`ConnectionEvent::ExitedIdle`] if it was idle.
+    pub(crate) fn on_busy<H: ConnectionHandler>(&mut self, handler: &mut H) {
+        if self.since.take().is_some()
+            && handler
+                .interested_events()
+                .contains(ConnectionEventMask::IDLE_CHANGE)
+        {
+            handler.on_connection_event(ConnectionEvent::ExitedIdle);
+        }
+    }
+
+    /// For how long the connection has been idle at `now`.
+    pub(crate) fn idle_for(&self, now: Instant) -> Duration {
+        self.since
+            .map_or(Duration::ZERO, |since| now.saturating_duration_since(since))
+    }
+
+    /// Replaces the waker, waking it right away if the connection is idle.
+    pub(crate) fn set_waker(&mut self, waker: Waker) {
+        if self.since.is_some() {
+            waker.wake_by_ref();
+        }
+        self.waker = Some(waker);
+    }
+}
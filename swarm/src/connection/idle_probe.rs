@@ -0,0 +1,112 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+
+use super::{Clock, Sleep};
+
+/// Probes the liveness of idle connections, independent of their
+/// [`ConnectionHandler`](crate::ConnectionHandler).
+///
+/// Once a connection is idle for `interval`, the probe is run. If it fails, the connection is
+/// closed with [`ConnectionError::IO`](crate::ConnectionError::IO). Otherwise, the connection is
+/// probed again after the next `interval` for as long as it stays idle.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use libp2p_swarm::IdleProbe;
+/// let probe = IdleProbe::new(Duration::from_secs(30), || async {
+///     // E.g. check the reachability of the remote out of band.
+///     Ok(())
+/// });
+/// ```
+#[derive(Clone)]
+pub struct IdleProbe {
+    interval: Duration,
+    probe: Arc<dyn Fn() -> BoxFuture<'static, io::Result<()>> + Send + Sync>,
+}
+
+impl IdleProbe {
+    /// Runs `probe` every `interval` while a connection is idle.
+    pub fn new<F, Fut>(interval: Duration, probe: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        Self {
+            interval,
+            probe: Arc::new(move || probe().boxed()),
+        }
+    }
+}
+
+impl fmt::Debug for IdleProbe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdleProbe")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Drives the [`IdleProbe`] of a single connection.
+pub(crate) struct IdleProbing {
+    probe: IdleProbe,
+    state: State,
+}
+
+enum State {
+    /// The connection is not idle.
+    Stopped,
+    /// The connection is idle, the next probe runs once the `timer` completes.
+    Waiting(Sleep),
+    /// The probe is running.
+    Probing(BoxFuture<'static, io::Result<()>>),
+}
+
+impl IdleProbing {
+    pub(crate) fn new(probe: IdleProbe) -> Self {
+        Self {
+            probe,
+            state: State::Stopped,
+        }
+    }
+
+    /// Stops probing, because the connection is no longer idle.
+    pub(crate) fn stop(&mut self) {
+        self.state = State::Stopped;
+    }
+
+    /// Advances probing of the idle connection, resolving with the error of a failed probe.
+    ///
+    /// The interval between probes is measured by the `clock` of the connection.
+    pub(crate) fn poll(&mut self, clock: &dyn Clock, cx: &mut Context<'_>) -> Poll<io::Error> {
+        loop {
+            match &mut self.state {
+                State::Stopped => {
+                    self.state = State::Waiting(Sleep::new(clock, self.probe.interval));
+                }
+                State::Waiting(timer) => {
+                    if timer.poll_unpin(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+
+                    self.state = State::Probing((self.probe.probe)());
+                }
+                State::Probing(probe) => match probe.poll_unpin(cx) {
+                    Poll::Ready(Ok(())) => self.state = State::Stopped,
+                    Poll::Ready(Err(error)) => {
+                        self.state = State::Stopped;
+                        return Poll::Ready(error);
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+            }
+        }
+    }
+}
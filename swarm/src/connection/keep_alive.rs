@@ -0,0 +1,127 @@
+use std::{fmt, sync::Arc};
+
+use web_time::Instant;
+
+/// Receives the keep-alive decisions and the shutdowns of [`Connection`](super::Connection)s,
+/// e.g. to tune
+/// [`Config::with_idle_connection_timeout`](crate::Config::with_idle_connection_timeout).
+///
+/// The methods are called from within the connection tasks and should thus return quickly.
+pub trait KeepAliveMetrics: fmt::Debug + Send + Sync + 'static {
+    /// Called whenever the value returned by
+    /// [`ConnectionHandler::connection_keep_alive`](crate::ConnectionHandler::connection_keep_alive)
+    /// for an idle connection differs from the previous one.
+    ///
+    /// The first decision of a connection is always reported.
+    fn on_keep_alive(&self, keep_alive: bool);
+
+    /// Called once the connection shuts down.
+    fn on_shutdown(&self, reason: ShutdownReason);
+}
+
+/// Reports the keep-alive decisions and the shutdown of a connection to its [`KeepAliveMetrics`],
+/// if any.
+#[derive(Default)]
+pub(crate) struct KeepAliveReporter {
+    /// Taken once the shutdown is reported.
+    metrics: Option<Arc<dyn KeepAliveMetrics>>,
+    /// The last keep-alive decision of the [`ConnectionHandler`](crate::ConnectionHandler) while
+    /// idle.
+    last_keep_alive: Option<bool>,
+}
+
+impl KeepAliveReporter {
+    pub(crate) fn new(metrics: Option<Arc<dyn KeepAliveMetrics>>) -> Self {
+        Self {
+            metrics,
+            last_keep_alive: None,
+        }
+    }
+
+    /// Reports the keep-alive decision for an idle connection, if it changed.
+    pub(crate) fn on_keep_alive(&mut self, keep_alive: bool) {
+        if self.last_keep_alive == Some(keep_alive) {
+            return;
+        }
+        self.last_keep_alive = Some(keep_alive);
+        if let Some(metrics) = &self.metrics {
+            metrics.on_keep_alive(keep_alive);
+        }
+    }
+
+    /// Reports the shutdown of the connection, unless one was already reported.
+    pub(crate) fn on_shutdown(&mut self, reason: ShutdownReason) {
+        if let Some(metrics) = self.metrics.take() {
+            metrics.on_shutdown(reason);
+        }
+    }
+}
+
+/// Why a [`Connection`](super::Connection) shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ShutdownReason {
+    /// The connection was idle for longer than the idle timeout.
+    KeepAliveTimeout,
+    /// The connection was closed locally, e.g. via
+    /// [`Swarm::disconnect_peer_id`](crate::Swarm::disconnect_peer_id).
+    Graceful,
+    /// The connection failed, e.g. because it was closed by the remote.
+    Error,
+}
+
+/// Why a [`Connection`](super::Connection) is going to shut down, see
+/// [`Connection::pending_shutdown_reason`](super::Connection::pending_shutdown_reason).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum PendingShutdownReason {
+    /// The connection is idle and not kept alive by its handler, it shuts down once the idle
+    /// timeout elapsed.
+    IdleTimeout,
+    /// The connection is idle and not kept alive by its handler, and the idle timeout elapsed.
+    ///
+    /// The connection shuts down when it is polled next.
+    IdleTimeoutElapsed,
+    /// The handler requested to close the connection via
+    /// [`ConnectionHandlerEvent::CloseGracefully`](crate::ConnectionHandlerEvent::CloseGracefully),
+    /// it shuts down once the negotiating streams drained.
+    HandlerClose,
+    /// The shutdown was scheduled via
+    /// [`Connection::schedule_shutdown`](super::Connection::schedule_shutdown), it happens once
+    /// the connection is idle and the `deadline` has passed.
+    Scheduled {
+        /// When the connection shuts down, or `None` if it shuts down as soon as it is idle.
+        deadline: Option<Instant>,
+    },
+}
+
+/// Why a [`Connection`](super::Connection) is kept alive, see
+/// [`Connection::liveness_reason`](super::Connection::liveness_reason).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LivenessReason {
+    /// Streams are negotiating.
+    Negotiating {
+        /// The number of inbound streams negotiating.
+        inbound: usize,
+        /// The number of outbound streams negotiating.
+        outbound: usize,
+    },
+    /// Outbound streams requested by the handler wait for the muxer to grant them.
+    RequestedStreams {
+        /// The number of requests waiting.
+        outbound: usize,
+    },
+    /// Negotiated streams are still in use, see
+    /// [`Stream::ignore_for_keep_alive`](crate::Stream::ignore_for_keep_alive).
+    ActiveStreams,
+    /// The connection is idle, but kept alive via
+    /// [`Connection::force_keep_alive_until`](super::Connection::force_keep_alive_until).
+    ForcedKeepAlive {
+        /// Until when the connection is kept alive.
+        until: Instant,
+    },
+    /// The connection is idle, but its handler keeps it alive, see
+    /// [`ConnectionHandler::connection_keep_alive`](crate::ConnectionHandler::connection_keep_alive).
+    HandlerKeepAlive,
+}
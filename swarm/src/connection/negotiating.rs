@@ -0,0 +1,61 @@
+use std::{
+    future::Future,
+    task::{Context, Poll},
+};
+
+use futures::{
+    stream::{FuturesOrdered, FuturesUnordered},
+    StreamExt,
+};
+
+/// Substream upgrades that negotiate concurrently, yielded either as they complete or in the order
+/// they were pushed.
+pub(crate) enum NegotiatingStreams<F: Future> {
+    /// Yields upgrades as they complete.
+    Unordered(FuturesUnordered<F>),
+    /// Yields upgrades in the order they were pushed, holding back completed upgrades until all
+    /// earlier ones completed.
+    Ordered(FuturesOrdered<F>),
+}
+
+impl<F: Future> NegotiatingStreams<F> {
+    pub(crate) fn new(ordered: bool) -> Self {
+        if ordered {
+            NegotiatingStreams::Ordered(FuturesOrdered::new())
+        } else {
+            NegotiatingStreams::Unordered(FuturesUnordered::new())
+        }
+    }
+
+    pub(crate) fn push(&mut self, upgrade: F) {
+        match self {
+            NegotiatingStreams::Unordered(upgrades) => upgrades.push(upgrade),
+            NegotiatingStreams::Ordered(upgrades) => upgrades.push_back(upgrade),
+        }
+    }
+
+    /// The number of upgrades that are negotiating or completed but held back.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            NegotiatingStreams::Unordered(upgrades) => upgrades.len(),
+            NegotiatingStreams::Ordered(upgrades) => upgrades.len(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops all upgrades, keeping the order mode.
+    #[cfg(test)]
+    pub(crate) fn clear(&mut self) {
+        *self = Self::new(matches!(self, NegotiatingStreams::Ordered(_)));
+    }
+
+    pub(crate) fn poll_next_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Option<F::Output>> {
+        match self {
+            NegotiatingStreams::Unordered(upgrades) => upgrades.poll_next_unpin(cx),
+            NegotiatingStreams::Ordered(upgrades) => upgrades.poll_next_unpin(cx),
+        }
+    }
+}
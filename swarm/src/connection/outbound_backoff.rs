@@ -0,0 +1,98 @@
+use std::{collections::HashMap, num::NonZeroU32, time::Duration};
+
+use web_time::Instant;
+
+/// Backs off outbound streams for protocols the remote repeatedly fails to upgrade.
+///
+/// After `max_failures` consecutive failed upgrades of an outbound stream, further requests for
+/// the same protocols are rejected with
+/// [`StreamUpgradeError::Throttled`](crate::StreamUpgradeError::Throttled) for `initial`. The
+/// backoff doubles with every further failure, up to `max`, and is reset by the first successful
+/// upgrade.
+///
+/// ```
+/// # use std::{num::NonZeroU32, time::Duration};
+/// # use libp2p_swarm::OutboundUpgradeBackoff;
+/// // Back off for 1s after 3 consecutive failures, for at most 1 minute.
+/// let backoff = OutboundUpgradeBackoff::new(
+///     NonZeroU32::new(3).unwrap(),
+///     Duration::from_secs(1),
+///     Duration::from_secs(60),
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboundUpgradeBackoff {
+    max_failures: NonZeroU32,
+    initial: Duration,
+    max: Duration,
+}
+
+impl OutboundUpgradeBackoff {
+    /// Back off for `initial` after `max_failures` consecutive failures, for at most `max`.
+    pub fn new(max_failures: NonZeroU32, initial: Duration, max: Duration) -> Self {
+        Self {
+            max_failures,
+            initial,
+            max,
+        }
+    }
+
+    /// The backoff after the given number of consecutive failures, if any.
+    fn backoff(&self, failures: u32) -> Option<Duration> {
+        let exceeding = failures.checked_sub(self.max_failures.get())?;
+
+        Some(
+            self.initial
+                .saturating_mul(1 << exceeding.min(31))
+                .min(self.max),
+        )
+    }
+}
+
+/// Tracks the failed outbound upgrades of a single connection, per set of protocols.
+pub(crate) struct OutboundUpgradeBackoffs {
+    config: OutboundUpgradeBackoff,
+    failures: HashMap<Vec<String>, Failures>,
+}
+
+struct Failures {
+    consecutive: u32,
+    /// Until when requests for the protocols are rejected.
+    backoff_until: Option<Instant>,
+}
+
+impl OutboundUpgradeBackoffs {
+    pub(crate) fn new(config: OutboundUpgradeBackoff) -> Self {
+        Self {
+            config,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// For how much longer requests for the protocols are backed off, if at all.
+    pub(crate) fn retry_after(&self, protocols: &[String], now: Instant) -> Option<Duration> {
+        let remaining = self
+            .failures
+            .get(protocols)?
+            .backoff_until?
+            .saturating_duration_since(now);
+
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    pub(crate) fn on_success(&mut self, protocols: &[String]) {
+        self.failures.remove(protocols);
+    }
+
+    pub(crate) fn on_failure(&mut self, protocols: Vec<String>, now: Instant) {
+        let failures = self.failures.entry(protocols).or_insert(Failures {
+            consecutive: 0,
+            backoff_until: None,
+        });
+        failures.consecutive = failures.consecutive.saturating_add(1);
+        failures.backoff_until = self
+            .config
+            .backoff(failures.consecutive)
+            .map(|backoff| now + backoff);
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    cmp::{Ordering as CmpOrdering, Reverse},
+    collections::{BinaryHeap, HashMap},
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use futures::{stream::FuturesUnordered, FutureExt};
+use web_time::Instant;
+
+use super::{
+    outbound_backoff::OutboundUpgradeBackoffs,
+    stream_upgrade::{cap_timeout, protocol_names},
+    Clock, Sleep,
+};
+use crate::{
+    handler::{OutboundPriority, UpgradeInfoSend},
+    SubstreamProtocol,
+};
+
+/// Whether any of the `requests` still waits for a substream, i.e. wasn't granted one yet.
+pub(crate) fn has_waiting_requests<UserData, Upgrade>(
+    requests: &FuturesUnordered<SubstreamRequested<UserData, Upgrade>>,
+) -> bool {
+    requests.iter().any(SubstreamRequested::is_waiting)
+}
+
+/// Queues an outbound substream request of the [`ConnectionHandler`](crate::ConnectionHandler).
+///
+/// Fails with the user data of the request and the remaining backoff if its protocols are backed
+/// off. `fair_grants` records the first protocol of the request, see
+/// [`ConnectionBuilder::fair_outbound_grants`](super::ConnectionBuilder::fair_outbound_grants), the
+/// `timeout_policy` adjusts its timeout and the `deadline` caps it.
+#[expect(clippy::too_many_arguments)] // Threads through the options of the connection.
+pub(crate) fn queue_outbound_request<UserData, Upgrade>(
+    protocol: SubstreamProtocol<Upgrade, UserData>,
+    requested_substreams: &mut FuturesUnordered<SubstreamRequested<UserData, Upgrade>>,
+    substream_requests: &mut u64,
+    outbound_backoffs: Option<&OutboundUpgradeBackoffs>,
+    fair_grants: bool,
+    timeout_policy: Option<&OutboundTimeoutPolicy>,
+    deadline: Option<Instant>,
+    clock: &dyn Clock,
+) -> Result<(), (UserData, Duration)>
+where
+    Upgrade: UpgradeInfoSend,
+{
+    if let Some(backoffs) = outbound_backoffs {
+        let protocols = protocol_names(protocol.upgrade());
+        if let Some(retry_after) = backoffs.retry_after(&protocols, clock.now()) {
+            tracing::debug!(
+                ?protocols,
+                "Rejecting outbound stream request, backing off for {retry_after:?}"
+            );
+            let (_, info) = protocol.into_upgrade();
+            return Err((info, retry_after));
+        }
+    }
+
+    let timeout = match timeout_policy {
+        Some(OutboundTimeoutPolicy(policy)) => policy(*protocol.timeout()),
+        None => *protocol.timeout(),
+    };
+    let timeout = cap_timeout(timeout, deadline, clock.now());
+    let priority = protocol.priority();
+    let (upgrade, user_data) = protocol.into_upgrade();
+    let protocol = fair_grants
+        .then(|| protocol_names(&upgrade).into_iter().next())
+        .flatten();
+
+    requested_substreams.push(SubstreamRequested::new(
+        user_data,
+        Sleep::new(clock, timeout),
+        upgrade,
+        priority,
+        *substream_requests,
+        protocol,
+    ));
+    *substream_requests += 1;
+    Ok(())
+}
+
+/// Adjusts the timeout of outbound stream requests, see
+/// [`ConnectionBuilder::outbound_timeout_policy`](super::ConnectionBuilder::outbound_timeout_policy).
+#[derive(Clone)]
+pub(crate) struct OutboundTimeoutPolicy(pub(crate) Arc<dyn Fn(Duration) -> Duration + Send + Sync>);
+
+impl fmt::Debug for OutboundTimeoutPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OutboundTimeoutPolicy")
+    }
+}
+
+pub(crate) enum SubstreamRequested<UserData, Upgrade> {
+    Waiting {
+        user_data: UserData,
+        timeout: Sleep,
+        upgrade: Upgrade,
+        priority: OutboundPriority,
+        /// The position of the request among all requests of the connection.
+        sequence: u64,
+        /// The first protocol of the `upgrade`, if
+        /// [`ConnectionBuilder::fair_outbound_grants`](super::ConnectionBuilder::fair_outbound_grants)
+        /// is enabled.
+        protocol: Option<String>,
+        /// A waker to notify our [`FuturesUnordered`] that we have extracted the data.
+        ///
+        /// This will ensure that we will get polled again in the next iteration which allows us to
+        /// resolve with `Ok(())` and be removed from the [`FuturesUnordered`].
+        extracted_waker: Option<Waker>,
+    },
+    Done,
+}
+
+impl<UserData, Upgrade> SubstreamRequested<UserData, Upgrade> {
+    pub(crate) fn new(
+        user_data: UserData,
+        timeout: Sleep,
+        upgrade: Upgrade,
+        priority: OutboundPriority,
+        sequence: u64,
+        protocol: Option<String>,
+    ) -> Self {
+        Self::Waiting {
+            user_data,
+            timeout,
+            upgrade,
+            priority,
+            sequence,
+            protocol,
+            extracted_waker: None,
+        }
+    }
+
+    /// The rank of a request that is still waiting for a substream, the highest ranked one is
+    /// served first.
+    ///
+    /// With `grants`, the protocol that was least recently granted a substream wins among
+    /// requests of the same priority.
+    fn rank(
+        &self,
+        grants: Option<&OutboundGrants>,
+    ) -> Option<(OutboundPriority, Reverse<u64>, Reverse<u64>)> {
+        match self {
+            SubstreamRequested::Waiting {
+                priority,
+                sequence,
+                protocol,
+                ..
+            } => {
+                let last_granted = grants
+                    .zip(protocol.as_deref())
+                    .map_or(0, |(grants, protocol)| grants.last_granted(protocol));
+
+                Some((*priority, Reverse(last_granted), Reverse(*sequence)))
+            }
+            SubstreamRequested::Done => None,
+        }
+    }
+
+    pub(crate) fn extract(&mut self) -> (UserData, Sleep, Upgrade, Option<String>) {
+        match mem::replace(self, Self::Done) {
+            SubstreamRequested::Waiting {
+                user_data,
+                timeout,
+                upgrade,
+                protocol,
+                extracted_waker: waker,
+                ..
+            } => {
+                // The `timeout` moves along with the extracted data, so from here on the request
+                // can only resolve with `Ok(())`, even if the `timeout` already elapsed.
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+
+                (user_data, timeout, upgrade, protocol)
+            }
+            SubstreamRequested::Done => panic!("cannot extract twice"),
+        }
+    }
+
+    /// Whether the request is still waiting for a substream.
+    pub(crate) fn is_waiting(&self) -> bool {
+        matches!(self, SubstreamRequested::Waiting { .. })
+    }
+
+    /// Borrows the user data of a request that is still waiting for a substream.
+    pub(crate) fn user_data(&self) -> Option<&UserData> {
+        match self {
+            SubstreamRequested::Waiting { user_data, .. } => Some(user_data),
+            SubstreamRequested::Done => None,
+        }
+    }
+
+    /// Returns the user data of a request that is still waiting for a substream.
+    pub(crate) fn into_user_data(self) -> Option<UserData> {
+        match self {
+            SubstreamRequested::Waiting { user_data, .. } => Some(user_data),
+            SubstreamRequested::Done => None,
+        }
+    }
+}
+
+impl<UserData, Upgrade> Unpin for SubstreamRequested<UserData, Upgrade> {}
+
+impl<UserData, Upgrade> Future for SubstreamRequested<UserData, Upgrade> {
+    type Output = Result<(), UserData>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        match mem::replace(this, Self::Done) {
+            SubstreamRequested::Waiting {
+                user_data,
+                upgrade,
+                mut timeout,
+                priority,
+                sequence,
+                protocol,
+                ..
+            } => match timeout.poll_unpin(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(user_data)),
+                Poll::Pending => {
+                    *this = Self::Waiting {
+                        user_data,
+                        upgrade,
+                        timeout,
+                        priority,
+                        sequence,
+                        protocol,
+                        extracted_waker: Some(cx.waker().clone()),
+                    };
+                    Poll::Pending
+                }
+            },
+            SubstreamRequested::Done => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// The outbound substream requests still waiting for a substream, ordered by
+/// [`SubstreamRequested::rank`] to grant them without scanning all requests per granted substream.
+pub(crate) struct OutboundQueue<'a, UserData, Upgrade> {
+    heap: BinaryHeap<RankedRequest<'a, UserData, Upgrade>>,
+}
+
+impl<UserData, Upgrade> Default for OutboundQueue<'_, UserData, Upgrade> {
+    fn default() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+        }
+    }
+}
+
+impl<'a, UserData, Upgrade> OutboundQueue<'a, UserData, Upgrade> {
+    pub(crate) fn new(
+        requests: &'a mut FuturesUnordered<SubstreamRequested<UserData, Upgrade>>,
+        grants: Option<&OutboundGrants>,
+    ) -> Self {
+        let heap = requests
+            .iter_mut()
+            .filter_map(|request| {
+                Some(RankedRequest {
+                    rank: request.rank(grants)?,
+                    request,
+                })
+            })
+            .collect();
+
+        Self { heap }
+    }
+
+    /// Removes the highest ranked request from the queue.
+    ///
+    /// Granting a substream to a protocol only lowers the rank of the other requests of that
+    /// protocol, so outdated ranks are refreshed once they reach the top of the queue.
+    pub(crate) fn pop(
+        &mut self,
+        grants: Option<&OutboundGrants>,
+    ) -> Option<&'a mut SubstreamRequested<UserData, Upgrade>> {
+        while let Some(mut top) = self.heap.pop() {
+            let Some(rank) = top.request.rank(grants) else {
+                continue;
+            };
+            if rank == top.rank || self.heap.peek().is_none_or(|next| rank > next.rank) {
+                return Some(top.request);
+            }
+            top.rank = rank;
+            self.heap.push(top);
+        }
+
+        None
+    }
+}
+
+/// A request of the [`OutboundQueue`], ordered by its rank at the time it was queued.
+struct RankedRequest<'a, UserData, Upgrade> {
+    rank: (OutboundPriority, Reverse<u64>, Reverse<u64>),
+    request: &'a mut SubstreamRequested<UserData, Upgrade>,
+}
+
+impl<UserData, Upgrade> PartialEq for RankedRequest<'_, UserData, Upgrade> {
+    fn eq(&self, other: &Self) -> bool {
+        self.rank == other.rank
+    }
+}
+
+impl<UserData, Upgrade> Eq for RankedRequest<'_, UserData, Upgrade> {}
+
+impl<UserData, Upgrade> PartialOrd for RankedRequest<'_, UserData, Upgrade> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<UserData, Upgrade> Ord for RankedRequest<'_, UserData, Upgrade> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.rank.cmp(&other.rank)
+    }
+}
+
+/// Remembers when each protocol was last granted an outbound substream, to grant substreams
+/// round-robin among protocols.
+#[derive(Default)]
+pub(crate) struct OutboundGrants {
+    /// The number of substreams granted so far.
+    grants: u64,
+    /// The value of `grants` after the last substream granted per protocol.
+    last_granted: HashMap<String, u64>,
+}
+
+impl OutboundGrants {
+    /// When the protocol was last granted a substream, `0` if never.
+    fn last_granted(&self, protocol: &str) -> u64 {
+        self.last_granted.get(protocol).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn on_granted(&mut self, protocol: String) {
+        self.grants += 1;
+        self.last_granted.insert(protocol, self.grants);
+    }
+}
@@ -0,0 +1,44 @@
+use std::io;
+
+/// Retries opening outbound streams after transient errors of the
+/// [`StreamMuxerBox`](libp2p_core::muxing::StreamMuxerBox), see
+/// [`ConnectionBuilder::outbound_stream_retries`](super::ConnectionBuilder::outbound_stream_retries).
+pub(crate) struct OutboundRetries {
+    /// How often opening an outbound stream is retried in a row.
+    max: usize,
+    /// The number of transient errors in a row while opening an outbound stream.
+    count: usize,
+}
+
+impl OutboundRetries {
+    pub(crate) fn new(max: usize) -> Self {
+        Self { max, count: 0 }
+    }
+
+    /// Whether opening the outbound stream should be retried after the `error`.
+    pub(crate) fn on_error(&mut self, error: &io::Error) -> bool {
+        if self.count >= self.max || !is_transient_muxer_error(error) {
+            return false;
+        }
+
+        self.count += 1;
+        true
+    }
+
+    /// Records that the muxer granted an outbound stream.
+    pub(crate) fn on_granted(&mut self) {
+        self.count = 0;
+    }
+}
+
+/// Whether the error of a [`StreamMuxerBox`](libp2p_core::muxing::StreamMuxerBox) is transient,
+/// i.e. the muxer may succeed when polled again.
+pub(crate) fn is_transient_muxer_error(error: &io::Error) -> bool {
+    // `StreamMuxerBox` wraps the errors of the muxer, which may be IO errors themselves.
+    let kind = error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<io::Error>())
+        .map_or(error.kind(), io::Error::kind);
+
+    matches!(kind, io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock)
+}
@@ -0,0 +1,110 @@
+use std::{task::Context, time::Duration};
+
+use futures::FutureExt;
+
+use super::{Clock, Sleep};
+use crate::handler::{ConnectionEvent, ConnectionEventMask, ConnectionHandler};
+
+/// Informs the [`ConnectionHandler`] while the
+/// [`StreamMuxerBox`](libp2p_core::muxing::StreamMuxerBox) withholds requested outbound
+/// substreams, via [`ConnectionEvent::OutboundCapacity`] and
+/// [`ConnectionEvent::OutboundStreamsStalled`].
+pub(crate) struct OutboundStall {
+    /// How long the muxer may withhold requested outbound substreams before the stall is
+    /// reported, see
+    /// [`ConnectionBuilder::outbound_stall_threshold`](super::ConnectionBuilder::outbound_stall_threshold).
+    threshold: Option<Duration>,
+    /// Since when the muxer has been withholding requested outbound substreams.
+    state: StallState,
+    /// Whether the muxer granted the last requested outbound substream.
+    capacity: bool,
+}
+
+/// Tracks for how long the muxer has been withholding requested outbound substreams.
+#[derive(Default)]
+enum StallState {
+    /// Outbound substreams are granted, or none are requested.
+    #[default]
+    None,
+    /// The muxer returned `Poll::Pending`, the `timer` completes once the threshold has passed.
+    Waiting { timer: Sleep },
+    /// The stall was reported to the [`ConnectionHandler`].
+    Reported,
+}
+
+impl OutboundStall {
+    pub(crate) fn new(threshold: Option<Duration>) -> Self {
+        Self {
+            threshold,
+            state: StallState::None,
+            capacity: true,
+        }
+    }
+
+    /// Records that no outbound substreams are requested.
+    pub(crate) fn on_no_requests(&mut self) {
+        self.state = StallState::None;
+    }
+
+    /// Records that the muxer granted an outbound substream.
+    pub(crate) fn on_granted<H: ConnectionHandler>(&mut self, handler: &mut H) {
+        self.state = StallState::None;
+        if !self.capacity {
+            self.capacity = true;
+            notify_capacity(handler, true);
+        }
+    }
+
+    /// Records that the muxer withholds a requested outbound substream.
+    ///
+    /// Returns `true` once the stall is reported to the `handler`, after the muxer has been
+    /// withholding outbound substreams for at least the threshold. Each stall is reported only
+    /// once. Until then, the current task is woken once the threshold has passed according to the
+    /// `clock`.
+    pub(crate) fn on_pending<H: ConnectionHandler>(
+        &mut self,
+        handler: &mut H,
+        clock: &dyn Clock,
+        cx: &mut Context<'_>,
+    ) -> bool {
+        if self.capacity {
+            self.capacity = false;
+            notify_capacity(handler, false);
+        }
+
+        let threshold = self.threshold.filter(|_| {
+            handler
+                .interested_events()
+                .contains(ConnectionEventMask::OUTBOUND_STREAMS_STALLED)
+        });
+        let Some(threshold) = threshold else {
+            return false;
+        };
+
+        if let StallState::None = self.state {
+            self.state = StallState::Waiting {
+                timer: Sleep::new(clock, threshold),
+            };
+        }
+        let StallState::Waiting { timer } = &mut self.state else {
+            return false;
+        };
+        // Registers the waker, so we are polled again once the threshold has passed.
+        if timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+
+        self.state = StallState::Reported;
+        handler.on_connection_event(ConnectionEvent::OutboundStreamsStalled);
+        true
+    }
+}
+
+fn notify_capacity<H: ConnectionHandler>(handler: &mut H, available: bool) {
+    if handler
+        .interested_events()
+        .contains(ConnectionEventMask::OUTBOUND_CAPACITY)
+    {
+        handler.on_connection_event(ConnectionEvent::OutboundCapacity { available });
+    }
+}
@@ -46,9 +46,11 @@ use web_time::{Duration, Instant};
 
 use crate::{
     connection::{
-        Connected, Connection, ConnectionError, ConnectionId, IncomingInfo, PendingConnectionError,
-        PendingInboundConnectionError, PendingOutboundConnectionError, PendingPoint,
+        Connected, ConnectionBuilder, ConnectionError, ConnectionId, ConnectionIdAllocator,
+        IncomingInfo, PendingConnectionError, PendingInboundConnectionError,
+        PendingOutboundConnectionError, PendingPoint, SubstreamPollPriority,
     },
+    handler::{MetricsDelta, ProtocolSupport},
     transport::TransportError,
     ConnectedPoint, ConnectionHandler, Executor, Multiaddr, PeerId,
 };
@@ -101,6 +103,9 @@ where
     /// The pending connections that are currently being negotiated.
     pending: HashMap<ConnectionId, PendingConnection>,
 
+    /// Allocates the [`ConnectionId`]s of incoming connections.
+    connection_ids: ConnectionIdAllocator,
+
     /// Size of the task command buffer (per task).
     task_command_buffer_size: usize,
 
@@ -115,6 +120,15 @@ where
     /// See [`Connection::max_negotiating_inbound_streams`].
     max_negotiating_inbound_streams: usize,
 
+    /// The order in which new substreams are requested from the muxer.
+    substream_poll_priority: SubstreamPollPriority,
+
+    /// See [`Connection::handler_poll_budget`].
+    handler_poll_budget: NonZeroUsize,
+
+    /// See [`Connection::poll_budget`].
+    poll_budget: NonZeroUsize,
+
     /// How many [`task::EstablishedConnectionEvent`]s can be buffered before the connection is
     /// back-pressured.
     per_connection_event_buffer_size: usize,
@@ -194,6 +208,57 @@ impl<TInEvent> EstablishedConnection<TInEvent> {
             Err(e) => assert!(e.is_disconnected(), "No capacity for close command."),
         };
     }
+
+    /// Registers a waker that is woken whenever the connection becomes idle.
+    ///
+    /// Has no effect if the connection is already closing.
+    pub(crate) fn set_idle_waker(&mut self, waker: Waker) {
+        // Clone the sender so that we are guaranteed to have
+        // capacity for the command (every sender gets a slot).
+        match self
+            .sender
+            .clone()
+            .try_send(task::Command::SetIdleWaker(waker))
+        {
+            Ok(()) => {}
+            Err(e) => assert!(e.is_disconnected(), "No capacity for idle waker command."),
+        };
+    }
+
+    /// Reports a change to the protocols the remote supports to the connection.
+    ///
+    /// Has no effect if the connection is already closing.
+    pub(crate) fn report_remote_protocols(&mut self, support: ProtocolSupport) {
+        // Clone the sender so that we are guaranteed to have
+        // capacity for the command (every sender gets a slot).
+        match self
+            .sender
+            .clone()
+            .try_send(task::Command::ReportRemoteProtocols(support))
+        {
+            Ok(()) => {}
+            Err(e) => assert!(
+                e.is_disconnected(),
+                "No capacity for remote protocols command."
+            ),
+        };
+    }
+
+    /// Shuts the connection down at `when` once idle, or as soon as possible if `None`.
+    ///
+    /// Has no effect if the connection is already closing.
+    pub(crate) fn schedule_shutdown(&mut self, when: Option<Instant>) {
+        // Clone the sender so that we are guaranteed to have
+        // capacity for the command (every sender gets a slot).
+        match self
+            .sender
+            .clone()
+            .try_send(task::Command::ScheduleShutdown(when))
+        {
+            Ok(()) => {}
+            Err(e) => assert!(e.is_disconnected(), "No capacity for shutdown command."),
+        };
+    }
 }
 
 struct PendingConnection {
@@ -260,6 +325,8 @@ pub(crate) enum PoolEvent<ToBehaviour> {
         /// The error that occurred, if any. If `None`, the connection
         /// was closed by the local peer.
         error: Option<ConnectionError>,
+        /// The reason given by the handler, if it requested the close.
+        reason: Option<String>,
         /// The remaining established connections to the same peer.
         remaining_established_connection_ids: Vec<ConnectionId>,
     },
@@ -294,6 +361,14 @@ pub(crate) enum PoolEvent<ToBehaviour> {
         event: ToBehaviour,
     },
 
+    /// The connection handler of a node has reported metrics.
+    ConnectionMetrics {
+        id: ConnectionId,
+        peer_id: PeerId,
+        /// The reported counters.
+        metrics: MetricsDelta,
+    },
+
     /// The connection to a node has changed its address.
     AddressChange {
         id: ConnectionId,
@@ -321,10 +396,14 @@ where
             counters: ConnectionCounters::new(),
             established: Default::default(),
             pending: Default::default(),
+            connection_ids: config.connection_id_allocator,
             task_command_buffer_size: config.task_command_buffer_size,
             dial_concurrency_factor: config.dial_concurrency_factor,
             substream_upgrade_protocol_override: config.substream_upgrade_protocol_override,
             max_negotiating_inbound_streams: config.max_negotiating_inbound_streams,
+            substream_poll_priority: config.substream_poll_priority,
+            handler_poll_budget: config.handler_poll_budget,
+            poll_budget: config.poll_budget,
             per_connection_event_buffer_size: config.per_connection_event_buffer_size,
             idle_connection_timeout: config.idle_connection_timeout,
             executor,
@@ -336,6 +415,11 @@ where
         }
     }
 
+    /// Allocates the [`ConnectionId`] for a new incoming connection.
+    pub(crate) fn next_connection_id(&mut self) -> ConnectionId {
+        self.connection_ids.next()
+    }
+
     /// Gets the dedicated connection counters.
     pub(crate) fn counters(&self) -> &ConnectionCounters {
         &self.counters
@@ -528,13 +612,18 @@ where
             waker.wake();
         }
 
-        let connection = Connection::new(
-            connection,
-            handler,
-            self.substream_upgrade_protocol_override,
-            self.max_negotiating_inbound_streams,
-            self.idle_connection_timeout,
-        );
+        let mut builder = ConnectionBuilder::new(obtained_peer_id)
+            .connection_id(id)
+            .endpoint(endpoint.clone())
+            .max_negotiating_inbound_streams(self.max_negotiating_inbound_streams)
+            .substream_poll_priority(self.substream_poll_priority)
+            .handler_poll_budget(self.handler_poll_budget)
+            .poll_budget(self.poll_budget)
+            .idle_timeout(self.idle_connection_timeout);
+        if let Some(version) = self.substream_upgrade_protocol_override {
+            builder = builder.upgrade_version_override(version);
+        }
+        let connection = builder.build(connection, handler);
 
         let span = tracing::debug_span!(parent: tracing::Span::none(), "new_established_connection", remote_addr = %endpoint.get_remote_address(), %id, peer = %obtained_peer_id);
         span.follows_from(tracing::Span::current());
@@ -572,9 +661,21 @@ where
             Poll::Ready(Some(task::EstablishedConnectionEvent::Notify { id, peer_id, event })) => {
                 return Poll::Ready(PoolEvent::ConnectionEvent { peer_id, id, event });
             }
+            Poll::Ready(Some(task::EstablishedConnectionEvent::Metrics {
+                id,
+                peer_id,
+                metrics,
+            })) => {
+                return Poll::Ready(PoolEvent::ConnectionMetrics {
+                    id,
+                    peer_id,
+                    metrics,
+                });
+            }
             Poll::Ready(Some(task::EstablishedConnectionEvent::AddressChange {
                 id,
                 peer_id,
+                old_address,
                 new_address,
             })) => {
                 let connection = self
@@ -583,6 +684,7 @@ where
                     .expect("Receive `AddressChange` event for established peer.")
                     .get_mut(&id)
                     .expect("Receive `AddressChange` event from established connection");
+                debug_assert_eq!(connection.endpoint.get_remote_address(), &old_address);
                 let mut new_endpoint = connection.endpoint.clone();
                 new_endpoint.set_remote_address(new_address);
                 let old_endpoint =
@@ -595,7 +697,12 @@ where
                     old_endpoint,
                 });
             }
-            Poll::Ready(Some(task::EstablishedConnectionEvent::Closed { id, peer_id, error })) => {
+            Poll::Ready(Some(task::EstablishedConnectionEvent::Closed {
+                id,
+                peer_id,
+                error,
+                reason,
+            })) => {
                 let connections = self
                     .established
                     .get_mut(&peer_id)
@@ -612,6 +719,7 @@ where
                     id,
                     connected: Connected { endpoint, peer_id },
                     error,
+                    reason,
                     remaining_established_connection_ids,
                 });
             }
@@ -981,6 +1089,18 @@ pub(crate) struct PoolConfig {
     ///
     /// See [`Connection::max_negotiating_inbound_streams`].
     max_negotiating_inbound_streams: usize,
+
+    /// The order in which new substreams are requested from the muxer.
+    substream_poll_priority: SubstreamPollPriority,
+
+    /// See [`Connection::handler_poll_budget`].
+    handler_poll_budget: NonZeroUsize,
+
+    /// See [`Connection::poll_budget`].
+    poll_budget: NonZeroUsize,
+
+    /// Allocates the [`ConnectionId`]s of incoming connections.
+    connection_id_allocator: ConnectionIdAllocator,
 }
 
 impl PoolConfig {
@@ -993,6 +1113,10 @@ impl PoolConfig {
             idle_connection_timeout: Duration::from_secs(10),
             substream_upgrade_protocol_override: None,
             max_negotiating_inbound_streams: 128,
+            substream_poll_priority: SubstreamPollPriority::default(),
+            handler_poll_budget: NonZeroUsize::MAX,
+            poll_budget: NonZeroUsize::new(128).expect("128 > 0"),
+            connection_id_allocator: ConnectionIdAllocator::default(),
         }
     }
 
@@ -1041,4 +1165,29 @@ impl PoolConfig {
         self.max_negotiating_inbound_streams = v;
         self
     }
+
+    /// The order in which new substreams are requested from the muxer.
+    pub(crate) fn with_substream_poll_priority(mut self, v: SubstreamPollPriority) -> Self {
+        self.substream_poll_priority = v;
+        self
+    }
+
+    /// See [`Connection::handler_poll_budget`].
+    pub(crate) fn with_handler_poll_budget(mut self, v: NonZeroUsize) -> Self {
+        self.handler_poll_budget = v;
+        self
+    }
+
+    /// See [`Connection::poll_budget`].
+    pub(crate) fn with_poll_budget(mut self, v: NonZeroUsize) -> Self {
+        self.poll_budget = v;
+        self
+    }
+
+    /// Allocates the [`ConnectionId`]s of incoming connections from the given allocator.
+    #[cfg(test)]
+    pub(crate) fn with_connection_id_allocator(mut self, v: ConnectionIdAllocator) -> Self {
+        self.connection_id_allocator = v;
+        self
+    }
 }
@@ -21,7 +21,7 @@
 
 //! Async functions driving pending and established connections in the form of a task.
 
-use std::{convert::Infallible, pin::Pin};
+use std::{convert::Infallible, pin::Pin, task::Waker};
 
 use futures::{
     channel::{mpsc, oneshot},
@@ -29,6 +29,7 @@ use futures::{
     SinkExt, StreamExt,
 };
 use libp2p_core::muxing::StreamMuxerBox;
+use web_time::Instant;
 
 use super::concurrent_dial::ConcurrentDial;
 use crate::{
@@ -36,6 +37,7 @@ use crate::{
         self, ConnectionError, ConnectionId, PendingInboundConnectionError,
         PendingOutboundConnectionError,
     },
+    handler::{MetricsDelta, ProtocolSupport},
     transport::TransportError,
     ConnectionHandler, Multiaddr, PeerId,
 };
@@ -48,6 +50,12 @@ pub(crate) enum Command<T> {
     /// Gracefully close the connection (active close) before
     /// terminating the task.
     Close,
+    /// Wake the given waker whenever the connection becomes idle.
+    SetIdleWaker(Waker),
+    /// Report a change to the protocols the remote supports.
+    ReportRemoteProtocols(ProtocolSupport),
+    /// Shut the connection down at the given time once idle, or as soon as possible if `None`.
+    ScheduleShutdown(Option<Instant>),
 }
 
 pub(crate) enum PendingConnectionEvent {
@@ -72,6 +80,7 @@ pub(crate) enum EstablishedConnectionEvent<ToBehaviour> {
     AddressChange {
         id: ConnectionId,
         peer_id: PeerId,
+        old_address: Multiaddr,
         new_address: Multiaddr,
     },
     /// Notify the manager of an event from the connection.
@@ -80,6 +89,12 @@ pub(crate) enum EstablishedConnectionEvent<ToBehaviour> {
         peer_id: PeerId,
         event: ToBehaviour,
     },
+    /// The connection handler reported metrics.
+    Metrics {
+        id: ConnectionId,
+        peer_id: PeerId,
+        metrics: MetricsDelta,
+    },
     /// A connection closed, possibly due to an error.
     ///
     /// If `error` is `None`, the connection has completed
@@ -88,6 +103,8 @@ pub(crate) enum EstablishedConnectionEvent<ToBehaviour> {
         id: ConnectionId,
         peer_id: PeerId,
         error: Option<ConnectionError>,
+        /// The reason given by the handler, if it requested the close.
+        reason: Option<String>,
     },
 }
 
@@ -189,29 +206,14 @@ pub(crate) async fn new_for_established_connection<THandler>(
         {
             Either::Left((Some(command), _)) => match command {
                 Command::NotifyHandler(event) => connection.on_behaviour_event(event),
+                Command::SetIdleWaker(waker) => connection.set_idle_waker(waker),
+                Command::ReportRemoteProtocols(support) => {
+                    connection.report_remote_protocols(support)
+                }
+                Command::ScheduleShutdown(when) => connection.schedule_shutdown(when),
                 Command::Close => {
                     command_receiver.close();
-                    let (remaining_events, closing_muxer) = connection.close();
-
-                    let _ = events
-                        .send_all(&mut remaining_events.map(|event| {
-                            Ok(EstablishedConnectionEvent::Notify {
-                                id: connection_id,
-                                event,
-                                peer_id,
-                            })
-                        }))
-                        .await;
-
-                    let error = closing_muxer.await.err().map(ConnectionError::IO);
-
-                    let _ = events
-                        .send(EstablishedConnectionEvent::Closed {
-                            id: connection_id,
-                            peer_id,
-                            error,
-                        })
-                        .await;
+                    close(connection_id, peer_id, connection, events, None).await;
                     return;
                 }
             },
@@ -230,18 +232,47 @@ pub(crate) async fn new_for_established_connection<THandler>(
                             })
                             .await;
                     }
-                    Ok(connection::Event::AddressChange(new_address)) => {
+                    Ok(connection::Event::AddressChange {
+                        old_address,
+                        new_address,
+                    }) => {
                         let _ = events
                             .send(EstablishedConnectionEvent::AddressChange {
                                 id: connection_id,
                                 peer_id,
+                                old_address,
                                 new_address,
                             })
                             .await;
                     }
+                    Ok(connection::Event::Metrics(metrics)) => {
+                        let _ = events
+                            .send(EstablishedConnectionEvent::Metrics {
+                                id: connection_id,
+                                peer_id,
+                                metrics,
+                            })
+                            .await;
+                    }
+                    Ok(connection::Event::CloseRequested { reason }) => {
+                        tracing::debug!(connection=%connection_id, "Handler closes connection: {reason}");
+
+                        command_receiver.close();
+                        close(connection_id, peer_id, connection, events, Some(reason)).await;
+                        return;
+                    }
                     Err(error) => {
+                        debug_assert_eq!(error.peer_id(), peer_id);
+                        tracing::debug!(
+                            connection=%connection_id,
+                            abandoned_substreams=%error.abandoned_substreams(),
+                            snapshot=?error.snapshot(),
+                            "{error}"
+                        );
+
                         command_receiver.close();
-                        let (remaining_events, _closing_muxer) = connection.close();
+                        let (remaining_events, _closing_muxer, _abandoned_requests) =
+                            connection.close();
 
                         let _ = events
                             .send_all(&mut remaining_events.map(|event| {
@@ -258,7 +289,8 @@ pub(crate) async fn new_for_established_connection<THandler>(
                             .send(EstablishedConnectionEvent::Closed {
                                 id: connection_id,
                                 peer_id,
-                                error: Some(error),
+                                error: Some(error.into_error()),
+                                reason: None,
                             })
                             .await;
                         return;
@@ -268,3 +300,53 @@ pub(crate) async fn new_for_established_connection<THandler>(
         }
     }
 }
+
+/// Gracefully closes the connection (active close) and reports it as closed.
+async fn close<THandler>(
+    connection_id: ConnectionId,
+    peer_id: PeerId,
+    connection: crate::connection::Connection<THandler>,
+    mut events: mpsc::Sender<EstablishedConnectionEvent<THandler::ToBehaviour>>,
+    reason: Option<String>,
+) where
+    THandler: ConnectionHandler,
+{
+    let (remaining_events, closing_muxer, abandoned_requests) = connection.close();
+
+    if !abandoned_requests.is_empty() {
+        tracing::debug!(
+            connection=%connection_id,
+            "Abandoned {} pending outbound substream requests",
+            abandoned_requests.len()
+        );
+    }
+
+    let _ = events
+        .send_all(&mut remaining_events.map(|event| {
+            Ok(EstablishedConnectionEvent::Notify {
+                id: connection_id,
+                event,
+                peer_id,
+            })
+        }))
+        .await;
+
+    let error = closing_muxer.await.err().map(|error| {
+        tracing::debug!(
+            connection=%connection_id,
+            abandoned_substreams=%error.abandoned_substreams,
+            "Failed to close connection: {}",
+            error.source
+        );
+        ConnectionError::IO(error.source)
+    });
+
+    let _ = events
+        .send(EstablishedConnectionEvent::Closed {
+            id: connection_id,
+            peer_id,
+            error,
+            reason,
+        })
+        .await;
+}
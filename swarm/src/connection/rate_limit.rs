@@ -0,0 +1,119 @@
+use std::{
+    num::NonZeroU32,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::FutureExt;
+use web_time::Instant;
+
+use super::{Clock, Sleep};
+
+/// Limits the rate at which a connection accepts new inbound streams.
+///
+/// Implemented as a token bucket: Up to `streams` inbound streams are accepted at once, after
+/// which a new one is accepted every `per / streams`. Inbound streams exceeding the limit remain
+/// queued in the [`StreamMuxer`](libp2p_core::StreamMuxer), streams that are already negotiating
+/// are not affected.
+///
+/// ```
+/// # use std::{num::NonZeroU32, time::Duration};
+/// # use libp2p_swarm::InboundStreamRateLimit;
+/// // Accept at most 50 new inbound streams per second.
+/// let limit = InboundStreamRateLimit::new(NonZeroU32::new(50).unwrap(), Duration::from_secs(1));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InboundStreamRateLimit {
+    streams: NonZeroU32,
+    per: Duration,
+}
+
+impl InboundStreamRateLimit {
+    /// Accept at most `streams` new inbound streams per `per`.
+    pub fn new(streams: NonZeroU32, per: Duration) -> Self {
+        Self { streams, per }
+    }
+
+    /// The time it takes to refill a single token.
+    fn refill_interval(&self) -> Duration {
+        self.per / self.streams.get()
+    }
+}
+
+/// The token bucket of a single connection.
+pub(crate) struct InboundStreamRateLimiter {
+    limit: InboundStreamRateLimit,
+    tokens: u32,
+    /// When the last token was refilled, according to the clock of the connection.
+    last_refill: Instant,
+    /// Wakes the connection once the next token is refilled, if the bucket is empty.
+    timer: Option<Sleep>,
+}
+
+impl InboundStreamRateLimiter {
+    pub(crate) fn new(limit: InboundStreamRateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.streams.get(),
+            last_refill: now,
+            timer: None,
+        }
+    }
+
+    /// Whether a new inbound stream may be accepted.
+    ///
+    /// If not, the current task is woken once the next token is refilled according to the
+    /// `clock`.
+    pub(crate) fn poll_ready(&mut self, clock: &dyn Clock, cx: &mut Context<'_>) -> bool {
+        let now = clock.now();
+        self.refill(now);
+
+        if self.tokens > 0 {
+            self.timer = None;
+            return true;
+        }
+
+        let timer = self.timer.get_or_insert_with(|| {
+            Sleep::new(
+                clock,
+                (self.last_refill + self.limit.refill_interval()).saturating_duration_since(now),
+            )
+        });
+        if let Poll::Ready(()) = timer.poll_unpin(cx) {
+            self.timer = None;
+            cx.waker().wake_by_ref();
+        }
+
+        false
+    }
+
+    /// Takes a token for an accepted inbound stream.
+    pub(crate) fn acquire(&mut self) {
+        debug_assert!(self.tokens > 0, "`poll_ready` returned `true`");
+        self.tokens = self.tokens.saturating_sub(1);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let capacity = self.limit.streams.get();
+        if self.tokens >= capacity {
+            self.last_refill = now;
+            return;
+        }
+
+        let interval = self.limit.refill_interval();
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let refilled = elapsed.as_nanos() / interval.as_nanos().max(1);
+        if refilled == 0 {
+            return;
+        }
+
+        let refilled = u32::try_from(refilled).unwrap_or(u32::MAX);
+        self.tokens = self.tokens.saturating_add(refilled).min(capacity);
+        self.last_refill = if self.tokens == capacity {
+            now
+        } else {
+            // Keep the fraction of the next token that already elapsed.
+            self.last_refill + interval * refilled
+        };
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::FutureExt;
+use web_time::Instant;
+
+use super::{Clock, PendingShutdownReason, PlannedShutdown, Sleep};
+
+/// The options for a planned connection & handler shutdown.
+///
+/// A shutdown is planned anew based on the return value of
+/// [`ConnectionHandler::connection_keep_alive`] of the underlying handler
+/// after every invocation of [`ConnectionHandler::poll`].
+///
+/// A planned shutdown is always postponed for as long as there are ingoing
+/// or outgoing substreams being negotiated, i.e. it is a graceful, "idle"
+/// shutdown.
+///
+/// [`ConnectionHandler::connection_keep_alive`]: crate::ConnectionHandler::connection_keep_alive
+/// [`ConnectionHandler::poll`]: crate::ConnectionHandler::poll
+#[derive(Debug)]
+pub(crate) enum Shutdown {
+    /// No shutdown is planned.
+    None,
+    /// A shut down is planned as soon as possible.
+    Asap,
+    /// A shut down is planned for when the `deadline` has passed.
+    ///
+    /// The `timer` wakes up the connection once the `deadline` has passed according to the
+    /// [`Clock`].
+    Later { deadline: Instant, timer: Sleep },
+    /// The [`ConnectionHandler`](crate::ConnectionHandler) requested to close the connection once
+    /// the negotiating substreams drained, see
+    /// [`ConnectionHandlerEvent::CloseGracefully`](crate::ConnectionHandlerEvent::CloseGracefully).
+    Graceful { reason: String },
+}
+
+impl Shutdown {
+    /// Plans the shutdown of an idle connection anew and returns whether it is due.
+    ///
+    /// A `scheduled` shutdown takes precedence over the `keep_alive` decision and the
+    /// `idle_timeout`. If the shutdown is not due yet, the current task is woken once it is
+    /// according to the `clock`.
+    pub(crate) fn poll_idle(
+        &mut self,
+        keep_alive: bool,
+        scheduled: Option<ScheduledShutdown>,
+        idle_timeout: Duration,
+        clock: &dyn Clock,
+        cx: &mut Context<'_>,
+    ) -> bool {
+        let new_shutdown = match scheduled {
+            Some(scheduled) => scheduled.compute_new_shutdown(self, clock),
+            None => compute_new_shutdown(keep_alive, self, idle_timeout, clock),
+        };
+        if let Some(new_shutdown) = new_shutdown {
+            *self = new_shutdown;
+        }
+
+        match self {
+            Shutdown::None | Shutdown::Graceful { .. } => false,
+            Shutdown::Asap => true,
+            Shutdown::Later { deadline, .. } if clock.now() >= *deadline => true,
+            Shutdown::Later { timer, .. } => timer.poll_unpin(cx).is_ready(),
+        }
+    }
+
+    /// The planned shutdown as reported by [`Connection::debug_snapshot`].
+    ///
+    /// [`Connection::debug_snapshot`]: super::Connection::debug_snapshot
+    pub(crate) fn planned(&self, now: Instant) -> PlannedShutdown {
+        match self {
+            Shutdown::None => PlannedShutdown::None,
+            Shutdown::Asap => PlannedShutdown::Asap,
+            Shutdown::Later { deadline, .. } => PlannedShutdown::Later {
+                remaining: deadline.saturating_duration_since(now),
+            },
+            Shutdown::Graceful { reason } => PlannedShutdown::Graceful {
+                reason: reason.clone(),
+            },
+        }
+    }
+
+    /// Why the shutdown is planned, given the `scheduled` shutdown of the connection, see
+    /// [`Connection::pending_shutdown_reason`].
+    ///
+    /// [`Connection::pending_shutdown_reason`]: super::Connection::pending_shutdown_reason
+    pub(crate) fn pending_reason(
+        &self,
+        scheduled: Option<ScheduledShutdown>,
+        now: Instant,
+    ) -> Option<PendingShutdownReason> {
+        match self {
+            Shutdown::None => None,
+            Shutdown::Asap if matches!(scheduled, Some(ScheduledShutdown::Asap)) => {
+                Some(PendingShutdownReason::Scheduled { deadline: None })
+            }
+            Shutdown::Later { deadline, .. } if matches!(scheduled, Some(ScheduledShutdown::At(at)) if at == *deadline) => {
+                Some(PendingShutdownReason::Scheduled {
+                    deadline: Some(*deadline),
+                })
+            }
+            Shutdown::Asap => Some(PendingShutdownReason::IdleTimeoutElapsed),
+            Shutdown::Later { deadline, .. } if now >= *deadline => {
+                Some(PendingShutdownReason::IdleTimeoutElapsed)
+            }
+            Shutdown::Later { .. } => Some(PendingShutdownReason::IdleTimeout),
+            Shutdown::Graceful { .. } => Some(PendingShutdownReason::HandlerClose),
+        }
+    }
+}
+
+fn compute_new_shutdown(
+    handler_keep_alive: bool,
+    current_shutdown: &Shutdown,
+    idle_timeout: Duration,
+    clock: &dyn Clock,
+) -> Option<Shutdown> {
+    match (current_shutdown, handler_keep_alive) {
+        (_, false) if idle_timeout == Duration::ZERO => Some(Shutdown::Asap),
+        // Do nothing, i.e. let the shutdown timer continue to tick.
+        (Shutdown::Later { .. }, false) => None,
+        (_, false) => {
+            let now = clock.now();
+            let safe_keep_alive = checked_add_fraction(now, idle_timeout);
+
+            Some(Shutdown::Later {
+                deadline: now + safe_keep_alive,
+                timer: Sleep::new(clock, safe_keep_alive),
+            })
+        }
+        (_, true) => Some(Shutdown::None),
+    }
+}
+
+/// Repeatedly halves and adds the [`Duration`]
+/// to the [`Instant`] until [`Instant::checked_add`] succeeds.
+///
+/// [`Instant`] depends on the underlying platform and has a limit of which points in time it can
+/// represent. The [`Duration`] computed by the this function may not be the longest possible that
+/// we can add to `now` but it will work.
+pub(crate) fn checked_add_fraction(start: Instant, mut duration: Duration) -> Duration {
+    while start.checked_add(duration).is_none() {
+        tracing::debug!(start=?start, duration=?duration, "start + duration cannot be presented, halving duration");
+
+        duration /= 2;
+    }
+
+    duration
+}
+
+/// Shuts a connection down once idle regardless of its keep-alive, see
+/// [`Connection::schedule_shutdown`](super::Connection::schedule_shutdown).
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ScheduledShutdown {
+    Asap,
+    At(Instant),
+}
+
+impl ScheduledShutdown {
+    /// Computes the [`Shutdown`] to replace `current` with, if any.
+    fn compute_new_shutdown(self, current: &Shutdown, clock: &dyn Clock) -> Option<Shutdown> {
+        match (self, current) {
+            (ScheduledShutdown::Asap, Shutdown::Asap) => None,
+            (ScheduledShutdown::Asap, _) => Some(Shutdown::Asap),
+            (ScheduledShutdown::At(at), Shutdown::Later { deadline, .. }) if *deadline == at => {
+                None
+            }
+            (ScheduledShutdown::At(at), _) => Some(Shutdown::Later {
+                deadline: at,
+                timer: Sleep::new(clock, at.saturating_duration_since(clock.now())),
+            }),
+        }
+    }
+}
+
+/// Keeps a connection alive regardless of its [`ConnectionHandler`](crate::ConnectionHandler),
+/// see [`Connection::force_keep_alive_until`](super::Connection::force_keep_alive_until).
+pub(crate) struct ForcedKeepAlive {
+    /// Until when the connection is kept alive.
+    until: Instant,
+    /// Wakes up the connection once `until` has passed according to the [`Clock`].
+    timer: Sleep,
+}
+
+impl ForcedKeepAlive {
+    pub(crate) fn new(until: Instant, clock: &dyn Clock) -> Self {
+        Self {
+            until,
+            timer: Sleep::new(clock, until.saturating_duration_since(clock.now())),
+        }
+    }
+
+    /// Until when the connection is kept alive, or `None` once that passed at `now`.
+    pub(crate) fn active_until(&self, now: Instant) -> Option<Instant> {
+        (now < self.until).then_some(self.until)
+    }
+
+    /// Whether the connection is kept alive at `now`, removing the override once it passed.
+    ///
+    /// While active, the current task is woken once it passes according to the [`Clock`].
+    pub(crate) fn poll_active(
+        forced: &mut Option<Self>,
+        now: Instant,
+        cx: &mut Context<'_>,
+    ) -> bool {
+        match forced {
+            Some(forced) if now < forced.until => {
+                // Only registers the waker, `now` decides when the override ends.
+                let _: Poll<()> = forced.timer.poll_unpin(cx);
+                true
+            }
+            Some(_) => {
+                *forced = None;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::*;
+    use tracing_subscriber::EnvFilter;
+
+    use super::*;
+    use crate::connection::SystemClock;
+
+    #[test]
+    fn checked_add_fraction_can_add_u64_max() {
+        let _ = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .try_init();
+        let start = Instant::now();
+
+        let duration = checked_add_fraction(start, Duration::from_secs(u64::MAX));
+
+        assert!(start.checked_add(duration).is_some())
+    }
+
+    #[test]
+    fn compute_new_shutdown_does_not_panic() {
+        let _ = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .try_init();
+
+        #[derive(Debug)]
+        struct ArbitraryShutdown(Shutdown);
+
+        impl Clone for ArbitraryShutdown {
+            fn clone(&self) -> Self {
+                let shutdown = match self.0 {
+                    Shutdown::None => Shutdown::None,
+                    Shutdown::Asap => Shutdown::Asap,
+                    Shutdown::Later { deadline, .. } => Shutdown::Later {
+                        deadline,
+                        // compute_new_shutdown does not touch the delay. Delay does not
+                        // implement Clone. Thus use a placeholder delay.
+                        timer: Sleep::new(&SystemClock, Duration::from_secs(1)),
+                    },
+                    Shutdown::Graceful { ref reason } => Shutdown::Graceful {
+                        reason: reason.clone(),
+                    },
+                };
+
+                ArbitraryShutdown(shutdown)
+            }
+        }
+
+        impl Arbitrary for ArbitraryShutdown {
+            fn arbitrary(g: &mut Gen) -> Self {
+                let shutdown = match g.gen_range(1u8..4) {
+                    1 => Shutdown::None,
+                    2 => Shutdown::Asap,
+                    3 => {
+                        let delay = Duration::from_secs(u32::arbitrary(g) as u64);
+                        Shutdown::Later {
+                            deadline: Instant::now() + delay,
+                            timer: Sleep::new(&SystemClock, delay),
+                        }
+                    }
+                    _ => unreachable!(),
+                };
+
+                Self(shutdown)
+            }
+        }
+
+        fn prop(
+            handler_keep_alive: bool,
+            current_shutdown: ArbitraryShutdown,
+            idle_timeout: Duration,
+        ) {
+            compute_new_shutdown(
+                handler_keep_alive,
+                &current_shutdown.0,
+                idle_timeout,
+                &SystemClock,
+            );
+        }
+
+        QuickCheck::new().quickcheck(prop as fn(_, _, _));
+    }
+}
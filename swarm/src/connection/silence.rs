@@ -0,0 +1,57 @@
+use std::{
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::FutureExt;
+use web_time::Instant;
+
+use super::{Clock, ConnectionError, Sleep};
+
+/// Fails a connection once it was silent for too long, see
+/// [`ConnectionBuilder::max_connection_silence`](super::ConnectionBuilder::max_connection_silence).
+pub(crate) struct SilenceWatchdog {
+    /// For how long the connection may be silent.
+    max: Duration,
+    /// When the connection was last active.
+    last_activity: Instant,
+    /// Wakes up the connection once it may have been silent for `max` according to the
+    /// [`Clock`].
+    timer: Sleep,
+}
+
+impl SilenceWatchdog {
+    pub(crate) fn new(max: Duration, clock: &dyn Clock) -> Self {
+        Self {
+            max,
+            last_activity: clock.now(),
+            timer: Sleep::new(clock, max),
+        }
+    }
+
+    /// Records activity of the connection, if the watchdog is enabled.
+    pub(crate) fn on_activity(watchdog: &mut Option<Self>, clock: &dyn Clock) {
+        if let Some(watchdog) = watchdog {
+            watchdog.last_activity = clock.now();
+        }
+    }
+
+    /// Fails the connection once it has been silent for longer than `max`.
+    pub(crate) fn poll(
+        &mut self,
+        clock: &dyn Clock,
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionError> {
+        loop {
+            let silent_for = clock.now().saturating_duration_since(self.last_activity);
+            if silent_for >= self.max {
+                return Poll::Ready(ConnectionError::Silent { silent_for });
+            }
+            if self.timer.poll_unpin(cx).is_pending() {
+                return Poll::Pending;
+            }
+            // The timer was armed before the most recent activity, re-arm it for the remainder.
+            self.timer = Sleep::new(clock, self.max - silent_for);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use crate::ConnectionId;
+
+/// A point-in-time view of the internals of a [`Connection`](crate::Connection), for diagnostics.
+///
+/// See [`Connection::debug_snapshot`](crate::Connection::debug_snapshot). With the `serde`
+/// feature enabled, snapshots can be serialized, e.g. to attach them to a bug report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct ConnectionSnapshot {
+    /// The id of the connection.
+    pub id: ConnectionId,
+    /// The number of inbound streams currently negotiating.
+    pub negotiating_inbound: usize,
+    /// The number of outbound streams currently negotiating.
+    pub negotiating_outbound: usize,
+    /// The number of outbound streams requested by the handler, but not yet granted by the muxer.
+    pub requested_outbound: usize,
+    /// The planned shutdown of the connection.
+    pub shutdown: PlannedShutdown,
+    /// The protocols the handler listens on, sorted.
+    pub local_protocols: Vec<String>,
+    /// The protocols the remote reported to support, sorted.
+    pub remote_protocols: Vec<String>,
+    /// For how long the connection has been established.
+    pub age: Duration,
+}
+
+/// The shutdown planned for a connection, see [`ConnectionSnapshot::shutdown`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum PlannedShutdown {
+    /// No shutdown is planned, the connection is kept alive.
+    None,
+    /// The connection shuts down as soon as it is idle.
+    Asap,
+    /// The connection shuts down once it was idle for `remaining`.
+    Later {
+        /// The time left until the connection shuts down.
+        remaining: Duration,
+    },
+    /// The handler requested to close the connection once the negotiating streams drained.
+    Graceful {
+        /// The reason given by the handler.
+        reason: String,
+    },
+}
@@ -0,0 +1,42 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+};
+
+use crate::stream::{ResetSignal, StreamId, StreamTag};
+
+/// Tracks the streams of a connection that can be reset via
+/// [`Connection::reset_substream`](crate::Connection::reset_substream).
+#[derive(Default)]
+pub(crate) struct ResettableStreams {
+    next_id: u64,
+    streams: HashMap<StreamId, Weak<ResetSignal>>,
+}
+
+impl ResettableStreams {
+    /// Tags a new stream.
+    pub(crate) fn register(&mut self) -> StreamTag {
+        // Forget dropped streams before the map grows, keeping the pruning amortized.
+        if self.streams.len() == self.streams.capacity() {
+            self.streams.retain(|_, signal| signal.strong_count() > 0);
+        }
+
+        let id = StreamId::new(self.next_id);
+        self.next_id += 1;
+        let signal = Arc::new(ResetSignal::default());
+        self.streams.insert(id, Arc::downgrade(&signal));
+
+        StreamTag { id, signal }
+    }
+
+    /// Resets the stream, returning whether it was still alive.
+    pub(crate) fn reset(&mut self, id: StreamId) -> bool {
+        match self.streams.remove(&id).and_then(|signal| signal.upgrade()) {
+            Some(signal) => {
+                signal.reset();
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, PoisonError, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use libp2p_core::{
+    muxing::SubstreamBox,
+    upgrade,
+    upgrade::{NegotiationError, ProtocolError},
+};
+use multistream_select::Negotiated;
+use web_time::Instant;
+
+use super::{Clock, Sleep};
+use crate::{
+    handler::UpgradeInfoSend,
+    stream::{ActiveStreamCounter, OpenStreamCounter, StreamTag},
+    upgrade::{InboundUpgradeSend, OutboundUpgradeSend},
+    Executor, Stream, StreamProtocol, StreamUpgradeError, SubstreamProtocol,
+};
+
+pub(crate) struct StreamUpgrade<UserData, TOk, TErr> {
+    pub(crate) user_data: Option<UserData>,
+    timeout: Sleep,
+    /// Resolves to the output of the upgrade, along with the negotiated protocol for new streams.
+    upgrade: BoxFuture<'static, Result<TOk, StreamUpgradeError<TErr>>>,
+}
+
+impl<UserData, TInfo, TOutput, TErr> StreamUpgrade<UserData, (TInfo, TOutput), TErr> {
+    #[expect(clippy::too_many_arguments)] // Threads through the options of the connection.
+    pub(crate) fn new_outbound<Upgrade>(
+        substream: SubstreamBox,
+        user_data: UserData,
+        timeout: Sleep,
+        upgrade: Upgrade,
+        version_override: Option<upgrade::Version>,
+        pre_negotiated: Option<StreamProtocol>,
+        counter: ActiveStreamCounter,
+        tag: Option<StreamTag>,
+        open_streams: Option<OpenStreamCounter>,
+    ) -> Self
+    where
+        Upgrade: OutboundUpgradeSend<Info = TInfo, Output = TOutput, Error = TErr>,
+        TInfo: AsRef<str> + Clone + Send + 'static,
+    {
+        let effective_version = effective_upgrade_version(version_override);
+        if effective_version != upgrade::Version::default() {
+            tracing::debug!(
+                "Substream upgrade protocol override: {:?} -> {:?}",
+                upgrade::Version::default(),
+                effective_version
+            );
+        }
+        let protocols = upgrade.protocol_info();
+
+        Self {
+            user_data: Some(user_data),
+            timeout,
+            upgrade: Box::pin(async move {
+                let (info, stream) = match pre_negotiated {
+                    Some(protocol) => pre_negotiated_stream(substream, protocols, &protocol)?,
+                    None => multistream_select::dialer_select_proto(
+                        substream,
+                        protocols,
+                        effective_version,
+                    )
+                    .await
+                    .map_err(|e| {
+                        tracing::debug!(
+                            protocols=?protocol_names(&upgrade),
+                            "Failed to negotiate outbound stream: {e}"
+                        );
+                        to_stream_upgrade_error(e)
+                    })?,
+                };
+
+                let output = upgrade
+                    .upgrade_outbound(
+                        Stream::new(stream, counter, tag, open_streams.as_ref()),
+                        info.clone(),
+                    )
+                    .await
+                    .map_err(StreamUpgradeError::Apply)?;
+
+                Ok((info, output))
+            }),
+        }
+    }
+}
+
+impl<UserData, TInfo, TOutput, TErr> StreamUpgrade<UserData, (TInfo, TOutput), TErr> {
+    /// Returns `None` if the upgrade does not support any protocol, e.g. a [`DeniedUpgrade`],
+    /// dropping the substream right away instead of negotiating it.
+    ///
+    /// [`DeniedUpgrade`]: libp2p_core::upgrade::DeniedUpgrade
+    #[expect(clippy::too_many_arguments)] // Threads through the options of the connection.
+    pub(crate) fn new_inbound<Upgrade>(
+        substream: SubstreamBox,
+        protocol: SubstreamProtocol<Upgrade, UserData>,
+        clock: &dyn Clock,
+        deadline: Option<Instant>,
+        rewriter: Option<ProtocolRewriter>,
+        listen_protocols: Option<Arc<RwLock<HashSet<String>>>>,
+        pre_negotiated: Option<StreamProtocol>,
+        counter: ActiveStreamCounter,
+        tag: Option<StreamTag>,
+        open_streams: Option<OpenStreamCounter>,
+    ) -> Option<Self>
+    where
+        Upgrade: InboundUpgradeSend<Info = TInfo, Output = TOutput, Error = TErr>,
+        TInfo: AsRef<str> + Clone + Send + 'static,
+    {
+        let timeout = cap_timeout(*protocol.timeout(), deadline, clock.now());
+        let (upgrade, open_info) = protocol.into_upgrade();
+        let mut protocols = upgrade
+            .protocol_info()
+            .map(move |info| RewrittenProtocol::new(info, rewriter.as_ref()))
+            .peekable();
+        if protocols.peek().is_none() {
+            tracing::trace!("Dropping inbound stream, handler does not accept any protocol");
+            return None;
+        }
+
+        Some(Self {
+            user_data: Some(open_info),
+            timeout: Sleep::new(clock, timeout),
+            upgrade: Box::pin(async move {
+                let (info, stream) = match pre_negotiated {
+                    Some(protocol) => pre_negotiated_stream(substream, protocols, &protocol)?,
+                    None => multistream_select::listener_select_proto(substream, protocols)
+                        .await
+                        .map_err(|e| {
+                            tracing::debug!(
+                                protocols=?protocol_names(&upgrade),
+                                "Failed to negotiate inbound stream: {e}"
+                            );
+                            to_stream_upgrade_error(e)
+                        })?,
+                };
+
+                // The handler may have stopped listening on the protocol while we negotiated it.
+                if listen_protocols.is_some_and(|protocols| {
+                    !protocols
+                        .read()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .contains(info.as_ref())
+                }) {
+                    tracing::debug!(
+                        protocol=%info.as_ref(),
+                        "Dropping inbound stream, protocol is no longer supported by the handler"
+                    );
+                    return Err(StreamUpgradeError::NegotiationFailed);
+                }
+
+                let output = upgrade
+                    .upgrade_inbound(
+                        Stream::new(stream, counter, tag, open_streams.as_ref()),
+                        info.info.clone(),
+                    )
+                    .await
+                    .map_err(StreamUpgradeError::Apply)?;
+
+                Ok((info.info, output))
+            }),
+        })
+    }
+}
+
+/// Picks the protocol among `protocols` that the substream was negotiated for by other means, see
+/// [`ConnectionBuilder::pre_negotiated_protocol`](super::ConnectionBuilder::pre_negotiated_protocol).
+fn pre_negotiated_stream<TInfo, TErr>(
+    substream: SubstreamBox,
+    protocols: impl IntoIterator<Item = TInfo>,
+    protocol: &StreamProtocol,
+) -> Result<(TInfo, Negotiated<SubstreamBox>), StreamUpgradeError<TErr>>
+where
+    TInfo: AsRef<str>,
+{
+    let Some(info) = protocols
+        .into_iter()
+        .find(|info| info.as_ref() == protocol.as_ref())
+    else {
+        tracing::debug!(%protocol, "Upgrade does not support the pre-negotiated protocol");
+        return Err(StreamUpgradeError::NegotiationFailed);
+    };
+
+    Ok((info, Negotiated::completed(substream)))
+}
+
+/// Caps `timeout` so that it expires by `deadline` at the latest, if any.
+pub(crate) fn cap_timeout(timeout: Duration, deadline: Option<Instant>, now: Instant) -> Duration {
+    match deadline {
+        Some(deadline) => timeout.min(deadline.saturating_duration_since(now)),
+        None => timeout,
+    }
+}
+
+/// The multistream-select version outbound substreams are negotiated with, given the override of
+/// the connection.
+pub(crate) fn effective_upgrade_version(
+    version_override: Option<upgrade::Version>,
+) -> upgrade::Version {
+    version_override.unwrap_or_default()
+}
+
+/// The names of the protocols offered by the given upgrade, for diagnostics.
+pub(crate) fn protocol_names(upgrade: &impl UpgradeInfoSend) -> Vec<String> {
+    upgrade
+        .protocol_info()
+        .map(|info| info.as_ref().to_owned())
+        .collect()
+}
+
+fn to_stream_upgrade_error<T>(e: NegotiationError) -> StreamUpgradeError<T> {
+    match e {
+        NegotiationError::Failed => StreamUpgradeError::NegotiationFailed,
+        NegotiationError::ProtocolError(ProtocolError::IoError(e)) => StreamUpgradeError::Io(e),
+        NegotiationError::ProtocolError(other) => {
+            StreamUpgradeError::Io(io::Error::new(io::ErrorKind::Other, other))
+        }
+    }
+}
+
+impl<UserData, TOk, TErr> StreamUpgrade<UserData, TOk, TErr>
+where
+    TOk: Send + 'static,
+    TErr: Send + 'static,
+{
+    /// Runs the negotiation on the given executor, leaving only the timeout to the connection.
+    ///
+    /// The negotiation is aborted once the [`StreamUpgrade`] is dropped, e.g. on timeout.
+    pub(crate) fn offload_to(mut self, executor: &NegotiationExecutor) -> Self {
+        let (negotiation, handle) = self.upgrade.remote_handle();
+        executor.0.exec(Box::pin(negotiation));
+        self.upgrade = handle.boxed();
+        self
+    }
+}
+
+/// The [`Executor`] substream negotiations are offloaded to, see
+/// [`ConnectionBuilder::negotiation_executor`](super::ConnectionBuilder::negotiation_executor).
+#[derive(Clone)]
+pub(crate) struct NegotiationExecutor(pub(crate) Arc<dyn Executor + Send + Sync>);
+
+impl fmt::Debug for NegotiationExecutor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NegotiationExecutor")
+    }
+}
+
+/// Rewrites the names of the protocols the handler listens on, see
+/// [`ConnectionBuilder::protocol_rewriter`](super::ConnectionBuilder::protocol_rewriter).
+#[derive(Clone)]
+pub(crate) struct ProtocolRewriter(pub(crate) Arc<dyn Fn(&str) -> Option<String> + Send + Sync>);
+
+impl fmt::Debug for ProtocolRewriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProtocolRewriter")
+    }
+}
+
+/// A protocol of the handler along with the name it is negotiated under, if rewritten by a
+/// [`ProtocolRewriter`].
+#[derive(Clone)]
+pub(crate) struct RewrittenProtocol<T> {
+    name: Option<String>,
+    pub(crate) info: T,
+}
+
+impl<T: AsRef<str>> RewrittenProtocol<T> {
+    pub(crate) fn new(info: T, rewriter: Option<&ProtocolRewriter>) -> Self {
+        Self {
+            name: rewriter.and_then(|ProtocolRewriter(rewrite)| rewrite(info.as_ref())),
+            info,
+        }
+    }
+}
+
+impl<T: AsRef<str>> AsRef<str> for RewrittenProtocol<T> {
+    fn as_ref(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.info.as_ref())
+    }
+}
+
+impl<UserData, TOk, TErr> Unpin for StreamUpgrade<UserData, TOk, TErr> {}
+
+impl<UserData, TOk, TErr> Future for StreamUpgrade<UserData, TOk, TErr> {
+    type Output = (UserData, Result<TOk, StreamUpgradeError<TErr>>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.timeout.poll_unpin(cx) {
+            Poll::Ready(()) => {
+                return Poll::Ready((
+                    self.user_data
+                        .take()
+                        .expect("Future not to be polled again once ready."),
+                    Err(StreamUpgradeError::Timeout),
+                ))
+            }
+
+            Poll::Pending => {}
+        }
+
+        let result = futures::ready!(self.upgrade.poll_unpin(cx));
+        let user_data = self
+            .user_data
+            .take()
+            .expect("Future not to be polled again once ready.");
+
+        Poll::Ready((user_data, result))
+    }
+}
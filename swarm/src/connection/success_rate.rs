@@ -0,0 +1,39 @@
+use std::collections::VecDeque;
+
+/// The outcomes of the most recent outbound stream negotiations of a connection, see
+/// [`Connection::outbound_success_rate`](super::Connection::outbound_success_rate).
+#[derive(Debug)]
+pub(crate) struct SuccessRate {
+    window: usize,
+    /// Oldest outcome first, `true` for a success.
+    outcomes: VecDeque<bool>,
+    successes: usize,
+}
+
+impl SuccessRate {
+    pub(crate) fn new(window: usize) -> Self {
+        Self {
+            window,
+            outcomes: VecDeque::new(),
+            successes: 0,
+        }
+    }
+
+    /// Records an outcome, evicting the oldest one once the window is full.
+    pub(crate) fn record(&mut self, success: bool) {
+        if self.outcomes.len() == self.window && self.outcomes.pop_front() == Some(true) {
+            self.successes -= 1;
+        }
+        self.outcomes.push_back(success);
+        self.successes += usize::from(success);
+    }
+
+    /// The share of successes among the recorded outcomes, `1.0` if none were recorded yet.
+    pub(crate) fn rate(&self) -> f32 {
+        if self.outcomes.is_empty() {
+            return 1.0;
+        }
+
+        self.successes as f32 / self.outcomes.len() as f32
+    }
+}
@@ -9,7 +9,10 @@ use libp2p_identity::PeerId;
 use crate::{
     behaviour::{FromSwarm, NetworkBehaviour, ToSwarm},
     connection::ConnectionId,
-    handler::{ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound},
+    handler::{
+        ConnectionEvent, ConnectionEventMask, DialUpgradeError, FullyNegotiatedInbound,
+        FullyNegotiatedOutbound,
+    },
     ConnectionDenied, ConnectionHandlerEvent, StreamUpgradeError, SubstreamProtocol, THandler,
     THandlerInEvent, THandlerOutEvent,
 };
@@ -77,6 +80,14 @@ impl crate::handler::ConnectionHandler for ConnectionHandler {
         SubstreamProtocol::new(DeniedUpgrade, ())
     }
 
+    fn wants_protocols_change(&self) -> bool {
+        false
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        ConnectionEventMask::NONE
+    }
+
     fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
         // TODO: remove when Rust 1.82 is MSRV
         #[allow(unreachable_patterns)]
@@ -110,7 +121,9 @@ impl crate::handler::ConnectionHandler for ConnectionHandler {
             ConnectionEvent::DialUpgradeError(DialUpgradeError { info: _, error }) => match error {
                 // TODO: remove when Rust 1.82 is MSRV
                 #[allow(unreachable_patterns)]
-                StreamUpgradeError::Timeout => unreachable!(),
+                StreamUpgradeError::Timeout | StreamUpgradeError::Throttled { .. } => {
+                    unreachable!()
+                }
                 StreamUpgradeError::Apply(e) => libp2p_core::util::unreachable(e),
                 StreamUpgradeError::NegotiationFailed | StreamUpgradeError::Io(_) => {
                     unreachable!("Denied upgrade does not support any protocols")
@@ -121,7 +134,12 @@ impl crate::handler::ConnectionHandler for ConnectionHandler {
             ConnectionEvent::AddressChange(_)
             | ConnectionEvent::ListenUpgradeError(_)
             | ConnectionEvent::LocalProtocolsChange(_)
-            | ConnectionEvent::RemoteProtocolsChange(_) => {}
+            | ConnectionEvent::RemoteProtocolsChange(_)
+            | ConnectionEvent::StreamHalfClosed
+            | ConnectionEvent::OutboundStreamsStalled
+            | ConnectionEvent::OutboundCapacity { .. }
+            | ConnectionEvent::EnteredIdle
+            | ConnectionEvent::ExitedIdle => {}
         }
     }
 }
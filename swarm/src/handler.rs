@@ -38,6 +38,7 @@
 //! > the network as a whole, see the
 //! > [`NetworkBehaviour`](crate::behaviour::NetworkBehaviour) trait.
 
+mod boxed;
 pub mod either;
 mod map_in;
 mod map_out;
@@ -49,12 +50,16 @@ mod select;
 use core::slice;
 use std::{
     collections::{HashMap, HashSet},
-    error, fmt, io,
+    error, fmt, io, ops,
     task::{Context, Poll},
     time::Duration,
 };
 
-use libp2p_core::Multiaddr;
+pub use boxed::{BoxedConnectionHandler, BoxedInboundUpgrade, BoxedOutboundUpgrade};
+use libp2p_core::{
+    upgrade::{NegotiationFailure, ProtocolError},
+    Multiaddr,
+};
 pub use map_in::MapInEvent;
 pub use map_out::MapOutEvent;
 pub use one_shot::{OneShotHandler, OneShotHandlerConfig};
@@ -157,6 +162,43 @@ pub trait ConnectionHandler: Send + 'static {
         false
     }
 
+    /// Whether the connection tracks the protocols of [`ConnectionHandler::listen_protocol`] and
+    /// reports changes via [`ConnectionEvent::LocalProtocolsChange`].
+    ///
+    /// Tracking requires collecting and comparing the protocols whenever the connection is idle.
+    /// Handlers that neither react to [`ConnectionEvent::LocalProtocolsChange`] nor change their
+    /// protocols at runtime can return `false` to skip this work. Inbound streams are then no
+    /// longer checked against the current protocols once negotiated. Leaving
+    /// [`ConnectionEventMask::LOCAL_PROTOCOLS_CHANGE`] out of
+    /// [`ConnectionHandler::interested_events`] merely skips dispatching the events.
+    ///
+    /// Defaults to `true`.
+    fn wants_protocols_change(&self) -> bool {
+        true
+    }
+
+    /// A counter that the handler increments whenever the protocols of
+    /// [`ConnectionHandler::listen_protocol`] change.
+    ///
+    /// If the handler provides a counter, the connection only collects and compares the protocols
+    /// once the counter changed, instead of whenever the connection is idle. Not consulted if the
+    /// handler opted out of tracking the protocols via
+    /// [`ConnectionHandler::wants_protocols_change`]. Defaults to `None`, i.e. no counter.
+    fn protocols_generation(&self) -> Option<u64> {
+        None
+    }
+
+    /// The kinds of informational [`ConnectionEvent`]s the handler wants to receive via
+    /// [`ConnectionHandler::on_connection_event`].
+    ///
+    /// The connection skips constructing and dispatching all other informational events. Events
+    /// that hand over a stream or the user data of a stream request are always dispatched.
+    ///
+    /// Defaults to [`ConnectionEventMask::ALL`].
+    fn interested_events(&self) -> ConnectionEventMask {
+        ConnectionEventMask::ALL
+    }
+
     /// Should behave like `Stream::poll()`.
     fn poll(
         &mut self,
@@ -222,6 +264,72 @@ pub trait ConnectionHandler: Send + 'static {
             Self::OutboundOpenInfo,
         >,
     );
+
+    /// Informs the handler that the remote address of the connection changed.
+    ///
+    /// Called right after [`ConnectionEvent::AddressChange`] was dispatched, sparing handlers
+    /// that only care about the address from matching on
+    /// [`ConnectionHandler::on_connection_event`]. Only called if
+    /// [`ConnectionHandler::interested_events`] contains [`ConnectionEventMask::ADDRESS_CHANGE`].
+    fn on_address_change(&mut self, _new_address: &Multiaddr) {}
+}
+
+/// A set of informational [`ConnectionEvent`] kinds, see
+/// [`ConnectionHandler::interested_events`].
+///
+/// ```
+/// # use libp2p_swarm::handler::ConnectionEventMask;
+/// let mask = ConnectionEventMask::ADDRESS_CHANGE | ConnectionEventMask::STREAM_HALF_CLOSED;
+///
+/// assert!(mask.contains(ConnectionEventMask::ADDRESS_CHANGE));
+/// assert!(!mask.contains(ConnectionEventMask::REMOTE_PROTOCOLS_CHANGE));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionEventMask(u8);
+
+impl ConnectionEventMask {
+    /// No informational events.
+    pub const NONE: Self = Self(0);
+    /// [`ConnectionEvent::AddressChange`].
+    pub const ADDRESS_CHANGE: Self = Self(1 << 0);
+    /// [`ConnectionEvent::LocalProtocolsChange`].
+    pub const LOCAL_PROTOCOLS_CHANGE: Self = Self(1 << 1);
+    /// [`ConnectionEvent::RemoteProtocolsChange`].
+    pub const REMOTE_PROTOCOLS_CHANGE: Self = Self(1 << 2);
+    /// [`ConnectionEvent::StreamHalfClosed`].
+    pub const STREAM_HALF_CLOSED: Self = Self(1 << 3);
+    /// [`ConnectionEvent::OutboundStreamsStalled`].
+    pub const OUTBOUND_STREAMS_STALLED: Self = Self(1 << 4);
+    /// [`ConnectionEvent::OutboundCapacity`].
+    pub const OUTBOUND_CAPACITY: Self = Self(1 << 5);
+    /// [`ConnectionEvent::EnteredIdle`] and [`ConnectionEvent::ExitedIdle`].
+    pub const IDLE_CHANGE: Self = Self(1 << 6);
+    /// All informational events.
+    pub const ALL: Self = Self(0b111_1111);
+
+    /// Returns the events contained in either `self` or `other`.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Whether all events of `other` are contained in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl ops::BitOr for ConnectionEventMask {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl ops::BitOrAssign for ConnectionEventMask {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = self.union(rhs);
+    }
 }
 
 /// Enumeration with the list of the possible stream events
@@ -242,14 +350,42 @@ pub enum ConnectionEvent<'a, IP: InboundUpgradeSend, OP: OutboundUpgradeSend, IO
     LocalProtocolsChange(ProtocolsChange<'a>),
     /// The remote [`ConnectionHandler`] now supports a different set of protocols.
     RemoteProtocolsChange(ProtocolsChange<'a>),
+    /// The remote half-closed one of the streams, i.e. it will not write to it anymore.
+    ///
+    /// The affected stream can still be written to and returns EOF when read from.
+    /// Only emitted if the [`StreamMuxer`](libp2p_core::StreamMuxer) of the connection reports
+    /// half-closed streams.
+    StreamHalfClosed,
+    /// The [`StreamMuxer`](libp2p_core::StreamMuxer) did not grant any of the requested outbound
+    /// streams for longer than the configured threshold.
+    ///
+    /// Reported once per stall. The handler may decide to close the connection instead of waiting
+    /// for the individual requests to time out.
+    OutboundStreamsStalled,
+    /// The [`StreamMuxer`](libp2p_core::StreamMuxer) ran out of or regained capacity for new
+    /// outbound streams.
+    ///
+    /// The capacity is only observed while outbound streams are requested and initially assumed to
+    /// be available. Handlers may hold back new requests while it is not, instead of queuing
+    /// requests that likely time out.
+    OutboundCapacity { available: bool },
+    /// The connection became idle, i.e. no streams are requested, negotiating or in use anymore.
+    ///
+    /// From now on, the connection is closed once the [`ConnectionHandler`] no longer keeps it
+    /// alive and the idle timeout expired. Only reported on the actual transition.
+    EnteredIdle,
+    /// The connection is no longer idle, see [`ConnectionEvent::EnteredIdle`].
+    ExitedIdle,
 }
 
 impl<IP, OP, IOI, OOI> fmt::Debug for ConnectionEvent<'_, IP, OP, IOI, OOI>
 where
     IP: InboundUpgradeSend + fmt::Debug,
+    IP::Info: fmt::Debug,
     IP::Output: fmt::Debug,
     IP::Error: fmt::Debug,
     OP: OutboundUpgradeSend + fmt::Debug,
+    OP::Info: fmt::Debug,
     OP::Output: fmt::Debug,
     OP::Error: fmt::Debug,
     IOI: fmt::Debug,
@@ -276,6 +412,14 @@ where
             ConnectionEvent::RemoteProtocolsChange(v) => {
                 f.debug_tuple("RemoteProtocolsChange").field(v).finish()
             }
+            ConnectionEvent::StreamHalfClosed => f.write_str("StreamHalfClosed"),
+            ConnectionEvent::OutboundStreamsStalled => f.write_str("OutboundStreamsStalled"),
+            ConnectionEvent::OutboundCapacity { available } => f
+                .debug_struct("OutboundCapacity")
+                .field("available", available)
+                .finish(),
+            ConnectionEvent::EnteredIdle => f.write_str("EnteredIdle"),
+            ConnectionEvent::ExitedIdle => f.write_str("ExitedIdle"),
         }
     }
 }
@@ -286,13 +430,17 @@ impl<IP: InboundUpgradeSend, OP: OutboundUpgradeSend, IOI, OOI>
     /// Whether the event concerns an outbound stream.
     pub fn is_outbound(&self) -> bool {
         match self {
-            ConnectionEvent::DialUpgradeError(_) | ConnectionEvent::FullyNegotiatedOutbound(_) => {
-                true
-            }
+            ConnectionEvent::DialUpgradeError(_)
+            | ConnectionEvent::FullyNegotiatedOutbound(_)
+            | ConnectionEvent::OutboundStreamsStalled
+            | ConnectionEvent::OutboundCapacity { .. } => true,
             ConnectionEvent::FullyNegotiatedInbound(_)
             | ConnectionEvent::AddressChange(_)
             | ConnectionEvent::LocalProtocolsChange(_)
             | ConnectionEvent::RemoteProtocolsChange(_)
+            | ConnectionEvent::StreamHalfClosed
+            | ConnectionEvent::EnteredIdle
+            | ConnectionEvent::ExitedIdle
             | ConnectionEvent::ListenUpgradeError(_) => false,
         }
     }
@@ -307,6 +455,11 @@ impl<IP: InboundUpgradeSend, OP: OutboundUpgradeSend, IOI, OOI>
             | ConnectionEvent::AddressChange(_)
             | ConnectionEvent::LocalProtocolsChange(_)
             | ConnectionEvent::RemoteProtocolsChange(_)
+            | ConnectionEvent::StreamHalfClosed
+            | ConnectionEvent::OutboundStreamsStalled
+            | ConnectionEvent::OutboundCapacity { .. }
+            | ConnectionEvent::EnteredIdle
+            | ConnectionEvent::ExitedIdle
             | ConnectionEvent::DialUpgradeError(_) => false,
         }
     }
@@ -324,6 +477,10 @@ impl<IP: InboundUpgradeSend, OP: OutboundUpgradeSend, IOI, OOI>
 pub struct FullyNegotiatedInbound<IP: InboundUpgradeSend, IOI = ()> {
     pub protocol: IP::Output,
     pub info: IOI,
+    /// The protocol that was selected by multistream-select, out of the
+    /// [`UpgradeInfo::protocol_info`](libp2p_core::upgrade::UpgradeInfo::protocol_info) of the
+    /// upgrade.
+    pub negotiated_protocol: IP::Info,
 }
 
 /// [`ConnectionEvent`] variant that informs the handler about successful upgrade on a new outbound
@@ -335,6 +492,10 @@ pub struct FullyNegotiatedInbound<IP: InboundUpgradeSend, IOI = ()> {
 pub struct FullyNegotiatedOutbound<OP: OutboundUpgradeSend, OOI = ()> {
     pub protocol: OP::Output,
     pub info: OOI,
+    /// The protocol that was selected by multistream-select, out of the
+    /// [`UpgradeInfo::protocol_info`](libp2p_core::upgrade::UpgradeInfo::protocol_info) of the
+    /// upgrade.
+    pub negotiated_protocol: OP::Info,
 }
 
 /// [`ConnectionEvent`] variant that informs the handler about a change in the address of the
@@ -531,6 +692,7 @@ pub struct SubstreamProtocol<TUpgrade, TInfo = ()> {
     upgrade: TUpgrade,
     info: TInfo,
     timeout: Duration,
+    priority: OutboundPriority,
 }
 
 impl<TUpgrade, TInfo> SubstreamProtocol<TUpgrade, TInfo> {
@@ -543,6 +705,7 @@ impl<TUpgrade, TInfo> SubstreamProtocol<TUpgrade, TInfo> {
             upgrade,
             info,
             timeout: Duration::from_secs(10),
+            priority: OutboundPriority::default(),
         }
     }
 
@@ -555,6 +718,7 @@ impl<TUpgrade, TInfo> SubstreamProtocol<TUpgrade, TInfo> {
             upgrade: f(self.upgrade),
             info: self.info,
             timeout: self.timeout,
+            priority: self.priority,
         }
     }
 
@@ -567,6 +731,7 @@ impl<TUpgrade, TInfo> SubstreamProtocol<TUpgrade, TInfo> {
             upgrade: self.upgrade,
             info: f(self.info),
             timeout: self.timeout,
+            priority: self.priority,
         }
     }
 
@@ -576,6 +741,16 @@ impl<TUpgrade, TInfo> SubstreamProtocol<TUpgrade, TInfo> {
         self
     }
 
+    /// Sets the priority of an outbound substream request.
+    ///
+    /// Once the muxer grants a new outbound substream, it is handed to the pending request with
+    /// the highest priority, and among those to the oldest one. Has no effect on inbound
+    /// substreams.
+    pub fn with_priority(mut self, priority: OutboundPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     /// Borrows the contained protocol upgrade.
     pub fn upgrade(&self) -> &TUpgrade {
         &self.upgrade
@@ -591,12 +766,29 @@ impl<TUpgrade, TInfo> SubstreamProtocol<TUpgrade, TInfo> {
         &self.timeout
     }
 
+    /// Returns the priority of an outbound substream request.
+    pub fn priority(&self) -> OutboundPriority {
+        self.priority
+    }
+
     /// Converts the substream protocol configuration into the contained upgrade.
     pub fn into_upgrade(self) -> (TUpgrade, TInfo) {
         (self.upgrade, self.info)
     }
 }
 
+/// Priority of an outbound substream request, see [`SubstreamProtocol::with_priority`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OutboundPriority {
+    /// Served after all other requests, e.g. for bulk transfers.
+    Low,
+    /// The default priority.
+    #[default]
+    Normal,
+    /// Served before all other requests, e.g. for latency-critical messages.
+    High,
+}
+
 /// Event produced by a handler.
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[non_exhaustive]
@@ -606,11 +798,35 @@ pub enum ConnectionHandlerEvent<TConnectionUpgrade, TOutboundOpenInfo, TCustom>
         /// The protocol(s) to apply on the substream.
         protocol: SubstreamProtocol<TConnectionUpgrade, TOutboundOpenInfo>,
     },
+    /// Request several new outbound substreams at once.
+    ///
+    /// Behaves like one [`ConnectionHandlerEvent::OutboundSubstreamRequest`] per protocol, but the
+    /// connection queues all of them in a single iteration and grants them together as far as the
+    /// stream muxer allows, e.g. for protocols that open many short-lived streams.
+    OutboundSubstreamRequestBatch {
+        /// The protocol(s) to apply on each of the substreams.
+        protocols: Vec<SubstreamProtocol<TConnectionUpgrade, TOutboundOpenInfo>>,
+    },
     /// We learned something about the protocols supported by the remote.
     ReportRemoteProtocols(ProtocolSupport),
 
     /// Event that is sent to a [`NetworkBehaviour`](crate::behaviour::NetworkBehaviour).
     NotifyBehaviour(TCustom),
+
+    /// Report counters of the handler, e.g. bytes sent or messages handled, to the
+    /// [`Swarm`](crate::Swarm).
+    ReportMetrics(MetricsDelta),
+
+    /// Close the connection because the protocol is done with it, as opposed to it failing.
+    ///
+    /// No new streams are accepted or opened anymore. Once the streams that are still
+    /// negotiating completed, the connection is closed like on
+    /// [`Swarm::close_connection`](crate::Swarm::close_connection) and the `reason` is reported
+    /// via [`SwarmEvent::ConnectionClosed`](crate::SwarmEvent::ConnectionClosed).
+    CloseGracefully {
+        /// Why the handler closes the connection.
+        reason: String,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -621,18 +837,81 @@ pub enum ProtocolSupport {
     Removed(HashSet<StreamProtocol>),
 }
 
+/// Labeled counter increments reported via [`ConnectionHandlerEvent::ReportMetrics`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsDelta {
+    deltas: SmallVec<[(&'static str, u64); 4]>,
+}
+
+impl MetricsDelta {
+    /// Label of the counter of inbound streams whose negotiation timed out.
+    ///
+    /// Reported by the connection itself rather than by the [`ConnectionHandler`]. A rising count
+    /// hints at remotes being slow to negotiate the streams they open.
+    pub const INBOUND_UPGRADE_TIMEOUTS: &'static str = "inbound_upgrade_timeouts";
+
+    /// Label of the counter of outbound streams whose negotiation timed out after the muxer
+    /// granted them.
+    ///
+    /// Reported by the connection itself rather than by the [`ConnectionHandler`].
+    pub const OUTBOUND_UPGRADE_TIMEOUTS: &'static str = "outbound_upgrade_timeouts";
+
+    /// Label of the counter of outbound stream requests that timed out before the muxer granted
+    /// them a stream.
+    ///
+    /// Reported by the connection itself rather than by the [`ConnectionHandler`]. A rising count
+    /// hints at the local muxer being slow to open streams.
+    pub const OUTBOUND_GRANT_TIMEOUTS: &'static str = "outbound_grant_timeouts";
+
+    /// Creates an empty [`MetricsDelta`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `delta` to the counter with the given `label`.
+    pub fn with(mut self, label: &'static str, delta: u64) -> Self {
+        self.add(label, delta);
+        self
+    }
+
+    /// Adds `delta` to the counter with the given `label`.
+    pub fn add(&mut self, label: &'static str, delta: u64) {
+        match self.deltas.iter_mut().find(|(l, _)| *l == label) {
+            Some((_, value)) => *value = value.saturating_add(delta),
+            None => self.deltas.push((label, delta)),
+        }
+    }
+
+    /// Returns the delta of the counter with the given `label`, if any.
+    pub fn get(&self, label: &str) -> Option<u64> {
+        self.deltas
+            .iter()
+            .find_map(|(l, value)| (*l == label).then_some(*value))
+    }
+
+    /// Iterates over all labeled deltas.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.deltas.iter().copied()
+    }
+
+    /// Whether no counters have been added.
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+}
+
 /// Event produced by a handler.
 impl<TConnectionUpgrade, TOutboundOpenInfo, TCustom>
     ConnectionHandlerEvent<TConnectionUpgrade, TOutboundOpenInfo, TCustom>
 {
-    /// If this is an `OutboundSubstreamRequest`, maps the `info` member from a
-    /// `TOutboundOpenInfo` to something else.
+    /// If this is an `OutboundSubstreamRequest` or `OutboundSubstreamRequestBatch`, maps the
+    /// `info` member(s) from a `TOutboundOpenInfo` to something else.
     pub fn map_outbound_open_info<F, I>(
         self,
-        map: F,
+        mut map: F,
     ) -> ConnectionHandlerEvent<TConnectionUpgrade, I, TCustom>
     where
-        F: FnOnce(TOutboundOpenInfo) -> I,
+        F: FnMut(TOutboundOpenInfo) -> I,
     {
         match self {
             ConnectionHandlerEvent::OutboundSubstreamRequest { protocol } => {
@@ -640,20 +919,37 @@ impl<TConnectionUpgrade, TOutboundOpenInfo, TCustom>
                     protocol: protocol.map_info(map),
                 }
             }
+            ConnectionHandlerEvent::OutboundSubstreamRequestBatch { protocols } => {
+                ConnectionHandlerEvent::OutboundSubstreamRequestBatch {
+                    protocols: protocols
+                        .into_iter()
+                        .map(|protocol| protocol.map_info(&mut map))
+                        .collect(),
+                }
+            }
             ConnectionHandlerEvent::NotifyBehaviour(val) => {
                 ConnectionHandlerEvent::NotifyBehaviour(val)
             }
             ConnectionHandlerEvent::ReportRemoteProtocols(support) => {
                 ConnectionHandlerEvent::ReportRemoteProtocols(support)
             }
+            ConnectionHandlerEvent::ReportMetrics(metrics) => {
+                ConnectionHandlerEvent::ReportMetrics(metrics)
+            }
+            ConnectionHandlerEvent::CloseGracefully { reason } => {
+                ConnectionHandlerEvent::CloseGracefully { reason }
+            }
         }
     }
 
-    /// If this is an `OutboundSubstreamRequest`, maps the protocol (`TConnectionUpgrade`)
-    /// to something else.
-    pub fn map_protocol<F, I>(self, map: F) -> ConnectionHandlerEvent<I, TOutboundOpenInfo, TCustom>
+    /// If this is an `OutboundSubstreamRequest` or `OutboundSubstreamRequestBatch`, maps the
+    /// protocol(s) (`TConnectionUpgrade`) to something else.
+    pub fn map_protocol<F, I>(
+        self,
+        mut map: F,
+    ) -> ConnectionHandlerEvent<I, TOutboundOpenInfo, TCustom>
     where
-        F: FnOnce(TConnectionUpgrade) -> I,
+        F: FnMut(TConnectionUpgrade) -> I,
     {
         match self {
             ConnectionHandlerEvent::OutboundSubstreamRequest { protocol } => {
@@ -661,16 +957,30 @@ impl<TConnectionUpgrade, TOutboundOpenInfo, TCustom>
                     protocol: protocol.map_upgrade(map),
                 }
             }
+            ConnectionHandlerEvent::OutboundSubstreamRequestBatch { protocols } => {
+                ConnectionHandlerEvent::OutboundSubstreamRequestBatch {
+                    protocols: protocols
+                        .into_iter()
+                        .map(|protocol| protocol.map_upgrade(&mut map))
+                        .collect(),
+                }
+            }
             ConnectionHandlerEvent::NotifyBehaviour(val) => {
                 ConnectionHandlerEvent::NotifyBehaviour(val)
             }
             ConnectionHandlerEvent::ReportRemoteProtocols(support) => {
                 ConnectionHandlerEvent::ReportRemoteProtocols(support)
             }
+            ConnectionHandlerEvent::ReportMetrics(metrics) => {
+                ConnectionHandlerEvent::ReportMetrics(metrics)
+            }
+            ConnectionHandlerEvent::CloseGracefully { reason } => {
+                ConnectionHandlerEvent::CloseGracefully { reason }
+            }
         }
     }
 
-    /// If this is a `Custom` event, maps the content to something else.
+    /// If this is a `NotifyBehaviour` event, maps the content to something else.
     pub fn map_custom<F, I>(
         self,
         map: F,
@@ -682,18 +992,44 @@ impl<TConnectionUpgrade, TOutboundOpenInfo, TCustom>
             ConnectionHandlerEvent::OutboundSubstreamRequest { protocol } => {
                 ConnectionHandlerEvent::OutboundSubstreamRequest { protocol }
             }
+            ConnectionHandlerEvent::OutboundSubstreamRequestBatch { protocols } => {
+                ConnectionHandlerEvent::OutboundSubstreamRequestBatch { protocols }
+            }
             ConnectionHandlerEvent::NotifyBehaviour(val) => {
                 ConnectionHandlerEvent::NotifyBehaviour(map(val))
             }
             ConnectionHandlerEvent::ReportRemoteProtocols(support) => {
                 ConnectionHandlerEvent::ReportRemoteProtocols(support)
             }
+            ConnectionHandlerEvent::ReportMetrics(metrics) => {
+                ConnectionHandlerEvent::ReportMetrics(metrics)
+            }
+            ConnectionHandlerEvent::CloseGracefully { reason } => {
+                ConnectionHandlerEvent::CloseGracefully { reason }
+            }
+        }
+    }
+
+    /// If this is a `CloseGracefully` event, maps the reason to something else, e.g. to tell
+    /// which of several combined handlers asked for the connection to be closed.
+    pub fn map_close<F>(self, map: F) -> Self
+    where
+        F: FnOnce(String) -> String,
+    {
+        match self {
+            ConnectionHandlerEvent::CloseGracefully { reason } => {
+                ConnectionHandlerEvent::CloseGracefully {
+                    reason: map(reason),
+                }
+            }
+            event => event,
         }
     }
 }
 
 /// Error that can happen on an outbound substream opening attempt.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum StreamUpgradeError<TUpgrErr> {
     /// The opening attempt timed out before the negotiation was fully completed.
     Timeout,
@@ -703,6 +1039,13 @@ pub enum StreamUpgradeError<TUpgrErr> {
     NegotiationFailed,
     /// An IO or otherwise unrecoverable error happened.
     Io(io::Error),
+    /// The stream was not requested, because upgrades of the same protocols failed repeatedly.
+    ///
+    /// See [`OutboundUpgradeBackoff`](crate::OutboundUpgradeBackoff).
+    Throttled {
+        /// How long requests for the protocols are still backed off.
+        retry_after: Duration,
+    },
 }
 
 impl<TUpgrErr> StreamUpgradeError<TUpgrErr> {
@@ -716,6 +1059,92 @@ impl<TUpgrErr> StreamUpgradeError<TUpgrErr> {
             StreamUpgradeError::Apply(e) => StreamUpgradeError::Apply(f(e)),
             StreamUpgradeError::NegotiationFailed => StreamUpgradeError::NegotiationFailed,
             StreamUpgradeError::Io(e) => StreamUpgradeError::Io(e),
+            StreamUpgradeError::Throttled { retry_after } => {
+                StreamUpgradeError::Throttled { retry_after }
+            }
+        }
+    }
+
+    /// Whether the stream could not be opened and negotiated in time.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, StreamUpgradeError::Timeout)
+    }
+
+    /// Whether the upgrade itself failed, i.e. this is [`StreamUpgradeError::Apply`].
+    pub fn is_upgrade_error(&self) -> bool {
+        matches!(self, StreamUpgradeError::Apply(_))
+    }
+
+    /// Returns the error of the upgrade, if the upgrade itself failed.
+    pub fn as_upgrade_error(&self) -> Option<&TUpgrErr> {
+        match self {
+            StreamUpgradeError::Apply(e) => Some(e),
+            StreamUpgradeError::Timeout
+            | StreamUpgradeError::NegotiationFailed
+            | StreamUpgradeError::Io(_)
+            | StreamUpgradeError::Throttled { .. } => None,
+        }
+    }
+
+    /// Classifies why the upgrade failed, without matching on the nested negotiation errors.
+    ///
+    /// Returns `None` if the negotiation never finished, i.e. for [`StreamUpgradeError::Timeout`]
+    /// and [`StreamUpgradeError::Throttled`].
+    pub fn classify(self) -> Option<NegotiationFailure<TUpgrErr>> {
+        match self {
+            StreamUpgradeError::NegotiationFailed => Some(NegotiationFailure::NoCommonProtocol),
+            StreamUpgradeError::Io(e) => match e.get_ref().map(|e| e.is::<ProtocolError>()) {
+                Some(true) => {
+                    let e = e
+                        .into_inner()
+                        .and_then(|e| e.downcast::<ProtocolError>().ok())
+                        .expect("checked to wrap a `ProtocolError`");
+                    Some(NegotiationFailure::ProtocolError(*e))
+                }
+                _ => Some(NegotiationFailure::Io(e)),
+            },
+            StreamUpgradeError::Apply(e) => Some(NegotiationFailure::ApplyFailed(e)),
+            StreamUpgradeError::Timeout | StreamUpgradeError::Throttled { .. } => None,
+        }
+    }
+
+    /// Why the stream failed during negotiation, if it failed with an IO error.
+    ///
+    /// See [`ResetCause`].
+    pub fn reset_cause(&self) -> Option<ResetCause> {
+        match self {
+            StreamUpgradeError::Io(e) => Some(ResetCause::from_io_error(e)),
+            StreamUpgradeError::Timeout
+            | StreamUpgradeError::Apply(_)
+            | StreamUpgradeError::NegotiationFailed
+            | StreamUpgradeError::Throttled { .. } => None,
+        }
+    }
+}
+
+/// Why a substream failed with an IO error while it was being negotiated.
+///
+/// Classified from the [`io::ErrorKind`] the stream muxer reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    /// The remote reset the stream ([`io::ErrorKind::ConnectionReset`]).
+    RemoteReset,
+    /// The stream muxer closed the stream because the connection is closing
+    /// ([`io::ErrorKind::BrokenPipe`], [`io::ErrorKind::NotConnected`] or
+    /// [`io::ErrorKind::ConnectionAborted`]).
+    ConnectionClosed,
+    /// Any other IO error.
+    Io,
+}
+
+impl ResetCause {
+    pub(crate) fn from_io_error(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionReset => ResetCause::RemoteReset,
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::ConnectionAborted => ResetCause::ConnectionClosed,
+            _ => ResetCause::Io,
         }
     }
 }
@@ -740,6 +1169,12 @@ where
                 write!(f, "IO error: ")?;
                 crate::print_error_chain(f, e)
             }
+            StreamUpgradeError::Throttled { retry_after } => {
+                write!(
+                    f,
+                    "outbound upgrades failed repeatedly, retry after {retry_after:?}"
+                )
+            }
         }
     }
 }
@@ -776,6 +1211,105 @@ mod test {
             .collect::<HashSet<_>>()
     }
 
+    #[test]
+    fn metrics_delta_accumulates_per_label() {
+        let mut metrics = MetricsDelta::new().with("a", 1).with("b", 2);
+        metrics.add("a", 3);
+        metrics.add("c", u64::MAX);
+        metrics.add("c", 1);
+
+        assert_eq!(metrics.get("a"), Some(4));
+        assert_eq!(metrics.get("b"), Some(2));
+        assert_eq!(metrics.get("c"), Some(u64::MAX));
+        assert_eq!(metrics.get("d"), None);
+        assert_eq!(metrics.iter().count(), 3);
+    }
+
+    type TestEvent = ConnectionHandlerEvent<&'static str, u8, u8>;
+
+    fn outbound_request(protocol: &'static str, info: u8) -> TestEvent {
+        ConnectionHandlerEvent::OutboundSubstreamRequest {
+            protocol: SubstreamProtocol::new(protocol, info),
+        }
+    }
+
+    fn notify(value: u8) -> TestEvent {
+        ConnectionHandlerEvent::NotifyBehaviour(value)
+    }
+
+    fn close(reason: &str) -> TestEvent {
+        ConnectionHandlerEvent::CloseGracefully {
+            reason: reason.to_owned(),
+        }
+    }
+
+    #[test]
+    fn map_custom_only_maps_notify_behaviour() {
+        let event = notify(1).map_custom(|v| v + 1);
+        assert_eq!(event, notify(2));
+
+        let event = outbound_request("/foo", 1).map_custom(|_| -> u8 { unreachable!() });
+        assert_eq!(event, outbound_request("/foo", 1));
+    }
+
+    #[test]
+    fn map_protocol_only_maps_outbound_request() {
+        let event = outbound_request("/foo", 1).map_protocol(|p| p.len());
+        let ConnectionHandlerEvent::OutboundSubstreamRequest { protocol } = event else {
+            panic!("unexpected event: {event:?}");
+        };
+        assert_eq!(protocol.into_upgrade(), (4, 1));
+
+        let event = close("done").map_protocol(|_| -> &str { unreachable!() });
+        assert_eq!(event, close("done"));
+    }
+
+    #[test]
+    fn map_outbound_open_info_only_maps_outbound_request() {
+        let event = outbound_request("/foo", 1).map_outbound_open_info(|i| i + 1);
+        assert_eq!(event, outbound_request("/foo", 2));
+
+        let event = notify(1).map_outbound_open_info(|_| -> u8 { unreachable!() });
+        assert_eq!(event, notify(1));
+    }
+
+    #[test]
+    fn map_close_only_maps_close_reason() {
+        let event = close("done").map_close(|reason| format!("inner: {reason}"));
+        assert_eq!(event, close("inner: done"));
+
+        let event = notify(1).map_close(|_| unreachable!());
+        assert_eq!(event, notify(1));
+    }
+
+    #[test]
+    fn stream_upgrade_error_classification() {
+        let errors = [
+            StreamUpgradeError::Timeout,
+            StreamUpgradeError::Apply("upgrade"),
+            StreamUpgradeError::NegotiationFailed,
+            StreamUpgradeError::Io(io::ErrorKind::Other.into()),
+            StreamUpgradeError::Throttled {
+                retry_after: Duration::from_secs(1),
+            },
+        ];
+        let classified = errors
+            .iter()
+            .map(|e| (e.is_timeout(), e.is_upgrade_error(), e.as_upgrade_error()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            classified,
+            [
+                (true, false, None),
+                (false, true, Some(&"upgrade")),
+                (false, false, None),
+                (false, false, None),
+                (false, false, None),
+            ]
+        );
+    }
+
     #[test]
     fn test_protocol_remove_subset() {
         let mut existing = protocol_set_of("a b c");
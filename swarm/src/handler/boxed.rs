@@ -0,0 +1,477 @@
+//! A [`ConnectionHandler`] with its types erased.
+
+use std::{
+    any::{self, Any},
+    fmt,
+    task::{Context, Poll},
+};
+
+use futures::{future::BoxFuture, FutureExt, TryFutureExt};
+use libp2p_core::{upgrade, Multiaddr};
+
+use crate::{
+    handler::{
+        ConnectionEvent, ConnectionEventMask, ConnectionHandler, ConnectionHandlerEvent,
+        DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound, InboundUpgradeSend,
+        ListenUpgradeError, OutboundUpgradeSend, SubstreamProtocol, UpgradeInfoSend,
+    },
+    Stream,
+};
+
+/// A [`ConnectionHandler`] with all its types erased, allowing to keep
+/// [`Connection`](crate::Connection)s with different handlers in one collection.
+///
+/// Events from and to the [`NetworkBehaviour`](crate::NetworkBehaviour) are boxed as [`Any`],
+/// use [`Box::downcast`] to recover the events of the inner handler. Events from the behaviour
+/// that are not of the type the inner handler expects are dropped. Negotiated streams and upgrade
+/// errors are unboxed before they reach the inner handler, which is thus unaware of the erasure.
+///
+/// ```
+/// # use libp2p_core::muxing::StreamMuxerBox;
+/// # use libp2p_identity::PeerId;
+/// # use libp2p_swarm::{
+/// #     dummy,
+/// #     handler::{BoxedConnectionHandler, PendingConnectionHandler},
+/// #     Connection, ConnectionBuilder,
+/// # };
+/// fn connections(
+///     peer_id: PeerId,
+///     a: StreamMuxerBox,
+///     b: StreamMuxerBox,
+/// ) -> Vec<Connection<BoxedConnectionHandler>> {
+///     vec![
+///         ConnectionBuilder::new(peer_id)
+///             .build(a, BoxedConnectionHandler::new(dummy::ConnectionHandler)),
+///         ConnectionBuilder::new(peer_id).build(
+///             b,
+///             BoxedConnectionHandler::new(PendingConnectionHandler::new("pending".to_owned())),
+///         ),
+///     ]
+/// }
+/// ```
+pub struct BoxedConnectionHandler {
+    inner: Box<dyn ErasedConnectionHandler>,
+}
+
+impl BoxedConnectionHandler {
+    /// Erases the types of the given handler.
+    pub fn new<H>(handler: H) -> Self
+    where
+        H: ConnectionHandler,
+    {
+        Self {
+            inner: Box::new(handler),
+        }
+    }
+
+    /// Returns a reference to the inner handler, if it is of type `H`.
+    pub fn downcast_ref<H>(&self) -> Option<&H>
+    where
+        H: ConnectionHandler,
+    {
+        self.inner.as_any().downcast_ref()
+    }
+}
+
+impl fmt::Debug for BoxedConnectionHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoxedConnectionHandler")
+            .field("inner", &self.inner.type_name())
+            .finish()
+    }
+}
+
+#[expect(deprecated)] // TODO: Remove when {In, Out}boundOpenInfo is fully removed.
+impl ConnectionHandler for BoxedConnectionHandler {
+    type FromBehaviour = Box<dyn Any + Send>;
+    type ToBehaviour = Box<dyn Any + Send>;
+    type InboundProtocol = BoxedInboundUpgrade;
+    type OutboundProtocol = BoxedOutboundUpgrade;
+    type InboundOpenInfo = Box<dyn Any + Send>;
+    type OutboundOpenInfo = Box<dyn Any + Send>;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        self.inner.listen_protocol()
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        self.inner.connection_keep_alive()
+    }
+
+    fn wants_protocols_change(&self) -> bool {
+        self.inner.wants_protocols_change()
+    }
+
+    fn protocols_generation(&self) -> Option<u64> {
+        self.inner.protocols_generation()
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        self.inner.interested_events()
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::ToBehaviour>,
+    > {
+        self.inner.poll(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Option<Self::ToBehaviour>> {
+        self.inner.poll_close(cx)
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        self.inner.on_behaviour_event(event);
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        self.inner.on_connection_event(event);
+    }
+
+    fn on_address_change(&mut self, new_address: &Multiaddr) {
+        self.inner.on_address_change(new_address);
+    }
+}
+
+/// Object-safe counterpart of [`ConnectionHandler`] with the types of
+/// [`BoxedConnectionHandler`].
+trait ErasedConnectionHandler: Send + 'static {
+    fn as_any(&self) -> &dyn Any;
+
+    fn type_name(&self) -> &'static str;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<BoxedInboundUpgrade, Box<dyn Any + Send>>;
+
+    fn connection_keep_alive(&self) -> bool;
+
+    fn wants_protocols_change(&self) -> bool;
+
+    fn protocols_generation(&self) -> Option<u64>;
+
+    fn interested_events(&self) -> ConnectionEventMask;
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<BoxedOutboundUpgrade, Box<dyn Any + Send>, Box<dyn Any + Send>>>;
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Option<Box<dyn Any + Send>>>;
+
+    fn on_behaviour_event(&mut self, event: Box<dyn Any + Send>);
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            BoxedInboundUpgrade,
+            BoxedOutboundUpgrade,
+            Box<dyn Any + Send>,
+            Box<dyn Any + Send>,
+        >,
+    );
+
+    fn on_address_change(&mut self, new_address: &Multiaddr);
+}
+
+impl<H> ErasedConnectionHandler for H
+where
+    H: ConnectionHandler,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn type_name(&self) -> &'static str {
+        any::type_name::<H>()
+    }
+
+    fn listen_protocol(&self) -> SubstreamProtocol<BoxedInboundUpgrade, Box<dyn Any + Send>> {
+        ConnectionHandler::listen_protocol(self)
+            .map_upgrade(BoxedInboundUpgrade::new)
+            .map_info(|info| Box::new(info) as Box<dyn Any + Send>)
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        ConnectionHandler::connection_keep_alive(self)
+    }
+
+    fn wants_protocols_change(&self) -> bool {
+        ConnectionHandler::wants_protocols_change(self)
+    }
+
+    fn protocols_generation(&self) -> Option<u64> {
+        ConnectionHandler::protocols_generation(self)
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        ConnectionHandler::interested_events(self)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<BoxedOutboundUpgrade, Box<dyn Any + Send>, Box<dyn Any + Send>>>
+    {
+        ConnectionHandler::poll(self, cx).map(|event| {
+            event
+                .map_protocol(BoxedOutboundUpgrade::new)
+                .map_outbound_open_info(|info| Box::new(info) as Box<dyn Any + Send>)
+                .map_custom(|event| Box::new(event) as Box<dyn Any + Send>)
+        })
+    }
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Option<Box<dyn Any + Send>>> {
+        ConnectionHandler::poll_close(self, cx)
+            .map(|event| event.map(|event| Box::new(event) as Box<dyn Any + Send>))
+    }
+
+    fn on_behaviour_event(&mut self, event: Box<dyn Any + Send>) {
+        match event.downcast::<H::FromBehaviour>() {
+            Ok(event) => ConnectionHandler::on_behaviour_event(self, *event),
+            Err(_) => tracing::warn!(
+                handler = %any::type_name::<H>(),
+                "Dropping behaviour event of unexpected type"
+            ),
+        }
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            BoxedInboundUpgrade,
+            BoxedOutboundUpgrade,
+            Box<dyn Any + Send>,
+            Box<dyn Any + Send>,
+        >,
+    ) {
+        let event = match event {
+            // The erased upgrades output the negotiated protocol of the handler along with the
+            // output of its upgrade.
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol,
+                info,
+                ..
+            }) => {
+                let (negotiated_protocol, protocol) = unbox(protocol);
+                ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                    protocol,
+                    info: unbox(info),
+                    negotiated_protocol,
+                })
+            }
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol,
+                info,
+                ..
+            }) => {
+                let (negotiated_protocol, protocol) = unbox(protocol);
+                ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                    protocol,
+                    info: unbox(info),
+                    negotiated_protocol,
+                })
+            }
+            ConnectionEvent::DialUpgradeError(DialUpgradeError { info, error }) => {
+                ConnectionEvent::DialUpgradeError(DialUpgradeError {
+                    info: unbox(info),
+                    error: error.map_upgrade_err(unbox),
+                })
+            }
+            ConnectionEvent::ListenUpgradeError(ListenUpgradeError { info, error }) => {
+                ConnectionEvent::ListenUpgradeError(ListenUpgradeError {
+                    info: unbox(info),
+                    error: unbox(error),
+                })
+            }
+            ConnectionEvent::AddressChange(address_change) => {
+                ConnectionEvent::AddressChange(address_change)
+            }
+            ConnectionEvent::LocalProtocolsChange(change) => {
+                ConnectionEvent::LocalProtocolsChange(change)
+            }
+            ConnectionEvent::RemoteProtocolsChange(change) => {
+                ConnectionEvent::RemoteProtocolsChange(change)
+            }
+            ConnectionEvent::StreamHalfClosed => ConnectionEvent::StreamHalfClosed,
+            ConnectionEvent::OutboundStreamsStalled => ConnectionEvent::OutboundStreamsStalled,
+            ConnectionEvent::OutboundCapacity { available } => {
+                ConnectionEvent::OutboundCapacity { available }
+            }
+            ConnectionEvent::EnteredIdle => ConnectionEvent::EnteredIdle,
+            ConnectionEvent::ExitedIdle => ConnectionEvent::ExitedIdle,
+        };
+
+        ConnectionHandler::on_connection_event(self, event);
+    }
+
+    fn on_address_change(&mut self, new_address: &Multiaddr) {
+        ConnectionHandler::on_address_change(self, new_address);
+    }
+}
+
+/// Recovers a value boxed by the [`ErasedConnectionHandler`] of the same handler, whose type is
+/// thus known.
+fn unbox<T: 'static>(value: Box<dyn Any + Send>) -> T {
+    *value
+        .downcast()
+        .expect("value to be boxed by the same handler")
+}
+
+/// Returns the info of `upgrade` with the given name.
+fn protocol_info_of<U>(upgrade: &U, protocol: &str) -> U::Info
+where
+    U: UpgradeInfoSend,
+{
+    upgrade
+        .protocol_info()
+        .find(|info| info.as_ref() == protocol)
+        .expect("negotiated protocol to be one of the protocols of the upgrade")
+}
+
+/// The inbound upgrade of a [`BoxedConnectionHandler`].
+pub struct BoxedInboundUpgrade {
+    inner: Box<dyn ErasedInboundUpgrade>,
+}
+
+impl BoxedInboundUpgrade {
+    fn new<U>(upgrade: U) -> Self
+    where
+        U: InboundUpgradeSend,
+    {
+        Self {
+            inner: Box::new(upgrade),
+        }
+    }
+}
+
+trait ErasedInboundUpgrade: Send + 'static {
+    fn protocol_info(&self) -> Vec<String>;
+
+    fn upgrade_inbound(
+        self: Box<Self>,
+        stream: Stream,
+        protocol: String,
+    ) -> BoxFuture<'static, Result<Box<dyn Any + Send>, Box<dyn Any + Send>>>;
+}
+
+impl<U> ErasedInboundUpgrade for U
+where
+    U: InboundUpgradeSend,
+{
+    fn protocol_info(&self) -> Vec<String> {
+        UpgradeInfoSend::protocol_info(self)
+            .map(|info| info.as_ref().to_owned())
+            .collect()
+    }
+
+    fn upgrade_inbound(
+        self: Box<Self>,
+        stream: Stream,
+        protocol: String,
+    ) -> BoxFuture<'static, Result<Box<dyn Any + Send>, Box<dyn Any + Send>>> {
+        let info = protocol_info_of(&*self, &protocol);
+
+        InboundUpgradeSend::upgrade_inbound(*self, stream, info.clone())
+            .map_ok(|output| Box::new((info, output)) as Box<dyn Any + Send>)
+            .map_err(|error| Box::new(error) as Box<dyn Any + Send>)
+            .boxed()
+    }
+}
+
+impl upgrade::UpgradeInfo for BoxedInboundUpgrade {
+    type Info = String;
+    type InfoIter = std::vec::IntoIter<String>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.inner.protocol_info().into_iter()
+    }
+}
+
+impl upgrade::InboundUpgrade<Stream> for BoxedInboundUpgrade {
+    type Output = Box<dyn Any + Send>;
+    type Error = Box<dyn Any + Send>;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, socket: Stream, info: String) -> Self::Future {
+        self.inner.upgrade_inbound(socket, info)
+    }
+}
+
+/// The outbound upgrade of a [`BoxedConnectionHandler`].
+pub struct BoxedOutboundUpgrade {
+    inner: Box<dyn ErasedOutboundUpgrade>,
+}
+
+impl BoxedOutboundUpgrade {
+    fn new<U>(upgrade: U) -> Self
+    where
+        U: OutboundUpgradeSend,
+    {
+        Self {
+            inner: Box::new(upgrade),
+        }
+    }
+}
+
+trait ErasedOutboundUpgrade: Send + 'static {
+    fn protocol_info(&self) -> Vec<String>;
+
+    fn upgrade_outbound(
+        self: Box<Self>,
+        stream: Stream,
+        protocol: String,
+    ) -> BoxFuture<'static, Result<Box<dyn Any + Send>, Box<dyn Any + Send>>>;
+}
+
+impl<U> ErasedOutboundUpgrade for U
+where
+    U: OutboundUpgradeSend,
+{
+    fn protocol_info(&self) -> Vec<String> {
+        UpgradeInfoSend::protocol_info(self)
+            .map(|info| info.as_ref().to_owned())
+            .collect()
+    }
+
+    fn upgrade_outbound(
+        self: Box<Self>,
+        stream: Stream,
+        protocol: String,
+    ) -> BoxFuture<'static, Result<Box<dyn Any + Send>, Box<dyn Any + Send>>> {
+        let info = protocol_info_of(&*self, &protocol);
+
+        OutboundUpgradeSend::upgrade_outbound(*self, stream, info.clone())
+            .map_ok(|output| Box::new((info, output)) as Box<dyn Any + Send>)
+            .map_err(|error| Box::new(error) as Box<dyn Any + Send>)
+            .boxed()
+    }
+}
+
+impl upgrade::UpgradeInfo for BoxedOutboundUpgrade {
+    type Info = String;
+    type InfoIter = std::vec::IntoIter<String>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.inner.protocol_info().into_iter()
+    }
+}
+
+impl upgrade::OutboundUpgrade<Stream> for BoxedOutboundUpgrade {
+    type Output = Box<dyn Any + Send>;
+    type Error = Box<dyn Any + Send>;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, socket: Stream, info: String) -> Self::Future {
+        self.inner.upgrade_outbound(socket, info)
+    }
+}
@@ -22,11 +22,12 @@ use std::task::{Context, Poll};
 
 use either::Either;
 use futures::future;
+use libp2p_core::Multiaddr;
 
 use crate::{
     handler::{
-        ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent, FullyNegotiatedInbound,
-        InboundUpgradeSend, ListenUpgradeError, SubstreamProtocol,
+        ConnectionEvent, ConnectionEventMask, ConnectionHandler, ConnectionHandlerEvent,
+        FullyNegotiatedInbound, InboundUpgradeSend, ListenUpgradeError, SubstreamProtocol,
     },
     upgrade::SendWrapper,
 };
@@ -44,11 +45,21 @@ where
             FullyNegotiatedInbound {
                 protocol: future::Either::Left(protocol),
                 info: Either::Left(info),
-            } => Either::Left(FullyNegotiatedInbound { protocol, info }),
+                negotiated_protocol: Either::Left(negotiated_protocol),
+            } => Either::Left(FullyNegotiatedInbound {
+                protocol,
+                info,
+                negotiated_protocol,
+            }),
             FullyNegotiatedInbound {
                 protocol: future::Either::Right(protocol),
                 info: Either::Right(info),
-            } => Either::Right(FullyNegotiatedInbound { protocol, info }),
+                negotiated_protocol: Either::Right(negotiated_protocol),
+            } => Either::Right(FullyNegotiatedInbound {
+                protocol,
+                info,
+                negotiated_protocol,
+            }),
             _ => unreachable!(),
         }
     }
@@ -119,6 +130,27 @@ where
         }
     }
 
+    fn wants_protocols_change(&self) -> bool {
+        match self {
+            Either::Left(handler) => handler.wants_protocols_change(),
+            Either::Right(handler) => handler.wants_protocols_change(),
+        }
+    }
+
+    fn protocols_generation(&self) -> Option<u64> {
+        match self {
+            Either::Left(handler) => handler.protocols_generation(),
+            Either::Right(handler) => handler.protocols_generation(),
+        }
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        match self {
+            Either::Left(handler) => handler.interested_events(),
+            Either::Right(handler) => handler.interested_events(),
+        }
+    }
+
     fn poll(
         &mut self,
         cx: &mut Context<'_>,
@@ -230,6 +262,45 @@ where
                     ConnectionEvent::RemoteProtocolsChange(supported_protocols),
                 ),
             },
+            ConnectionEvent::StreamHalfClosed => match self {
+                Either::Left(handler) => {
+                    handler.on_connection_event(ConnectionEvent::StreamHalfClosed)
+                }
+                Either::Right(handler) => {
+                    handler.on_connection_event(ConnectionEvent::StreamHalfClosed)
+                }
+            },
+            ConnectionEvent::OutboundStreamsStalled => match self {
+                Either::Left(handler) => {
+                    handler.on_connection_event(ConnectionEvent::OutboundStreamsStalled)
+                }
+                Either::Right(handler) => {
+                    handler.on_connection_event(ConnectionEvent::OutboundStreamsStalled)
+                }
+            },
+            ConnectionEvent::OutboundCapacity { available } => match self {
+                Either::Left(handler) => {
+                    handler.on_connection_event(ConnectionEvent::OutboundCapacity { available })
+                }
+                Either::Right(handler) => {
+                    handler.on_connection_event(ConnectionEvent::OutboundCapacity { available })
+                }
+            },
+            ConnectionEvent::EnteredIdle => match self {
+                Either::Left(handler) => handler.on_connection_event(ConnectionEvent::EnteredIdle),
+                Either::Right(handler) => handler.on_connection_event(ConnectionEvent::EnteredIdle),
+            },
+            ConnectionEvent::ExitedIdle => match self {
+                Either::Left(handler) => handler.on_connection_event(ConnectionEvent::ExitedIdle),
+                Either::Right(handler) => handler.on_connection_event(ConnectionEvent::ExitedIdle),
+            },
+        }
+    }
+
+    fn on_address_change(&mut self, new_address: &Multiaddr) {
+        match self {
+            Either::Left(handler) => handler.on_address_change(new_address),
+            Either::Right(handler) => handler.on_address_change(new_address),
         }
     }
 }
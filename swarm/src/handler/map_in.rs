@@ -24,8 +24,11 @@ use std::{
     task::{Context, Poll},
 };
 
+use libp2p_core::Multiaddr;
+
 use crate::handler::{
-    ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent, SubstreamProtocol,
+    ConnectionEvent, ConnectionEventMask, ConnectionHandler, ConnectionHandlerEvent,
+    SubstreamProtocol,
 };
 
 /// Wrapper around a protocol handler that turns the input event into something else.
@@ -77,6 +80,18 @@ where
         self.inner.connection_keep_alive()
     }
 
+    fn wants_protocols_change(&self) -> bool {
+        self.inner.wants_protocols_change()
+    }
+
+    fn protocols_generation(&self) -> Option<u64> {
+        self.inner.protocols_generation()
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        self.inner.interested_events()
+    }
+
     fn poll(
         &mut self,
         cx: &mut Context<'_>,
@@ -101,4 +116,8 @@ where
     ) {
         self.inner.on_connection_event(event);
     }
+
+    fn on_address_change(&mut self, new_address: &Multiaddr) {
+        self.inner.on_address_change(new_address);
+    }
 }
@@ -24,9 +24,11 @@ use std::{
 };
 
 use futures::ready;
+use libp2p_core::Multiaddr;
 
 use crate::handler::{
-    ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent, SubstreamProtocol,
+    ConnectionEvent, ConnectionEventMask, ConnectionHandler, ConnectionHandlerEvent,
+    SubstreamProtocol,
 };
 
 /// Wrapper around a protocol handler that turns the output event into something else.
@@ -70,6 +72,18 @@ where
         self.inner.connection_keep_alive()
     }
 
+    fn wants_protocols_change(&self) -> bool {
+        self.inner.wants_protocols_change()
+    }
+
+    fn protocols_generation(&self) -> Option<u64> {
+        self.inner.protocols_generation()
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        self.inner.interested_events()
+    }
+
     fn poll(
         &mut self,
         cx: &mut Context<'_>,
@@ -83,9 +97,18 @@ where
             ConnectionHandlerEvent::OutboundSubstreamRequest { protocol } => {
                 ConnectionHandlerEvent::OutboundSubstreamRequest { protocol }
             }
+            ConnectionHandlerEvent::OutboundSubstreamRequestBatch { protocols } => {
+                ConnectionHandlerEvent::OutboundSubstreamRequestBatch { protocols }
+            }
             ConnectionHandlerEvent::ReportRemoteProtocols(support) => {
                 ConnectionHandlerEvent::ReportRemoteProtocols(support)
             }
+            ConnectionHandlerEvent::ReportMetrics(metrics) => {
+                ConnectionHandlerEvent::ReportMetrics(metrics)
+            }
+            ConnectionHandlerEvent::CloseGracefully { reason } => {
+                ConnectionHandlerEvent::CloseGracefully { reason }
+            }
         })
     }
 
@@ -108,4 +131,8 @@ where
     ) {
         self.inner.on_connection_event(event);
     }
+
+    fn on_address_change(&mut self, new_address: &Multiaddr) {
+        self.inner.on_address_change(new_address);
+    }
 }
@@ -33,13 +33,14 @@ use std::{
 };
 
 use futures::{future::BoxFuture, prelude::*, ready};
+use libp2p_core::Multiaddr;
 use rand::Rng;
 
 use crate::{
     handler::{
-        AddressChange, ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent,
-        DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound, ListenUpgradeError,
-        SubstreamProtocol,
+        AddressChange, ConnectionEvent, ConnectionEventMask, ConnectionHandler,
+        ConnectionHandlerEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
+        ListenUpgradeError, SubstreamProtocol,
     },
     upgrade::{InboundUpgradeSend, OutboundUpgradeSend, UpgradeInfoSend},
     Stream,
@@ -158,12 +159,14 @@ where
             ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
                 protocol,
                 info: (key, arg),
+                negotiated_protocol,
             }) => {
                 if let Some(h) = self.handlers.get_mut(&key) {
                     h.on_connection_event(ConnectionEvent::FullyNegotiatedOutbound(
                         FullyNegotiatedOutbound {
                             protocol,
                             info: arg,
+                            negotiated_protocol,
                         },
                     ));
                 } else {
@@ -173,6 +176,7 @@ where
             ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
                 protocol: (key, arg),
                 mut info,
+                negotiated_protocol: IndexedProtoName(_, negotiated_protocol),
             }) => {
                 if let Some(h) = self.handlers.get_mut(&key) {
                     if let Some(i) = info.take(&key) {
@@ -180,6 +184,7 @@ where
                             FullyNegotiatedInbound {
                                 protocol: arg,
                                 info: i,
+                                negotiated_protocol,
                             },
                         ));
                     }
@@ -224,6 +229,31 @@ where
                     ));
                 }
             }
+            ConnectionEvent::StreamHalfClosed => {
+                for h in self.handlers.values_mut() {
+                    h.on_connection_event(ConnectionEvent::StreamHalfClosed);
+                }
+            }
+            ConnectionEvent::OutboundStreamsStalled => {
+                for h in self.handlers.values_mut() {
+                    h.on_connection_event(ConnectionEvent::OutboundStreamsStalled);
+                }
+            }
+            ConnectionEvent::OutboundCapacity { available } => {
+                for h in self.handlers.values_mut() {
+                    h.on_connection_event(ConnectionEvent::OutboundCapacity { available });
+                }
+            }
+            ConnectionEvent::EnteredIdle => {
+                for h in self.handlers.values_mut() {
+                    h.on_connection_event(ConnectionEvent::EnteredIdle);
+                }
+            }
+            ConnectionEvent::ExitedIdle => {
+                for h in self.handlers.values_mut() {
+                    h.on_connection_event(ConnectionEvent::ExitedIdle);
+                }
+            }
         }
     }
 
@@ -243,6 +273,25 @@ where
             .unwrap_or(false)
     }
 
+    fn wants_protocols_change(&self) -> bool {
+        self.handlers.values().any(|h| h.wants_protocols_change())
+    }
+
+    fn protocols_generation(&self) -> Option<u64> {
+        // The sum changes whenever one of the counters does.
+        self.handlers.values().try_fold(0u64, |sum, h| {
+            h.protocols_generation().map(|g| sum.wrapping_add(g))
+        })
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        self.handlers
+            .values()
+            .fold(ConnectionEventMask::NONE, |mask, h| {
+                mask | h.interested_events()
+            })
+    }
+
     fn poll(
         &mut self,
         cx: &mut Context<'_>,
@@ -290,6 +339,12 @@ where
 
         Poll::Ready(None)
     }
+
+    fn on_address_change(&mut self, new_address: &Multiaddr) {
+        for h in self.handlers.values_mut() {
+            h.on_address_change(new_address);
+        }
+    }
 }
 
 /// Split [`MultiHandler`] into parts.
@@ -189,7 +189,12 @@ where
             ConnectionEvent::AddressChange(_)
             | ConnectionEvent::ListenUpgradeError(_)
             | ConnectionEvent::LocalProtocolsChange(_)
-            | ConnectionEvent::RemoteProtocolsChange(_) => {}
+            | ConnectionEvent::RemoteProtocolsChange(_)
+            | ConnectionEvent::StreamHalfClosed
+            | ConnectionEvent::OutboundStreamsStalled
+            | ConnectionEvent::OutboundCapacity { .. }
+            | ConnectionEvent::EnteredIdle
+            | ConnectionEvent::ExitedIdle => {}
         }
     }
 }
@@ -27,8 +27,8 @@ use std::{
 use libp2p_core::upgrade::PendingUpgrade;
 
 use crate::handler::{
-    ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent, FullyNegotiatedInbound,
-    FullyNegotiatedOutbound, SubstreamProtocol,
+    ConnectionEvent, ConnectionEventMask, ConnectionHandler, ConnectionHandlerEvent,
+    FullyNegotiatedInbound, FullyNegotiatedOutbound, SubstreamProtocol,
 };
 
 /// Implementation of [`ConnectionHandler`] that returns a pending upgrade.
@@ -55,6 +55,14 @@ impl ConnectionHandler for PendingConnectionHandler {
         SubstreamProtocol::new(PendingUpgrade::new(self.protocol_name.clone()), ())
     }
 
+    fn wants_protocols_change(&self) -> bool {
+        false
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        ConnectionEventMask::NONE
+    }
+
     fn on_behaviour_event(&mut self, v: Self::FromBehaviour) {
         // TODO: remove when Rust 1.82 is MSRV
         #[allow(unreachable_patterns)]
@@ -83,6 +91,7 @@ impl ConnectionHandler for PendingConnectionHandler {
             ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
                 protocol,
                 info: _info,
+                ..
             }) => {
                 libp2p_core::util::unreachable(protocol);
                 #[allow(unreachable_code, clippy::used_underscore_binding)]
@@ -96,7 +105,12 @@ impl ConnectionHandler for PendingConnectionHandler {
             | ConnectionEvent::DialUpgradeError(_)
             | ConnectionEvent::ListenUpgradeError(_)
             | ConnectionEvent::LocalProtocolsChange(_)
-            | ConnectionEvent::RemoteProtocolsChange(_) => {}
+            | ConnectionEvent::RemoteProtocolsChange(_)
+            | ConnectionEvent::StreamHalfClosed
+            | ConnectionEvent::OutboundStreamsStalled
+            | ConnectionEvent::OutboundCapacity { .. }
+            | ConnectionEvent::EnteredIdle
+            | ConnectionEvent::ExitedIdle => {}
         }
     }
 }
@@ -25,13 +25,14 @@ use std::{
 
 use either::Either;
 use futures::{future, ready};
-use libp2p_core::upgrade::SelectUpgrade;
+use libp2p_core::{upgrade::SelectUpgrade, Multiaddr};
 
 use crate::{
     handler::{
-        AddressChange, ConnectionEvent, ConnectionHandler, ConnectionHandlerEvent,
-        DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound, InboundUpgradeSend,
-        ListenUpgradeError, OutboundUpgradeSend, StreamUpgradeError, SubstreamProtocol,
+        AddressChange, ConnectionEvent, ConnectionEventMask, ConnectionHandler,
+        ConnectionHandlerEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
+        InboundUpgradeSend, ListenUpgradeError, OutboundUpgradeSend, StreamUpgradeError,
+        SubstreamProtocol,
     },
     upgrade::SendWrapper,
 };
@@ -71,11 +72,21 @@ where
             FullyNegotiatedOutbound {
                 protocol: future::Either::Left(protocol),
                 info: Either::Left(info),
-            } => Either::Left(FullyNegotiatedOutbound { protocol, info }),
+                negotiated_protocol: Either::Left(negotiated_protocol),
+            } => Either::Left(FullyNegotiatedOutbound {
+                protocol,
+                info,
+                negotiated_protocol,
+            }),
             FullyNegotiatedOutbound {
                 protocol: future::Either::Right(protocol),
                 info: Either::Right(info),
-            } => Either::Right(FullyNegotiatedOutbound { protocol, info }),
+                negotiated_protocol: Either::Right(negotiated_protocol),
+            } => Either::Right(FullyNegotiatedOutbound {
+                protocol,
+                info,
+                negotiated_protocol,
+            }),
             _ => panic!("wrong API usage: the protocol doesn't match the upgrade info"),
         }
     }
@@ -94,11 +105,22 @@ where
             FullyNegotiatedInbound {
                 protocol: future::Either::Left(protocol),
                 info: (i1, _i2),
-            } => Either::Left(FullyNegotiatedInbound { protocol, info: i1 }),
+                negotiated_protocol: Either::Left(negotiated_protocol),
+            } => Either::Left(FullyNegotiatedInbound {
+                protocol,
+                info: i1,
+                negotiated_protocol,
+            }),
             FullyNegotiatedInbound {
                 protocol: future::Either::Right(protocol),
                 info: (_i1, i2),
-            } => Either::Right(FullyNegotiatedInbound { protocol, info: i2 }),
+                negotiated_protocol: Either::Right(negotiated_protocol),
+            } => Either::Right(FullyNegotiatedInbound {
+                protocol,
+                info: i2,
+                negotiated_protocol,
+            }),
+            _ => panic!("wrong API usage: the protocol doesn't match the upgrade info"),
         }
     }
 }
@@ -223,6 +245,23 @@ where
         )
     }
 
+    fn wants_protocols_change(&self) -> bool {
+        self.proto1.wants_protocols_change() || self.proto2.wants_protocols_change()
+    }
+
+    fn protocols_generation(&self) -> Option<u64> {
+        // The sum changes whenever one of the counters does.
+        Some(
+            self.proto1
+                .protocols_generation()?
+                .wrapping_add(self.proto2.protocols_generation()?),
+        )
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        self.proto1.interested_events() | self.proto2.interested_events()
+    }
+
     fn poll(
         &mut self,
         cx: &mut Context<'_>,
@@ -240,9 +279,27 @@ where
                         .map_info(Either::Left),
                 });
             }
+            Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequestBatch { protocols }) => {
+                return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequestBatch {
+                    protocols: protocols
+                        .into_iter()
+                        .map(|protocol| {
+                            protocol
+                                .map_upgrade(|u| Either::Left(SendWrapper(u)))
+                                .map_info(Either::Left)
+                        })
+                        .collect(),
+                });
+            }
             Poll::Ready(ConnectionHandlerEvent::ReportRemoteProtocols(support)) => {
                 return Poll::Ready(ConnectionHandlerEvent::ReportRemoteProtocols(support));
             }
+            Poll::Ready(ConnectionHandlerEvent::ReportMetrics(metrics)) => {
+                return Poll::Ready(ConnectionHandlerEvent::ReportMetrics(metrics));
+            }
+            Poll::Ready(ConnectionHandlerEvent::CloseGracefully { reason }) => {
+                return Poll::Ready(ConnectionHandlerEvent::CloseGracefully { reason });
+            }
             Poll::Pending => (),
         };
 
@@ -259,9 +316,27 @@ where
                         .map_info(Either::Right),
                 });
             }
+            Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequestBatch { protocols }) => {
+                return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequestBatch {
+                    protocols: protocols
+                        .into_iter()
+                        .map(|protocol| {
+                            protocol
+                                .map_upgrade(|u| Either::Right(SendWrapper(u)))
+                                .map_info(Either::Right)
+                        })
+                        .collect(),
+                });
+            }
             Poll::Ready(ConnectionHandlerEvent::ReportRemoteProtocols(support)) => {
                 return Poll::Ready(ConnectionHandlerEvent::ReportRemoteProtocols(support));
             }
+            Poll::Ready(ConnectionHandlerEvent::ReportMetrics(metrics)) => {
+                return Poll::Ready(ConnectionHandlerEvent::ReportMetrics(metrics));
+            }
+            Poll::Ready(ConnectionHandlerEvent::CloseGracefully { reason }) => {
+                return Poll::Ready(ConnectionHandlerEvent::CloseGracefully { reason });
+            }
             Poll::Pending => (),
         };
 
@@ -354,6 +429,39 @@ where
                         supported_protocols,
                     ));
             }
+            ConnectionEvent::StreamHalfClosed => {
+                self.proto1
+                    .on_connection_event(ConnectionEvent::StreamHalfClosed);
+                self.proto2
+                    .on_connection_event(ConnectionEvent::StreamHalfClosed);
+            }
+            ConnectionEvent::OutboundStreamsStalled => {
+                self.proto1
+                    .on_connection_event(ConnectionEvent::OutboundStreamsStalled);
+                self.proto2
+                    .on_connection_event(ConnectionEvent::OutboundStreamsStalled);
+            }
+            ConnectionEvent::OutboundCapacity { available } => {
+                self.proto1
+                    .on_connection_event(ConnectionEvent::OutboundCapacity { available });
+                self.proto2
+                    .on_connection_event(ConnectionEvent::OutboundCapacity { available });
+            }
+            ConnectionEvent::EnteredIdle => {
+                self.proto1
+                    .on_connection_event(ConnectionEvent::EnteredIdle);
+                self.proto2
+                    .on_connection_event(ConnectionEvent::EnteredIdle);
+            }
+            ConnectionEvent::ExitedIdle => {
+                self.proto1.on_connection_event(ConnectionEvent::ExitedIdle);
+                self.proto2.on_connection_event(ConnectionEvent::ExitedIdle);
+            }
         }
     }
+
+    fn on_address_change(&mut self, new_address: &Multiaddr) {
+        self.proto1.on_address_change(new_address);
+        self.proto2.on_address_change(new_address);
+    }
 }
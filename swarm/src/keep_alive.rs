@@ -0,0 +1,171 @@
+//! [`ConnectionHandler`](crate::ConnectionHandler)s that keep the connection alive without
+//! handling any protocols.
+//!
+//! Use them directly, or as the handler of the [`NetworkBehaviour`](crate::NetworkBehaviour) that
+//! is responsible for keeping a connection open, instead of writing an empty handler by hand.
+
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::FutureExt;
+use libp2p_core::upgrade::DeniedUpgrade;
+use web_time::Instant;
+
+use crate::{
+    connection::{checked_add_fraction, Sleep, SystemClock},
+    dummy,
+    handler::{ConnectionEvent, ConnectionEventMask},
+    Clock, ConnectionHandlerEvent, SubstreamProtocol,
+};
+
+/// An implementation of [`ConnectionHandler`](crate::ConnectionHandler) that doesn't handle any
+/// protocols but keeps the connection alive indefinitely, i.e. until it is closed explicitly or
+/// fails.
+///
+/// The handler is zero-sized and neither allocates nor registers any timers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionHandler;
+
+impl crate::handler::ConnectionHandler for ConnectionHandler {
+    type FromBehaviour = Infallible;
+    type ToBehaviour = Infallible;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        true
+    }
+
+    fn wants_protocols_change(&self) -> bool {
+        false
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        ConnectionEventMask::NONE
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        // TODO: remove when Rust 1.82 is MSRV
+        #[allow(unreachable_patterns)]
+        libp2p_core::util::unreachable(event)
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+        Poll::Pending
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+    ) {
+        dummy::ConnectionHandler.on_connection_event(event)
+    }
+}
+
+/// Creates a [`ForDuration`] handler that keeps the connection alive until `duration` has passed.
+///
+/// The duration starts when the handler is created, i.e. usually when the connection is
+/// established. It is measured by the clock of the operating system, see
+/// [`for_duration_with_clock`] for connections with a custom [`Clock`]. A `duration` too long to be
+/// represented is shortened, i.e. the connection is kept alive for as long as possible.
+pub fn for_duration(duration: Duration) -> ForDuration {
+    for_duration_with_clock(duration, Arc::new(SystemClock))
+}
+
+/// Like [`for_duration`], but measures the duration by the given [`Clock`].
+///
+/// A handler has no access to the [`Clock`] of its connection. Pass the one given to
+/// [`ConnectionBuilder::clock`](crate::ConnectionBuilder::clock) for the deadline to agree with
+/// the idle timeout of the connection.
+pub fn for_duration_with_clock(duration: Duration, clock: Arc<dyn Clock>) -> ForDuration {
+    let now = clock.now();
+    let duration = checked_add_fraction(now, duration);
+
+    ForDuration {
+        until: now + duration,
+        timer: Sleep::new(&*clock, duration),
+        clock,
+    }
+}
+
+/// An implementation of [`ConnectionHandler`](crate::ConnectionHandler) that doesn't handle any
+/// protocols but keeps the connection alive until a deadline, see [`for_duration`].
+///
+/// Once the deadline has passed, the connection shuts down after its idle timeout unless something
+/// else keeps it alive.
+#[derive(Debug)]
+pub struct ForDuration {
+    until: Instant,
+    /// Wakes up the connection once `until` has passed, for it to re-evaluate the keep-alive.
+    timer: Sleep,
+    /// Decides whether `until` has passed.
+    clock: Arc<dyn Clock>,
+}
+
+impl ForDuration {
+    /// The point in time until which the handler keeps the connection alive.
+    pub fn until(&self) -> Instant {
+        self.until
+    }
+}
+
+impl crate::handler::ConnectionHandler for ForDuration {
+    type FromBehaviour = Infallible;
+    type ToBehaviour = Infallible;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        self.clock.now() < self.until
+    }
+
+    fn wants_protocols_change(&self) -> bool {
+        false
+    }
+
+    fn interested_events(&self) -> ConnectionEventMask {
+        ConnectionEventMask::NONE
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        // TODO: remove when Rust 1.82 is MSRV
+        #[allow(unreachable_patterns)]
+        libp2p_core::util::unreachable(event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+        // Only registers the wake-up, the keep-alive is checked against `until`.
+        let _ = self.timer.poll_unpin(cx);
+
+        Poll::Pending
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+    ) {
+        dummy::ConnectionHandler.on_connection_event(event)
+    }
+}
@@ -65,7 +65,10 @@ pub mod behaviour;
 pub mod dial_opts;
 pub mod dummy;
 pub mod handler;
+pub mod keep_alive;
 mod listen_opts;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod translation;
 
 /// Bundles all symbols required for the [`libp2p_swarm_derive::NetworkBehaviour`] macro.
@@ -97,7 +100,7 @@ use std::{
     error, fmt, io,
     num::{NonZeroU32, NonZeroU8, NonZeroUsize},
     pin::Pin,
-    task::{Context, Poll},
+    task::{Context, Poll, Waker},
     time::Duration,
 };
 
@@ -107,7 +110,14 @@ pub use behaviour::{
     ListenerClosed, ListenerError, NetworkBehaviour, NewExternalAddrCandidate,
     NewExternalAddrOfPeer, NewListenAddr, NotifyHandler, PeerAddresses, ToSwarm,
 };
-pub use connection::{pool::ConnectionCounters, ConnectionError, ConnectionId, SupportedProtocols};
+pub use connection::{
+    pool::ConnectionCounters, Clock, Connection, ConnectionBuilder, ConnectionError, ConnectionId,
+    ConnectionSnapshot, Event as ConnectionPollEvent,
+    IdentifiedEvent as IdentifiedConnectionPollEvent, IdleProbe, InboundStreamRateLimit,
+    KeepAliveMetrics, LivenessReason, OutboundUpgradeBackoff, PeerConnectionError,
+    PendingShutdownReason, PlannedShutdown, ShutdownReason, SubstreamDirection,
+    SubstreamPollPriority, SupportedProtocols,
+};
 use connection::{
     pool::{EstablishedConnection, Pool, PoolConfig, PoolEvent},
     IncomingInfo, PendingConnectionError, PendingInboundConnectionError,
@@ -118,7 +128,7 @@ pub use executor::Executor;
 use futures::{prelude::*, stream::FusedStream};
 pub use handler::{
     ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerSelect, OneShotHandler,
-    OneShotHandlerConfig, StreamUpgradeError, SubstreamProtocol,
+    OneShotHandlerConfig, OutboundPriority, ResetCause, StreamUpgradeError, SubstreamProtocol,
 };
 use libp2p_core::{
     connection::ConnectedPoint,
@@ -131,11 +141,12 @@ use libp2p_identity::PeerId;
 pub use libp2p_swarm_derive::NetworkBehaviour;
 pub use listen_opts::ListenOpts;
 use smallvec::SmallVec;
-pub use stream::Stream;
+pub use stream::{Stream, StreamId};
 pub use stream_protocol::{InvalidProtocol, StreamProtocol};
 use tracing::Instrument;
 #[doc(hidden)]
 pub use translation::_address_translation;
+use web_time::Instant;
 
 use crate::{behaviour::ExternalAddrConfirmed, handler::UpgradeInfoSend};
 
@@ -191,6 +202,9 @@ pub enum SwarmEvent<TBehaviourOutEvent> {
         /// Reason for the disconnection, if it was not a successful
         /// active close.
         cause: Option<ConnectionError>,
+        /// The reason given by the [`ConnectionHandler`], if it closed the connection via
+        /// [`ConnectionHandlerEvent::CloseGracefully`].
+        reason: Option<String>,
     },
     /// A new connection arrived on a listener and is in the process of protocol negotiation.
     ///
@@ -287,6 +301,17 @@ pub enum SwarmEvent<TBehaviourOutEvent> {
     ExternalAddrExpired { address: Multiaddr },
     /// We have discovered a new address of a peer.
     NewExternalAddrOfPeer { peer_id: PeerId, address: Multiaddr },
+    /// The [`ConnectionHandler`] of a connection reported metrics via
+    /// [`ConnectionHandlerEvent::ReportMetrics`], or the connection counted upgrade timeouts, see
+    /// [`handler::MetricsDelta::INBOUND_UPGRADE_TIMEOUTS`] and its siblings.
+    ConnectionMetrics {
+        /// Identity of the peer that we are connected to.
+        peer_id: PeerId,
+        /// Identifier of the connection.
+        connection_id: ConnectionId,
+        /// The reported counters.
+        metrics: handler::MetricsDelta,
+    },
 }
 
 impl<TBehaviourOutEvent> SwarmEvent<TBehaviourOutEvent> {
@@ -663,6 +688,72 @@ where
         false
     }
 
+    /// Registers a [`Waker`] that is woken whenever the given connection becomes
+    /// idle, i.e. no substreams are requested, negotiating or in use.
+    ///
+    /// Replaces the waker previously registered for the connection. If the connection is already
+    /// idle, the waker is woken right away.
+    ///
+    /// # Returns
+    ///
+    /// - `true` if the connection is established and the waker is being registered.
+    /// - `false` if the connection was not found or is no longer established.
+    pub fn set_idle_waker(&mut self, connection_id: ConnectionId, waker: Waker) -> bool {
+        if let Some(established) = self.pool.get_established(connection_id) {
+            established.set_idle_waker(waker);
+            return true;
+        }
+
+        false
+    }
+
+    /// Reports a change to the protocols the remote of the given connection supports, e.g. as
+    /// learned via identify.
+    ///
+    /// The [`ConnectionHandler`] of the connection is notified via
+    /// [`ConnectionEvent::RemoteProtocolsChange`](handler::ConnectionEvent::RemoteProtocolsChange)
+    /// if the protocols changed.
+    ///
+    /// # Returns
+    ///
+    /// - `true` if the connection is established and the change is being reported.
+    /// - `false` if the connection was not found or is no longer established.
+    pub fn report_remote_protocols(
+        &mut self,
+        connection_id: ConnectionId,
+        support: handler::ProtocolSupport,
+    ) -> bool {
+        if let Some(established) = self.pool.get_established(connection_id) {
+            established.report_remote_protocols(support);
+            return true;
+        }
+
+        false
+    }
+
+    /// Shuts the given connection down at `when`, or as soon as possible if `None`, regardless of
+    /// whether its [`ConnectionHandler`] keeps it alive, e.g. to enforce a budget of connections.
+    ///
+    /// The shutdown is postponed while the connection is not idle. See
+    /// [`Connection::schedule_shutdown`].
+    ///
+    /// # Returns
+    ///
+    /// - `true` if the connection is established and the shutdown is being scheduled.
+    /// - `false` if the connection was not found or is no longer established.
+    pub fn schedule_shutdown(
+        &mut self,
+        connection_id: ConnectionId,
+        when: Option<Instant>,
+    ) -> bool {
+        if let Some(established) = self.pool.get_established(connection_id) {
+            established.schedule_shutdown(when);
+            return true;
+        }
+
+        false
+    }
+
     /// Checks whether there is an established connection to a peer.
     pub fn is_connected(&self, peer_id: &PeerId) -> bool {
         self.pool.is_connected(*peer_id)
@@ -875,6 +966,7 @@ where
                 id,
                 connected,
                 error,
+                reason,
                 remaining_established_connection_ids,
                 ..
             } => {
@@ -911,6 +1003,7 @@ where
                         connection_id: id,
                         endpoint,
                         cause: error,
+                        reason,
                         num_established,
                     });
             }
@@ -918,6 +1011,18 @@ where
                 self.behaviour
                     .on_connection_handler_event(peer_id, id, event);
             }
+            PoolEvent::ConnectionMetrics {
+                peer_id,
+                id,
+                metrics,
+            } => {
+                self.pending_swarm_events
+                    .push_back(SwarmEvent::ConnectionMetrics {
+                        peer_id,
+                        connection_id: id,
+                        metrics,
+                    });
+            }
             PoolEvent::AddressChange {
                 peer_id,
                 id,
@@ -949,7 +1054,7 @@ where
                 local_addr,
                 send_back_addr,
             } => {
-                let connection_id = ConnectionId::next();
+                let connection_id = self.pool.next_connection_id();
 
                 match self.behaviour.handle_pending_inbound_connection(
                     connection_id,
@@ -1493,6 +1598,41 @@ impl Config {
         self
     }
 
+    /// The order in which a connection grants outbound substream requests and accepts inbound
+    /// substreams from its [`StreamMuxerBox`].
+    ///
+    /// Defaults to [`SubstreamPollPriority::OutboundFirst`].
+    ///
+    /// Nodes mostly answering requests may want to favour inbound substreams to reduce the latency
+    /// of incoming requests, whereas nodes mostly issuing requests may want the opposite.
+    pub fn with_substream_poll_priority(mut self, priority: SubstreamPollPriority) -> Self {
+        self.pool_config = self.pool_config.with_substream_poll_priority(priority);
+        self
+    }
+
+    /// The maximum number of consecutive times a [`ConnectionHandler`] is polled before the
+    /// connection gives substream negotiation and the [`StreamMuxerBox`] a chance to make progress.
+    ///
+    /// Defaults to no limit.
+    ///
+    /// Setting a limit ensures that a [`ConnectionHandler`] which is always ready to produce events
+    /// cannot starve the negotiation of new substreams.
+    pub fn with_handler_poll_budget(mut self, budget: NonZeroUsize) -> Self {
+        self.pool_config = self.pool_config.with_handler_poll_budget(budget);
+        self
+    }
+
+    /// The maximum number of units of work a connection performs before yielding to other tasks.
+    ///
+    /// Defaults to 128.
+    ///
+    /// A connection whose [`ConnectionHandler`] and [`StreamMuxerBox`] are always ready would
+    /// otherwise never yield and could starve other connections sharing the same executor.
+    pub fn with_connection_poll_budget(mut self, budget: NonZeroUsize) -> Self {
+        self.pool_config = self.pool_config.with_poll_budget(budget);
+        self
+    }
+
     /// How long to keep a connection alive once it is idling.
     ///
     /// Defaults to 10s.
@@ -1777,7 +1917,10 @@ mod tests {
     use quickcheck::*;
 
     use super::*;
-    use crate::test::{CallTraceBehaviour, MockBehaviour};
+    use crate::{
+        connection::ConnectionIdAllocator,
+        test::{CallTraceBehaviour, MockBehaviour},
+    };
 
     // Test execution state.
     // Connection => Disconnecting => Connecting.
@@ -1905,6 +2048,47 @@ mod tests {
         .await
     }
 
+    #[tokio::test]
+    async fn local_connection_id_allocator_is_deterministic() {
+        let mut config = Config::with_tokio_executor();
+        config.pool_config = config
+            .pool_config
+            .with_connection_id_allocator(ConnectionIdAllocator::local());
+        let mut swarm1 = new_test_swarm(Config::with_tokio_executor());
+        let mut swarm2 = new_test_swarm(config);
+
+        let addr2: Multiaddr = multiaddr::Protocol::Memory(rand::random::<u64>()).into();
+        swarm2.listen_on(addr2.clone()).unwrap();
+        swarm1.dial(addr2.clone()).unwrap();
+        swarm1.dial(addr2).unwrap();
+
+        let mut incoming = Vec::new();
+        future::poll_fn(|cx| loop {
+            let poll1 = Swarm::poll_next_event(Pin::new(&mut swarm1), cx);
+            let poll2 = Swarm::poll_next_event(Pin::new(&mut swarm2), cx);
+
+            if let Poll::Ready(SwarmEvent::IncomingConnection { connection_id, .. }) = poll2 {
+                incoming.push(connection_id);
+                if incoming.len() == 2 {
+                    return Poll::Ready(());
+                }
+            }
+
+            if poll1.is_pending() && poll2.is_pending() {
+                return Poll::Pending;
+            }
+        })
+        .await;
+
+        assert_eq!(
+            incoming,
+            [
+                ConnectionId::new_unchecked(1),
+                ConnectionId::new_unchecked(2)
+            ]
+        );
+    }
+
     /// Establishes multiple connections between two peers,
     /// after which one peer disconnects the other
     /// using [`ToSwarm::CloseConnection`] returned by a [`NetworkBehaviour`].
@@ -2355,4 +2539,74 @@ mod tests {
         // that lead to multiple colons without text but that is the best we can do.
         assert_eq!("Failed to negotiate transport protocol(s): [(/ip4/127.0.0.1/tcp/80: : No listener on the given port.)]", string)
     }
+
+    #[test]
+    fn errors_chain_their_sources() {
+        fn sources(error: &dyn error::Error) -> Vec<String> {
+            std::iter::successors(error.source(), |e| e.source())
+                .map(ToString::to_string)
+                .collect()
+        }
+        let transport_error =
+            || TransportError::Other(io::Error::other(MemoryTransportError::Unreachable));
+        let endpoint = ConnectedPoint::Listener {
+            local_addr: Multiaddr::empty(),
+            send_back_addr: Multiaddr::empty(),
+        };
+
+        assert_eq!(
+            sources(&ConnectionError::IO(io::Error::other(
+                MemoryTransportError::Unreachable
+            ))),
+            ["No listener on the given port."]
+        );
+        assert!(sources(&ConnectionError::KeepAliveTimeout {
+            idle_for: Duration::ZERO
+        })
+        .is_empty());
+        assert!(sources(&ConnectionError::NegotiationDeadline {
+            deadline: Duration::ZERO
+        })
+        .is_empty());
+        assert!(sources(&ConnectionError::Silent {
+            silent_for: Duration::ZERO
+        })
+        .is_empty());
+
+        assert_eq!(
+            sources(&PendingInboundConnectionError::Transport(transport_error())),
+            ["", "No listener on the given port."],
+            "the transport error wraps the I/O error"
+        );
+        assert!(sources(&PendingInboundConnectionError::Aborted).is_empty());
+        assert!(sources(&PendingInboundConnectionError::WrongPeerId {
+            obtained: PeerId::random(),
+            endpoint: endpoint.clone(),
+        })
+        .is_empty());
+        assert!(sources(&PendingInboundConnectionError::LocalPeerId {
+            endpoint: endpoint.clone()
+        })
+        .is_empty());
+
+        assert_eq!(
+            sources(&ListenError::from(
+                PendingInboundConnectionError::Transport(transport_error())
+            ))
+            .len(),
+            2
+        );
+        assert_eq!(
+            sources(&ListenError::Denied {
+                cause: ConnectionDenied::new(MemoryTransportError::Unreachable)
+            }),
+            ["connection denied", "No listener on the given port."]
+        );
+        assert_eq!(
+            sources(&DialError::Denied {
+                cause: ConnectionDenied::new(MemoryTransportError::Unreachable)
+            }),
+            ["connection denied", "No listener on the given port."]
+        );
+    }
 }
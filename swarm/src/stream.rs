@@ -1,11 +1,15 @@
 use std::{
+    io,
     io::{IoSlice, IoSliceMut},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
     task::{Context, Poll},
 };
 
-use futures::{AsyncRead, AsyncWrite};
+use futures::{task::AtomicWaker, AsyncRead, AsyncWrite};
 use libp2p_core::{muxing::SubstreamBox, Negotiated};
 
 /// Counter for the number of active streams on a connection.
@@ -26,17 +30,105 @@ impl ActiveStreamCounter {
     }
 }
 
+/// Counter for the number of [`Stream`]s of a connection that were not dropped yet, including
+/// those ignored for keep-alive.
+///
+/// Every [`Stream`] holds a [`Weak`] reference to the counter until it is dropped.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OpenStreamCounter(Arc<()>);
+
+impl OpenStreamCounter {
+    pub(crate) fn guard(&self) -> Weak<()> {
+        Arc::downgrade(&self.0)
+    }
+
+    pub(crate) fn num_open_streams(&self) -> usize {
+        Arc::weak_count(&self.0)
+    }
+}
+
+/// Identifies a [`Stream`] among the streams of its connection, see [`Stream::id`].
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId(u64);
+
+impl StreamId {
+    pub(crate) fn new(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+/// Allows the connection to reset a [`Stream`] after handing it out.
+#[derive(Debug, Default)]
+pub(crate) struct ResetSignal {
+    reset: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl ResetSignal {
+    pub(crate) fn reset(&self) {
+        self.reset.store(true, Ordering::SeqCst);
+        self.waker.wake();
+    }
+}
+
+/// The id of a resettable [`Stream`] together with its [`ResetSignal`].
+#[derive(Debug)]
+pub(crate) struct StreamTag {
+    pub(crate) id: StreamId,
+    pub(crate) signal: Arc<ResetSignal>,
+}
+
 #[derive(Debug)]
 pub struct Stream {
-    stream: Negotiated<SubstreamBox>,
+    /// The underlying substream, dropped once the stream is reset.
+    stream: Option<Negotiated<SubstreamBox>>,
     counter: Option<ActiveStreamCounter>,
+    tag: Option<StreamTag>,
+    /// Counts the stream as open until it is dropped, if the connection tracks open streams.
+    _open: Option<Weak<()>>,
 }
 
 impl Stream {
-    pub(crate) fn new(stream: Negotiated<SubstreamBox>, counter: ActiveStreamCounter) -> Self {
+    pub(crate) fn new(
+        stream: Negotiated<SubstreamBox>,
+        counter: ActiveStreamCounter,
+        tag: Option<StreamTag>,
+        open: Option<&OpenStreamCounter>,
+    ) -> Self {
         Self {
-            stream,
+            stream: Some(stream),
             counter: Some(counter),
+            tag,
+            _open: open.map(OpenStreamCounter::guard),
+        }
+    }
+
+    /// The id of the stream, if the connection tracks its streams to be able to reset them.
+    ///
+    /// See [`ConnectionBuilder::resettable_streams`](crate::ConnectionBuilder::resettable_streams).
+    pub fn id(&self) -> Option<StreamId> {
+        self.tag.as_ref().map(|tag| tag.id)
+    }
+
+    /// Returns the underlying substream, unless the stream was reset by the connection.
+    fn substream(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> io::Result<Pin<&mut Negotiated<SubstreamBox>>> {
+        if let Some(tag) = &self.tag {
+            tag.signal.waker.register(cx.waker());
+            if tag.signal.reset.load(Ordering::SeqCst) {
+                // Dropping the substream resets it at the muxer.
+                self.stream = None;
+            }
+        }
+
+        match &mut self.stream {
+            Some(stream) => Ok(Pin::new(stream)),
+            None => Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "stream was reset by the connection",
+            )),
         }
     }
 
@@ -58,16 +150,22 @@ impl AsyncRead for Stream {
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut [u8],
-    ) -> Poll<std::io::Result<usize>> {
-        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut().substream(cx) {
+            Ok(stream) => stream.poll_read(cx, buf),
+            Err(e) => Poll::Ready(Err(e)),
+        }
     }
 
     fn poll_read_vectored(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         bufs: &mut [IoSliceMut<'_>],
-    ) -> Poll<std::io::Result<usize>> {
-        Pin::new(&mut self.get_mut().stream).poll_read_vectored(cx, bufs)
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut().substream(cx) {
+            Ok(stream) => stream.poll_read_vectored(cx, bufs),
+            Err(e) => Poll::Ready(Err(e)),
+        }
     }
 }
 
@@ -76,23 +174,35 @@ impl AsyncWrite for Stream {
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
-    ) -> Poll<std::io::Result<usize>> {
-        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut().substream(cx) {
+            Ok(stream) => stream.poll_write(cx, buf),
+            Err(e) => Poll::Ready(Err(e)),
+        }
     }
 
     fn poll_write_vectored(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         bufs: &[IoSlice<'_>],
-    ) -> Poll<std::io::Result<usize>> {
-        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut().substream(cx) {
+            Ok(stream) => stream.poll_write_vectored(cx, bufs),
+            Err(e) => Poll::Ready(Err(e)),
+        }
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut().substream(cx) {
+            Ok(stream) => stream.poll_flush(cx),
+            Err(e) => Poll::Ready(Err(e)),
+        }
     }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        Pin::new(&mut self.get_mut().stream).poll_close(cx)
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut().substream(cx) {
+            Ok(stream) => stream.poll_close(cx),
+            Err(e) => Poll::Ready(Err(e)),
+        }
     }
 }
@@ -0,0 +1,339 @@
+//! Utilities for testing [`Connection`]s and [`ConnectionHandler`]s.
+//!
+//! Only available with the `testing` feature.
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    fmt,
+    task::{Context, Poll, Waker},
+};
+
+use futures::{task::noop_waker_ref, StreamExt};
+use libp2p_core::Multiaddr;
+
+use crate::{
+    handler::{
+        ConnectionEvent, DialUpgradeError, FullyNegotiatedInbound, FullyNegotiatedOutbound,
+        ListenUpgradeError, ProtocolsChange,
+    },
+    upgrade::{InboundUpgradeSend, OutboundUpgradeSend},
+    Connection, ConnectionHandler, ConnectionHandlerEvent, ConnectionPollEvent,
+    PeerConnectionError, StreamProtocol, StreamUpgradeError, SubstreamProtocol,
+};
+
+/// Polls the [`Connection`] `steps` times with a no-op waker, returning the emitted events and the
+/// error the connection terminated with, if any.
+///
+/// Every poll counts as a step, regardless of whether it produced an event. Polling stops early
+/// once the connection terminated.
+///
+/// ```
+/// # use libp2p_core::muxing::StreamMuxerBox;
+/// # use libp2p_identity::PeerId;
+/// # use libp2p_swarm::{dummy, testing, ConnectionBuilder};
+/// # fn check(peer_id: PeerId, muxer: StreamMuxerBox) {
+/// let mut connection = ConnectionBuilder::new(peer_id).build(muxer, dummy::ConnectionHandler);
+///
+/// let (events, error) = testing::drive(&mut connection, 10);
+/// # }
+/// ```
+pub fn drive<THandler>(
+    connection: &mut Connection<THandler>,
+    steps: usize,
+) -> (
+    Vec<ConnectionPollEvent<THandler::ToBehaviour>>,
+    Option<PeerConnectionError>,
+)
+where
+    THandler: ConnectionHandler,
+{
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut events = Vec::new();
+
+    for _ in 0..steps {
+        match connection.poll_next_unpin(&mut cx) {
+            Poll::Ready(Some(Ok(event))) => events.push(event.into_event()),
+            Poll::Ready(Some(Err(error))) => return (events, Some(error)),
+            Poll::Ready(None) => break,
+            Poll::Pending => {}
+        }
+    }
+
+    (events, None)
+}
+
+/// A [`ConnectionHandler`] that emits a script of [`ConnectionHandlerEvent`]s and records all
+/// [`ConnectionEvent`]s and events of the behaviour it receives.
+///
+/// Listens on the given inbound upgrade and keeps the connection alive unless told otherwise.
+///
+/// ```
+/// # use libp2p_core::{muxing::StreamMuxerBox, upgrade::DeniedUpgrade};
+/// # use libp2p_identity::PeerId;
+/// # use libp2p_swarm::{testing, ConnectionBuilder, ConnectionHandlerEvent, ConnectionPollEvent};
+/// # fn check(peer_id: PeerId, muxer: StreamMuxerBox) {
+/// let mut handler = testing::ScriptedHandler::<DeniedUpgrade, DeniedUpgrade, u8>::new(DeniedUpgrade);
+/// handler.push_event(ConnectionHandlerEvent::NotifyBehaviour(1));
+/// let mut connection = ConnectionBuilder::new(peer_id).build(muxer, handler);
+///
+/// let (events, _) = testing::drive(&mut connection, 10);
+/// assert!(matches!(events[..], [ConnectionPollEvent::Handler(1)]));
+/// assert!(connection.handler().received().is_empty());
+/// # }
+/// ```
+pub struct ScriptedHandler<TInbound, TOutbound, TToBehaviour = (), TFromBehaviour = Infallible>
+where
+    TInbound: InboundUpgradeSend,
+    TOutbound: OutboundUpgradeSend,
+{
+    listen_protocol: TInbound,
+    script: VecDeque<ConnectionHandlerEvent<TOutbound, (), TToBehaviour>>,
+    received: Vec<RecordedEvent<TInbound, TOutbound>>,
+    behaviour_events: Vec<TFromBehaviour>,
+    keep_alive: bool,
+    waker: Option<Waker>,
+}
+
+impl<TInbound, TOutbound, TToBehaviour, TFromBehaviour>
+    ScriptedHandler<TInbound, TOutbound, TToBehaviour, TFromBehaviour>
+where
+    TInbound: InboundUpgradeSend,
+    TOutbound: OutboundUpgradeSend,
+{
+    /// Creates a handler that listens on `listen_protocol` and has nothing to emit yet.
+    pub fn new(listen_protocol: TInbound) -> Self {
+        Self {
+            listen_protocol,
+            script: VecDeque::new(),
+            received: Vec::new(),
+            behaviour_events: Vec::new(),
+            keep_alive: true,
+            waker: None,
+        }
+    }
+
+    /// Appends an event to the script, to be emitted once all earlier events were emitted.
+    pub fn push_event(&mut self, event: ConnectionHandlerEvent<TOutbound, (), TToBehaviour>) {
+        self.script.push_back(event);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Sets what [`ConnectionHandler::connection_keep_alive`] returns.
+    pub fn set_keep_alive(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    /// The [`ConnectionEvent`]s received so far, in order.
+    pub fn received(&self) -> &[RecordedEvent<TInbound, TOutbound>] {
+        &self.received
+    }
+
+    /// Takes the [`ConnectionEvent`]s received so far, e.g. to take ownership of the negotiated
+    /// streams.
+    pub fn take_received(&mut self) -> Vec<RecordedEvent<TInbound, TOutbound>> {
+        std::mem::take(&mut self.received)
+    }
+
+    /// The events of the behaviour received so far, in order.
+    pub fn behaviour_events(&self) -> &[TFromBehaviour] {
+        &self.behaviour_events
+    }
+}
+
+impl<TInbound, TOutbound, TToBehaviour, TFromBehaviour> ConnectionHandler
+    for ScriptedHandler<TInbound, TOutbound, TToBehaviour, TFromBehaviour>
+where
+    TInbound: InboundUpgradeSend + Clone,
+    TOutbound: OutboundUpgradeSend,
+    TToBehaviour: fmt::Debug + Send + 'static,
+    TFromBehaviour: fmt::Debug + Send + 'static,
+{
+    type FromBehaviour = TFromBehaviour;
+    type ToBehaviour = TToBehaviour;
+    type InboundProtocol = TInbound;
+    type OutboundProtocol = TOutbound;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+        SubstreamProtocol::new(self.listen_protocol.clone(), ())
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        self.behaviour_events.push(event);
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+        match self.script.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+    ) {
+        let recorded = match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol,
+                negotiated_protocol,
+                ..
+            }) => RecordedEvent::FullyNegotiatedInbound {
+                output: protocol,
+                negotiated_protocol,
+            },
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol,
+                negotiated_protocol,
+                ..
+            }) => RecordedEvent::FullyNegotiatedOutbound {
+                output: protocol,
+                negotiated_protocol,
+            },
+            ConnectionEvent::AddressChange(change) => {
+                RecordedEvent::AddressChange(change.new_address.clone())
+            }
+            ConnectionEvent::DialUpgradeError(DialUpgradeError { error, .. }) => {
+                RecordedEvent::DialUpgradeError(error)
+            }
+            ConnectionEvent::ListenUpgradeError(ListenUpgradeError { error, .. }) => {
+                RecordedEvent::ListenUpgradeError(error)
+            }
+            ConnectionEvent::LocalProtocolsChange(change) => {
+                RecordedEvent::LocalProtocolsChange(ProtocolsDelta::from(change))
+            }
+            ConnectionEvent::RemoteProtocolsChange(change) => {
+                RecordedEvent::RemoteProtocolsChange(ProtocolsDelta::from(change))
+            }
+            ConnectionEvent::StreamHalfClosed => RecordedEvent::StreamHalfClosed,
+            ConnectionEvent::OutboundStreamsStalled => RecordedEvent::OutboundStreamsStalled,
+            ConnectionEvent::OutboundCapacity { available } => {
+                RecordedEvent::OutboundCapacity { available }
+            }
+            ConnectionEvent::EnteredIdle => RecordedEvent::EnteredIdle,
+            ConnectionEvent::ExitedIdle => RecordedEvent::ExitedIdle,
+        };
+        self.received.push(recorded);
+    }
+}
+
+/// A [`ConnectionEvent`] received by a [`ScriptedHandler`], owning all of its data.
+#[non_exhaustive]
+pub enum RecordedEvent<TInbound: InboundUpgradeSend, TOutbound: OutboundUpgradeSend> {
+    /// See [`ConnectionEvent::FullyNegotiatedInbound`].
+    FullyNegotiatedInbound {
+        output: TInbound::Output,
+        negotiated_protocol: TInbound::Info,
+    },
+    /// See [`ConnectionEvent::FullyNegotiatedOutbound`].
+    FullyNegotiatedOutbound {
+        output: TOutbound::Output,
+        negotiated_protocol: TOutbound::Info,
+    },
+    /// See [`ConnectionEvent::AddressChange`], with the new address.
+    AddressChange(Multiaddr),
+    /// See [`ConnectionEvent::DialUpgradeError`].
+    DialUpgradeError(StreamUpgradeError<TOutbound::Error>),
+    /// See [`ConnectionEvent::ListenUpgradeError`].
+    ListenUpgradeError(TInbound::Error),
+    /// See [`ConnectionEvent::LocalProtocolsChange`].
+    LocalProtocolsChange(ProtocolsDelta),
+    /// See [`ConnectionEvent::RemoteProtocolsChange`].
+    RemoteProtocolsChange(ProtocolsDelta),
+    /// See [`ConnectionEvent::StreamHalfClosed`].
+    StreamHalfClosed,
+    /// See [`ConnectionEvent::OutboundStreamsStalled`].
+    OutboundStreamsStalled,
+    /// See [`ConnectionEvent::OutboundCapacity`].
+    OutboundCapacity { available: bool },
+    /// See [`ConnectionEvent::EnteredIdle`].
+    EnteredIdle,
+    /// See [`ConnectionEvent::ExitedIdle`].
+    ExitedIdle,
+}
+
+impl<TInbound, TOutbound> fmt::Debug for RecordedEvent<TInbound, TOutbound>
+where
+    TInbound: InboundUpgradeSend,
+    TInbound::Info: fmt::Debug,
+    TInbound::Output: fmt::Debug,
+    TInbound::Error: fmt::Debug,
+    TOutbound: OutboundUpgradeSend,
+    TOutbound::Info: fmt::Debug,
+    TOutbound::Output: fmt::Debug,
+    TOutbound::Error: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordedEvent::FullyNegotiatedInbound {
+                output,
+                negotiated_protocol,
+            } => f
+                .debug_struct("FullyNegotiatedInbound")
+                .field("output", output)
+                .field("negotiated_protocol", negotiated_protocol)
+                .finish(),
+            RecordedEvent::FullyNegotiatedOutbound {
+                output,
+                negotiated_protocol,
+            } => f
+                .debug_struct("FullyNegotiatedOutbound")
+                .field("output", output)
+                .field("negotiated_protocol", negotiated_protocol)
+                .finish(),
+            RecordedEvent::AddressChange(v) => f.debug_tuple("AddressChange").field(v).finish(),
+            RecordedEvent::DialUpgradeError(v) => {
+                f.debug_tuple("DialUpgradeError").field(v).finish()
+            }
+            RecordedEvent::ListenUpgradeError(v) => {
+                f.debug_tuple("ListenUpgradeError").field(v).finish()
+            }
+            RecordedEvent::LocalProtocolsChange(v) => {
+                f.debug_tuple("LocalProtocolsChange").field(v).finish()
+            }
+            RecordedEvent::RemoteProtocolsChange(v) => {
+                f.debug_tuple("RemoteProtocolsChange").field(v).finish()
+            }
+            RecordedEvent::StreamHalfClosed => f.write_str("StreamHalfClosed"),
+            RecordedEvent::OutboundStreamsStalled => f.write_str("OutboundStreamsStalled"),
+            RecordedEvent::OutboundCapacity { available } => f
+                .debug_struct("OutboundCapacity")
+                .field("available", available)
+                .finish(),
+            RecordedEvent::EnteredIdle => f.write_str("EnteredIdle"),
+            RecordedEvent::ExitedIdle => f.write_str("ExitedIdle"),
+        }
+    }
+}
+
+/// The protocols of a [`ProtocolsChange`], see [`RecordedEvent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolsDelta {
+    Added(Vec<StreamProtocol>),
+    Removed(Vec<StreamProtocol>),
+}
+
+impl From<ProtocolsChange<'_>> for ProtocolsDelta {
+    fn from(change: ProtocolsChange<'_>) -> Self {
+        match change {
+            ProtocolsChange::Added(added) => ProtocolsDelta::Added(added.cloned().collect()),
+            ProtocolsChange::Removed(removed) => {
+                ProtocolsDelta::Removed(removed.cloned().collect())
+            }
+        }
+    }
+}
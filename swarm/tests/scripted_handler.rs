@@ -0,0 +1,117 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{io::Cursor, AsyncRead, AsyncWrite};
+use libp2p_core::{
+    muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent},
+    upgrade::{DeniedUpgrade, ReadyUpgrade},
+};
+use libp2p_identity::PeerId;
+use libp2p_swarm::{
+    testing::{self, RecordedEvent, ScriptedHandler},
+    ConnectionBuilder, ConnectionHandlerEvent, StreamProtocol, StreamUpgradeError,
+    SubstreamProtocol,
+};
+
+#[test]
+fn rejected_outbound_protocol_is_reported_as_negotiation_failure() {
+    let mut handler =
+        ScriptedHandler::<DeniedUpgrade, ReadyUpgrade<StreamProtocol>>::new(DeniedUpgrade);
+    handler.push_event(ConnectionHandlerEvent::OutboundSubstreamRequest {
+        protocol: SubstreamProtocol::new(ReadyUpgrade::new(StreamProtocol::new("/foo")), ()),
+    });
+    let mut connection = ConnectionBuilder::new(PeerId::random())
+        .build(StreamMuxerBox::new(RejectingStreamMuxer), handler);
+
+    let (events, error) = testing::drive(&mut connection, 10);
+    assert!(events.is_empty());
+    assert!(error.is_none());
+
+    let dial_errors = connection
+        .handler()
+        .received()
+        .iter()
+        .filter(|event| matches!(event, RecordedEvent::DialUpgradeError(_)))
+        .collect::<Vec<_>>();
+    assert!(
+        matches!(
+            dial_errors[..],
+            [RecordedEvent::DialUpgradeError(
+                StreamUpgradeError::NegotiationFailed
+            )]
+        ),
+        "unexpected dial upgrade errors: {dial_errors:?}"
+    );
+}
+
+/// A [`StreamMuxer`] whose outbound streams are connected to a remote that supports no protocols.
+struct RejectingStreamMuxer;
+
+impl StreamMuxer for RejectingStreamMuxer {
+    type Substream = RejectingSubstream;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        Poll::Pending
+    }
+
+    fn poll_outbound(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        // The multistream-select header, followed by rejecting the proposed protocol.
+        Poll::Ready(Ok(RejectingSubstream {
+            response: Cursor::new(b"\x13/multistream/1.0.0\n\x03na\n"),
+        }))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Poll::Pending
+    }
+}
+
+/// Replies with a fixed response and discards everything written to it.
+struct RejectingSubstream {
+    response: Cursor<&'static [u8]>,
+}
+
+impl AsyncRead for RejectingSubstream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.response).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RejectingSubstream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}